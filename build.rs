@@ -5,18 +5,41 @@
  * License: Apache License 2.0
  **********************************************************************************************************************/
 //! Build script to pre-compile the assembly files containing the majority of the boot up and initial configuration
-//! code
+//! code, to generate the linker script from the layout the code is built with and to pass the build information and
+//! the public key kernels are verified with to the loader
 //!
 
 extern crate cc;
-use std::env;
+use std::{
+    env, fs,
+    path::Path,
+    process::{self, Command},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[allow(dead_code)]
+#[path = "src/board.rs"]
+mod board;
+#[allow(dead_code)]
+#[path = "src/layout.rs"]
+mod layout;
 
 fn main() {
     if let Some(target_arch) = env::var_os("CARGO_CFG_TARGET_ARCH") {
         if env::var_os("CARGO_FEATURE_RUSPIRO_PI3").is_some() && target_arch == "aarch64" {
+            // the parking code of the aarch32 cores polls the mailboxes of the ARM local peripherals, the secondary
+            // cores take their stacks of the size the linker script reserves
             cc::Build::new()
                 .file("src/asm/bootstrap.S")
                 .flag("-march=armv8-a")
+                .define(
+                    "ARM_LOCAL_BASE",
+                    Some(format!("{:#x}", board::ARM_LOCAL_BASE).as_str()),
+                )
+                .define(
+                    "CORE_STACK_SIZE",
+                    Some(format!("{:#x}", layout::CORE_STACK_SIZE).as_str()),
+                )
                 .compile("bootstrap");
             cc::Build::new()
                 .file("src/asm/exceptionvector.S")
@@ -24,4 +47,311 @@ fn main() {
                 .compile("excvector");
         }
     }
+
+    if let Err(err) = validate_layout() {
+        eprintln!("invalid loader layout: {}", err);
+        process::exit(1);
+    }
+    generate_linker_script();
+    emit_build_info();
+    if let Err(err) = emit_fallback() {
+        eprintln!("invalid SD card fallback: {}", err);
+        process::exit(1);
+    }
+    if let Err(err) = generate_uart_config() {
+        eprintln!("invalid UART configuration: {}", err);
+        process::exit(1);
+    }
+    if env::var_os("CARGO_FEATURE_SIGNED_KERNELS").is_some() {
+        if let Err(err) = generate_public_key() {
+            eprintln!("invalid public key: {}", err);
+            process::exit(1);
+        }
+    }
+
+    println!("cargo:rerun-if-changed=linkbl.ld.in");
+    println!("cargo:rerun-if-changed=src/layout.rs");
+    println!("cargo:rerun-if-changed=src/board.rs");
+    println!("cargo:rerun-if-changed=src/asm/bootstrap.S");
+    println!("cargo:rerun-if-changed=src/asm/exceptionvector.S");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-env-changed=RUSPIRO_LOADER_PUBLIC_KEY");
+    println!("cargo:rerun-if-env-changed=RUSPIRO_LOADER_FALLBACK_TIMEOUT");
+    println!("cargo:rerun-if-env-changed=RUSPIRO_LOADER_FALLBACK_KERNEL");
+    println!("cargo:rerun-if-env-changed=RUSPIRO_LOADER_PL011_BAUD_RATE");
+    println!("cargo:rerun-if-env-changed=RUSPIRO_LOADER_DMA_BUFFER_SIZE");
+    println!("cargo:rerun-if-env-changed=RUSPIRO_LOADER_PL011_PINS");
+}
+
+/// Check the layout against the regions reserved by the board. What could only be checked once the sections are
+/// placed is asserted in the linker script.
+fn validate_layout() -> Result<(), String> {
+    use layout::*;
+
+    if LOAD_ADDRESS < board::SPIN_TABLE_END {
+        return Err(format!(
+            "the load address {:#x} overlaps the firmware stub and spin table",
+            LOAD_ADDRESS
+        ));
+    }
+    if LOAD_ADDRESS < board::KERNEL_ADDRESS_64 {
+        return Err(format!(
+            "the load address {:#x} is below the address aarch64 kernels are placed at",
+            LOAD_ADDRESS
+        ));
+    }
+    if LOADER_ADDRESS <= LOAD_ADDRESS || LOADER_ADDRESS % board::BLOCK_SIZE != 0 {
+        return Err(format!(
+            "the loader address {:#x} need to be a {:#x} aligned address above the load address",
+            LOADER_ADDRESS,
+            board::BLOCK_SIZE
+        ));
+    }
+    if CORE_STACK_SIZE % 16 != 0 || EL_STACK_SIZE % 16 != 0 {
+        return Err("the stack sizes need to be multiples of 16".into());
+    }
+    if !HEAP_ALIGN.is_power_of_two() {
+        return Err(format!(
+            "the heap alignment {:#x} is not a power of 2",
+            HEAP_ALIGN
+        ));
+    }
+    if HEAP_END > board::PERIPHERAL_BASE {
+        return Err(format!(
+            "the heap end {:#x} overlaps the peripherals at {:#x}",
+            HEAP_END,
+            board::PERIPHERAL_BASE
+        ));
+    }
+    if HEAP_END > board::MEMORY_SIZE {
+        return Err(format!(
+            "the heap end {:#x} is beyond the memory of the board ending at {:#x}",
+            HEAP_END,
+            board::MEMORY_SIZE
+        ));
+    }
+    let stacks_end = LOADER_ADDRESS + 3 * CORE_STACK_SIZE + 4 * EL_STACK_SIZE;
+    if stacks_end >= HEAP_END {
+        return Err(format!(
+            "the stacks end at {:#x} beyond the heap end {:#x}",
+            stacks_end, HEAP_END
+        ));
+    }
+    // the received kernel and the kernel inflated from it are kept in the heap at the same time
+    if stacks_end + 2 * MAX_TRANSFER_SIZE > HEAP_END {
+        return Err(format!(
+            "the heap ending at {:#x} does not take two kernels of {:#x} bytes",
+            HEAP_END, MAX_TRANSFER_SIZE
+        ));
+    }
+    if IMAGE_BASE < HEAP_END || IMAGE_BASE % board::BLOCK_SIZE != 0 {
+        return Err(format!(
+            "the kernel image base {:#x} need to be a {:#x} aligned address above the heap end {:#x}",
+            IMAGE_BASE,
+            board::BLOCK_SIZE,
+            HEAP_END
+        ));
+    }
+    if IMAGE_BASE >= board::MEMORY_SIZE.min(board::PERIPHERAL_BASE) {
+        return Err(format!(
+            "the kernel image base {:#x} is beyond the memory of the board",
+            IMAGE_BASE
+        ));
+    }
+    Ok(())
+}
+
+/// Pass the git revision, the build time and the enabled features to the loader, see ``src/buildinfo.rs``
+fn emit_build_info() {
+    let revision = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+        .map_or(false, |status| !status.is_empty());
+    println!(
+        "cargo:rustc-env=LOADER_GIT_REVISION={}{}",
+        revision,
+        if dirty { "-dirty" } else { "" }
+    );
+
+    // reproducible builds pass the time of the sources instead of the current time
+    let secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs())
+        });
+    println!("cargo:rustc-env=LOADER_BUILD_TIME={}", utc_time(secs));
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(str::to_lowercase))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=LOADER_FEATURES={}", features.join(","));
+}
+
+/// Pass the seconds without a host after which the kernel of the SD card is booted, 0 to never boot it, and the path
+/// of this kernel to the loader, see ``src/loader.rs``
+fn emit_fallback() -> Result<(), String> {
+    let timeout = env::var("RUSPIRO_LOADER_FALLBACK_TIMEOUT").unwrap_or_else(|_| "0".into());
+    let timeout = timeout.trim();
+    if timeout.parse::<u32>().is_err() {
+        return Err(format!("{} is not a timeout in seconds", timeout));
+    }
+    let kernel =
+        env::var("RUSPIRO_LOADER_FALLBACK_KERNEL").unwrap_or_else(|_| "kernel8.img".into());
+    let kernel = kernel.trim();
+    if kernel.is_empty() || kernel.contains('\\') {
+        return Err(format!(
+            "{:?} is not a path within the boot partition",
+            kernel
+        ));
+    }
+    println!("cargo:rustc-env=LOADER_FALLBACK_TIMEOUT={}", timeout);
+    println!("cargo:rustc-env=LOADER_FALLBACK_KERNEL={}", kernel);
+    Ok(())
+}
+
+/// Write the baud rate of the PL011 given with ``RUSPIRO_LOADER_PL011_BAUD_RATE``, the first of its pins on the
+/// carrier board of a compute module given with ``RUSPIRO_LOADER_PL011_PINS`` and the size of its DMA receive buffers
+/// given with ``RUSPIRO_LOADER_DMA_BUFFER_SIZE`` for the loader to include, see ``src/pl011.rs``
+fn generate_uart_config() -> Result<(), String> {
+    let baud_rate = env::var("RUSPIRO_LOADER_PL011_BAUD_RATE").unwrap_or_else(|_| "115200".into());
+    let baud_rate = baud_rate.trim();
+    // the UART samples with 16 times the baud rate, its clock is usually 48MHz
+    match baud_rate.parse::<u32>() {
+        Ok(rate) if rate > 0 && rate <= 3_000_000 => (),
+        _ => return Err(format!("{} is not a baud rate up to 3000000", baud_rate)),
+    }
+    let buffer_size = env::var("RUSPIRO_LOADER_DMA_BUFFER_SIZE").unwrap_or_else(|_| "4096".into());
+    let buffer_size = buffer_size.trim();
+    // each byte takes a 32 bit word, a buffer need to cover whole pages and fit a transfer of the lite DMA channels
+    match buffer_size.parse::<u32>() {
+        Ok(size) if size.is_power_of_two() && size >= 1024 && size <= 8192 => (),
+        _ => {
+            return Err(format!(
+                "{} is not a buffer size of 1024, 2048, 4096 or 8192 bytes",
+                buffer_size
+            ))
+        }
+    }
+    let pins = env::var("RUSPIRO_LOADER_PL011_PINS").unwrap_or_else(|_| "32/33".into());
+    // the pin header of the boards carries GPIO 14/15 for the miniUART, carrier boards of the compute modules bring
+    // out either of the other pin pairs the PL011 could be routed to
+    let tx_pin = match pins.trim() {
+        "32/33" => "32",
+        "36/37" => "36",
+        pins => return Err(format!("{} are not the PL011 pins 32/33 or 36/37", pins)),
+    };
+    let out_dir = Path::new(&env::var_os("OUT_DIR").unwrap()).to_path_buf();
+    for &(name, value) in [
+        ("pl011_baud_rate.rs", baud_rate),
+        ("pl011_tx_pin.rs", tx_pin),
+        ("dma_buffer_size.rs", buffer_size),
+    ]
+    .iter()
+    {
+        let target = out_dir.join(name);
+        fs::write(&target, format!("{}\n", value))
+            .map_err(|err| format!("writing {} failed: {}", target.display(), err))?;
+    }
+    Ok(())
+}
+
+/// Write the ed25519 public key given as 64 hex digits with ``RUSPIRO_LOADER_PUBLIC_KEY`` as byte array the loader
+/// includes, see ``src/verify.rs``
+fn generate_public_key() -> Result<(), String> {
+    let key = env::var("RUSPIRO_LOADER_PUBLIC_KEY")
+        .map_err(|_| "signed_kernels requires RUSPIRO_LOADER_PUBLIC_KEY".to_string())?;
+    let key = key.trim();
+    if key.len() != 64 || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("{} is not a key of 64 hex digits", key));
+    }
+    let bytes: Vec<String> = (0..key.len())
+        .step_by(2)
+        .map(|idx| format!("0x{}", &key[idx..idx + 2]))
+        .collect();
+    let target = Path::new(&env::var_os("OUT_DIR").unwrap()).join("public_key.rs");
+    fs::write(&target, format!("[{}]\n", bytes.join(", ")))
+        .map_err(|err| format!("writing {} failed: {}", target.display(), err))
+}
+
+/// Run git with the arguments and return its trimmed output, ``None`` if it could not be run or failed
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Format the seconds since the epoch as UTC date and time, the date is calculated from the days since the epoch
+/// with the civil calendar algorithm by Howard Hinnant
+fn utc_time(secs: u64) -> String {
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let time = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Generate the linker script from its template and place it in the crate root, where the linker is pointed to
+fn generate_linker_script() {
+    let template =
+        fs::read_to_string("linkbl.ld.in").expect("linker script template linkbl.ld.in missing");
+    let values = [
+        ("{LOAD_ADDRESS}", format!("{:#x}", layout::LOAD_ADDRESS)),
+        ("{LOADER_ADDRESS}", format!("{:#x}", layout::LOADER_ADDRESS)),
+        (
+            "{CORE_STACK_SIZE}",
+            format!("{:#x}", layout::CORE_STACK_SIZE),
+        ),
+        ("{EL_STACK_SIZE}", format!("{:#x}", layout::EL_STACK_SIZE)),
+        ("{HEAP_ALIGN}", format!("{:#x}", layout::HEAP_ALIGN)),
+        ("{HEAP_END}", format!("{:#x}", layout::HEAP_END)),
+        (
+            "{MAX_TRANSFER_SIZE}",
+            format!("{:#x}", layout::MAX_TRANSFER_SIZE),
+        ),
+        (
+            "{PERIPHERAL_BASE}",
+            format!("{:#x}", board::PERIPHERAL_BASE),
+        ),
+    ];
+    let script = values
+        .iter()
+        .fold(template, |script, (key, value)| script.replace(key, value));
+
+    let out_dir = Path::new(&env::var_os("OUT_DIR").unwrap()).to_path_buf();
+    let target = out_dir.join("linkbl.ld");
+    // only touch the linker script if it has changed to not trigger needless relinking
+    if fs::read_to_string(&target).ok().as_deref() != Some(script.as_str()) {
+        fs::write(&target, script).expect("writing the linker script failed");
+    }
+    // the linker finds the script given with -Tlinkbl.ld in the search path, but a script left in the package
+    // directory by earlier builds would be found first
+    let _ =
+        fs::remove_file(Path::new(&env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("linkbl.ld"));
+    println!("cargo:rustc-link-search=native={}", out_dir.display());
 }
@@ -11,7 +11,7 @@
 set +ev
 
 export CFLAGS="-march=armv8-a -Wall -O3 -nostdlib -nostartfiles -ffreestanding -mtune=cortex-a53"
-export RUSTFLAGS="-C target-cpu=cortex-a53 -C target-feature=+strict-align,+a53,+fp-armv8,+neon -C link-arg=-nostartfiles -C opt-level=3 -C debuginfo=0 -C link-arg=-T./linkbl.ld"
+export RUSTFLAGS="-C target-cpu=cortex-a53 -C target-feature=+strict-align,+a53,+fp-armv8,+neon -C link-arg=-nostartfiles -C opt-level=3 -C debuginfo=0 -C link-arg=-Tlinkbl.ld"
 
 # if there is any 1 command line argument given don't set the CC/AR variables
 # this is required to be set only when building locally, but not on travis
@@ -23,7 +23,8 @@ if [ -z "$1" ]
         export RUSTFLAGS="-C linker=aarch64-elf-gcc ${RUSTFLAGS}"
 fi
 
-cargo xbuild --target aarch64-unknown-linux-gnu --release --target-dir ./target/
+# additional features of the loader could be given like LOADER_FEATURES="no_panic" ./build.sh
+cargo xbuild --target aarch64-unknown-linux-gnu --release --target-dir ./target/ --features "${LOADER_FEATURES}"
 # only local builds need the final binary img file to be used on actual hardware
 # no need to provide this on travis build
 if [ -z "$1" ]
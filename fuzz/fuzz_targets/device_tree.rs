@@ -0,0 +1,40 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+#![no_main]
+
+//! # Fuzz the device tree parser
+//!
+//! Validate arbitrary blobs as device tree and patch the ``/chosen`` node of the accepted ones. The parser is taken
+//! from the sources of the loader, it must never panic or read beyond the blob. A patched device tree must be
+//! valid again.
+//!
+
+extern crate alloc;
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/fdt.rs"]
+mod fdt;
+
+use fdt::{ChosenPatch, Fdt};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let fdt = match Fdt::from_slice(data) {
+        Ok(fdt) => fdt,
+        Err(_) => return,
+    };
+    assert!(fdt.total_size() <= data.len());
+    let patch = ChosenPatch {
+        bootargs: Some("console=serial0,115200"),
+        initrd: Some((0x0200_0000, 0x0280_0000)),
+        reserve: Some((0x0800_0000, 0x10_0000)),
+    };
+    if let Ok(patched) = fdt.patch_chosen(&patch) {
+        let patched = Fdt::from_slice(&patched).expect("the patched device tree is not valid");
+        assert!(patched.patch_chosen(&patch).is_ok());
+    }
+});
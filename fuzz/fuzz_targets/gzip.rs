@@ -0,0 +1,42 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+#![no_main]
+
+//! # Fuzz the gzip inflater
+//!
+//! Inflate arbitrary binaries as compressed kernels. The inflater is taken from the sources of the loader, it must
+//! never panic, read beyond the binary nor produce more data than allowed. Inflated data always matches the trailer.
+//!
+
+extern crate alloc;
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/crc.rs"]
+mod crc;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/gzip.rs"]
+mod gzip;
+
+use libfuzzer_sys::fuzz_target;
+
+/// The largest inflated size accepted, small enough to not run out of memory on any input
+const MAX_SIZE: usize = 0x10_0000;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(inflated) = gzip::decompress(data, MAX_SIZE) {
+        let trailer = &data[data.len() - 8..];
+        assert!(inflated.len() <= MAX_SIZE);
+        assert_eq!(
+            inflated.len() as u32,
+            u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]])
+        );
+        assert_eq!(
+            crc::crc32(&inflated),
+            u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]])
+        );
+    }
+});
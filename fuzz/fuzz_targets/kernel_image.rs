@@ -0,0 +1,46 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+#![no_main]
+
+//! # Fuzz the kernel image parsers
+//!
+//! Run the format check and the header parsers of the loader over arbitrary kernel binaries. The parsers are taken
+//! from the sources of the loader, they must never panic or read beyond the binary. The layout of an ELF kernel
+//! accepted must cover its entry address.
+//!
+
+extern crate alloc;
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/crc.rs"]
+mod crc;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/elf.rs"]
+mod elf;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/gzip.rs"]
+mod gzip;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/image.rs"]
+mod image;
+
+use image::{Arm64Image, BootMode, KernelHeader};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = image::check_format(data, BootMode::Aarch32);
+    let _ = image::check_format(data, BootMode::Aarch64);
+    if let Some(Ok(offset)) = KernelHeader::find(data) {
+        assert!(offset + core::mem::size_of::<KernelHeader>() <= data.len());
+    }
+    if let Some(Ok(image)) = Arm64Image::parse(data) {
+        assert!(data.len() as u64 <= image.image_size);
+    }
+    if let Ok(layout) = elf::parse(data) {
+        assert!(layout.start <= layout.entry && layout.entry < layout.end);
+    }
+});
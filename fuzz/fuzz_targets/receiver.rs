@@ -0,0 +1,36 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+#![no_main]
+
+//! # Fuzz the receiver
+//!
+//! Feed arbitrary data to the receiver. Each input byte is preceded by the time passed since the previous one, so
+//! timeouts are hit as well. The receiver must never panic, never acknowledge more than the header and the binary
+//! at once and only hand out kernels of the size announced.
+//!
+
+use libfuzzer_sys::fuzz_target;
+use ruspiro_loader_protocol::{Received, Receiver, MAX_COMMAND_LEN};
+
+const TIMEOUT: u64 = 100;
+const MAX_SIZE: usize = 0x1_0000;
+
+fuzz_target!(|data: &[u8]| {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    let mut now = 0;
+    for pair in data.chunks_exact(2) {
+        now += pair[0] as u64;
+        receiver.poll(now);
+        let output = receiver.receive(pair[1], now);
+        assert!(output.acks <= 2);
+        match output.request {
+            Some(Received::Kernel(kernel)) => assert!(kernel.binary.len() <= MAX_SIZE),
+            Some(Received::Command(line)) => assert!(line.len() <= MAX_COMMAND_LEN),
+            None => (),
+        }
+    }
+});
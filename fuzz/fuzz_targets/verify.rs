@@ -0,0 +1,43 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+#![no_main]
+
+//! # Fuzz the kernel verification
+//!
+//! Verify arbitrary binaries as kernels with a verification header and arbitrary public keys and signatures with
+//! ed25519. The verification is taken from the sources of the loader, it must never panic. A verified payload lies
+//! within the binary and matches its digest.
+//!
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/ed25519.rs"]
+mod ed25519;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/sha2.rs"]
+mod sha2;
+// the signatures are verified with the ed25519 module directly, the loader feature requiring them is not known here
+#[allow(dead_code, unexpected_cfgs, clippy::all)]
+#[path = "../../src/verify.rs"]
+mod verify;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(payload) = verify::verify(data) {
+        assert!(payload.start == verify::HEADER_SIZE && payload.end <= data.len());
+        assert_eq!(sha2::sha256(&data[payload])[..], data[16..48]);
+    }
+    if data.len() >= ed25519::PUBLIC_KEY_SIZE + ed25519::SIGNATURE_SIZE {
+        let (key, rest) = data.split_at(ed25519::PUBLIC_KEY_SIZE);
+        let (signature, message) = rest.split_at(ed25519::SIGNATURE_SIZE);
+        let mut public_key = [0; ed25519::PUBLIC_KEY_SIZE];
+        public_key.copy_from_slice(key);
+        let mut bytes = [0; ed25519::SIGNATURE_SIZE];
+        bytes.copy_from_slice(signature);
+        let _ = ed25519::verify(&public_key, message, &bytes);
+    }
+});
@@ -0,0 +1,38 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+#![no_main]
+
+//! # Fuzz the XMODEM receiver
+//!
+//! Feed arbitrary data to the XMODEM receiver, each input byte preceded by the time passed since the previous one
+//! like for the receiver of the host tool's protocol. The receiver must never panic, never hand out a file larger
+//! than accepted or cut the data of a YMODEM file to another size than the header has given.
+//!
+
+use libfuzzer_sys::fuzz_target;
+use ruspiro_loader_protocol::xmodem::Receiver;
+
+const TIMEOUT: u64 = 100;
+const MAX_SIZE: usize = 0x1_0000;
+
+fuzz_target!(|data: &[u8]| {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    let mut now = 0;
+    for pair in data.chunks_exact(2) {
+        now += pair[0] as u64;
+        let outputs = [receiver.poll(now), receiver.receive(pair[1], now)];
+        for output in outputs.iter() {
+            assert!(output.reply.len() <= 2);
+            if let Some(Ok(file)) = &output.done {
+                assert!(file.data.len() <= MAX_SIZE);
+                if let Some(size) = file.size {
+                    assert!(file.data.len() <= size);
+                }
+            }
+        }
+    }
+});
@@ -0,0 +1,22 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+//! Build script providing the public key the verification of the loader checks the signatures with. The tests sign
+//! with the secret key of the first test vector of RFC 8032, so this is its public key.
+//!
+
+use std::{env, fs, path::Path};
+
+const PUBLIC_KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+
+fn main() {
+    let bytes: Vec<String> = (0..PUBLIC_KEY.len())
+        .step_by(2)
+        .map(|idx| format!("0x{}", &PUBLIC_KEY[idx..idx + 2]))
+        .collect();
+    let target = Path::new(&env::var_os("OUT_DIR").unwrap()).join("public_key.rs");
+    fs::write(&target, format!("[{}]\n", bytes.join(", "))).expect("writing the public key failed");
+}
@@ -0,0 +1,75 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Shared helpers of the tests
+//!
+//! The block device the file systems and partition tables are read from is an image in memory built by the tests.
+//!
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+
+/// A block device backed by an image in memory, accessing blocks beyond its end fails
+pub struct Image {
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// An image of the given number of blocks filled with zeros
+    pub fn new(blocks: usize) -> Self {
+        Image {
+            data: vec![0; blocks * BLOCK_SIZE],
+        }
+    }
+
+    /// The data of the given block and the following ones
+    pub fn blocks_mut(&mut self, block: usize, count: usize) -> &mut [u8] {
+        &mut self.data[block * BLOCK_SIZE..(block + count) * BLOCK_SIZE]
+    }
+
+    fn range(&self, block: u64, len: usize) -> Result<core::ops::Range<usize>, ()> {
+        let start = block as usize * BLOCK_SIZE;
+        if !len.is_multiple_of(BLOCK_SIZE) || start + len > self.data.len() {
+            return Err(());
+        }
+        Ok(start..start + len)
+    }
+}
+
+impl BlockDevice for Image {
+    type Error = ();
+
+    fn read_blocks(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let range = self.range(block, buffer.len())?;
+        buffer.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, block: u64, data: &[u8]) -> Result<(), Self::Error> {
+        let range = self.range(block, data.len())?;
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+pub fn put16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn put32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn put64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Data of the given length that differs for each seed, so misplaced clusters or blocks do not go unnoticed
+pub fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|idx| (idx as u8).wrapping_mul(31).wrapping_add(seed) ^ (idx >> 8) as u8)
+        .collect()
+}
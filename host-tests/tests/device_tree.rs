@@ -0,0 +1,381 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Device tree tests
+//!
+//! Patch the ``/chosen`` node of small device trees built here and check which properties the patched ones contain.
+//!
+
+extern crate alloc;
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/fdt.rs"]
+mod fdt;
+
+use fdt::{ChosenPatch, Fdt};
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+/// A node of the device trees built and read by the tests
+#[derive(Debug, Default)]
+struct Node {
+    name: String,
+    props: Vec<(String, Vec<u8>)>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    fn new(name: &str, props: &[(&str, &[u8])], children: Vec<Node>) -> Self {
+        Node {
+            name: name.to_string(),
+            props: props
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_vec()))
+                .collect(),
+            children,
+        }
+    }
+
+    fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    fn prop(&self, name: &str) -> Option<&[u8]> {
+        self.props
+            .iter()
+            .find(|(prop, _)| prop == name)
+            .map(|(_, value)| &value[..])
+    }
+}
+
+fn put_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_be_bytes());
+}
+
+fn pad4(data: &mut Vec<u8>) {
+    data.resize((data.len() + 3) & !3, 0);
+}
+
+fn be32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn put_node(node: &Node, dt_struct: &mut Vec<u8>, strings: &mut Vec<u8>) {
+    put_u32(dt_struct, FDT_BEGIN_NODE);
+    dt_struct.extend_from_slice(node.name.as_bytes());
+    dt_struct.push(0);
+    pad4(dt_struct);
+    for (name, value) in node.props.iter() {
+        put_u32(dt_struct, FDT_PROP);
+        put_u32(dt_struct, value.len() as u32);
+        put_u32(dt_struct, strings.len() as u32);
+        strings.extend_from_slice(name.as_bytes());
+        strings.push(0);
+        dt_struct.extend_from_slice(value);
+        pad4(dt_struct);
+    }
+    for child in node.children.iter() {
+        put_node(child, dt_struct, strings);
+    }
+    put_u32(dt_struct, FDT_END_NODE);
+}
+
+/// Build a device tree blob of version 17 with an empty memory reservation block
+fn build(root: &Node) -> Vec<u8> {
+    let mut dt_struct = Vec::new();
+    let mut strings = Vec::new();
+    put_node(root, &mut dt_struct, &mut strings);
+    put_u32(&mut dt_struct, FDT_END);
+    let rsvmap_offset = 40;
+    let struct_offset = rsvmap_offset + 16;
+    let strings_offset = struct_offset + dt_struct.len();
+    let total_size = strings_offset + strings.len();
+    let mut blob = Vec::new();
+    for &value in [
+        0xD00D_FEED,
+        total_size,
+        struct_offset,
+        strings_offset,
+        rsvmap_offset,
+        17,
+        16,
+        0,
+        strings.len(),
+        dt_struct.len(),
+    ]
+    .iter()
+    {
+        put_u32(&mut blob, value as u32);
+    }
+    blob.extend_from_slice(&[0; 16]);
+    blob.extend_from_slice(&dt_struct);
+    blob.extend_from_slice(&strings);
+    blob
+}
+
+/// Read the nodes of the structure block of a device tree blob
+fn parse(blob: &[u8]) -> Node {
+    let struct_offset = be32(blob, 8) as usize;
+    let strings_offset = be32(blob, 12) as usize;
+    let c_str = |offset: usize| {
+        let len = blob[offset..].iter().position(|&b| b == 0).unwrap();
+        String::from_utf8(blob[offset..offset + len].to_vec()).unwrap()
+    };
+    let mut stack: Vec<Node> = Vec::new();
+    let mut offset = struct_offset;
+    loop {
+        let token = be32(blob, offset);
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = c_str(offset);
+                offset = (offset + name.len() + 4) & !3;
+                stack.push(Node::new(&name, &[], Vec::new()));
+            }
+            FDT_PROP => {
+                let len = be32(blob, offset) as usize;
+                let name = c_str(strings_offset + be32(blob, offset + 4) as usize);
+                let value = blob[offset + 8..offset + 8 + len].to_vec();
+                offset = (offset + 8 + len + 3) & !3;
+                let node = stack.last_mut().unwrap();
+                assert!(
+                    node.children.is_empty(),
+                    "property {} follows a child node",
+                    name
+                );
+                node.props.push((name, value));
+            }
+            FDT_END_NODE => {
+                let node = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(node),
+                    None => return node,
+                }
+            }
+            FDT_END => panic!("the structure block ends within a node"),
+            _ => (),
+        }
+    }
+}
+
+/// Read the entries of the memory reservation block of a device tree blob without the terminating entry
+fn reservations(blob: &[u8]) -> Vec<(u64, u64)> {
+    let be64 = |offset: usize| (be32(blob, offset) as u64) << 32 | be32(blob, offset + 4) as u64;
+    let mut entries = Vec::new();
+    let mut offset = be32(blob, 16) as usize;
+    while (be64(offset), be64(offset + 8)) != (0, 0) {
+        entries.push((be64(offset), be64(offset + 8)));
+        offset += 16;
+    }
+    entries
+}
+
+fn patch(root: &Node, patch: &ChosenPatch) -> Node {
+    let blob = build(root);
+    let patched = Fdt::from_slice(&blob).unwrap().patch_chosen(patch).unwrap();
+    assert_eq!(
+        Fdt::from_slice(&patched).unwrap().total_size(),
+        patched.len()
+    );
+    parse(&patched)
+}
+
+fn firmware_chosen() -> Node {
+    Node::new(
+        "chosen",
+        &[
+            ("bootargs", b"console=tty1\0"),
+            ("linux,initrd-start", &0x0200_0000u32.to_be_bytes()),
+            ("linux,initrd-end", &0x0280_0000u32.to_be_bytes()),
+            ("stdout-path", b"serial0\0"),
+        ],
+        Vec::new(),
+    )
+}
+
+fn firmware_tree() -> Node {
+    Node::new(
+        "",
+        &[("model", b"Raspberry Pi 3 Model B Rev 1.2\0")],
+        vec![
+            firmware_chosen(),
+            Node::new("memory", &[("device_type", b"memory\0")], Vec::new()),
+        ],
+    )
+}
+
+#[test]
+fn bootargs_keep_the_initrd() {
+    let patched = patch(
+        &firmware_tree(),
+        &ChosenPatch {
+            bootargs: Some("console=serial0,115200"),
+            initrd: None,
+            reserve: None,
+        },
+    );
+    let chosen = patched.child("chosen").unwrap();
+    assert_eq!(chosen.props.len(), 4);
+    assert_eq!(
+        chosen.prop("bootargs").unwrap(),
+        b"console=serial0,115200\0"
+    );
+    assert_eq!(
+        chosen.prop("linux,initrd-start").unwrap(),
+        0x0200_0000u32.to_be_bytes()
+    );
+    assert_eq!(
+        chosen.prop("linux,initrd-end").unwrap(),
+        0x0280_0000u32.to_be_bytes()
+    );
+    assert_eq!(chosen.prop("stdout-path").unwrap(), b"serial0\0");
+}
+
+#[test]
+fn initrd_keeps_the_bootargs() {
+    let patched = patch(
+        &firmware_tree(),
+        &ChosenPatch {
+            bootargs: None,
+            initrd: Some((0x0300_0000, 0x0340_0000)),
+            reserve: None,
+        },
+    );
+    let chosen = patched.child("chosen").unwrap();
+    assert_eq!(chosen.props.len(), 4);
+    assert_eq!(chosen.prop("bootargs").unwrap(), b"console=tty1\0");
+    assert_eq!(
+        chosen.prop("linux,initrd-start").unwrap(),
+        0x0300_0000u64.to_be_bytes()
+    );
+    assert_eq!(
+        chosen.prop("linux,initrd-end").unwrap(),
+        0x0340_0000u64.to_be_bytes()
+    );
+    assert_eq!(chosen.prop("stdout-path").unwrap(), b"serial0\0");
+}
+
+#[test]
+fn other_nodes_are_kept() {
+    let patched = patch(
+        &firmware_tree(),
+        &ChosenPatch {
+            bootargs: Some("quiet"),
+            initrd: Some((0x0300_0000, 0x0340_0000)),
+            reserve: None,
+        },
+    );
+    assert_eq!(
+        patched.prop("model").unwrap(),
+        &b"Raspberry Pi 3 Model B Rev 1.2\0"[..]
+    );
+    assert_eq!(
+        patched
+            .child("memory")
+            .unwrap()
+            .prop("device_type")
+            .unwrap(),
+        b"memory\0"
+    );
+    assert_eq!(patched.child("chosen").unwrap().props.len(), 4);
+}
+
+#[test]
+fn chosen_is_created() {
+    let root = Node::new("", &[("model", b"test\0")], Vec::new());
+    let patched = patch(
+        &root,
+        &ChosenPatch {
+            bootargs: Some("quiet"),
+            initrd: None,
+            reserve: None,
+        },
+    );
+    let chosen = patched.child("chosen").unwrap();
+    assert_eq!(chosen.props.len(), 1);
+    assert_eq!(chosen.prop("bootargs").unwrap(), b"quiet\0");
+}
+
+#[test]
+fn nested_properties_are_kept() {
+    let mut chosen = firmware_chosen();
+    chosen.children.push(Node::new(
+        "framebuffer",
+        &[("bootargs", b"nested\0")],
+        Vec::new(),
+    ));
+    let root = Node::new("", &[], vec![chosen]);
+    let patched = patch(
+        &root,
+        &ChosenPatch {
+            bootargs: Some("quiet"),
+            initrd: None,
+            reserve: None,
+        },
+    );
+    let chosen = patched.child("chosen").unwrap();
+    assert_eq!(chosen.prop("bootargs").unwrap(), b"quiet\0");
+    assert_eq!(
+        chosen
+            .child("framebuffer")
+            .unwrap()
+            .prop("bootargs")
+            .unwrap(),
+        b"nested\0"
+    );
+}
+
+#[test]
+fn memory_is_reserved() {
+    let reserve = ChosenPatch {
+        bootargs: None,
+        initrd: None,
+        reserve: Some((0x0800_0000, 0x10_4000)),
+    };
+    let blob = build(&firmware_tree());
+    let patched = Fdt::from_slice(&blob)
+        .unwrap()
+        .patch_chosen(&reserve)
+        .unwrap();
+    assert_eq!(reservations(&patched), [(0x0800_0000, 0x10_4000)]);
+    assert_eq!(parse(&patched).child("chosen").unwrap().props.len(), 4);
+    // a device tree patched before keeps a single entry
+    let twice = Fdt::from_slice(&patched)
+        .unwrap()
+        .patch_chosen(&reserve)
+        .unwrap();
+    assert_eq!(reservations(&twice), [(0x0800_0000, 0x10_4000)]);
+    let other = ChosenPatch {
+        reserve: Some((0x0400_0000, 0x1000)),
+        ..reserve
+    };
+    let both = Fdt::from_slice(&twice)
+        .unwrap()
+        .patch_chosen(&other)
+        .unwrap();
+    assert_eq!(
+        reservations(&both),
+        [(0x0800_0000, 0x10_4000), (0x0400_0000, 0x1000)]
+    );
+}
+
+#[test]
+fn bad_blobs_are_refused() {
+    let blob = build(&firmware_tree());
+    assert!(Fdt::from_slice(&blob[..blob.len() - 1]).is_err());
+    let mut bad_magic = blob.clone();
+    bad_magic[0] ^= 1;
+    assert!(Fdt::from_slice(&bad_magic).is_err());
+}
@@ -0,0 +1,387 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # exFAT tests
+//!
+//! Look up and read the files of small exFAT images built here, chained in the allocation table and contiguous, with
+//! names spanning several name entries and clusters of two blocks. Broken cluster chains must be refused.
+//!
+
+extern crate alloc;
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/block.rs"]
+mod block;
+#[allow(dead_code)]
+mod common;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/exfat.rs"]
+mod exfat;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/fat.rs"]
+mod fat;
+
+use block::BLOCK_SIZE;
+use common::{pattern, put16, put32, put64, Image};
+use exfat::ExFat;
+use fat::FatError;
+
+const FAT_OFFSET: usize = 24;
+const FAT_BLOCKS: usize = 1;
+const HEAP_OFFSET: usize = 32;
+const CLUSTER_SHIFT: u8 = 1;
+const BLOCKS_PER_CLUSTER: usize = 1 << CLUSTER_SHIFT;
+const CLUSTER_SIZE: usize = BLOCKS_PER_CLUSTER * BLOCK_SIZE;
+const CLUSTERS: u32 = 100;
+const ROOT: u32 = 2;
+const END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+
+const ENTRY_BITMAP: u8 = 0x81;
+const ENTRY_UP_CASE: u8 = 0x82;
+const ENTRY_LABEL: u8 = 0x83;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xC0;
+const ENTRY_NAME: u8 = 0xC1;
+const ATTR_DIRECTORY: u16 = 0x10;
+const FLAG_ALLOCATION_POSSIBLE: u8 = 0x01;
+const FLAG_NO_FAT_CHAIN: u8 = 0x02;
+
+/// The files of the image built by [Volume::with_files] as their name, their first cluster and their size. The
+/// kernel is chained in the allocation table, the long one is contiguous.
+const KERNEL: (&str, u32, usize) = ("Kernel8.img", 3, 2 * CLUSTER_SIZE + 452);
+const LONG: (&str, u32, usize) = ("ruspiro-kernel-image.bin", 6, 2 * CLUSTER_SIZE + 1);
+const OVERLAYS: u32 = 9;
+const NESTED: (&str, u32, usize) = ("kernel-debug.img", 10, 10);
+
+/// The modification date and time of all files, 2020-04-12 18:30:10
+const DATE: u16 = 40 << 9 | 4 << 5 | 12;
+const TIME: u16 = 18 << 11 | 30 << 5 | 5;
+
+/// An exFAT file system with a single FAT and the root directory in the first cluster
+struct Volume {
+    image: Image,
+}
+
+impl Volume {
+    fn new() -> Self {
+        let blocks = HEAP_OFFSET + CLUSTERS as usize * BLOCKS_PER_CLUSTER;
+        let mut image = Image::new(blocks);
+        let boot = image.blocks_mut(0, 1);
+        boot[0..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+        boot[3..11].copy_from_slice(b"EXFAT   ");
+        put64(boot, 72, blocks as u64);
+        put32(boot, 80, FAT_OFFSET as u32);
+        put32(boot, 84, FAT_BLOCKS as u32);
+        put32(boot, 88, HEAP_OFFSET as u32);
+        put32(boot, 92, CLUSTERS);
+        put32(boot, 96, ROOT);
+        put16(boot, 104, 0x0100);
+        boot[108] = 9;
+        boot[109] = CLUSTER_SHIFT;
+        boot[110] = 1;
+        put16(boot, 510, fat::BOOT_SIGNATURE);
+        let mut volume = Volume { image };
+        volume.set_next(0, 0xFFFF_FFF8);
+        volume.set_next(1, END_OF_CHAIN);
+        volume.set_next(ROOT, END_OF_CHAIN);
+        volume
+    }
+
+    /// The volume with a label, the allocation bitmap, the up-case table, the files and a directory
+    fn with_files() -> Self {
+        let mut volume = Volume::new();
+        let mut label = [0; 32];
+        label[0] = ENTRY_LABEL;
+        label[1] = 4;
+        for (idx, c) in "BOOT".encode_utf16().enumerate() {
+            put16(&mut label, 2 + idx * 2, c);
+        }
+        volume.add_entries(ROOT, &[label]);
+        // the clusters are not allocated in the image, the entries are only skipped
+        for &kind in [ENTRY_BITMAP, ENTRY_UP_CASE].iter() {
+            let mut entry = [0; 32];
+            entry[0] = kind;
+            put32(&mut entry, 20, 20);
+            put64(&mut entry, 24, 1);
+            volume.add_entries(ROOT, &[entry]);
+        }
+
+        let (name, cluster, size) = KERNEL;
+        volume.add_entries(ROOT, &file_set(name, 0, cluster, size, false));
+        volume.write_chain(&[cluster, cluster + 2, cluster + 1], &pattern(size, 1));
+
+        let (name, cluster, size) = LONG;
+        volume.add_entries(ROOT, &file_set(name, 0, cluster, size, true));
+        volume.write_contiguous(cluster, &pattern(size, 2));
+
+        let dir = file_set("overlays", ATTR_DIRECTORY, OVERLAYS, CLUSTER_SIZE, true);
+        volume.add_entries(ROOT, &dir);
+
+        let (name, cluster, size) = NESTED;
+        volume.add_entries(OVERLAYS, &file_set(name, 0, cluster, size, false));
+        volume.write_chain(&[cluster], &pattern(size, 3));
+        volume
+    }
+
+    fn set_next(&mut self, cluster: u32, next: u32) {
+        let fat = self.image.blocks_mut(FAT_OFFSET, FAT_BLOCKS);
+        put32(fat, cluster as usize * 4, next);
+    }
+
+    fn cluster_mut(&mut self, cluster: u32) -> &mut [u8] {
+        let block = HEAP_OFFSET + (cluster - 2) as usize * BLOCKS_PER_CLUSTER;
+        self.image.blocks_mut(block, BLOCKS_PER_CLUSTER)
+    }
+
+    /// Store the data in the given clusters and chain them in this order
+    fn write_chain(&mut self, clusters: &[u32], data: &[u8]) {
+        for (idx, chunk) in data.chunks(CLUSTER_SIZE).enumerate() {
+            self.cluster_mut(clusters[idx])[..chunk.len()].copy_from_slice(chunk);
+        }
+        for pair in clusters.windows(2) {
+            self.set_next(pair[0], pair[1]);
+        }
+        self.set_next(clusters[clusters.len() - 1], END_OF_CHAIN);
+    }
+
+    /// Store the data in the clusters following the given one without touching the allocation table
+    fn write_contiguous(&mut self, cluster: u32, data: &[u8]) {
+        for (idx, chunk) in data.chunks(CLUSTER_SIZE).enumerate() {
+            self.cluster_mut(cluster + idx as u32)[..chunk.len()].copy_from_slice(chunk);
+        }
+    }
+
+    /// Add the entries after the last entry of the directory in the given cluster
+    fn add_entries(&mut self, dir: u32, entries: &[[u8; 32]]) {
+        let data = self.cluster_mut(dir);
+        let free = data.chunks(32).position(|raw| raw[0] == 0).unwrap();
+        for (idx, entry) in entries.iter().enumerate() {
+            data[(free + idx) * 32..(free + idx + 1) * 32].copy_from_slice(entry);
+        }
+    }
+
+    fn mount(self) -> ExFat<Image> {
+        ExFat::mount(self.image, 0).unwrap()
+    }
+}
+
+/// The file entry, the stream extension and the name entries of a file or directory with their checksum
+fn file_set(name: &str, attr: u16, cluster: u32, size: usize, contiguous: bool) -> Vec<[u8; 32]> {
+    let chars: Vec<u16> = name.encode_utf16().collect();
+    let names = chars.len().div_ceil(15);
+    let mut entries = vec![[0; 32]; 2 + names];
+
+    let file = &mut entries[0];
+    file[0] = ENTRY_FILE;
+    file[1] = 1 + names as u8;
+    put16(file, 4, attr);
+    put32(file, 8, (DATE as u32) << 16 | TIME as u32);
+    put32(file, 12, (DATE as u32) << 16 | TIME as u32);
+
+    let stream = &mut entries[1];
+    stream[0] = ENTRY_STREAM;
+    stream[1] = FLAG_ALLOCATION_POSSIBLE | if contiguous { FLAG_NO_FAT_CHAIN } else { 0 };
+    stream[3] = chars.len() as u8;
+    put64(stream, 8, size as u64);
+    put32(stream, 20, cluster);
+    put64(stream, 24, size as u64);
+
+    for (idx, part) in chars.chunks(15).enumerate() {
+        let entry = &mut entries[2 + idx];
+        entry[0] = ENTRY_NAME;
+        for (offset, &c) in part.iter().enumerate() {
+            put16(entry, 2 + offset * 2, c);
+        }
+    }
+
+    let checksum = entries
+        .iter()
+        .flat_map(|entry| entry.iter())
+        .enumerate()
+        .filter(|&(idx, _)| idx != 2 && idx != 3)
+        .fold(0u16, |sum, (_, &c)| {
+            sum.rotate_right(1).wrapping_add(c as u16)
+        });
+    put16(&mut entries[0], 2, checksum);
+    entries
+}
+
+#[test]
+fn names() {
+    let mut fs = Volume::with_files().mount();
+    assert_eq!(fs.cluster_size(), CLUSTER_SIZE);
+    // the label, the allocation bitmap and the up-case table are skipped
+    let names: Vec<String> = fs
+        .list("/")
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, [KERNEL.0, LONG.0, "overlays"]);
+
+    for &path in ["kernel8.img", "KERNEL8.IMG", "/Kernel8.img"].iter() {
+        let entry = fs.find(path).unwrap();
+        assert_eq!(entry.name, KERNEL.0);
+        assert_eq!(entry.cluster, KERNEL.1);
+        assert_eq!(entry.size, KERNEL.2 as u64);
+        assert!(!entry.is_dir);
+        assert!(!entry.contiguous);
+        assert_eq!(entry.date, DATE);
+        assert_eq!(entry.time, TIME);
+    }
+    // the names spanning two name entries
+    let entry = fs.find("RusPiRo-Kernel-Image.bin").unwrap();
+    assert_eq!(entry.name, LONG.0);
+    assert!(entry.contiguous);
+    assert_eq!(fs.find("overlays/Kernel-Debug.IMG").unwrap().name, NESTED.0);
+    assert_eq!(fs.find("boot").unwrap_err(), FatError::NotFound);
+}
+
+#[test]
+fn bad_checksum() {
+    // the entry set of the kernel is dropped, the following ones are still found
+    let mut volume = Volume::with_files();
+    let set = volume
+        .cluster_mut(ROOT)
+        .chunks(32)
+        .position(|raw| raw[0] == ENTRY_FILE)
+        .unwrap();
+    volume.cluster_mut(ROOT)[(set + 2) * 32 + 2] ^= 0x20;
+    let mut fs = volume.mount();
+    assert_eq!(fs.find(KERNEL.0).unwrap_err(), FatError::NotFound);
+    assert_eq!(fs.find(LONG.0).unwrap().cluster, LONG.1);
+}
+
+#[test]
+fn directories() {
+    let mut fs = Volume::with_files().mount();
+    let entries = fs.list("overlays/").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, NESTED.0);
+
+    let dir = fs.find("overlays").unwrap();
+    assert!(dir.is_dir);
+    assert_eq!(fs.read_file(&dir, 0x1000).unwrap_err(), FatError::NotAFile);
+    assert_eq!(fs.list("kernel8.img").unwrap_err(), FatError::NotADirectory);
+    assert_eq!(
+        fs.find("kernel8.img/kernel8.img").unwrap_err(),
+        FatError::NotADirectory
+    );
+    assert_eq!(fs.find("overlays/missing").unwrap_err(), FatError::NotFound);
+}
+
+#[test]
+fn read_file() {
+    let mut fs = Volume::with_files().mount();
+    // neither size is a multiple of the cluster size, the chain of the kernel is not in order
+    let entry = fs.find(KERNEL.0).unwrap();
+    assert_eq!(
+        fs.read_file(&entry, KERNEL.2).unwrap(),
+        pattern(KERNEL.2, 1)
+    );
+    let entry = fs.find(LONG.0).unwrap();
+    assert_eq!(fs.read_file(&entry, LONG.2).unwrap(), pattern(LONG.2, 2));
+    let entry = fs.find("overlays/kernel-debug.img").unwrap();
+    assert_eq!(
+        fs.read_file(&entry, NESTED.2).unwrap(),
+        pattern(NESTED.2, 3)
+    );
+}
+
+#[test]
+fn too_large() {
+    let mut fs = Volume::with_files().mount();
+    let entry = fs.find(LONG.0).unwrap();
+    assert_eq!(
+        fs.read_file(&entry, LONG.2 - 1).unwrap_err(),
+        FatError::TooLarge(LONG.2)
+    );
+}
+
+#[test]
+fn chain_out_of_range() {
+    for &next in [CLUSTERS + 2, 0, 1, 0xFFFF_FFF7].iter() {
+        let mut volume = Volume::with_files();
+        volume.set_next(KERNEL.1, next);
+        let mut fs = volume.mount();
+        let entry = fs.find(KERNEL.0).unwrap();
+        assert_eq!(
+            fs.read_file(&entry, KERNEL.2).unwrap_err(),
+            FatError::BadCluster
+        );
+    }
+}
+
+#[test]
+fn chain_ends_early() {
+    let mut volume = Volume::with_files();
+    volume.set_next(KERNEL.1 + 2, END_OF_CHAIN);
+    let mut fs = volume.mount();
+    let entry = fs.find(KERNEL.0).unwrap();
+    assert_eq!(
+        fs.read_file(&entry, KERNEL.2).unwrap_err(),
+        FatError::BadCluster
+    );
+}
+
+#[test]
+fn contiguous_beyond_heap() {
+    // the second cluster of the file is beyond the end of the cluster heap
+    let mut volume = Volume::new();
+    let last = CLUSTERS + 1;
+    volume.add_entries(
+        ROOT,
+        &file_set("kernel8.img", 0, last, CLUSTER_SIZE + 1, true),
+    );
+    let mut fs = volume.mount();
+    let entry = fs.find("kernel8.img").unwrap();
+    assert_eq!(
+        fs.read_file(&entry, CLUSTER_SIZE + 1).unwrap_err(),
+        FatError::BadCluster
+    );
+}
+
+#[test]
+fn looping_root_directory() {
+    // the root directory never ends: its clusters are full of deleted entries and chained to each other
+    let mut volume = Volume::new();
+    for &cluster in [ROOT, 20].iter() {
+        for entry in volume.cluster_mut(cluster).chunks_mut(32) {
+            entry[0] = ENTRY_FILE & 0x7F;
+        }
+    }
+    volume.set_next(ROOT, 20);
+    volume.set_next(20, ROOT);
+    let mut fs = volume.mount();
+    assert_eq!(fs.list("/").unwrap_err(), FatError::BadCluster);
+    assert_eq!(fs.find("kernel8.img").unwrap_err(), FatError::BadCluster);
+}
+
+#[test]
+fn bad_boot_sector() {
+    let mut volume = Volume::new();
+    put32(volume.image.blocks_mut(0, 1), 96, CLUSTERS + 2);
+    assert_eq!(
+        ExFat::mount(volume.image, 0).err(),
+        Some(FatError::BadCluster)
+    );
+
+    // the BIOS parameter block of FAT is not zero
+    let mut volume = Volume::new();
+    volume.image.blocks_mut(0, 1)[13] = 8;
+    assert_eq!(
+        ExFat::mount(volume.image, 0).err(),
+        Some(FatError::NoFileSystem)
+    );
+
+    let mut volume = Volume::new();
+    volume.image.blocks_mut(0, 1)[108] = 12;
+    assert_eq!(
+        ExFat::mount(volume.image, 0).err(),
+        Some(FatError::NoFileSystem)
+    );
+}
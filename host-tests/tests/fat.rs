@@ -0,0 +1,364 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # FAT32 tests
+//!
+//! Look up and read the files of small FAT32 images built here, with 8.3 and long names, a directory and clusters of
+//! two blocks. Broken cluster chains must be refused.
+//!
+
+extern crate alloc;
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/block.rs"]
+mod block;
+#[allow(dead_code)]
+mod common;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/fat.rs"]
+mod fat;
+
+use block::BLOCK_SIZE;
+use common::{pattern, put16, put32, Image};
+use fat::{FatError, FileSystem};
+
+const RESERVED_BLOCKS: usize = 32;
+const FAT_BLOCKS: usize = 1;
+const BLOCKS_PER_CLUSTER: usize = 2;
+const CLUSTER_SIZE: usize = BLOCKS_PER_CLUSTER * BLOCK_SIZE;
+const CLUSTERS: u32 = 100;
+const ROOT: u32 = 2;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const NAME_LOWER_CASE: u8 = 0x08;
+const EXT_LOWER_CASE: u8 = 0x10;
+const ENTRY_DELETED: u8 = 0xE5;
+
+/// The files of the image built by [Volume::with_files] as their name, their first cluster and their size
+const KERNEL: (&str, u32, usize) = ("kernel8.img", 3, 2 * CLUSTER_SIZE + 452);
+const CONFIG: (&str, u32, usize) = ("CONFIG.TXT", 6, 100);
+const LONG: (&str, u32, usize) = ("RusPiRo Kernel Image.bin", 7, CLUSTER_SIZE);
+const OVERLAYS: u32 = 8;
+const NESTED: (&str, u32, usize) = ("kernel-debug.img", 9, 10);
+
+/// A FAT32 file system with a single FAT and the root directory in the first cluster
+struct Volume {
+    image: Image,
+}
+
+impl Volume {
+    fn new() -> Self {
+        let blocks = RESERVED_BLOCKS + FAT_BLOCKS + CLUSTERS as usize * BLOCKS_PER_CLUSTER;
+        let mut image = Image::new(blocks);
+        let boot = image.blocks_mut(0, 1);
+        boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        boot[3..11].copy_from_slice(b"mkfs.fat");
+        put16(boot, 11, BLOCK_SIZE as u16);
+        boot[13] = BLOCKS_PER_CLUSTER as u8;
+        put16(boot, 14, RESERVED_BLOCKS as u16);
+        boot[16] = 1;
+        boot[21] = 0xF8;
+        put32(boot, 32, blocks as u32);
+        put32(boot, 36, FAT_BLOCKS as u32);
+        put32(boot, 44, ROOT);
+        boot[82..90].copy_from_slice(b"FAT32   ");
+        put16(boot, 510, fat::BOOT_SIGNATURE);
+        let mut volume = Volume { image };
+        volume.set_next(0, 0x0FFF_FFF8);
+        volume.set_next(1, END_OF_CHAIN);
+        volume.set_next(ROOT, END_OF_CHAIN);
+        volume
+    }
+
+    /// The volume with a label, a deleted entry, [KERNEL] in the chain 3, 5, 4 and the other files in one cluster
+    /// each
+    fn with_files() -> Self {
+        let mut volume = Volume::new();
+        volume.add_entry(ROOT, &short_entry(b"BOOT       ", ATTR_VOLUME_ID, 0, 0, 0));
+        let mut deleted = short_entry(b"OLDKERNLIMG", 0, 0, 10, 1);
+        deleted[0] = ENTRY_DELETED;
+        volume.add_entry(ROOT, &deleted);
+
+        let (_, cluster, size) = KERNEL;
+        let lower = NAME_LOWER_CASE | EXT_LOWER_CASE;
+        volume.add_entry(ROOT, &short_entry(b"KERNEL8 IMG", 0, lower, cluster, size));
+        volume.write_chain(&[cluster, cluster + 2, cluster + 1], &pattern(size, 1));
+
+        let (_, cluster, size) = CONFIG;
+        volume.add_entry(ROOT, &short_entry(b"CONFIG  TXT", 0, 0, cluster, size));
+        volume.write_chain(&[cluster], &pattern(size, 2));
+
+        let (name, cluster, size) = LONG;
+        volume.add_long_name(ROOT, name, b"RUSPIR~1BIN");
+        volume.add_entry(ROOT, &short_entry(b"RUSPIR~1BIN", 0, 0, cluster, size));
+        volume.write_chain(&[cluster], &pattern(size, 3));
+
+        let dir = short_entry(b"OVERLAYS   ", ATTR_DIRECTORY, NAME_LOWER_CASE, OVERLAYS, 0);
+        volume.add_entry(ROOT, &dir);
+        volume.set_next(OVERLAYS, END_OF_CHAIN);
+        volume.add_entry(
+            OVERLAYS,
+            &short_entry(b".          ", ATTR_DIRECTORY, 0, OVERLAYS, 0),
+        );
+        volume.add_entry(
+            OVERLAYS,
+            &short_entry(b"..         ", ATTR_DIRECTORY, 0, 0, 0),
+        );
+
+        let (name, cluster, size) = NESTED;
+        volume.add_long_name(OVERLAYS, name, b"KERNEL~1IMG");
+        volume.add_entry(OVERLAYS, &short_entry(b"KERNEL~1IMG", 0, 0, cluster, size));
+        volume.write_chain(&[cluster], &pattern(size, 4));
+        volume
+    }
+
+    fn set_next(&mut self, cluster: u32, next: u32) {
+        let fat = self.image.blocks_mut(RESERVED_BLOCKS, FAT_BLOCKS);
+        put32(fat, cluster as usize * 4, next);
+    }
+
+    fn cluster_mut(&mut self, cluster: u32) -> &mut [u8] {
+        let block = RESERVED_BLOCKS + FAT_BLOCKS + (cluster - 2) as usize * BLOCKS_PER_CLUSTER;
+        self.image.blocks_mut(block, BLOCKS_PER_CLUSTER)
+    }
+
+    /// Store the data in the given clusters and chain them in this order
+    fn write_chain(&mut self, clusters: &[u32], data: &[u8]) {
+        for (idx, chunk) in data.chunks(CLUSTER_SIZE).enumerate() {
+            self.cluster_mut(clusters[idx])[..chunk.len()].copy_from_slice(chunk);
+        }
+        for pair in clusters.windows(2) {
+            self.set_next(pair[0], pair[1]);
+        }
+        self.set_next(clusters[clusters.len() - 1], END_OF_CHAIN);
+    }
+
+    /// Add the entry after the last entry of the directory in the given cluster
+    fn add_entry(&mut self, dir: u32, entry: &[u8; 32]) {
+        let data = self.cluster_mut(dir);
+        let free = data.chunks(32).position(|raw| raw[0] == 0).unwrap();
+        data[free * 32..free * 32 + 32].copy_from_slice(entry);
+    }
+
+    /// Add the long name entries of the 8.3 name, the last part of the long name is stored first
+    fn add_long_name(&mut self, dir: u32, name: &str, short: &[u8; 11]) {
+        let checksum = short
+            .iter()
+            .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c));
+        let mut chars: Vec<u16> = name.encode_utf16().collect();
+        chars.push(0);
+        let parts = chars.len().div_ceil(13);
+        chars.resize(parts * 13, 0xFFFF);
+        for part in (0..parts).rev() {
+            let mut entry = [0; 32];
+            entry[0] = (part as u8 + 1) | if part == parts - 1 { 0x40 } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let offsets = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain((28..32).step_by(2));
+            for (idx, offset) in offsets.enumerate() {
+                put16(&mut entry, offset, chars[part * 13 + idx]);
+            }
+            self.add_entry(dir, &entry);
+        }
+    }
+
+    fn mount(self) -> FileSystem<Image> {
+        FileSystem::mount(self.image, 0).unwrap()
+    }
+}
+
+fn short_entry(name: &[u8; 11], attr: u8, case: u8, cluster: u32, size: usize) -> [u8; 32] {
+    let mut entry = [0; 32];
+    entry[0..11].copy_from_slice(name);
+    entry[11] = attr;
+    entry[12] = case;
+    put16(&mut entry, 20, (cluster >> 16) as u16);
+    // 2020-04-12 18:30:10
+    put16(&mut entry, 22, 18 << 11 | 30 << 5 | 5);
+    put16(&mut entry, 24, 40 << 9 | 4 << 5 | 12);
+    put16(&mut entry, 26, cluster as u16);
+    put32(&mut entry, 28, size as u32);
+    entry
+}
+
+#[test]
+fn short_names() {
+    let mut fs = Volume::with_files().mount();
+    assert_eq!(fs.cluster_size(), CLUSTER_SIZE);
+    let names: Vec<String> = fs
+        .list("/")
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, ["kernel8.img", "CONFIG.TXT", LONG.0, "overlays"]);
+
+    for &path in ["kernel8.img", "KERNEL8.IMG", "/Kernel8.Img"].iter() {
+        let entry = fs.find(path).unwrap();
+        assert_eq!(entry.name, KERNEL.0);
+        assert_eq!(entry.cluster, KERNEL.1);
+        assert_eq!(entry.size, KERNEL.2 as u64);
+        assert!(!entry.is_dir);
+        assert_eq!(entry.date, 40 << 9 | 4 << 5 | 12);
+        assert_eq!(entry.time, 18 << 11 | 30 << 5 | 5);
+    }
+    assert_eq!(fs.find("config.txt").unwrap().cluster, CONFIG.1);
+    // the 8.3 names of deleted entries and the volume label are not found
+    assert_eq!(fs.find("oldkernl.img").unwrap_err(), FatError::NotFound);
+    assert_eq!(fs.find("boot").unwrap_err(), FatError::NotFound);
+}
+
+#[test]
+fn long_names() {
+    let mut fs = Volume::with_files().mount();
+    let entry = fs.find("ruspiro kernel image.BIN").unwrap();
+    assert_eq!(entry.name, LONG.0);
+    assert_eq!(entry.cluster, LONG.1);
+
+    let entry = fs.find("overlays/Kernel-Debug.img").unwrap();
+    assert_eq!(entry.name, NESTED.0);
+    assert_eq!(fs.read_file(&entry, 0x1000).unwrap(), pattern(NESTED.2, 4));
+}
+
+#[test]
+fn long_name_of_other_entry() {
+    // a long name whose checksum does not match the following 8.3 name is dropped
+    let mut volume = Volume::new();
+    volume.add_long_name(ROOT, "kernel7l.img", b"KERNEL7LIMG");
+    volume.add_entry(ROOT, &short_entry(b"KERNEL8 IMG", 0, 0, 3, 1));
+    volume.write_chain(&[3], &[0]);
+    let mut fs = volume.mount();
+    assert_eq!(fs.list("").unwrap()[0].name, "KERNEL8.IMG");
+    assert_eq!(fs.find("kernel7l.img").unwrap_err(), FatError::NotFound);
+}
+
+#[test]
+fn directories() {
+    let mut fs = Volume::with_files().mount();
+    // the . and .. entries are skipped
+    let entries = fs.list("overlays/").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, NESTED.0);
+
+    let dir = fs.find("overlays").unwrap();
+    assert!(dir.is_dir);
+    assert_eq!(fs.read_file(&dir, 0x1000).unwrap_err(), FatError::NotAFile);
+    assert_eq!(fs.list("kernel8.img").unwrap_err(), FatError::NotADirectory);
+    assert_eq!(
+        fs.find("kernel8.img/kernel8.img").unwrap_err(),
+        FatError::NotADirectory
+    );
+    assert_eq!(fs.find("overlays/missing").unwrap_err(), FatError::NotFound);
+    assert_eq!(fs.find("").unwrap_err(), FatError::NotFound);
+}
+
+#[test]
+fn read_file() {
+    let mut fs = Volume::with_files().mount();
+    // the size of the kernel is not a multiple of the cluster size and its chain is not in order
+    for &(name, _, size) in [KERNEL, CONFIG, LONG].iter() {
+        let entry = fs.find(name).unwrap();
+        let seed = match name {
+            "kernel8.img" => 1,
+            "CONFIG.TXT" => 2,
+            _ => 3,
+        };
+        assert_eq!(fs.read_file(&entry, size).unwrap(), pattern(size, seed));
+    }
+}
+
+#[test]
+fn too_large() {
+    let mut fs = Volume::with_files().mount();
+    let entry = fs.find(KERNEL.0).unwrap();
+    assert_eq!(
+        fs.read_file(&entry, KERNEL.2 - 1).unwrap_err(),
+        FatError::TooLarge(KERNEL.2)
+    );
+}
+
+#[test]
+fn chain_out_of_range() {
+    for &next in [CLUSTERS + 2, 1, 0x0FFF_FFF7].iter() {
+        let mut volume = Volume::with_files();
+        volume.set_next(KERNEL.1, next);
+        let mut fs = volume.mount();
+        let entry = fs.find(KERNEL.0).unwrap();
+        assert_eq!(
+            fs.read_file(&entry, KERNEL.2).unwrap_err(),
+            FatError::BadCluster
+        );
+    }
+}
+
+#[test]
+fn chain_ends_early() {
+    let mut volume = Volume::with_files();
+    volume.set_next(KERNEL.1 + 2, END_OF_CHAIN);
+    let mut fs = volume.mount();
+    let entry = fs.find(KERNEL.0).unwrap();
+    assert_eq!(
+        fs.read_file(&entry, KERNEL.2).unwrap_err(),
+        FatError::BadCluster
+    );
+}
+
+#[test]
+fn first_cluster_out_of_range() {
+    for &cluster in [0, 1, CLUSTERS + 2].iter() {
+        let mut volume = Volume::new();
+        volume.add_entry(ROOT, &short_entry(b"KERNEL8 IMG", 0, 0, cluster, 1));
+        let mut fs = volume.mount();
+        let entry = fs.find(KERNEL.0).unwrap();
+        assert_eq!(fs.read_file(&entry, 1).unwrap_err(), FatError::BadCluster);
+    }
+}
+
+#[test]
+fn looping_directory() {
+    // the root directory never ends: its clusters are full of deleted entries and chained to each other
+    let mut volume = Volume::new();
+    for &cluster in [ROOT, 10].iter() {
+        for entry in volume.cluster_mut(cluster).chunks_mut(32) {
+            entry[0] = ENTRY_DELETED;
+        }
+    }
+    volume.set_next(ROOT, 10);
+    volume.set_next(10, ROOT);
+    let mut fs = volume.mount();
+    assert_eq!(fs.list("/").unwrap_err(), FatError::BadCluster);
+    assert_eq!(fs.find("kernel8.img").unwrap_err(), FatError::BadCluster);
+}
+
+#[test]
+fn bad_boot_sector() {
+    let mut volume = Volume::new();
+    put32(volume.image.blocks_mut(0, 1), 44, CLUSTERS + 2);
+    assert_eq!(
+        FileSystem::mount(volume.image, 0).err(),
+        Some(FatError::BadCluster)
+    );
+
+    let mut volume = Volume::new();
+    put16(volume.image.blocks_mut(0, 1), 510, 0);
+    assert_eq!(
+        FileSystem::mount(volume.image, 0).err(),
+        Some(FatError::NoFileSystem)
+    );
+
+    // the device ends before the boot sector
+    assert_eq!(
+        FileSystem::mount(Image::new(0), 0).err(),
+        Some(FatError::Device(()))
+    );
+}
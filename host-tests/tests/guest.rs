@@ -0,0 +1,154 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Guest exception tests
+//!
+//! Decode synthetic frames laid out like the ``save_state`` macro of the exception vector stores them and the
+//! syndromes of the exceptions the guest takes to EL2.
+//!
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/guest.rs"]
+mod guest;
+
+use guest::{ExceptionFrame, GuestException, HypervisorCall};
+
+/// The size of the frame ``save_state`` reserves on the stack
+const FRAME_SIZE: usize = 272;
+/// The offsets ``save_state`` stores SPSR_EL2 and ELR_EL2 at
+const SPSR_OFFSET: usize = 256;
+const ELR_OFFSET: usize = 264;
+
+/// The frame as ``save_state`` stores it, x0-x30 in order followed by SPSR_EL2 and ELR_EL2
+fn frame(registers: &[u64], spsr: u64, elr: u64) -> ExceptionFrame {
+    let mut data = [0u8; FRAME_SIZE];
+    for (idx, value) in registers.iter().enumerate() {
+        data[idx * 8..idx * 8 + 8].copy_from_slice(&value.to_le_bytes());
+    }
+    data[SPSR_OFFSET..SPSR_OFFSET + 8].copy_from_slice(&spsr.to_le_bytes());
+    data[ELR_OFFSET..ELR_OFFSET + 8].copy_from_slice(&elr.to_le_bytes());
+    unsafe { core::mem::transmute(data) }
+}
+
+/// The syndrome with the exception class and the ISS
+fn syndrome(ec: u64, iss: u64) -> u64 {
+    ec << 26 | 1 << 25 | iss
+}
+
+/// The ISS of a trapped ``msr``/``mrs`` of the register with the given encoding
+fn sysreg_iss(id: (u64, u64, u64, u64, u64), rt: u64, read: bool) -> u64 {
+    let (op0, op1, crn, crm, op2) = id;
+    op0 << 20 | op2 << 17 | op1 << 14 | crn << 10 | rt << 5 | crm << 1 | read as u64
+}
+
+#[test]
+fn frame_layout() {
+    assert_eq!(core::mem::size_of::<ExceptionFrame>(), FRAME_SIZE);
+    let registers: Vec<u64> = (0..31).map(|idx| 0x1000 + idx).collect();
+    let frame = frame(&registers, 0x3C5, 0x8_0000);
+    assert_eq!(&frame.x[..], &registers[..]);
+    assert_eq!(frame.spsr, 0x3C5);
+    assert_eq!(frame.elr, 0x8_0000);
+    assert_eq!(frame.register(30), 0x101E);
+    assert_eq!(frame.register(31), 0);
+}
+
+#[test]
+fn hypervisor_calls() {
+    // the function id of the guest is in x0, never the id of the exception loaded by the vector entry
+    for &(function, call) in [
+        (0, HypervisorCall::Version),
+        (1, HypervisorCall::Reload),
+        (2, HypervisorCall::Reset),
+        (3, HypervisorCall::BootSucceeded),
+        (0x21, HypervisorCall::Unknown(0x21)),
+    ]
+    .iter()
+    {
+        let frame = frame(&[function, 0xDEAD], 0, 0);
+        assert_eq!(frame.hypervisor_call(), call);
+    }
+    assert_eq!(
+        GuestException::decode(syndrome(guest::EC_HVC64, 0)),
+        GuestException::HypervisorCall
+    );
+}
+
+#[test]
+fn trapped_operations() {
+    assert_eq!(
+        GuestException::decode(syndrome(guest::EC_WFX, 0)),
+        GuestException::Wfi
+    );
+    assert_eq!(
+        GuestException::decode(syndrome(guest::EC_WFX, 1)),
+        GuestException::Wfe
+    );
+    assert_eq!(
+        GuestException::decode(syndrome(guest::EC_SMC64, 0)),
+        GuestException::Smc
+    );
+    let sctlr = (3, 0, 1, 0, 0);
+    let tcr = (3, 0, 2, 0, 2);
+    assert_eq!(
+        GuestException::decode(syndrome(guest::EC_SYSREG, sysreg_iss(sctlr, 5, false))),
+        GuestException::SysregWrite(sctlr, 5)
+    );
+    assert_eq!(
+        GuestException::decode(syndrome(guest::EC_SYSREG, sysreg_iss(tcr, 31, true))),
+        GuestException::SysregRead(tcr, 31)
+    );
+}
+
+#[test]
+fn aborts() {
+    for &ec in [guest::EC_IABT_LOWER, guest::EC_DABT_LOWER].iter() {
+        assert_eq!(
+            GuestException::decode(syndrome(ec, 0x7)),
+            GuestException::Abort
+        );
+    }
+    assert_eq!(
+        GuestException::decode(syndrome(0x3C, 0)),
+        GuestException::Other(0x3C)
+    );
+}
+
+#[test]
+fn resume_after_trap() {
+    // the guest resumes behind the trapped instruction with its registers, x0 included, as it has left them
+    let registers: Vec<u64> = (0..31).map(|idx| 0x1000 + idx).collect();
+    let sctlr = (3, 0, 1, 0, 0);
+    for &exception in [
+        GuestException::Wfi,
+        GuestException::Wfe,
+        GuestException::SysregWrite(sctlr, 0),
+        GuestException::SysregRead(sctlr, 31),
+    ]
+    .iter()
+    {
+        let mut frame = frame(&registers, 0x3C5, 0x8_0000);
+        frame.complete(exception, 0x55);
+        assert_eq!(&frame.x[..], &registers[..], "{:?}", exception);
+        assert_eq!((frame.spsr, frame.elr), (0x3C5, 0x8_0004));
+    }
+}
+
+#[test]
+fn trap_results() {
+    let registers: Vec<u64> = (0..31).map(|idx| 0x1000 + idx).collect();
+    let mut smc = frame(&registers, 0, 0x8_0000);
+    smc.complete(GuestException::Smc, 0);
+    assert_eq!(smc.x[0], guest::SMC_NOT_SUPPORTED);
+    assert_eq!(&smc.x[1..], &registers[1..]);
+    let mut mrs = frame(&registers, 0, 0x8_0000);
+    mrs.complete(GuestException::SysregRead((3, 0, 2, 0, 2), 7), 0x55);
+    assert_eq!(mrs.x[7], 0x55);
+    for idx in (0..31).filter(|&idx| idx != 7) {
+        assert_eq!(mrs.x[idx], registers[idx]);
+    }
+}
@@ -0,0 +1,209 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Gzip tests
+//!
+//! Inflate the samples in ``data/gzip``, all compressed from [sample] with zlib: with ``gzip -1`` and ``gzip -9``,
+//! with fixed Huffman codes only, as stored blocks, with all optional header fields and an empty one. Truncated and
+//! corrupted samples must be refused.
+//!
+
+extern crate alloc;
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/crc.rs"]
+mod crc;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/gzip.rs"]
+mod gzip;
+
+use gzip::GzipError;
+
+const LEVEL_1: &[u8] = include_bytes!("data/gzip/level1.gz");
+const LEVEL_9: &[u8] = include_bytes!("data/gzip/level9.gz");
+const FIXED: &[u8] = include_bytes!("data/gzip/fixed.gz");
+const STORED: &[u8] = include_bytes!("data/gzip/stored.gz");
+const HEADER: &[u8] = include_bytes!("data/gzip/header.gz");
+const EMPTY: &[u8] = include_bytes!("data/gzip/empty.gz");
+
+const MAX_SIZE: usize = 0x10_0000;
+/// The size of the gzip header without optional fields
+const HEADER_SIZE: usize = 10;
+
+/// The data all samples are compressed from: text lines repeated at varying distances with a few random bytes in
+/// between, generated with a linear congruential generator
+fn sample() -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut seed: u32 = 1;
+    for line in 0..400 {
+        data.extend_from_slice(
+            format!("{:04} the kernel of the RusPiRo loader test\n", line).as_bytes(),
+        );
+        for _ in 0..line % 16 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            data.push((seed >> 24) as u8);
+        }
+    }
+    data
+}
+
+#[test]
+fn is_gzip() {
+    assert!(gzip::is_gzip(LEVEL_9));
+    assert!(!gzip::is_gzip(&sample()));
+    assert!(!gzip::is_gzip(&LEVEL_9[..1]));
+}
+
+#[test]
+fn dynamic_huffman() {
+    // the first block of both samples uses dynamic Huffman codes
+    for &sample_data in [LEVEL_1, LEVEL_9].iter() {
+        assert_eq!(sample_data[HEADER_SIZE] >> 1 & 0b11, 0b10);
+        assert_eq!(gzip::decompress(sample_data, MAX_SIZE).unwrap(), sample());
+    }
+}
+
+#[test]
+fn fixed_huffman() {
+    assert_eq!(FIXED[HEADER_SIZE] >> 1 & 0b11, 0b01);
+    assert_eq!(gzip::decompress(FIXED, MAX_SIZE).unwrap(), sample());
+}
+
+#[test]
+fn stored_blocks() {
+    assert_eq!(STORED[HEADER_SIZE] >> 1 & 0b11, 0b00);
+    assert_eq!(gzip::decompress(STORED, MAX_SIZE).unwrap(), sample());
+}
+
+#[test]
+fn optional_header_fields() {
+    // extra field, file name, comment and header CRC
+    assert_eq!(HEADER[3], 0x1E);
+    assert_eq!(gzip::decompress(HEADER, MAX_SIZE).unwrap(), sample());
+}
+
+#[test]
+fn empty() {
+    assert!(gzip::decompress(EMPTY, MAX_SIZE).unwrap().is_empty());
+}
+
+#[test]
+fn too_large() {
+    let size = sample().len();
+    assert_eq!(
+        gzip::decompress(LEVEL_9, size - 1),
+        Err(GzipError::TooLarge(size))
+    );
+    assert!(gzip::decompress(LEVEL_9, size).is_ok());
+}
+
+#[test]
+fn bad_header() {
+    let mut other_method = LEVEL_9.to_vec();
+    other_method[2] = 7;
+    assert_eq!(
+        gzip::decompress(&other_method, MAX_SIZE),
+        Err(GzipError::BadHeader)
+    );
+    // the file name is not terminated
+    let mut unterminated = LEVEL_9[..HEADER_SIZE].to_vec();
+    unterminated[3] = 1 << 3;
+    unterminated.extend_from_slice(b"kernel8.img");
+    assert_eq!(
+        gzip::decompress(&unterminated, MAX_SIZE),
+        Err(GzipError::BadHeader)
+    );
+}
+
+#[test]
+fn truncated() {
+    for &sample_data in [LEVEL_1, LEVEL_9, FIXED, STORED, HEADER, EMPTY].iter() {
+        for len in 0..sample_data.len() {
+            assert!(
+                gzip::decompress(&sample_data[..len], MAX_SIZE).is_err(),
+                "truncated to {} bytes",
+                len
+            );
+        }
+    }
+}
+
+#[test]
+fn truncated_stream_with_trailer() {
+    // the deflate stream ends early but the trailer is still in place
+    let trailer = &LEVEL_9[LEVEL_9.len() - 8..];
+    for &len in [
+        HEADER_SIZE,
+        HEADER_SIZE + 1,
+        LEVEL_9.len() / 2,
+        LEVEL_9.len() - 9,
+    ]
+    .iter()
+    {
+        let mut truncated = LEVEL_9[..len].to_vec();
+        truncated.extend_from_slice(trailer);
+        assert!(gzip::decompress(&truncated, MAX_SIZE).is_err());
+    }
+}
+
+#[test]
+fn corrupted_trailer() {
+    let len = LEVEL_9.len();
+    let mut bad_crc = LEVEL_9.to_vec();
+    bad_crc[len - 8] ^= 1;
+    assert_eq!(
+        gzip::decompress(&bad_crc, MAX_SIZE),
+        Err(GzipError::BadChecksum)
+    );
+    let mut bad_size = LEVEL_9.to_vec();
+    bad_size[len - 4] ^= 1;
+    assert!(gzip::decompress(&bad_size, MAX_SIZE).is_err());
+}
+
+#[test]
+fn reserved_block_type() {
+    let mut reserved = LEVEL_9.to_vec();
+    reserved[HEADER_SIZE] |= 0b110;
+    assert_eq!(
+        gzip::decompress(&reserved, MAX_SIZE),
+        Err(GzipError::BadData)
+    );
+}
+
+#[test]
+fn corrupted_stored_length() {
+    // the length of a stored block is followed by its ones' complement
+    let mut corrupted = STORED.to_vec();
+    corrupted[HEADER_SIZE + 3] ^= 1;
+    assert_eq!(
+        gzip::decompress(&corrupted, MAX_SIZE),
+        Err(GzipError::BadData)
+    );
+}
+
+#[test]
+fn corrupted() {
+    // a flipped bit of the deflate stream or the trailer never inflates to other data than the checked one
+    let expected = sample();
+    for &sample_data in [LEVEL_1, FIXED, STORED].iter() {
+        let mut refused = 0;
+        let mut flipped = 0;
+        for idx in (HEADER_SIZE..sample_data.len()).step_by(7) {
+            for bit in [0, 3, 7].iter() {
+                flipped += 1;
+                let mut corrupted = sample_data.to_vec();
+                corrupted[idx] ^= 1 << bit;
+                match gzip::decompress(&corrupted, MAX_SIZE) {
+                    Ok(data) => assert_eq!(data, expected, "byte {} bit {}", idx, bit),
+                    Err(_) => refused += 1,
+                }
+            }
+        }
+        // only the padding bits of the last byte of the stream could flip without being noticed
+        assert!(refused >= flipped - 3);
+    }
+}
@@ -0,0 +1,94 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Kernel image tests
+//!
+//! Parse arm64 Image headers built in memory. The text offset and the image size are taken from the header of an
+//! untrusted kernel, so sizes that exceed the address space must be refused.
+//!
+
+extern crate alloc;
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/crc.rs"]
+mod crc;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/gzip.rs"]
+mod gzip;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/image.rs"]
+mod image;
+
+use image::{Arm64Image, ImageError};
+
+/// The magic value of the arm64 Image header, "ARM\x64"
+const ARM64_MAGIC: u32 = 0x644D_5241;
+/// The 2MB aligned base address kernels are placed at above the loader
+const IMAGE_BASE: u64 = 0x2000_0000;
+
+/// A kernel of the given size with an arm64 Image header
+fn arm64_image(len: usize, text_offset: u64, image_size: u64) -> Vec<u8> {
+    let mut binary = vec![0; len];
+    binary[8..16].copy_from_slice(&text_offset.to_le_bytes());
+    binary[16..24].copy_from_slice(&image_size.to_le_bytes());
+    binary[56..60].copy_from_slice(&ARM64_MAGIC.to_le_bytes());
+    binary
+}
+
+#[test]
+fn arm64_header() {
+    let binary = arm64_image(0x1000, 0x8_0000, 0x2_0000);
+    let image = Arm64Image::parse(&binary).unwrap().unwrap();
+    assert_eq!(image.text_offset, 0x8_0000);
+    assert_eq!(image.image_size, 0x2_0000);
+    assert_eq!(image.load_address(IMAGE_BASE), Some(0x2008_0000));
+    assert_eq!(image.end(IMAGE_BASE), Some(0x200A_0000));
+    // kernels prior to Linux 3.17 do not provide their image size
+    let binary = arm64_image(0x1000, 0x8_0000, 0);
+    let image = Arm64Image::parse(&binary).unwrap().unwrap();
+    assert_eq!(image.image_size, 0x100_0000);
+    // binaries without the magic value have no header
+    let mut binary = arm64_image(0x1000, 0x8_0000, 0x2_0000);
+    binary[56] = 0;
+    assert!(Arm64Image::parse(&binary).is_none());
+    assert!(Arm64Image::parse(&[0; 32]).is_none());
+}
+
+#[test]
+fn arm64_bad_size() {
+    // the binary exceeds the image size
+    let binary = arm64_image(0x1000, 0x8_0000, 0x800);
+    assert_eq!(
+        Arm64Image::parse(&binary).unwrap().unwrap_err(),
+        ImageError::BadSize
+    );
+    // the end of the image exceeds the address space
+    let binary = arm64_image(0x1000, 0x8_0000, u64::MAX - 0x1000);
+    assert_eq!(
+        Arm64Image::parse(&binary).unwrap().unwrap_err(),
+        ImageError::BadSize
+    );
+    let binary = arm64_image(0x1000, u64::MAX - 0x1000, 0x2_0000);
+    assert_eq!(
+        Arm64Image::parse(&binary).unwrap().unwrap_err(),
+        ImageError::BadSize
+    );
+}
+
+#[test]
+fn arm64_address_space() {
+    // the header alone is representable, but not placed at the base address
+    let binary = arm64_image(0x1000, 0x8_0000, u64::MAX - 0x10_0000);
+    let image = Arm64Image::parse(&binary).unwrap().unwrap();
+    assert_eq!(image.load_address(IMAGE_BASE), Some(0x2008_0000));
+    assert_eq!(image.end(0), Some(u64::MAX - 0x8_0000));
+    assert_eq!(image.end(IMAGE_BASE), None);
+    let binary = arm64_image(0x1000, u64::MAX - 0x10_0000, 0x2_0000);
+    let image = Arm64Image::parse(&binary).unwrap().unwrap();
+    assert_eq!(image.load_address(IMAGE_BASE), None);
+    assert_eq!(image.end(IMAGE_BASE), None);
+}
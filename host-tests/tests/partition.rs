@@ -0,0 +1,318 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Partition table tests
+//!
+//! Read the partitions of images built here with a master boot record and with a protective master boot record and a
+//! GPT. GPTs with wrong checksums or entries that do not fit the specification must be refused.
+//!
+
+extern crate alloc;
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/block.rs"]
+mod block;
+#[allow(dead_code)]
+mod common;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/crc.rs"]
+mod crc;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/fat.rs"]
+mod fat;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/partition.rs"]
+mod partition;
+
+use block::BLOCK_SIZE;
+use common::{put16, put32, put64, Image};
+use partition::{PartitionError, PartitionType};
+
+const IMAGE_BLOCKS: usize = 64;
+const ENTRIES_START: usize = 2;
+const ENTRY_SIZE: usize = 128;
+const ENTRIES: usize = 128;
+
+const MBR_FAT32_LBA: u8 = 0x0C;
+const MBR_LINUX: u8 = 0x83;
+const MBR_KERNEL_SLOT: u8 = 0xDA;
+const MBR_PROTECTIVE: u8 = 0xEE;
+
+const EFI_SYSTEM: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+const LINUX_DATA: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+const KERNEL_SLOT: &str = "52755069-526F-4B53-8C6F-72656C536C6F";
+
+/// The partitions of the GPT built by [gpt] as their type, their first and last block and their name
+const GPT_PARTITIONS: [(&str, u64, u64, &str); 3] = [
+    (EFI_SYSTEM, 2048, 526_335, "boot"),
+    (KERNEL_SLOT, 526_336, 657_407, "kernel-a"),
+    (LINUX_DATA, 657_408, 8_000_000, "root"),
+];
+
+/// A master boot record with the given partitions as their type, their first block and their number of blocks
+fn mbr(partitions: &[(u8, u32, u32)]) -> Image {
+    let mut image = Image::new(IMAGE_BLOCKS);
+    let block = image.blocks_mut(0, 1);
+    for (idx, &(kind, start, blocks)) in partitions.iter().enumerate() {
+        let entry = &mut block[446 + idx * 16..446 + (idx + 1) * 16];
+        entry[4] = kind;
+        put32(entry, 8, start);
+        put32(entry, 12, blocks);
+    }
+    put16(block, 510, fat::BOOT_SIGNATURE);
+    image
+}
+
+/// The GUID in the mixed endian layout of the GPT, the first three fields are little endian
+fn guid(text: &str) -> [u8; 16] {
+    let hex: String = text.chars().filter(|&c| c != '-').collect();
+    let mut guid = [0; 16];
+    for (idx, byte) in guid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).unwrap();
+    }
+    guid[0..4].reverse();
+    guid[4..6].reverse();
+    guid[6..8].reverse();
+    guid
+}
+
+/// A protective master boot record followed by a GPT with the partitions in its first entries
+fn gpt(partitions: &[(&str, u64, u64, &str)], count: usize, entry_size: usize) -> Image {
+    let mut image = mbr(&[(MBR_PROTECTIVE, 1, u32::MAX)]);
+    let size = count * entry_size;
+    let entries = image.blocks_mut(ENTRIES_START, size.div_ceil(BLOCK_SIZE));
+    for (idx, &(kind, first, last, name)) in partitions.iter().enumerate() {
+        let entry = &mut entries[idx * entry_size..(idx + 1) * entry_size];
+        entry[0..16].copy_from_slice(&guid(kind));
+        entry[16..32].copy_from_slice(&[idx as u8 + 1; 16]);
+        put64(entry, 32, first);
+        put64(entry, 40, last);
+        for (offset, c) in name.encode_utf16().enumerate() {
+            put16(entry, 56 + offset * 2, c);
+        }
+    }
+    let entries_crc = crc::crc32(&entries[..size]);
+
+    let header = image.blocks_mut(1, 1);
+    header[0..8].copy_from_slice(b"EFI PART");
+    put32(header, 8, 0x0001_0000);
+    put32(header, 12, 92);
+    put64(header, 24, 1);
+    put64(header, 32, 8_388_607);
+    put64(header, 40, 34);
+    put64(header, 48, 8_388_574);
+    header[56..72].copy_from_slice(&[0x5A; 16]);
+    put64(header, 72, ENTRIES_START as u64);
+    put32(header, 80, count as u32);
+    put32(header, 84, entry_size as u32);
+    put32(header, 88, entries_crc);
+    seal(&mut image);
+    image
+}
+
+/// Update the checksum of the GPT header after it has been changed
+fn seal(image: &mut Image) {
+    let header = image.blocks_mut(1, 1);
+    let size = u32::from_le_bytes([header[12], header[13], header[14], header[15]]) as usize;
+    put32(header, 16, 0);
+    let header_crc = crc::crc32(&header[..size.min(BLOCK_SIZE)]);
+    put32(header, 16, header_crc);
+}
+
+#[test]
+fn crc32() {
+    // the check value of CRC-32, the checksums of the images are calculated with the implementation under test
+    assert_eq!(crc::crc32(b"123456789"), 0xCBF4_3926);
+}
+
+#[test]
+fn mbr_partitions() {
+    let mut image = mbr(&[
+        (MBR_FAT32_LBA, 8192, 524_288),
+        (0, 0, 0),
+        (MBR_KERNEL_SLOT, 532_480, 131_072),
+        (MBR_LINUX, 663_552, 7_000_000),
+    ]);
+    let partitions = partition::read(&mut image).unwrap();
+    let numbers: Vec<usize> = partitions.iter().map(|p| p.number).collect();
+    assert_eq!(numbers, [1, 3, 4]);
+
+    assert_eq!(partitions[0].kind, PartitionType::Mbr(MBR_FAT32_LBA));
+    assert_eq!(partitions[0].start, 8192);
+    assert_eq!(partitions[0].blocks, 524_288);
+    assert!(partitions[0].is_boot() && !partitions[0].is_kernel_slot());
+    assert!(partitions[1].is_kernel_slot() && !partitions[1].is_boot());
+    assert!(!partitions[2].is_boot() && !partitions[2].is_kernel_slot());
+    assert!(partitions.iter().all(|p| p.name.is_empty()));
+    assert_eq!(partitions[2].kind.to_string(), "0x83");
+}
+
+#[test]
+fn empty_mbr_entries() {
+    // entries without a type, a start or a size are skipped
+    let mut image = mbr(&[
+        (0, 8192, 1024),
+        (MBR_FAT32_LBA, 0, 1024),
+        (MBR_FAT32_LBA, 8192, 0),
+    ]);
+    assert!(partition::read(&mut image).unwrap().is_empty());
+}
+
+#[test]
+fn no_partition_table() {
+    let mut image = mbr(&[(MBR_FAT32_LBA, 8192, 524_288)]);
+    put16(image.blocks_mut(0, 1), 510, 0);
+    assert_eq!(
+        partition::read(&mut image).unwrap_err(),
+        PartitionError::NoPartitionTable
+    );
+    assert_eq!(
+        partition::read(&mut Image::new(0)).unwrap_err(),
+        PartitionError::Device(())
+    );
+}
+
+#[test]
+fn gpt_partitions() {
+    let mut image = gpt(&GPT_PARTITIONS, ENTRIES, ENTRY_SIZE);
+    let partitions = partition::read(&mut image).unwrap();
+    assert_eq!(partitions.len(), GPT_PARTITIONS.len());
+    for (idx, (partition, &(kind, first, last, name))) in
+        partitions.iter().zip(GPT_PARTITIONS.iter()).enumerate()
+    {
+        assert_eq!(partition.number, idx + 1);
+        assert_eq!(partition.kind.to_string(), kind);
+        assert_eq!(partition.start, first);
+        // the last block is part of the partition
+        assert_eq!(partition.blocks, last - first + 1);
+        assert_eq!(partition.name, name);
+    }
+    assert!(partitions[0].is_boot() && !partitions[0].is_kernel_slot());
+    assert!(partitions[1].is_kernel_slot() && !partitions[1].is_boot());
+    assert!(!partitions[2].is_boot() && !partitions[2].is_kernel_slot());
+}
+
+#[test]
+fn gpt_skips_entries() {
+    // unused entries and entries ending before they start are skipped, the numbers follow the entries
+    let partitions = [
+        ("00000000-0000-0000-0000-000000000000", 2048, 4095, ""),
+        (LINUX_DATA, 8192, 8191, "broken"),
+        (KERNEL_SLOT, 8192, 8192, "kernel-b"),
+    ];
+    let mut image = gpt(&partitions, ENTRIES, ENTRY_SIZE);
+    let partitions = partition::read(&mut image).unwrap();
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0].number, 3);
+    assert_eq!(partitions[0].blocks, 1);
+    assert_eq!(partitions[0].name, "kernel-b");
+}
+
+#[test]
+fn gpt_larger_entries() {
+    // entries larger than 128 bytes are read with their size
+    let mut image = gpt(&GPT_PARTITIONS, 32, 256);
+    let partitions = partition::read(&mut image).unwrap();
+    assert_eq!(partitions.len(), GPT_PARTITIONS.len());
+    assert_eq!(partitions[2].start, GPT_PARTITIONS[2].1);
+    assert_eq!(partitions[2].name, "root");
+}
+
+#[test]
+fn gpt_header_checksum() {
+    let mut image = gpt(&GPT_PARTITIONS, ENTRIES, ENTRY_SIZE);
+    // the first usable block changes without updating the checksum
+    image.blocks_mut(1, 1)[40] ^= 1;
+    assert_eq!(
+        partition::read(&mut image).unwrap_err(),
+        PartitionError::BadChecksum
+    );
+
+    let mut image = gpt(&GPT_PARTITIONS, ENTRIES, ENTRY_SIZE);
+    image.blocks_mut(1, 1)[16] ^= 0x80;
+    assert_eq!(
+        partition::read(&mut image).unwrap_err(),
+        PartitionError::BadChecksum
+    );
+}
+
+#[test]
+fn gpt_entries_checksum() {
+    for &offset in [0, 32, ENTRY_SIZE * 2 + 56, ENTRY_SIZE * (ENTRIES - 1)].iter() {
+        let mut image = gpt(&GPT_PARTITIONS, ENTRIES, ENTRY_SIZE);
+        let block = ENTRIES_START + offset / BLOCK_SIZE;
+        image.blocks_mut(block, 1)[offset % BLOCK_SIZE] ^= 1;
+        assert_eq!(
+            partition::read(&mut image).unwrap_err(),
+            PartitionError::BadChecksum,
+            "entry byte {}",
+            offset
+        );
+    }
+}
+
+#[test]
+fn gpt_bad_header() {
+    let mut image = gpt(&GPT_PARTITIONS, ENTRIES, ENTRY_SIZE);
+    image.blocks_mut(1, 1)[0] = b'e';
+    seal(&mut image);
+    assert_eq!(
+        partition::read(&mut image).unwrap_err(),
+        PartitionError::BadTable
+    );
+
+    for &size in [0, 91, BLOCK_SIZE + 1].iter() {
+        let mut image = gpt(&GPT_PARTITIONS, ENTRIES, ENTRY_SIZE);
+        put32(image.blocks_mut(1, 1), 12, size as u32);
+        seal(&mut image);
+        assert_eq!(
+            partition::read(&mut image).unwrap_err(),
+            PartitionError::BadTable,
+            "header size {}",
+            size
+        );
+    }
+}
+
+#[test]
+fn gpt_bad_entries() {
+    // entries smaller than 128 bytes or not a power of two, no entries and more than 16kB of entries
+    for &(count, entry_size) in [
+        (128, 64),
+        (128, 0),
+        (64, 192),
+        (0, 128),
+        (129, 128),
+        (65, 256),
+    ]
+    .iter()
+    {
+        let mut image = gpt(&GPT_PARTITIONS, ENTRIES, ENTRY_SIZE);
+        let header = image.blocks_mut(1, 1);
+        put32(header, 80, count);
+        put32(header, 84, entry_size);
+        seal(&mut image);
+        assert_eq!(
+            partition::read(&mut image).unwrap_err(),
+            PartitionError::BadTable,
+            "{} entries of {} bytes",
+            count,
+            entry_size
+        );
+    }
+}
+
+#[test]
+fn gpt_entries_beyond_device() {
+    let mut image = gpt(&GPT_PARTITIONS, ENTRIES, ENTRY_SIZE);
+    put64(image.blocks_mut(1, 1), 72, IMAGE_BLOCKS as u64 - 1);
+    seal(&mut image);
+    assert_eq!(
+        partition::read(&mut image).unwrap_err(),
+        PartitionError::Device(())
+    );
+}
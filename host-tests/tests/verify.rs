@@ -0,0 +1,321 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Verification tests
+//!
+//! Check the hash functions against the messages of FIPS 180-4 and the signatures against the test vectors of
+//! RFC 8032, and verify kernels with intact and tampered verification headers. The signatures of the headers are
+//! only checked with ``cargo test --features signed_kernels``.
+//!
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/ed25519.rs"]
+mod ed25519;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/sha2.rs"]
+mod sha2;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/verify.rs"]
+mod verify;
+
+use verify::VerifyError;
+
+/// The payload signed with the secret key of the first test vector of RFC 8032
+const PAYLOAD: &[u8] = b"RusPiRo test kernel payload";
+const PAYLOAD_SIGNATURE: &str = "cb58aca6bb0edb031664672ab4c873d895bc39e49cdde897bb7643f41957368b\
+                                 6ec5c07f230c035c2216573ee18b752c7da85fee24a44fa37d91e5cff01ce900";
+
+/// The messages of FIPS 180-4 with their SHA-256 and SHA-512
+const HASHES: &[(&[u8], &str, &str)] = &[
+    (
+        b"abc",
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+         2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+    ),
+    (
+        b"",
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce\
+         47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e",
+    ),
+    (
+        b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+        "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+        "204a8fc6dda82f0a0ced7beb8e08a41657c16ef468b228a8279be331a703c335\
+         96fd15c13b1b07f9aa1d3bea57789ca031ad85c7a71dd70354ec631238ca3445",
+    ),
+    (
+        b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrst\
+          nopqrstu",
+        "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1",
+        "8e959b75dae313da8cf4f72814fc143f8f7779c6eb9f7fa17299aeadb6889018\
+         501d289e4900f7e4331b99dec4b5433ac7d329eeb6dd26545e96e55b874be909",
+    ),
+];
+
+/// The test vectors of RFC 8032 section 7.1: public key, message and signature
+const SIGNATURES: &[(&str, &str, &str)] = &[
+    (
+        "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+        "",
+        "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+         5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+    ),
+    (
+        "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+        "72",
+        "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+         085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+    ),
+    (
+        "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+        "af82",
+        "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+         18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+    ),
+    (
+        "ec172b93ad5e563bf4932c70e1245034c35467ef2efd4d64ebf819683467e2bf",
+        "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+         2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+        "dc2a4459e7369633a52b1bf277839a00201009a3efbf3ecb69bea2186c26b589\
+         09351fc9ac90b3ecfdfbc7c66431e0303dca179c138ac17ad9bef1177331a704",
+    ),
+];
+
+fn hex(digits: &str) -> Vec<u8> {
+    (0..digits.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&digits[idx..idx + 2], 16).unwrap())
+        .collect()
+}
+
+fn sha512(data: &[u8], chunk_size: usize) -> [u8; 64] {
+    let mut hasher = sha2::Sha512::new();
+    for chunk in data.chunks(chunk_size) {
+        hasher.update(chunk);
+    }
+    hasher.finish()
+}
+
+fn sha256(data: &[u8], chunk_size: usize) -> [u8; 32] {
+    let mut hasher = sha2::Sha256::new();
+    for chunk in data.chunks(chunk_size) {
+        hasher.update(chunk);
+    }
+    hasher.finish()
+}
+
+fn check_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let mut key = [0; ed25519::PUBLIC_KEY_SIZE];
+    key.copy_from_slice(public_key);
+    let mut bytes = [0; ed25519::SIGNATURE_SIZE];
+    bytes.copy_from_slice(signature);
+    ed25519::verify(&key, message, &bytes)
+}
+
+/// Put a verification header with the digest of the payload and the signature in front of the payload
+fn with_header(payload: &[u8], signature: &[u8]) -> Vec<u8> {
+    let mut binary = Vec::new();
+    binary.extend_from_slice(b"RPVH");
+    binary.extend_from_slice(&1u16.to_le_bytes());
+    binary.extend_from_slice(&(verify::HEADER_SIZE as u16).to_le_bytes());
+    binary.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    binary.extend_from_slice(&[0; 4]);
+    binary.extend_from_slice(&sha2::sha256(payload));
+    binary.extend_from_slice(signature);
+    binary.extend_from_slice(payload);
+    binary
+}
+
+fn signed() -> Vec<u8> {
+    with_header(PAYLOAD, &hex(PAYLOAD_SIGNATURE))
+}
+
+#[test]
+fn sha256_known_answers() {
+    for (message, digest, _) in HASHES.iter() {
+        assert_eq!(sha2::sha256(message)[..], hex(digest)[..]);
+        for &chunk_size in [1, 3, 63, 64, 65].iter() {
+            assert_eq!(sha256(message, chunk_size)[..], hex(digest)[..]);
+        }
+    }
+}
+
+#[test]
+fn sha512_known_answers() {
+    for (message, _, digest) in HASHES.iter() {
+        for &chunk_size in [1, 3, 127, 128, 129].iter() {
+            assert_eq!(sha512(message, chunk_size)[..], hex(digest)[..]);
+        }
+    }
+}
+
+#[test]
+fn sha_million_a() {
+    let message = vec![b'a'; 1_000_000];
+    assert_eq!(
+        sha256(&message, 1000)[..],
+        hex("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0")[..]
+    );
+    assert_eq!(
+        sha512(&message, 1000)[..],
+        hex(
+            "e718483d0ce769644e2e42c7bc15b4638e1f98b13b2044285632a803afa973eb\
+             de0ff244877ea60a4cb0432ce577c31beb009c5c2c49aa2e4eadb217ad8cc09b"
+        )[..]
+    );
+}
+
+#[test]
+fn ed25519_known_answers() {
+    for (public_key, message, signature) in SIGNATURES.iter() {
+        assert!(check_signature(
+            &hex(public_key),
+            &hex(message),
+            &hex(signature)
+        ));
+    }
+}
+
+#[test]
+fn ed25519_tampered() {
+    for (public_key, message, signature) in SIGNATURES.iter() {
+        let (public_key, message, signature) = (hex(public_key), hex(message), hex(signature));
+        for idx in 0..signature.len() {
+            let mut tampered = signature.clone();
+            tampered[idx] ^= 0x10;
+            assert!(!check_signature(&public_key, &message, &tampered));
+        }
+        let mut longer = message.clone();
+        longer.push(0);
+        assert!(!check_signature(&public_key, &longer, &signature));
+        if let Some((first, rest)) = message.split_first() {
+            let mut tampered = vec![first ^ 1];
+            tampered.extend_from_slice(rest);
+            assert!(!check_signature(&public_key, &tampered, &signature));
+        }
+        let mut other_key = public_key.clone();
+        other_key[0] ^= 1;
+        assert!(!check_signature(&other_key, &message, &signature));
+    }
+}
+
+#[test]
+fn ed25519_non_canonical_s() {
+    // S + L of the first test vector verifies with a malleable implementation but has to be refused
+    let (public_key, message, signature) = SIGNATURES[0];
+    let mut signature = hex(signature);
+    let order = hex("edd3f55c1a631258d69cf7a2def9de1400000000000000000000000000000010");
+    let mut carry = 0u16;
+    for idx in 0..32 {
+        let sum = signature[32 + idx] as u16 + order[idx] as u16 + carry;
+        signature[32 + idx] = sum as u8;
+        carry = sum >> 8;
+    }
+    assert!(!check_signature(
+        &hex(public_key),
+        &hex(message),
+        &signature
+    ));
+}
+
+#[test]
+fn intact_header() {
+    let binary = signed();
+    assert!(verify::has_header(&binary));
+    let payload = verify::verify(&binary).unwrap();
+    assert_eq!(&binary[payload], PAYLOAD);
+}
+
+#[test]
+fn trailing_data_is_dropped() {
+    let mut binary = signed();
+    binary.extend_from_slice(&[0x1A; 100]);
+    let payload = verify::verify(&binary).unwrap();
+    assert_eq!(&binary[payload], PAYLOAD);
+}
+
+#[test]
+fn tampered_payload() {
+    let mut binary = signed();
+    let last = binary.len() - 1;
+    binary[last] ^= 1;
+    assert_eq!(verify::verify(&binary), Err(VerifyError::DigestMismatch));
+}
+
+#[test]
+fn tampered_digest() {
+    let mut binary = signed();
+    binary[16] ^= 1;
+    assert_eq!(verify::verify(&binary), Err(VerifyError::DigestMismatch));
+}
+
+#[test]
+fn tampered_header_fields() {
+    let tamper = |offset: usize, value: u8| {
+        let mut binary = signed();
+        binary[offset] = value;
+        verify::verify(&binary)
+    };
+    // magic, version and header size
+    assert_eq!(tamper(0, b'X'), Err(VerifyError::BadHeader));
+    assert_eq!(tamper(4, 2), Err(VerifyError::BadHeader));
+    assert_eq!(tamper(6, 111), Err(VerifyError::BadHeader));
+    // a payload size beyond the binary or a shorter payload than the one digested
+    assert_eq!(tamper(9, 1), Err(VerifyError::BadHeader));
+    assert_eq!(
+        tamper(8, PAYLOAD.len() as u8 - 1),
+        Err(VerifyError::DigestMismatch)
+    );
+}
+
+#[test]
+fn truncated_header() {
+    let binary = signed();
+    for len in 0..verify::HEADER_SIZE {
+        assert_eq!(verify::verify(&binary[..len]), Err(VerifyError::BadHeader));
+    }
+    assert_eq!(
+        verify::verify(&binary[..binary.len() - 1]),
+        Err(VerifyError::BadHeader)
+    );
+}
+
+#[cfg(not(feature = "signed_kernels"))]
+#[test]
+fn signature_not_checked() {
+    let binary = with_header(PAYLOAD, &[0; ed25519::SIGNATURE_SIZE]);
+    assert!(verify::verify(&binary).is_ok());
+}
+
+#[cfg(feature = "signed_kernels")]
+#[test]
+fn unsigned_header() {
+    let binary = with_header(PAYLOAD, &[0; ed25519::SIGNATURE_SIZE]);
+    assert_eq!(verify::verify(&binary), Err(VerifyError::Unsigned));
+}
+
+#[cfg(feature = "signed_kernels")]
+#[test]
+fn tampered_signature() {
+    for offset in [48, 79, 80, 111].iter() {
+        let mut binary = signed();
+        binary[*offset] ^= 1;
+        assert_eq!(verify::verify(&binary), Err(VerifyError::BadSignature));
+    }
+}
+
+#[cfg(feature = "signed_kernels")]
+#[test]
+fn signature_of_other_payload() {
+    // a valid header for another payload keeps the signature of the signed one
+    let mut binary = with_header(b"another payload", &hex(PAYLOAD_SIGNATURE));
+    assert_eq!(verify::verify(&binary), Err(VerifyError::BadSignature));
+    binary.truncate(verify::HEADER_SIZE);
+    assert_eq!(verify::verify(&binary), Err(VerifyError::BadHeader));
+}
@@ -0,0 +1,343 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+#![no_std]
+
+//! # Transfer protocol
+//!
+//! The state machine receiving the requests of the host byte by byte. The host initiates a request with an 8 byte
+//! token:
+//! - ``DEADBEEF`` is followed by 5 bytes of metadata (size and architecture) and the kernel binary
+//! - ``RUSPIRO2`` is followed by the 16 byte extended header and the kernel binary
+//! - ``COMMAND:`` is followed by a command line terminated with ``\n``
+//!
+//! The receipt of the token, the header and the binary is acknowledged with ``ACK`` each, the receipt of the command
+//! line with a single ``ACK``. Bytes not forming a token while waiting for one are ignored.
+//!
+//! The state machine does not do any I/O on its own. It is fed with the received bytes and the current time and
+//! tells what to send to the host and which request has been received. This keeps it independent of the UART and
+//! the timer of the device, so it is tested on the host. The time is given in ticks of any clock, the timeout
+//! passed to the [Receiver] just need to use the same clock.
+//!
+//! If the loader listens on several transports at once, the [Arbiter] feeds each transport to its own receiver and
+//! locks onto the transport the host has started a request on first.
+//!
+//! Kernels sent from a terminal program with XMODEM or YMODEM are received by the [xmodem] module instead.
+//!
+
+extern crate alloc;
+use alloc::{string::String, vec::Vec};
+
+pub mod xmodem;
+
+/// The token the host sends to initiate the transfer of a kernel
+pub const TOKEN_KERNEL: &[u8; 8] = b"DEADBEEF";
+/// The token the host sends to initiate the transfer of a kernel with the extended header
+pub const TOKEN_EXTENDED: &[u8; 8] = b"RUSPIRO2";
+/// The token the host sends to initiate a command, followed by the command line terminated with '\n'
+pub const TOKEN_COMMAND: &[u8; 8] = b"COMMAND:";
+/// The acknowledge sent to the host
+pub const ACK: &[u8] = b"ACK";
+/// The maximum length of a command line, longer lines are truncated
+pub const MAX_COMMAND_LEN: usize = 256;
+
+/// Size of the metadata following [TOKEN_KERNEL]
+pub const METADATA_SIZE: usize = 5;
+/// Size of the extended header following [TOKEN_EXTENDED]
+pub const EXTENDED_HEADER_SIZE: usize = 16;
+
+/// A kernel transferred by the host
+#[derive(Debug, Clone, PartialEq)]
+pub struct KernelTransfer {
+    /// The architecture of the kernel, 32 or 64
+    pub aarch: u8,
+    /// The exception level to enter the kernel in, 0 for the default
+    pub entry_el: u8,
+    /// The flags of the extended header
+    pub flags: u16,
+    /// The address to load the kernel to, 0 for the default
+    pub address: u64,
+    /// The kernel binary
+    pub binary: Vec<u8>,
+}
+
+/// A request completely received from the host
+#[derive(Debug, Clone, PartialEq)]
+pub enum Received {
+    /// A kernel to be booted
+    Kernel(KernelTransfer),
+    /// A command line to be executed
+    Command(String),
+}
+
+/// Reasons why a request is refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferError {
+    /// The kernel is larger than the receiver accepts, the size announced is given
+    TooLarge(usize),
+    /// The memory for the kernel of the size given could not be allocated
+    OutOfMemory(usize),
+}
+
+/// What need to be done after a byte has been processed
+#[derive(Debug, Default, PartialEq)]
+pub struct Output {
+    /// The number of [ACK]s to be sent to the host, in order
+    pub acks: usize,
+    /// The request that has been completed with this byte
+    pub request: Option<Received>,
+    /// The request that has been refused with this byte. It is not acknowledged, so the host gives up on it.
+    pub error: Option<TransferError>,
+}
+
+#[derive(Debug)]
+enum State {
+    /// Waiting for a token
+    Idle,
+    /// Receiving a command line
+    Command(String),
+    /// Receiving the metadata or the extended header of the given size
+    Header {
+        data: [u8; EXTENDED_HEADER_SIZE],
+        len: usize,
+        size: usize,
+    },
+    /// Receiving the kernel binary
+    Binary(KernelTransfer, usize),
+}
+
+/// The receiving state machine
+#[derive(Debug)]
+pub struct Receiver {
+    state: State,
+    window: [u8; 8],
+    /// A request is dropped if no byte is received for this number of ticks in the middle of it
+    timeout: u64,
+    /// The time the last byte has been received
+    last: u64,
+    /// The maximum size of a kernel binary accepted
+    max_size: usize,
+}
+
+impl Receiver {
+    /// Create a receiver that drops a request the host does not continue within ``timeout`` ticks and refuses
+    /// kernels larger than ``max_size`` bytes. As the memory of the kernel is allocated before it is transferred,
+    /// the maximum size need to fit into the memory available.
+    pub const fn new(timeout: u64, max_size: usize) -> Self {
+        Receiver {
+            state: State::Idle,
+            window: [0; 8],
+            timeout,
+            last: 0,
+            max_size,
+        }
+    }
+
+    /// Check whether the receiver is waiting for a new request
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, State::Idle)
+    }
+
+    /// The number of bytes of the kernel binary received so far and its size, ``None`` if no binary is being
+    /// received
+    pub fn progress(&self) -> Option<(usize, usize)> {
+        match &self.state {
+            State::Binary(transfer, size) => Some((transfer.binary.len(), *size)),
+            _ => None,
+        }
+    }
+
+    /// Drop a partly received request and wait for a new one
+    pub fn reset(&mut self) {
+        self.state = State::Idle;
+        self.window = [0; 8];
+    }
+
+    /// Check whether the request in progress has timed out at the time ``now``. A timed out request is dropped and
+    /// ``true`` is returned.
+    pub fn poll(&mut self, now: u64) -> bool {
+        if self.is_idle() || now.saturating_sub(self.last) <= self.timeout {
+            return false;
+        }
+        self.reset();
+        true
+    }
+
+    /// Process the next byte received at the time ``now``
+    pub fn receive(&mut self, byte: u8, now: u64) -> Output {
+        // a byte arriving after the request has timed out starts over
+        self.poll(now);
+        self.last = now;
+        let mut output = Output::default();
+        match &mut self.state {
+            State::Idle => {
+                self.window.copy_within(1.., 0);
+                self.window[7] = byte;
+                let next = match &self.window {
+                    TOKEN_KERNEL => Some(header(METADATA_SIZE)),
+                    TOKEN_EXTENDED => Some(header(EXTENDED_HEADER_SIZE)),
+                    TOKEN_COMMAND => Some(State::Command(String::new())),
+                    _ => None,
+                };
+                if let Some(next) = next {
+                    if let State::Header { .. } = next {
+                        output.acks = 1;
+                    }
+                    self.window = [0; 8];
+                    self.state = next;
+                }
+            }
+            State::Command(line) => match byte {
+                b'\n' => {
+                    let line = core::mem::take(line);
+                    self.state = State::Idle;
+                    output.acks = 1;
+                    output.request = Some(Received::Command(line));
+                }
+                b'\r' => (),
+                _ if line.len() >= MAX_COMMAND_LEN => (),
+                c => line.push(c as char),
+            },
+            State::Header { data, len, size } => {
+                data[*len] = byte;
+                *len += 1;
+                if *len < *size {
+                    return output;
+                }
+                let mut transfer = parse_header(&data[..*size]);
+                let binary_size = le32(&data[0..4]) as usize;
+                if binary_size > self.max_size {
+                    self.state = State::Idle;
+                    output.error = Some(TransferError::TooLarge(binary_size));
+                    return output;
+                }
+                // the memory for the binary is allocated before the host is requested to send it
+                if transfer.binary.try_reserve_exact(binary_size).is_err() {
+                    self.state = State::Idle;
+                    output.error = Some(TransferError::OutOfMemory(binary_size));
+                    return output;
+                }
+                output.acks = 1;
+                if binary_size == 0 {
+                    self.state = State::Idle;
+                    output.acks = 2;
+                    output.request = Some(Received::Kernel(transfer));
+                } else {
+                    self.state = State::Binary(transfer, binary_size);
+                }
+            }
+            State::Binary(transfer, size) => {
+                transfer.binary.push(byte);
+                if transfer.binary.len() < *size {
+                    return output;
+                }
+                output.acks = 1;
+                if let State::Binary(transfer, _) = core::mem::replace(&mut self.state, State::Idle)
+                {
+                    output.request = Some(Received::Kernel(transfer));
+                }
+            }
+        }
+        output
+    }
+}
+
+/// The receivers of several transports, locking onto the first transport a request is started on
+#[derive(Debug)]
+pub struct Arbiter {
+    receivers: Vec<Receiver>,
+    active: Option<usize>,
+}
+
+impl Arbiter {
+    /// Create an arbiter for the given number of transports, each with a [Receiver] created with ``timeout`` and
+    /// ``max_size``
+    pub fn new(transports: usize, timeout: u64, max_size: usize) -> Self {
+        Arbiter {
+            receivers: (0..transports)
+                .map(|_| Receiver::new(timeout, max_size))
+                .collect(),
+            active: None,
+        }
+    }
+
+    /// The transport locked onto, ``None`` as long as the host has not started a request on any transport
+    pub fn active(&self) -> Option<usize> {
+        self.active
+    }
+
+    /// Check whether the receivers are waiting for a new request
+    pub fn is_idle(&self) -> bool {
+        self.receivers.iter().all(Receiver::is_idle)
+    }
+
+    /// The progress of the kernel binary being received on any transport, see [Receiver::progress]
+    pub fn progress(&self) -> Option<(usize, usize)> {
+        self.receivers.iter().find_map(Receiver::progress)
+    }
+
+    /// Check whether a request in progress has timed out at the time ``now``, see [Receiver::poll]
+    pub fn poll(&mut self, now: u64) -> bool {
+        // every receiver is polled, even if another one has timed out already
+        let mut timed_out = false;
+        for receiver in self.receivers.iter_mut() {
+            timed_out |= receiver.poll(now);
+        }
+        timed_out
+    }
+
+    /// Process the next byte received on the transport at the time ``now``. Once a token has been received on a
+    /// transport the partial requests of the other transports are dropped and anything they receive is ignored
+    /// from then on.
+    pub fn receive(&mut self, transport: usize, byte: u8, now: u64) -> Output {
+        if matches!(self.active, Some(active) if active != transport) {
+            return Output::default();
+        }
+        let receiver = match self.receivers.get_mut(transport) {
+            Some(receiver) => receiver,
+            None => return Output::default(),
+        };
+        let output = receiver.receive(byte, now);
+        if self.active.is_none() && (!receiver.is_idle() || output.request.is_some()) {
+            self.active = Some(transport);
+            for (idx, receiver) in self.receivers.iter_mut().enumerate() {
+                if idx != transport {
+                    receiver.reset();
+                }
+            }
+        }
+        output
+    }
+}
+
+const fn header(size: usize) -> State {
+    State::Header {
+        data: [0; EXTENDED_HEADER_SIZE],
+        len: 0,
+        size,
+    }
+}
+
+/// Extract the transfer parameters from the metadata or the extended header
+fn parse_header(data: &[u8]) -> KernelTransfer {
+    let mut transfer = KernelTransfer {
+        aarch: data[4],
+        entry_el: 0,
+        flags: 0,
+        address: 0,
+        binary: Vec::new(),
+    };
+    if data.len() == EXTENDED_HEADER_SIZE {
+        transfer.entry_el = data[5];
+        transfer.flags = data[6] as u16 | (data[7] as u16) << 8;
+        transfer.address = le32(&data[8..12]) as u64 | (le32(&data[12..16]) as u64) << 32;
+    }
+    transfer
+}
+
+fn le32(data: &[u8]) -> u32 {
+    data[0] as u32 | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24
+}
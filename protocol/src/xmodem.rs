@@ -0,0 +1,369 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # XMODEM and YMODEM
+//!
+//! The receiving side of XMODEM and YMODEM, the file transfer protocols built into most terminal programs, like
+//! ``sx`` and ``sb`` of lrzsz used by minicom and picocom. They are an alternative to the transfer of the host tool:
+//! each block carries its number and a CRC-16, a corrupted or lost block is requested again and the transfer only
+//! completes if every block has been received intact.
+//!
+//! The receiver asks the sender to start with [CRC_REQUEST] every timeout, so only the CRC-16 variant is used, with
+//! blocks of 128 ([SOH]) or 1024 ([STX]) bytes. The protocol is told by the first block: YMODEM starts with the
+//! block 0 carrying the name and the size of the file, XMODEM right away with the data in block 1. The data of an
+//! XMODEM transfer keeps the padding of its last block, YMODEM cuts the data to the size given in the header. Only
+//! the first file of a YMODEM batch is received, the sender is cancelled if it offers more.
+//!
+//! Like the [Receiver](crate::Receiver) this is an I/O free state machine, fed with the received bytes and the
+//! current time in ticks of any clock, that tells what to reply to the sender.
+//!
+
+use alloc::{string::String, vec::Vec};
+
+/// Starts a block of 128 bytes
+pub const SOH: u8 = 0x01;
+/// Starts a block of 1024 bytes
+pub const STX: u8 = 0x02;
+/// Sent by the sender after the last block
+pub const EOT: u8 = 0x04;
+/// The block has been received intact
+pub const ACK: u8 = 0x06;
+/// The block has not been received intact and need to be sent again
+pub const NAK: u8 = 0x15;
+/// Two of them cancel the transfer
+pub const CAN: u8 = 0x18;
+/// Requests the sender to start the transfer with CRC-16 instead of the arithmetic checksum
+pub const CRC_REQUEST: u8 = b'C';
+
+/// The number of requests to start before the receiver gives up
+pub const MAX_START_REQUESTS: u32 = 20;
+/// The number of errors in a row before the transfer is cancelled
+pub const MAX_RETRIES: u32 = 10;
+
+const REPLY_START: &[u8] = &[CRC_REQUEST];
+const REPLY_ACK: &[u8] = &[ACK];
+const REPLY_NAK: &[u8] = &[NAK];
+/// The header of a YMODEM file is acknowledged and the data is requested like the header before
+const REPLY_HEADER_ACK: &[u8] = &[ACK, CRC_REQUEST];
+const REPLY_CANCEL: &[u8] = &[CAN, CAN];
+
+/// A file received completely
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct File {
+    /// The name of the file, empty for XMODEM
+    pub name: String,
+    /// The size of the file given in the YMODEM header, the data is cut to this size
+    pub size: Option<usize>,
+    pub data: Vec<u8>,
+}
+
+/// Reasons why a transfer failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XmodemError {
+    /// The sender has not started within [MAX_START_REQUESTS] requests
+    NoSender,
+    /// The sender has cancelled the transfer
+    Cancelled,
+    /// The sender has ended the YMODEM batch without sending a file
+    NoFile,
+    /// A block has not been received intact [MAX_RETRIES] times in a row
+    TooManyErrors,
+    /// The sender sent a block out of order, the number of the block is given
+    OutOfSequence(u8),
+    /// The file is larger than the receiver accepts, the size is given
+    TooLarge(usize),
+    /// The memory for the file of the size given could not be allocated
+    OutOfMemory(usize),
+}
+
+/// What need to be done after a byte has been processed or the time has been checked
+#[derive(Debug, Default, PartialEq)]
+pub struct Output {
+    /// The bytes to send to the sender
+    pub reply: &'static [u8],
+    /// The transfer has ended with this byte, the receiver ignores anything received afterwards
+    pub done: Option<Result<File, XmodemError>>,
+}
+
+impl Output {
+    fn reply(reply: &'static [u8]) -> Self {
+        Output { reply, done: None }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    /// Waiting for the first block, the number of requests to start sent so far is given
+    Start(u32),
+    /// Receiving the data blocks
+    Data,
+    /// The first end of the transfer has been refused to be sure it was not a corrupted byte
+    End,
+    /// The YMODEM file has been received, waiting for the empty header ending the batch
+    BatchEnd,
+    /// The transfer has ended
+    Done,
+}
+
+/// The receiving state machine
+#[derive(Debug)]
+pub struct Receiver {
+    stage: Stage,
+    /// The block being received without its first byte, empty while waiting for the next block
+    block: Vec<u8>,
+    /// The size of the data of the block being received, 0 while waiting for the next block
+    block_size: usize,
+    /// The number of the next data block
+    next: u8,
+    ymodem: bool,
+    file: File,
+    /// The errors in a row
+    retries: u32,
+    /// The previous byte has been a [CAN]
+    cancelling: bool,
+    /// The sender is requested to start or to repeat a block if nothing is received for this number of ticks
+    timeout: u64,
+    /// The time the last byte has been received or the last request has been sent
+    last: Option<u64>,
+    /// The maximum size of a file accepted
+    max_size: usize,
+}
+
+impl Receiver {
+    /// Create a receiver that requests the sender to start or to repeat a block every ``timeout`` ticks and refuses
+    /// files larger than ``max_size`` bytes. The first request is sent with the first [Receiver::poll].
+    pub fn new(timeout: u64, max_size: usize) -> Self {
+        Receiver {
+            stage: Stage::Start(0),
+            block: Vec::new(),
+            block_size: 0,
+            next: 1,
+            ymodem: false,
+            file: File::default(),
+            retries: 0,
+            cancelling: false,
+            timeout,
+            last: None,
+            max_size,
+        }
+    }
+
+    /// The number of bytes received so far and the size of the file if the YMODEM header has given it
+    pub fn progress(&self) -> (usize, Option<usize>) {
+        (self.file.data.len(), self.file.size)
+    }
+
+    /// Check whether the sender needs to be requested to start or to repeat a block at the time ``now``
+    pub fn poll(&mut self, now: u64) -> Output {
+        let timed_out = match self.last {
+            Some(last) => now.saturating_sub(last) > self.timeout,
+            None => true,
+        };
+        if self.stage == Stage::Done || !timed_out {
+            return Output::default();
+        }
+        self.last = Some(now);
+        if self.block_size != 0 {
+            // the rest of the block got lost
+            self.block_size = 0;
+            return self.retry();
+        }
+        match self.stage {
+            Stage::Start(requests) if requests >= MAX_START_REQUESTS => {
+                self.stage = Stage::Done;
+                Output {
+                    reply: &[],
+                    done: Some(Err(XmodemError::NoSender)),
+                }
+            }
+            Stage::Start(requests) => {
+                self.stage = Stage::Start(requests + 1);
+                Output::reply(REPLY_START)
+            }
+            _ => self.retry(),
+        }
+    }
+
+    /// Process the next byte received at the time ``now``
+    pub fn receive(&mut self, byte: u8, now: u64) -> Output {
+        if self.stage == Stage::Done {
+            return Output::default();
+        }
+        let waiting = self.block_size == 0;
+        // anything but a block received before the sender has started does not hold up the requests to start
+        if !(waiting && matches!(self.stage, Stage::Start(_)) && byte != SOH && byte != STX) {
+            self.last = Some(now);
+        }
+        if !waiting {
+            self.block.push(byte);
+            if self.block.len() < self.block_size + 4 {
+                return Output::default();
+            }
+            self.block_size = 0;
+            return self.block_received();
+        }
+
+        let cancelling = core::mem::replace(&mut self.cancelling, byte == CAN);
+        match (byte, self.stage) {
+            (SOH, _) | (STX, _) => {
+                self.block.clear();
+                self.block_size = if byte == SOH { 128 } else { 1024 };
+                Output::default()
+            }
+            (CAN, _) if cancelling => self.end(&[], Err(XmodemError::Cancelled)),
+            (EOT, Stage::Data) => {
+                self.stage = Stage::End;
+                Output::reply(REPLY_NAK)
+            }
+            (EOT, Stage::End) if self.ymodem => {
+                self.stage = Stage::BatchEnd;
+                Output::reply(REPLY_HEADER_ACK)
+            }
+            (EOT, Stage::End) => {
+                let file = core::mem::take(&mut self.file);
+                self.end(REPLY_ACK, Ok(file))
+            }
+            // the acknowledge of the end got lost
+            (EOT, Stage::BatchEnd) => Output::reply(REPLY_HEADER_ACK),
+            // noise between the blocks
+            _ => Output::default(),
+        }
+    }
+
+    /// Check the block completely received and take its data
+    fn block_received(&mut self) -> Output {
+        let block = core::mem::take(&mut self.block);
+        let size = block.len() - 4;
+        let (number, inverse) = (block[0], block[1]);
+        let data = &block[2..size + 2];
+        let crc = (block[size + 2] as u16) << 8 | block[size + 3] as u16;
+        if number != !inverse || crc16(data) != crc {
+            return self.retry();
+        }
+        self.retries = 0;
+
+        match self.stage {
+            Stage::Start(_) | Stage::BatchEnd if number == 0 => self.header(data),
+            Stage::Start(_) if number == 1 => {
+                self.stage = Stage::Data;
+                self.append(data)
+            }
+            Stage::Data | Stage::End if number == self.next => {
+                // a block after the end has been refused, so the end has been noise
+                self.stage = Stage::Data;
+                self.append(data)
+            }
+            // the acknowledge of the previous block got lost
+            Stage::Data | Stage::End if number == self.next.wrapping_sub(1) => {
+                if self.ymodem && number == 0 && self.file.data.is_empty() {
+                    Output::reply(REPLY_HEADER_ACK)
+                } else {
+                    Output::reply(REPLY_ACK)
+                }
+            }
+            _ => self.end(REPLY_CANCEL, Err(XmodemError::OutOfSequence(number))),
+        }
+    }
+
+    /// Take the YMODEM header with the name and the size of the file, an empty one ends the batch
+    fn header(&mut self, data: &[u8]) -> Output {
+        let name_len = data
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(data.len());
+        if name_len == 0 {
+            return match self.stage {
+                Stage::BatchEnd => {
+                    let file = core::mem::take(&mut self.file);
+                    self.end(REPLY_ACK, Ok(file))
+                }
+                _ => self.end(REPLY_ACK, Err(XmodemError::NoFile)),
+            };
+        }
+        if self.stage == Stage::BatchEnd {
+            // only the first file is taken
+            let file = core::mem::take(&mut self.file);
+            return self.end(REPLY_CANCEL, Ok(file));
+        }
+
+        let digits = data[name_len..]
+            .iter()
+            .skip(1)
+            .take_while(|byte| byte.is_ascii_digit());
+        let size = digits.clone().next().map(|_| {
+            digits.fold(0_usize, |size, digit| {
+                size.saturating_mul(10)
+                    .saturating_add((digit - b'0') as usize)
+            })
+        });
+        if let Some(size) = size {
+            if size > self.max_size {
+                return self.end(REPLY_CANCEL, Err(XmodemError::TooLarge(size)));
+            }
+            if self.file.data.try_reserve_exact(size).is_err() {
+                return self.end(REPLY_CANCEL, Err(XmodemError::OutOfMemory(size)));
+            }
+        }
+        self.file.name = String::from_utf8_lossy(&data[..name_len]).into_owned();
+        self.file.size = size;
+        self.ymodem = true;
+        self.stage = Stage::Data;
+        self.next = 1;
+        Output::reply(REPLY_HEADER_ACK)
+    }
+
+    /// Append the data of the next block to the file
+    fn append(&mut self, data: &[u8]) -> Output {
+        let data = match self.file.size {
+            Some(size) => &data[..data.len().min(size.saturating_sub(self.file.data.len()))],
+            None => data,
+        };
+        let size = self.file.data.len() + data.len();
+        if size > self.max_size {
+            return self.end(REPLY_CANCEL, Err(XmodemError::TooLarge(size)));
+        }
+        if self.file.data.try_reserve(data.len()).is_err() {
+            return self.end(REPLY_CANCEL, Err(XmodemError::OutOfMemory(size)));
+        }
+        self.file.data.extend_from_slice(data);
+        self.next = self.next.wrapping_add(1);
+        Output::reply(REPLY_ACK)
+    }
+
+    /// Request the block again, or cancel the transfer after too many errors in a row
+    fn retry(&mut self) -> Output {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            return self.end(REPLY_CANCEL, Err(XmodemError::TooManyErrors));
+        }
+        match self.stage {
+            Stage::BatchEnd => Output::reply(REPLY_START),
+            _ => Output::reply(REPLY_NAK),
+        }
+    }
+
+    fn end(&mut self, reply: &'static [u8], result: Result<File, XmodemError>) -> Output {
+        self.stage = Stage::Done;
+        Output {
+            reply,
+            done: Some(result),
+        }
+    }
+}
+
+/// The CRC-16 of the blocks, CCITT polynomial 0x1021 starting with 0
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
@@ -0,0 +1,98 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Arbiter tests
+//!
+//! Feed several transports at once and check which one the arbiter locks onto.
+//!
+
+use ruspiro_loader_protocol::*;
+
+const TIMEOUT: u64 = 100;
+const MAX_SIZE: usize = 0x1000;
+
+/// Feed all bytes to the transport and collect the acknowledges and requests
+fn feed(arbiter: &mut Arbiter, transport: usize, data: &[u8]) -> (usize, Vec<Received>) {
+    let mut acks = 0;
+    let mut requests = Vec::new();
+    for &byte in data {
+        let output = arbiter.receive(transport, byte, 0);
+        acks += output.acks;
+        requests.extend(output.request);
+    }
+    (acks, requests)
+}
+
+#[test]
+fn noise_does_not_lock() {
+    let mut arbiter = Arbiter::new(2, TIMEOUT, MAX_SIZE);
+    assert_eq!(feed(&mut arbiter, 0, b"\r\nlogin: "), (0, vec![]));
+    assert_eq!(feed(&mut arbiter, 1, b"DEADBEE"), (0, vec![]));
+    assert_eq!(arbiter.active(), None);
+    assert!(arbiter.is_idle());
+}
+
+#[test]
+fn first_token_wins() {
+    let mut arbiter = Arbiter::new(2, TIMEOUT, MAX_SIZE);
+    feed(&mut arbiter, 0, b"DEAD");
+    assert_eq!(feed(&mut arbiter, 1, b"COMMAND:"), (0, vec![]));
+    assert_eq!(arbiter.active(), Some(1));
+    // the partial token of the other transport has been dropped and it is ignored from now on
+    assert_eq!(feed(&mut arbiter, 0, b"BEEF"), (0, vec![]));
+    assert_eq!(feed(&mut arbiter, 0, b"COMMAND:help\n"), (0, vec![]));
+    assert_eq!(
+        feed(&mut arbiter, 1, b"help\n"),
+        (1, vec![Received::Command("help".into())])
+    );
+    assert_eq!(arbiter.active(), Some(1));
+}
+
+#[test]
+fn kernel_on_locked_transport() {
+    let mut arbiter = Arbiter::new(2, TIMEOUT, MAX_SIZE);
+    let mut data = TOKEN_KERNEL.to_vec();
+    data.extend_from_slice(&2u32.to_le_bytes());
+    data.push(64);
+    data.extend_from_slice(&[1, 2]);
+    let (acks, requests) = feed(&mut arbiter, 0, &data);
+    assert_eq!(acks, 3);
+    assert_eq!(requests.len(), 1);
+    assert_eq!(arbiter.active(), Some(0));
+    assert!(arbiter.is_idle());
+}
+
+#[test]
+fn progress_of_locked_transport() {
+    let mut arbiter = Arbiter::new(2, TIMEOUT, MAX_SIZE);
+    let mut data = TOKEN_KERNEL.to_vec();
+    data.extend_from_slice(&8u32.to_le_bytes());
+    data.push(64);
+    data.extend_from_slice(&[1, 2, 3]);
+    feed(&mut arbiter, 1, &data);
+    assert_eq!(arbiter.progress(), Some((3, 8)));
+    feed(&mut arbiter, 0, &[4, 5]);
+    assert_eq!(arbiter.progress(), Some((3, 8)));
+}
+
+#[test]
+fn unknown_transport_ignored() {
+    let mut arbiter = Arbiter::new(1, TIMEOUT, MAX_SIZE);
+    assert_eq!(feed(&mut arbiter, 3, b"COMMAND:help\n"), (0, vec![]));
+    assert_eq!(arbiter.active(), None);
+}
+
+#[test]
+fn timeout_on_any_transport() {
+    let mut arbiter = Arbiter::new(2, TIMEOUT, MAX_SIZE);
+    feed(&mut arbiter, 1, TOKEN_KERNEL);
+    assert!(!arbiter.is_idle());
+    assert!(arbiter.poll(TIMEOUT + 1));
+    assert!(arbiter.is_idle());
+    // the transport stays locked after the request has been dropped
+    assert_eq!(arbiter.active(), Some(1));
+}
@@ -0,0 +1,317 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Receiver tests
+//!
+//! Feed the receiver the way the host tool does and check the acknowledges and requests it produces.
+//!
+
+use ruspiro_loader_protocol::*;
+
+const TIMEOUT: u64 = 100;
+const MAX_SIZE: usize = 0x1000;
+
+/// Feed all bytes at the given time and collect the acknowledges and requests
+fn feed(receiver: &mut Receiver, data: &[u8], now: u64) -> (usize, Vec<Received>) {
+    let mut acks = 0;
+    let mut requests = Vec::new();
+    for &byte in data {
+        let output = receiver.receive(byte, now);
+        acks += output.acks;
+        requests.extend(output.request);
+    }
+    (acks, requests)
+}
+
+fn metadata(size: u32, aarch: u8) -> Vec<u8> {
+    let mut data = size.to_le_bytes().to_vec();
+    data.push(aarch);
+    data
+}
+
+fn extended_header(size: u32, aarch: u8, entry_el: u8, flags: u16, address: u64) -> Vec<u8> {
+    let mut data = size.to_le_bytes().to_vec();
+    data.push(aarch);
+    data.push(entry_el);
+    data.extend_from_slice(&flags.to_le_bytes());
+    data.extend_from_slice(&address.to_le_bytes());
+    data
+}
+
+fn kernel(aarch: u8, entry_el: u8, flags: u16, address: u64, binary: &[u8]) -> Received {
+    Received::Kernel(KernelTransfer {
+        aarch,
+        entry_el,
+        flags,
+        address,
+        binary: binary.to_vec(),
+    })
+}
+
+#[test]
+fn kernel_transfer() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    assert_eq!(feed(&mut receiver, TOKEN_KERNEL, 0), (1, vec![]));
+    assert_eq!(feed(&mut receiver, &metadata(4, 64), 0), (1, vec![]));
+    assert!(!receiver.is_idle());
+    assert_eq!(
+        feed(&mut receiver, &[1, 2, 3, 4], 0),
+        (1, vec![kernel(64, 0, 0, 0, &[1, 2, 3, 4])])
+    );
+    assert!(receiver.is_idle());
+}
+
+#[test]
+fn binary_progress() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    feed(&mut receiver, TOKEN_KERNEL, 0);
+    assert_eq!(receiver.progress(), None);
+    feed(&mut receiver, &metadata(4, 64), 0);
+    assert_eq!(receiver.progress(), Some((0, 4)));
+    feed(&mut receiver, &[1, 2, 3], 0);
+    assert_eq!(receiver.progress(), Some((3, 4)));
+    feed(&mut receiver, &[4], 0);
+    assert_eq!(receiver.progress(), None);
+}
+
+#[test]
+fn extended_transfer() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    let mut data = TOKEN_EXTENDED.to_vec();
+    data.extend(extended_header(3, 64, 2, 1, 0x2000_0000));
+    data.extend_from_slice(&[7, 8, 9]);
+    assert_eq!(
+        feed(&mut receiver, &data, 0),
+        (3, vec![kernel(64, 2, 1, 0x2000_0000, &[7, 8, 9])])
+    );
+}
+
+#[test]
+fn empty_kernel() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    let mut data = TOKEN_KERNEL.to_vec();
+    data.extend(metadata(0, 32));
+    // the header and the (empty) binary are acknowledged at once
+    assert_eq!(
+        feed(&mut receiver, &data, 0),
+        (3, vec![kernel(32, 0, 0, 0, &[])])
+    );
+    assert!(receiver.is_idle());
+}
+
+#[test]
+fn kernel_too_large() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    feed(&mut receiver, TOKEN_KERNEL, 0);
+    let mut output = Output::default();
+    for &byte in &metadata(MAX_SIZE as u32 + 1, 64) {
+        output = receiver.receive(byte, 0);
+    }
+    // the header is not acknowledged and the receiver waits for the next request
+    assert_eq!(output.acks, 0);
+    assert_eq!(output.error, Some(TransferError::TooLarge(MAX_SIZE + 1)));
+    assert!(receiver.is_idle());
+    assert_eq!(
+        feed(&mut receiver, b"COMMAND:help\n", 0),
+        (1, vec![Received::Command("help".into())])
+    );
+}
+
+#[test]
+fn largest_kernel() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    let binary = vec![0x5A; MAX_SIZE];
+    let mut data = TOKEN_KERNEL.to_vec();
+    data.extend(metadata(MAX_SIZE as u32, 64));
+    data.extend_from_slice(&binary);
+    assert_eq!(
+        feed(&mut receiver, &data, 0),
+        (3, vec![kernel(64, 0, 0, 0, &binary)])
+    );
+}
+
+#[test]
+fn command() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    assert_eq!(
+        feed(&mut receiver, b"COMMAND:bootcount clear\r\n", 0),
+        (1, vec![Received::Command("bootcount clear".into())])
+    );
+}
+
+#[test]
+fn command_truncated() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    let mut data = TOKEN_COMMAND.to_vec();
+    data.extend_from_slice("x".repeat(MAX_COMMAND_LEN + 10).as_bytes());
+    data.push(b'\n');
+    let (acks, requests) = feed(&mut receiver, &data, 0);
+    assert_eq!(acks, 1);
+    assert_eq!(
+        requests,
+        vec![Received::Command("x".repeat(MAX_COMMAND_LEN))]
+    );
+}
+
+#[test]
+fn noise_before_token() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    // terminal output, a partial token and a token overlapping a partial one are skipped
+    assert_eq!(feed(&mut receiver, b"hello\r\nDEADDEADBEE", 0), (0, vec![]));
+    assert!(receiver.is_idle());
+    assert_eq!(feed(&mut receiver, b"F", 0), (1, vec![]));
+    assert!(!receiver.is_idle());
+}
+
+#[test]
+fn token_inside_command() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    // a token inside the command line is part of the line and does not start a new request
+    assert_eq!(
+        feed(&mut receiver, b"COMMAND:echo DEADBEEF\n", 0),
+        (1, vec![Received::Command("echo DEADBEEF".into())])
+    );
+    assert!(receiver.is_idle());
+}
+
+#[test]
+fn back_to_back_requests() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    let mut data = b"COMMAND:dryrun on\n".to_vec();
+    data.extend_from_slice(TOKEN_KERNEL);
+    data.extend(metadata(2, 32));
+    data.extend_from_slice(&[0xAA, 0xBB]);
+    data.extend_from_slice(b"COMMAND:help\n");
+    assert_eq!(
+        feed(&mut receiver, &data, 0),
+        (
+            5,
+            vec![
+                Received::Command("dryrun on".into()),
+                kernel(32, 0, 0, 0, &[0xAA, 0xBB]),
+                Received::Command("help".into()),
+            ]
+        )
+    );
+}
+
+#[test]
+fn binary_containing_tokens() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    let binary = b"DEADBEEFCOMMAND:";
+    let mut data = TOKEN_KERNEL.to_vec();
+    data.extend(metadata(binary.len() as u32, 64));
+    data.extend_from_slice(binary);
+    assert_eq!(
+        feed(&mut receiver, &data, 0),
+        (3, vec![kernel(64, 0, 0, 0, binary)])
+    );
+}
+
+#[test]
+fn timeout_drops_transfer() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    feed(&mut receiver, TOKEN_KERNEL, 0);
+    feed(&mut receiver, &metadata(100, 64), 10);
+    feed(&mut receiver, &[0; 10], 20);
+    assert!(!receiver.poll(20 + TIMEOUT));
+    assert!(receiver.poll(21 + TIMEOUT));
+    assert!(receiver.is_idle());
+    // nothing to drop any more
+    assert!(!receiver.poll(1000));
+}
+
+#[test]
+fn idle_never_times_out() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    feed(&mut receiver, b"DEAD", 0);
+    assert!(!receiver.poll(10 * TIMEOUT));
+    // the partial token is still kept
+    assert_eq!(feed(&mut receiver, b"BEEF", 10 * TIMEOUT), (1, vec![]));
+}
+
+#[test]
+fn retry_after_timeout() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    // the host gave up after the header and starts over, the late retry is not taken as binary data
+    feed(&mut receiver, TOKEN_KERNEL, 0);
+    feed(&mut receiver, &metadata(4, 64), 0);
+    let mut data = TOKEN_KERNEL.to_vec();
+    data.extend(metadata(2, 64));
+    data.extend_from_slice(&[5, 6]);
+    assert_eq!(
+        feed(&mut receiver, &data, TIMEOUT + 1),
+        (3, vec![kernel(64, 0, 0, 0, &[5, 6])])
+    );
+}
+
+#[test]
+fn duplicate_request() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    // the host resends the whole request as it has missed the last acknowledge, both are received
+    let mut data = TOKEN_KERNEL.to_vec();
+    data.extend(metadata(1, 64));
+    data.push(0x42);
+    let (acks, first) = feed(&mut receiver, &data, 0);
+    let (retry_acks, second) = feed(&mut receiver, &data, 1);
+    assert_eq!((acks, retry_acks), (3, 3));
+    assert_eq!(first, second);
+}
+
+#[test]
+fn duplicate_token() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    // a token sent twice is taken as start of the header announcing a huge kernel that is refused right away
+    feed(&mut receiver, TOKEN_KERNEL, 0);
+    let (acks, requests) = feed(&mut receiver, TOKEN_KERNEL, 0);
+    assert_eq!(acks, 0);
+    assert!(requests.is_empty());
+    assert!(receiver.is_idle());
+    // the following retry of the host is received
+    let mut data = TOKEN_KERNEL.to_vec();
+    data.extend(metadata(1, 64));
+    data.push(0x42);
+    assert_eq!(
+        feed(&mut receiver, &data, 0),
+        (3, vec![kernel(64, 0, 0, 0, &[0x42])])
+    );
+}
+
+#[test]
+fn out_of_order_header() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    // metadata and binary without a token are ignored
+    assert_eq!(feed(&mut receiver, &metadata(2, 64), 0), (0, vec![]));
+    assert_eq!(feed(&mut receiver, &[1, 2], 0), (0, vec![]));
+    assert!(receiver.is_idle());
+}
+
+#[test]
+fn reset_drops_partial_request() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    feed(&mut receiver, b"COMMAND:rebo", 0);
+    receiver.reset();
+    assert_eq!(feed(&mut receiver, b"ot\n", 0), (0, vec![]));
+    assert!(receiver.is_idle());
+}
+
+#[test]
+fn every_split_point() {
+    // the result does not depend on how the data is split into chunks with pauses shorter than the timeout
+    let mut data = TOKEN_EXTENDED.to_vec();
+    data.extend(extended_header(5, 64, 1, 0, 0x8_0000));
+    data.extend_from_slice(b"12345");
+    let expected = kernel(64, 1, 0, 0x8_0000, b"12345");
+    for split in 0..=data.len() {
+        let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+        let (acks, mut requests) = feed(&mut receiver, &data[..split], 0);
+        let (more_acks, more) = feed(&mut receiver, &data[split..], TIMEOUT);
+        requests.extend(more);
+        assert_eq!(acks + more_acks, 3);
+        assert_eq!(requests, vec![expected.clone()]);
+    }
+}
@@ -0,0 +1,253 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # XMODEM tests
+//!
+//! Feed the receiver the blocks the way ``sx`` and ``sb`` send them and check the replies and the files received.
+//!
+
+use ruspiro_loader_protocol::xmodem::*;
+
+const TIMEOUT: u64 = 100;
+const MAX_SIZE: usize = 0x1000;
+
+/// A data block with the given number, the data is padded with SUB like the senders do
+fn block(number: u8, data: &[u8], size: usize) -> Vec<u8> {
+    padded_block(number, data, size, 0x1A)
+}
+
+fn padded_block(number: u8, data: &[u8], size: usize, padding: u8) -> Vec<u8> {
+    let mut block = vec![if size == 128 { SOH } else { STX }, number, !number];
+    let mut payload = data.to_vec();
+    payload.resize(size, padding);
+    let crc = crc16(&payload);
+    block.extend(payload);
+    block.extend_from_slice(&crc.to_be_bytes());
+    block
+}
+
+/// The YMODEM header of a file with its name, size, modification time and mode, padded with zeros
+fn header(name: &str, size: usize) -> Vec<u8> {
+    let mut data = name.as_bytes().to_vec();
+    data.push(0);
+    data.extend_from_slice(format!("{} 13753634456 100644", size).as_bytes());
+    padded_block(0, &data, 128, 0)
+}
+
+/// The empty YMODEM header ending the batch
+fn end_of_batch() -> Vec<u8> {
+    padded_block(0, &[], 128, 0)
+}
+
+/// Feed all bytes at the given time and collect the replies and the result
+fn feed(
+    receiver: &mut Receiver,
+    data: &[u8],
+    now: u64,
+) -> (Vec<u8>, Option<Result<File, XmodemError>>) {
+    let mut replies = Vec::new();
+    let mut done = None;
+    for &byte in data {
+        let output = receiver.receive(byte, now);
+        replies.extend_from_slice(output.reply);
+        if output.done.is_some() {
+            done = output.done;
+        }
+    }
+    (replies, done)
+}
+
+#[test]
+fn crc_of_check_string() {
+    assert_eq!(crc16(b"123456789"), 0x31C3);
+}
+
+#[test]
+fn requests_to_start() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    assert_eq!(receiver.poll(0).reply, &[CRC_REQUEST]);
+    assert_eq!(receiver.poll(TIMEOUT).reply, &[] as &[u8]);
+    // noise does not hold up the requests
+    feed(&mut receiver, b"\r\n", TIMEOUT);
+    assert_eq!(receiver.poll(TIMEOUT + 1).reply, &[CRC_REQUEST]);
+}
+
+#[test]
+fn sender_not_starting() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    let mut now = 0;
+    for _ in 0..MAX_START_REQUESTS {
+        assert_eq!(receiver.poll(now).reply, &[CRC_REQUEST]);
+        now += TIMEOUT + 1;
+    }
+    assert_eq!(receiver.poll(now).done, Some(Err(XmodemError::NoSender)));
+}
+
+#[test]
+fn xmodem_transfer() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    receiver.poll(0);
+    let data: Vec<u8> = (0..200).map(|idx| idx as u8).collect();
+    assert_eq!(
+        feed(&mut receiver, &block(1, &data[..128], 128), 0),
+        (vec![ACK], None)
+    );
+    assert_eq!(
+        feed(&mut receiver, &block(2, &data[128..], 128), 0),
+        (vec![ACK], None)
+    );
+    assert_eq!(receiver.progress(), (256, None));
+    // the first end is refused in case it has been a corrupted byte
+    assert_eq!(feed(&mut receiver, &[EOT], 0), (vec![NAK], None));
+    let (replies, done) = feed(&mut receiver, &[EOT], 0);
+    assert_eq!(replies, vec![ACK]);
+    let file = done.unwrap().unwrap();
+    assert_eq!(file.name, "");
+    assert_eq!(file.size, None);
+    // XMODEM keeps the padding of the last block
+    assert_eq!(file.data.len(), 256);
+    assert_eq!(&file.data[..200], &data[..]);
+    assert!(file.data[200..].iter().all(|&byte| byte == 0x1A));
+}
+
+#[test]
+fn ymodem_transfer() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    receiver.poll(0);
+    let data: Vec<u8> = (0..1100).map(|idx| (idx * 7) as u8).collect();
+    assert_eq!(
+        feed(&mut receiver, &header("kernel8.img", data.len()), 0),
+        (vec![ACK, CRC_REQUEST], None)
+    );
+    assert_eq!(receiver.progress(), (0, Some(1100)));
+    assert_eq!(
+        feed(&mut receiver, &block(1, &data[..1024], 1024), 0),
+        (vec![ACK], None)
+    );
+    assert_eq!(
+        feed(&mut receiver, &block(2, &data[1024..], 128), 0),
+        (vec![ACK], None)
+    );
+    assert_eq!(feed(&mut receiver, &[EOT], 0), (vec![NAK], None));
+    assert_eq!(
+        feed(&mut receiver, &[EOT], 0),
+        (vec![ACK, CRC_REQUEST], None)
+    );
+    let (replies, done) = feed(&mut receiver, &end_of_batch(), 0);
+    assert_eq!(replies, vec![ACK]);
+    assert_eq!(
+        done,
+        Some(Ok(File {
+            name: "kernel8.img".into(),
+            size: Some(1100),
+            data,
+        }))
+    );
+}
+
+#[test]
+fn corrupted_block_repeated() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    receiver.poll(0);
+    let mut corrupted = block(1, b"kernel", 128);
+    corrupted[10] ^= 0x40;
+    assert_eq!(feed(&mut receiver, &corrupted, 0), (vec![NAK], None));
+    assert_eq!(
+        feed(&mut receiver, &block(1, b"kernel", 128), 0),
+        (vec![ACK], None)
+    );
+    assert_eq!(receiver.progress(), (128, None));
+}
+
+#[test]
+fn lost_bytes_repeated_after_timeout() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    receiver.poll(0);
+    let data = block(1, b"kernel", 128);
+    feed(&mut receiver, &data[..100], 0);
+    assert_eq!(receiver.poll(TIMEOUT).reply, &[] as &[u8]);
+    assert_eq!(receiver.poll(TIMEOUT + 1).reply, &[NAK]);
+    assert_eq!(feed(&mut receiver, &data, TIMEOUT + 2), (vec![ACK], None));
+}
+
+#[test]
+fn duplicate_block_acknowledged() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    receiver.poll(0);
+    feed(&mut receiver, &block(1, b"kernel", 128), 0);
+    // the acknowledge got lost, the block is sent again
+    assert_eq!(
+        feed(&mut receiver, &block(1, b"kernel", 128), 0),
+        (vec![ACK], None)
+    );
+    assert_eq!(receiver.progress(), (128, None));
+}
+
+#[test]
+fn block_out_of_sequence() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    receiver.poll(0);
+    feed(&mut receiver, &block(1, b"kernel", 128), 0);
+    assert_eq!(
+        feed(&mut receiver, &block(3, b"kernel", 128), 0),
+        (vec![CAN, CAN], Some(Err(XmodemError::OutOfSequence(3))))
+    );
+}
+
+#[test]
+fn too_many_errors() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    receiver.poll(0);
+    let mut corrupted = block(1, b"kernel", 128);
+    corrupted[10] ^= 0x40;
+    for _ in 0..MAX_RETRIES {
+        assert_eq!(feed(&mut receiver, &corrupted, 0), (vec![NAK], None));
+    }
+    assert_eq!(
+        feed(&mut receiver, &corrupted, 0),
+        (vec![CAN, CAN], Some(Err(XmodemError::TooManyErrors)))
+    );
+}
+
+#[test]
+fn file_too_large() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    receiver.poll(0);
+    assert_eq!(
+        feed(&mut receiver, &header("kernel8.img", MAX_SIZE + 1), 0),
+        (
+            vec![CAN, CAN],
+            Some(Err(XmodemError::TooLarge(MAX_SIZE + 1)))
+        )
+    );
+}
+
+#[test]
+fn cancelled_by_sender() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    receiver.poll(0);
+    feed(&mut receiver, &block(1, b"kernel", 128), 0);
+    assert_eq!(
+        feed(&mut receiver, &[CAN, CAN], 0),
+        (vec![], Some(Err(XmodemError::Cancelled)))
+    );
+    // anything received after the end is ignored
+    assert_eq!(
+        feed(&mut receiver, &block(2, b"kernel", 128), 0),
+        (vec![], None)
+    );
+}
+
+#[test]
+fn empty_batch() {
+    let mut receiver = Receiver::new(TIMEOUT, MAX_SIZE);
+    receiver.poll(0);
+    assert_eq!(
+        feed(&mut receiver, &end_of_batch(), 0),
+        (vec![ACK], Some(Err(XmodemError::NoFile)))
+    );
+}
@@ -0,0 +1,57 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Assertions
+//!
+//! Checks of conditions the loader relies on. A failed assertion prints the location and the expression on the
+//! console and stops the loader with the activity LED blinking rapidly. This tells far more than a panic while
+//! bringing up a driver and does not depend on the panic machinery, so it is also available with the feature
+//! "no_panic".
+//!
+
+use crate::console;
+use crate::led;
+use core::fmt;
+
+/// Report the failed assertion and stop the loader. This is used by the [loader_assert!] macro.
+pub fn failed(file: &str, line: u32, expression: &str, message: Option<fmt::Arguments>) -> ! {
+    // the output need to be sent right away as the loader does not continue
+    console::set_buffered(false);
+    match message {
+        Some(message) => println!(
+            "\r\nASSERT {}:{}: {} failed, {}",
+            file, line, expression, message
+        ),
+        None => println!("\r\nASSERT {}:{}: {} failed", file, line, expression),
+    }
+    led::error_blink()
+}
+
+/// Check the condition and stop the loader with its location and expression if it does not hold. An additional
+/// message could be given with format arguments.
+macro_rules! loader_assert {
+    ($cond:expr) => {
+        if !$cond {
+            $crate::assert::failed(file!(), line!(), stringify!($cond), None)
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::assert::failed(file!(), line!(), stringify!($cond), Some(format_args!($($arg)+)))
+        }
+    };
+}
+
+/// Like [loader_assert!] but only checked in debug builds
+#[allow(unused_macros)]
+macro_rules! loader_debug_assert {
+    ($($arg:tt)*) => {
+        if cfg!(debug_assertions) {
+            loader_assert!($($arg)*)
+        }
+    };
+}
@@ -0,0 +1,32 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Block devices
+//!
+//! Storage read and written in blocks of 512 bytes. The file systems only use this trait, so they do not depend on
+//! the driver of the storage and could be tested with an image in memory. They only read, writing is used for the
+//! raw kernel slots.
+//!
+
+use core::fmt::Debug;
+
+/// The size of a block
+pub const BLOCK_SIZE: usize = 512;
+
+/// A storage read and written in blocks
+pub trait BlockDevice {
+    /// The errors of the device
+    type Error: Debug;
+
+    /// Read consecutive blocks starting at the given block into the buffer, that need to be a multiple of the
+    /// [BLOCK_SIZE]
+    fn read_blocks(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Write the data to consecutive blocks starting at the given block, the data need to be a multiple of the
+    /// [BLOCK_SIZE]
+    fn write_blocks(&mut self, block: u64, data: &[u8]) -> Result<(), Self::Error>;
+}
@@ -0,0 +1,91 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Board layout
+//!
+//! The physical memory layout of the board the loader is built for. All knowledge about where the peripherals,
+//! the firmware stub and the kernels are located is kept here, so the drivers of the loader only use offsets into
+//! the peripheral blocks. The board is selected with the ``ruspiro_pi3`` feature that also selects the board of
+//! the RusPiRo crates used. The memory split between the ARM and the VideoCore is configured in the firmware and
+//! therefore queried at runtime from the mailbox.
+//!
+//! The Raspberry Pi Zero 2 W is built with the ``ruspiro_zero2w`` feature on top of ``ruspiro_pi3``. Its
+//! BCM2710A1 is the SoC of the Raspberry Pi 3 with the same peripherals, but the board only has 512MB of memory and
+//! the pins of the PL011 are taken by the wireless module. Its activity LED is chosen at runtime, see
+//! [crate::model].
+//!
+//! The boards with the BCM2711 like the Raspberry Pi 4 are not supported. The console, the GPIO and the interrupts
+//! are driven with RusPiRo crates that only know the BCM2837, so a loader for its peripherals at ``0xFE00_0000``
+//! would not get a line out. The layout is kept here to be extended once these crates support the BCM2711.
+//!
+
+#[cfg(not(feature = "ruspiro_pi3"))]
+compile_error!(
+    "the board need to be selected with the 'ruspiro_pi3' or the 'ruspiro_zero2w' feature"
+);
+#[cfg(all(feature = "ruspiro_zero2w", feature = "second_uart"))]
+compile_error!("the PL011 pins GPIO 32/33 are connected to the wireless module of the Zero 2 W");
+
+/// The size of a level 2 block of the translation tables
+pub const BLOCK_SIZE: u64 = 0x20_0000;
+
+/// The size of the memory of the board shared by the ARM and the VideoCore
+#[cfg(not(feature = "ruspiro_zero2w"))]
+pub const MEMORY_SIZE: u64 = 0x4000_0000;
+#[cfg(feature = "ruspiro_zero2w")]
+pub const MEMORY_SIZE: u64 = 0x2000_0000;
+
+/// The base address of the peripherals as seen by the ARM
+pub const PERIPHERAL_BASE: u64 = 0x3F00_0000;
+/// The size of the peripheral address range
+pub const PERIPHERAL_SIZE: u64 = 0x0100_0000;
+/// The base address of the ARM local peripherals like the core timers and mailboxes
+pub const ARM_LOCAL_BASE: u64 = 0x4000_0000;
+/// The size of the ARM local peripheral address range
+pub const ARM_LOCAL_SIZE: u64 = 0x0004_0000;
+
+/// The system timer
+pub const SYSTIMER_BASE: u64 = PERIPHERAL_BASE + 0x0000_3000;
+/// The DMA controller with the channels 0 to 14
+pub const DMA_BASE: u64 = PERIPHERAL_BASE + 0x0000_7000;
+/// The mailbox to the VideoCore
+pub const MAILBOX_BASE: u64 = PERIPHERAL_BASE + 0x0000_B880;
+/// The power management block containing the watchdog
+pub const PM_BASE: u64 = PERIPHERAL_BASE + 0x0010_0000;
+/// The GPIO controller
+pub const GPIO_BASE: u64 = PERIPHERAL_BASE + 0x0020_0000;
+/// The PL011 UART
+pub const UART0_BASE: u64 = PERIPHERAL_BASE + 0x0020_1000;
+/// The auxiliary peripherals containing the miniUART
+pub const AUX_BASE: u64 = PERIPHERAL_BASE + 0x0021_5000;
+/// The EMMC controller the SD card is connected to
+pub const EMMC_BASE: u64 = PERIPHERAL_BASE + 0x0030_0000;
+
+/// The VideoCore accesses the ARM memory through this bus address alias bypassing its L2 cache
+pub const VC_BUS_ALIAS: u32 = 0xC000_0000;
+/// Buffers shared with the VideoCore need to be located below this address
+pub const VC_BUS_LIMIT: u64 = 0x4000_0000;
+/// The bus address of the peripherals, e.g. as seen by the DMA controller
+pub const PERIPHERAL_BUS_BASE: u32 = 0x7E00_0000;
+
+/// The spin table of the firmware stub, the secondary cores wait for their entry address at ``SPIN_TABLE + 8 * core``
+pub const SPIN_TABLE: u64 = 0xD8;
+/// The end of the firmware stub containing the spin table the secondary cores are parked in
+pub const SPIN_TABLE_END: u64 = 0x1000;
+/// The address aarch32 kernels are placed at
+pub const KERNEL_ADDRESS_32: u64 = 0x8000;
+/// The address aarch64 kernels are placed at
+pub const KERNEL_ADDRESS_64: u64 = 0x8_0000;
+/// The number of cores
+pub const CORES: u32 = 4;
+/// The rate of the core clock the miniUART is clocked from, the firmware keeps it fixed while the UART is enabled
+pub const CORE_CLOCK_RATE: u32 = 250_000_000;
+
+/// The address of a register within a peripheral block
+pub const fn register(base: u64, offset: u64) -> *mut u32 {
+    (base + offset) as *mut u32
+}
@@ -0,0 +1,56 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Firmware boot arguments
+//!
+//! The firmware (or the armstub it runs before the loader) passes up to 4 arguments in the registers x0-x3 when
+//! branching to the kernel entry point. With a device tree loaded by the firmware x0 contains its address. The
+//! bootstrap code stores those values before anything else is done so they can be forwarded to the kernel the
+//! loader hands over to.
+
+/// The values of the registers x0-x3 at loader entry. They are written by the bootstrap code before this is
+/// entering the Rust code line and are never changed afterwards.
+#[no_mangle]
+static mut __firmware_boot_args: BootArgs = BootArgs::empty();
+
+/// Arguments passed in the registers x0-x3 (or r0-r3 in aarch32) to a kernel entry point
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootArgs {
+    pub x0: u64,
+    pub x1: u64,
+    pub x2: u64,
+    pub x3: u64,
+}
+
+impl BootArgs {
+    pub const fn empty() -> Self {
+        BootArgs {
+            x0: 0,
+            x1: 0,
+            x2: 0,
+            x3: 0,
+        }
+    }
+
+    /// Provide the arguments to be passed to an aarch32 kernel. The aarch32 boot protocol expects r0 = 0,
+    /// r1 = machine type (all bits set when booting with a device tree) and r2 = address of the device tree.
+    pub fn for_aarch32(&self) -> Self {
+        BootArgs {
+            x0: 0,
+            x1: 0xFFFF_FFFF,
+            x2: self.x0,
+            x3: 0,
+        }
+    }
+}
+
+/// Get the boot arguments the firmware has passed to the loader
+pub fn firmware() -> BootArgs {
+    // the value is only written once in the bootstrap code before any Rust code is running
+    unsafe { core::ptr::read_volatile(&__firmware_boot_args) }
+}
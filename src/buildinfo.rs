@@ -0,0 +1,39 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Build information
+//!
+//! The identity of the loader binary, passed in by the build script: the version of the crate, the git revision
+//! the loader is built from, marked ``-dirty`` if the working tree has uncommitted changes, the time of the build
+//! and the cargo features enabled. The build time is taken from ``SOURCE_DATE_EPOCH`` if it is set, so
+//! reproducible builds yield the same binary.
+//!
+
+use core::fmt;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_REVISION: &str = env!("LOADER_GIT_REVISION");
+/// The build time in UTC, e.g. "2020-10-14 11:34:56 UTC"
+pub const BUILD_TIME: &str = env!("LOADER_BUILD_TIME");
+/// The cargo features enabled, separated by commas
+pub const FEATURES: &str = env!("LOADER_FEATURES");
+
+/// The build information on a single line, as printed when the loader starts
+pub struct BuildInfo;
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "version {} git {} built {} features {}",
+            VERSION,
+            GIT_REVISION,
+            BUILD_TIME,
+            if FEATURES.is_empty() { "-" } else { FEATURES }
+        )
+    }
+}
@@ -0,0 +1,86 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Cache maintenance by address range
+//!
+//! ``ruspiro_cache`` cleans and invalidates the whole data cache by set and way. This only covers the caches of the
+//! core running it and not the lines held by the cluster on its behalf, so code written to memory is made visible to
+//! the instruction fetch by the virtual address of each cache line as well. The line sizes are read from CTR_EL0.
+//!
+//! Kernels start with the MMU and the caches off, so their memory is cleaned to the point of coherency, which
+//! covers the point of unification the instruction fetch of the core itself reads from.
+//!
+
+pub use ruspiro_cache::cleaninvalidate;
+
+/// Write the cached data of the range back to the point of coherency, so it could be read with the caches off or by
+/// any other observer of the memory
+pub fn clean_dcache_range(addr: u64, len: u64) {
+    for line in lines(addr, len, dcache_line_size()) {
+        unsafe { llvm_asm!("dc cvac, $0" :: "r"(line) :: "volatile") };
+    }
+    unsafe { llvm_asm!("dsb sy" :::: "volatile") };
+}
+
+/// Write the cached data of the range back to the point of coherency and drop it from the caches, so memory written
+/// by other observers like the DMA controller is read from memory again
+pub fn clean_invalidate_dcache_range(addr: u64, len: u64) {
+    for line in lines(addr, len, dcache_line_size()) {
+        unsafe { llvm_asm!("dc civac, $0" :: "r"(line) :: "volatile") };
+    }
+    unsafe { llvm_asm!("dsb sy" :::: "volatile") };
+}
+
+/// Drop all instructions of the instruction cache, the following instructions are fetched again
+pub fn invalidate_icache_all() {
+    unsafe {
+        llvm_asm!(
+            "ic iallu
+             dsb ish
+             isb" :::: "volatile"
+        )
+    };
+}
+
+/// Prepare the freshly written code of the range to be executed: the data is cleaned to the point of coherency
+/// and the instructions of the range are invalidated, so neither the core nor a kernel starting with the caches
+/// off fetches stale instructions
+pub fn flush_for_execution(addr: u64, len: u64) {
+    clean_dcache_range(addr, len);
+    for line in lines(addr, len, icache_line_size()) {
+        unsafe { llvm_asm!("ic ivau, $0" :: "r"(line) :: "volatile") };
+    }
+    unsafe {
+        llvm_asm!(
+            "dsb ish
+             isb" :::: "volatile"
+        )
+    };
+}
+
+/// The addresses of the cache lines covering the range
+fn lines(addr: u64, len: u64, line_size: u64) -> impl Iterator<Item = u64> {
+    let start = addr & !(line_size - 1);
+    let end = addr.saturating_add(len);
+    (start..end).step_by(line_size as usize)
+}
+
+/// The size of the smallest data cache line in bytes, CTR_EL0 gives the log2 of its words in DminLine
+fn dcache_line_size() -> u64 {
+    4 << (ctr() >> 16 & 0xF)
+}
+
+/// The size of the smallest instruction cache line in bytes, CTR_EL0 gives the log2 of its words in IminLine
+fn icache_line_size() -> u64 {
+    4 << (ctr() & 0xF)
+}
+
+fn ctr() -> u64 {
+    let ctr: u64;
+    unsafe { llvm_asm!("mrs $0, ctr_el0" : "=r"(ctr) ::: "volatile") };
+    ctr
+}
@@ -0,0 +1,538 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Commands
+//!
+//! Commands the host could send to the loader instead of a kernel. A command is a single line of text starting with
+//! the command name followed by its arguments separated by whitespace.
+//!
+
+use crate::buildinfo;
+use crate::console;
+use crate::crc;
+use crate::loader;
+use crate::log::{self, Level, Timestamp};
+use crate::model;
+use crate::monitor;
+use crate::pm;
+use crate::pmu;
+use crate::retained;
+use crate::slot;
+use crate::smp;
+use crate::storage;
+use crate::time::{self, Duration};
+use alloc::{string::ToString, vec, vec::Vec};
+use ruspiro_loader_protocol::KernelTransfer;
+
+/// The size of the data the ``pmu`` command operates on
+const PMU_DATA_SIZE: usize = 0x4_0000;
+/// The time the secondary cores released by ``cores test`` have to park again
+const CORE_TEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Reasons why a command could not be executed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandError {
+    /// There is no command with the given name
+    Unknown,
+    /// The arguments do not fit the command
+    BadArguments,
+    /// The command could not be completed, the reason is logged
+    Failed,
+}
+
+/// A command known to the loader
+pub struct Command {
+    /// The name the command is called with
+    pub name: &'static str,
+    /// The arguments of the command
+    pub usage: &'static str,
+    /// Short description of the command
+    pub help: &'static str,
+    /// The function executing the command, called with the arguments following the name
+    pub run: fn(args: &[&str]) -> Result<(), CommandError>,
+}
+
+/// All commands known to the loader
+pub static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "",
+        help: "list the available commands",
+        run: help,
+    },
+    Command {
+        name: "beacon",
+        usage: "[on|off]",
+        help: "show or set whether the loader announces itself periodically while waiting",
+        run: beacon,
+    },
+    Command {
+        name: "bootcount",
+        usage: "[clear]",
+        help: "show the boot attempts without success signal, 'clear' resets them",
+        run: bootcount,
+    },
+    Command {
+        name: "bootfile",
+        usage: "<path> [32|64]",
+        help: "boot the kernel file from the boot partition of the SD card, aarch64 by default",
+        run: bootfile,
+    },
+    Command {
+        name: "cores",
+        usage: "[test]",
+        help: "show whether the secondary cores are parked, 'test' releases them to report their EL",
+        run: cores,
+    },
+    Command {
+        name: "dryrun",
+        usage: "[on|off]",
+        help: "show or set whether kernels are only received and verified but not started",
+        run: dryrun,
+    },
+    Command {
+        name: "halt",
+        usage: "",
+        help: "quiesce the device and park all cores, it could be powered off afterwards",
+        run: halt,
+    },
+    Command {
+        name: "log",
+        usage: "[<level>]",
+        help: "show or set the log level, one of error, warn, info, debug or trace",
+        run: loglevel,
+    },
+    Command {
+        name: "logformat",
+        usage: "[text|binary]",
+        help: "show or set whether log messages are sent as text lines or binary frames",
+        run: logformat,
+    },
+    Command {
+        name: "logtime",
+        usage: "[off|absolute|delta]",
+        help: "show or set whether log lines start with the time since power on or since the previous line",
+        run: logtime,
+    },
+    Command {
+        name: "ls",
+        usage: "[<path>]",
+        help: "list the directory of the boot partition of the SD card, the root directory by default",
+        run: ls,
+    },
+    Command {
+        name: "monitor",
+        usage: "",
+        help: "enter the interactive monitor, e.g. from a terminal program",
+        run: monitor,
+    },
+    Command {
+        name: "partitions",
+        usage: "",
+        help: "list the partitions of the SD card with their type and role",
+        run: partitions,
+    },
+    Command {
+        name: "pmu",
+        usage: "<copy|crc>",
+        help: "count cycles, cache and TLB refills while copying or checksumming 256kB",
+        run: pmu,
+    },
+    Command {
+        name: "reboot",
+        usage: "[loader]",
+        help: "reset the device, with 'loader' it stays in the loader after the reset",
+        run: reboot,
+    },
+    Command {
+        name: "slot",
+        usage: "[boot]",
+        help: "show the kernels in the slots of the SD card, 'boot' boots the newest one",
+        run: slot,
+    },
+    Command {
+        name: "term",
+        usage: "[<type>]",
+        help: "show or set the terminal type of the host, colors and a status line are used unless it is 'dumb'",
+        run: term,
+    },
+    Command {
+        name: "version",
+        usage: "",
+        help: "show the version, git revision, build time and features of the loader and the board",
+        run: version,
+    },
+    Command {
+        name: "watchdog",
+        usage: "[<secs>|off]",
+        help:
+            "show, start or stop the watchdog resetting the device if the loader stops responding",
+        run: watchdog,
+    },
+    Command {
+        name: "xmodem",
+        usage: "[32|64]",
+        help: "receive an aarch64 or the given kernel with XMODEM-CRC or YMODEM, like sent by sx or sb",
+        run: xmodem,
+    },
+];
+
+/// Execute the given command line
+pub fn execute(line: &str) -> Result<(), CommandError> {
+    let mut words = line.split_whitespace();
+    let name = words.next().ok_or(CommandError::Unknown)?;
+    let args: Vec<&str> = words.collect();
+    let command = COMMANDS
+        .iter()
+        .find(|command| command.name == name)
+        .ok_or(CommandError::Unknown)?;
+
+    (command.run)(&args)
+}
+
+fn help(_args: &[&str]) -> Result<(), CommandError> {
+    for command in COMMANDS {
+        println!("{:8} {:10} {}", command.name, command.usage, command.help);
+    }
+    Ok(())
+}
+
+fn beacon(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => (),
+        ["on"] => loader::set_beacon(true),
+        ["off"] => loader::set_beacon(false),
+        _ => return Err(CommandError::BadArguments),
+    }
+    println!("beacon {}", if loader::is_beacon() { "on" } else { "off" });
+    Ok(())
+}
+
+fn bootcount(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => (),
+        ["clear"] => retained::boot_succeeded(),
+        _ => return Err(CommandError::BadArguments),
+    }
+    println!(
+        "{} boot attempts without success signal, boot loop limit {}",
+        retained::boot_count(),
+        retained::BOOT_LOOP_LIMIT
+    );
+    if retained::rolled_back() {
+        println!("rolled back to the last known good kernel");
+    }
+    Ok(())
+}
+
+fn bootfile(args: &[&str]) -> Result<(), CommandError> {
+    let (path, aarch) = match args {
+        [path] => (path, 64),
+        [path, "32"] => (path, 32),
+        [path, "64"] => (path, 64),
+        _ => return Err(CommandError::BadArguments),
+    };
+    let binary = storage::read_file(path, loader::MAX_TRANSFER_SIZE).map_err(|err| {
+        error!("reading {} failed: {:?}", path, err);
+        CommandError::Failed
+    })?;
+    info!("{} bytes read from {}", binary.len(), path);
+    loader::request_boot(KernelTransfer {
+        aarch,
+        entry_el: 0,
+        flags: 0,
+        address: 0,
+        binary,
+    });
+    Ok(())
+}
+
+fn cores(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => (),
+        ["test"] => {
+            for core in 1..smp::CORES {
+                if let Err(err) = smp::release(core, core_test) {
+                    error!("releasing core {} failed: {:?}", core, err);
+                }
+            }
+            if !smp::wait_parked(CORE_TEST_TIMEOUT) {
+                error!("the secondary cores have not parked again");
+            }
+        }
+        _ => return Err(CommandError::BadArguments),
+    }
+    for core in 1..smp::CORES {
+        let state = if smp::is_running(core) {
+            "running"
+        } else {
+            "parked"
+        };
+        println!("core {} {}", core, state);
+    }
+    Ok(())
+}
+
+/// Run by the secondary cores released with ``cores test``
+fn core_test(core: usize) {
+    let el: u64;
+    unsafe { llvm_asm!("mrs $0, CurrentEL" : "=r"(el) ::: "volatile") };
+    println!("core {} released, running in EL{}", core, el >> 2 & 0b11);
+}
+
+fn dryrun(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => (),
+        ["on"] => loader::set_dry_run(true),
+        ["off"] => loader::set_dry_run(false),
+        _ => return Err(CommandError::BadArguments),
+    }
+    println!(
+        "dry run {}",
+        if loader::is_dry_run() { "on" } else { "off" }
+    );
+    Ok(())
+}
+
+fn halt(args: &[&str]) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::BadArguments);
+    }
+    println!("halted, the power could be removed now");
+    // give the UART the chance to send the last message before it is stopped
+    console::flush();
+    time::sleep(Duration::from_millis(10));
+    pm::halt()
+}
+
+fn loglevel(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => (),
+        [name] => log::set_level(Level::from_name(name).ok_or(CommandError::BadArguments)?),
+        _ => return Err(CommandError::BadArguments),
+    }
+    println!("log level {}", log::level().name());
+    Ok(())
+}
+
+fn logformat(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => (),
+        ["text"] => log::set_binary(false),
+        ["binary"] => log::set_binary(true),
+        _ => return Err(CommandError::BadArguments),
+    }
+    println!(
+        "log format {}",
+        if log::is_binary() { "binary" } else { "text" }
+    );
+    Ok(())
+}
+
+fn logtime(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => (),
+        [name] => log::set_timestamp(Timestamp::from_name(name).ok_or(CommandError::BadArguments)?),
+        _ => return Err(CommandError::BadArguments),
+    }
+    println!("log timestamp {}", log::timestamp().name());
+    Ok(())
+}
+
+fn ls(args: &[&str]) -> Result<(), CommandError> {
+    let path = match args {
+        [] => "/",
+        [path] => path,
+        _ => return Err(CommandError::BadArguments),
+    };
+    let entries = storage::list(path).map_err(|err| {
+        error!("listing {} failed: {:?}", path, err);
+        CommandError::Failed
+    })?;
+    for entry in entries {
+        // the FAT date and time fields as described with the directory entry
+        println!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {:>10} {}",
+            1980 + (entry.date >> 9),
+            entry.date >> 5 & 0xF,
+            entry.date & 0x1F,
+            entry.time >> 11,
+            entry.time >> 5 & 0x3F,
+            (entry.time & 0x1F) * 2,
+            if entry.is_dir {
+                "<DIR>".into()
+            } else {
+                entry.size.to_string()
+            },
+            entry.name
+        );
+    }
+    Ok(())
+}
+
+fn monitor(args: &[&str]) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::BadArguments);
+    }
+    monitor::request();
+    Ok(())
+}
+
+fn partitions(args: &[&str]) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::BadArguments);
+    }
+    let partitions = storage::partitions().map_err(|err| {
+        error!("reading the partition table failed: {:?}", err);
+        CommandError::Failed
+    })?;
+    for partition in partitions {
+        let role = if partition.is_boot() {
+            "boot"
+        } else if partition.is_kernel_slot() {
+            "slot"
+        } else {
+            "-"
+        };
+        println!(
+            "{:2} {:>10} {:>10} {:4} {} {}",
+            partition.number,
+            partition.start,
+            partition.blocks,
+            role,
+            partition.kind,
+            partition.name
+        );
+    }
+    Ok(())
+}
+
+fn pmu(args: &[&str]) -> Result<(), CommandError> {
+    let source = vec![0x5A_u8; PMU_DATA_SIZE];
+    let mut target = vec![0_u8; PMU_DATA_SIZE];
+    let counters = match args {
+        ["copy"] => pmu::measure(|| target.copy_from_slice(&source)).1,
+        ["crc"] => pmu::measure(|| crc::crc32(&source)).1,
+        _ => return Err(CommandError::BadArguments),
+    };
+    println!("{} of {} bytes", args[0], PMU_DATA_SIZE);
+    println!("{:16} {}", "cycles", counters.cycles);
+    for (event, count) in pmu::EVENTS.iter().zip(counters.events.iter()) {
+        match count {
+            Some(count) => println!("{:16} {}", event.name, count),
+            None => println!("{:16} not counted", event.name),
+        }
+    }
+    Ok(())
+}
+
+fn reboot(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => (),
+        ["loader"] => retained::set_stay_in_loader(),
+        _ => return Err(CommandError::BadArguments),
+    }
+    println!("rebooting...");
+    // give the UART the chance to send the last message before the reset kicks in
+    console::flush();
+    time::sleep(Duration::from_millis(10));
+    pm::reset()
+}
+
+fn slot(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            let kernels = slot::list().map_err(|err| {
+                error!("reading the kernel slots failed: {:?}", err);
+                CommandError::Failed
+            })?;
+            for (name, kernel) in slot::SLOT_NAMES.iter().zip(kernels.iter()) {
+                match kernel {
+                    Some(kernel) => println!(
+                        "slot {} version {} aarch{} {} bytes hash {:#010x}",
+                        name, kernel.version, kernel.aarch, kernel.size, kernel.hash
+                    ),
+                    None => println!("slot {} empty", name),
+                }
+            }
+        }
+        ["boot"] => {
+            let (idx, kernel, transfer) = slot::load().map_err(|err| {
+                error!("loading a kernel from the slots failed: {:?}", err);
+                CommandError::Failed
+            })?;
+            info!(
+                "booting kernel version {} from slot {}",
+                kernel.version,
+                slot::SLOT_NAMES[idx]
+            );
+            loader::request_boot(transfer);
+        }
+        _ => return Err(CommandError::BadArguments),
+    }
+    Ok(())
+}
+
+fn term(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => (),
+        ["dumb"] => console::set_ansi(false),
+        [_] => console::set_ansi(true),
+        _ => return Err(CommandError::BadArguments),
+    }
+    println!("term {}", if console::is_ansi() { "ansi" } else { "dumb" });
+    Ok(())
+}
+
+fn version(args: &[&str]) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::BadArguments);
+    }
+    println!("version {}", buildinfo::VERSION);
+    println!("git {}", buildinfo::GIT_REVISION);
+    println!("built {}", buildinfo::BUILD_TIME);
+    println!("features {}", buildinfo::FEATURES);
+    match model::current() {
+        Some(revision) => println!("board {}", revision),
+        None => println!("board unknown"),
+    }
+    Ok(())
+}
+
+fn watchdog(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => (),
+        ["off"] => loader::set_watchdog(None),
+        [secs] => match secs.parse::<u64>() {
+            // the watchdog could not count beyond ~16s
+            Ok(secs) if secs > 0 && secs <= 15 => {
+                loader::set_watchdog(Some(Duration::from_secs(secs)))
+            }
+            _ => return Err(CommandError::BadArguments),
+        },
+        _ => return Err(CommandError::BadArguments),
+    }
+    match loader::watchdog() {
+        Some(timeout) => println!("watchdog running, timeout {}s", timeout.as_secs()),
+        None => println!("watchdog off"),
+    }
+    Ok(())
+}
+
+fn xmodem(args: &[&str]) -> Result<(), CommandError> {
+    let aarch = match args {
+        [] | ["64"] => 64,
+        ["32"] => 32,
+        _ => return Err(CommandError::BadArguments),
+    };
+    loader::request_xmodem(aarch);
+    println!(
+        "start the XMODEM or YMODEM transfer of the aarch{} kernel",
+        aarch
+    );
+    Ok(())
+}
@@ -0,0 +1,337 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Console output
+//!
+//! Formatted output to the miniUART. As the UART is used from the main processing as well as from the interrupt
+//! handler receiving a new kernel, it is kept in a singleton that is shared by both.
+//!
+//! The interrupt handler only queues the received bytes, the main processing picks them up from the queue. While
+//! the loader is waiting for requests the output is buffered as well and sent by the scheduler whenever the UART
+//! could take more data, so sending does not hold up the receiving. Before the loader hands over to the kernel the
+//! buffering is switched off and any output is sent right away.
+//!
+//! The output of several cores is serialized with a [BakeryLock], which also works for the secondary cores running
+//! without the MMU, so lines printed at the same time do not interleave. The output of the secondary cores is
+//! never buffered, as they could not share the queue through the caches, it is sent right away.
+//!
+//! With the feature ``second_uart`` the loader listens on the PL011 as well, see [crate::pl011]. The output goes
+//! to both UARTs until the host has started a request on one of them, from then on only to that one.
+//!
+//! If the host tells the loader that its terminal understands ANSI escape sequences, the log messages are colored
+//! by their level and a status line, like the progress of a transfer, is redrawn in place below the output. The
+//! status line is erased before any log message is printed. The output stays plain text by default and for dumb
+//! terminals, so tools parsing the output are not confused by the escape sequences.
+//!
+
+use crate::board::{self, AUX_BASE};
+#[cfg(feature = "second_uart")]
+use crate::pl011;
+use crate::smp::{self, BakeryLock};
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use ruspiro_singleton::Singleton;
+use ruspiro_uart::Uart1;
+
+const AUX_MU_IO: *mut u32 = board::register(AUX_BASE, 0x40);
+const AUX_MU_LSR: *mut u32 = board::register(AUX_BASE, 0x54);
+const LSR_TX_EMPTY: u32 = 1 << 5;
+
+/// Size of the receive and the transmit queue, a power of 2
+const QUEUE_SIZE: usize = 4096;
+
+/// Moves the cursor to the beginning of the line and erases the line
+const ERASE_LINE: &str = "\r\x1b[K";
+
+/// The transports the console could use
+pub const MINI_UART: usize = 0;
+#[cfg(feature = "second_uart")]
+pub const SECOND_UART: usize = 1;
+
+/// Define singleton Uart1 accessor to ensure safe access from main processing as well as
+/// from interrupt handler
+pub static UART: Singleton<Uart1> = Singleton::new(Uart1::new());
+
+/// Adapter to use the formatting machinery of ``core`` sending right away, the output need to be locked
+struct DirectWriter;
+
+impl Write for DirectWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            while !is_ready() {}
+            write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// A queue with a single producer and a single consumer
+struct Queue {
+    data: UnsafeCell<[u8; QUEUE_SIZE]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// the producer only writes the head and the consumer only writes the tail
+unsafe impl Sync for Queue {}
+
+impl Queue {
+    const fn new() -> Self {
+        Queue {
+            data: UnsafeCell::new([0; QUEUE_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add the byte to the queue, returns false if the queue is full
+    fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) == QUEUE_SIZE {
+            return false;
+        }
+        unsafe { (*self.data.get())[head % QUEUE_SIZE] = byte };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Take the oldest byte from the queue
+    fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = unsafe { (*self.data.get())[tail % QUEUE_SIZE] };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    /// Take the oldest byte from the queue without removing it
+    fn peek(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(unsafe { (*self.data.get())[tail % QUEUE_SIZE] })
+    }
+}
+
+/// Bytes received by the interrupt handler
+static RECEIVED: Queue = Queue::new();
+/// Output waiting to be sent while buffering is active
+static TRANSMIT: Queue = Queue::new();
+static BUFFERED: AtomicBool = AtomicBool::new(false);
+/// Serializes the output of the cores
+static OUTPUT_LOCK: BakeryLock = BakeryLock::new();
+/// Number of received bytes dropped as the queue was full
+static OVERRUNS: AtomicUsize = AtomicUsize::new(0);
+/// The transports the output is sent to, one bit for each
+static OUTPUTS: AtomicUsize = AtomicUsize::new(1 << MINI_UART);
+/// The terminal of the host understands ANSI escape sequences
+static ANSI: AtomicBool = AtomicBool::new(false);
+/// The status line is currently shown below the output
+static STATUS_SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Send the output to the transport in addition to the transports already used
+#[cfg(feature = "second_uart")]
+pub fn add_output(transport: usize) {
+    OUTPUTS.fetch_or(1 << transport, Ordering::AcqRel);
+}
+
+/// Send the output only to the transport, once the host talks to the loader on it
+pub fn select_output(transport: usize) {
+    OUTPUTS.store(1 << transport, Ordering::Release);
+}
+
+/// Check whether all transports used could take another byte
+fn is_ready() -> bool {
+    let outputs = OUTPUTS.load(Ordering::Acquire);
+    if outputs & 1 << MINI_UART != 0 && unsafe { read_volatile(AUX_MU_LSR) } & LSR_TX_EMPTY == 0 {
+        return false;
+    }
+    #[cfg(feature = "second_uart")]
+    {
+        if outputs & 1 << SECOND_UART != 0 && !pl011::is_ready() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Send the byte to all transports used, they need to be ready
+fn write_byte(byte: u8) {
+    let outputs = OUTPUTS.load(Ordering::Acquire);
+    if outputs & 1 << MINI_UART != 0 {
+        unsafe { write_volatile(AUX_MU_IO, byte as u32) };
+    }
+    #[cfg(feature = "second_uart")]
+    {
+        if outputs & 1 << SECOND_UART != 0 {
+            pl011::write_byte(byte);
+        }
+    }
+}
+
+/// Run ``f`` with the output of the other cores held back, so the output of ``f`` is not interleaved with theirs.
+/// The lock could be taken again while being held, e.g. by a log message written while printing.
+pub fn lock<R>(f: impl FnOnce() -> R) -> R {
+    OUTPUT_LOCK.with(f)
+}
+
+/// Use ANSI escape sequences for colors and the status line, the host enables them for terminals supporting them
+pub fn set_ansi(ansi: bool) {
+    if !ansi {
+        clear_status();
+    }
+    ANSI.store(ansi, Ordering::Release);
+}
+
+/// Check whether ANSI escape sequences are used
+pub fn is_ansi() -> bool {
+    ANSI.load(Ordering::Acquire)
+}
+
+/// Draw the status line, replacing the one shown before. Nothing is drawn unless ANSI escape sequences are used.
+pub fn show_status(args: fmt::Arguments) {
+    if is_ansi() {
+        lock(|| {
+            print(format_args!("{}{}", ERASE_LINE, args));
+            STATUS_SHOWN.store(true, Ordering::Release);
+        })
+    }
+}
+
+/// Erase the status line if it is shown, so the following output starts at the beginning of the line
+pub fn clear_status() {
+    lock(|| {
+        if STATUS_SHOWN.swap(false, Ordering::AcqRel) {
+            print(format_args!("{}", ERASE_LINE));
+        }
+    })
+}
+
+/// Check whether the output is buffered, which is only done for the main core
+fn is_buffered() -> bool {
+    smp::core_id() == 0 && BUFFERED.load(Ordering::Acquire)
+}
+
+/// Queue a received byte, called from the interrupt handler
+pub fn push_received(byte: u8) {
+    if !RECEIVED.push(byte) {
+        OVERRUNS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Take the next received byte
+pub fn read_byte() -> Option<u8> {
+    RECEIVED.pop()
+}
+
+/// Check whether received bytes are waiting to be read
+pub fn has_received() -> bool {
+    RECEIVED.peek().is_some()
+}
+
+/// The number of received bytes dropped so far as they were not picked up in time
+pub fn overruns() -> usize {
+    OVERRUNS.load(Ordering::Relaxed)
+}
+
+/// Switch the buffering of the output on or off. Any buffered output is sent before the buffering is switched off.
+pub fn set_buffered(buffered: bool) {
+    if !buffered {
+        flush();
+    }
+    BUFFERED.store(buffered, Ordering::Release);
+}
+
+/// Send as much of the buffered output as the UART could take right now without waiting
+pub fn transmit() {
+    lock(|| {
+        while let Some(byte) = TRANSMIT.peek() {
+            if !is_ready() {
+                return;
+            }
+            write_byte(byte);
+            TRANSMIT.pop();
+        }
+    })
+}
+
+/// Check whether buffered output is waiting to be sent
+pub fn has_pending_output() -> bool {
+    TRANSMIT.peek().is_some()
+}
+
+/// Send all of the buffered output
+pub fn flush() {
+    while TRANSMIT.peek().is_some() {
+        transmit();
+    }
+}
+
+/// Send the raw data, this is buffered like any other output to keep the order
+pub fn send(data: &[u8]) {
+    lock(|| {
+        if is_buffered() {
+            for &byte in data {
+                // if the queue is full wait until there is space again
+                while !TRANSMIT.push(byte) {
+                    transmit();
+                }
+            }
+        } else {
+            for &byte in data {
+                while !is_ready() {}
+                write_byte(byte);
+            }
+        }
+    })
+}
+
+/// Adapter to use the formatting machinery of ``core`` with the output queue
+struct QueueWriter;
+
+impl Write for QueueWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        send(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Write the formatted arguments to the console. This is used by the [print!] and [println!] macros.
+pub fn print(args: fmt::Arguments) {
+    lock(|| {
+        let _ = if is_buffered() {
+            QueueWriter.write_fmt(args)
+        } else {
+            DirectWriter.write_fmt(args)
+        };
+    })
+}
+
+/// Print formatted text to the console
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::console::print(format_args!($($arg)*))
+    };
+}
+
+/// Print formatted text to the console followed by a line break
+macro_rules! println {
+    () => {
+        $crate::console::print(format_args!("\r\n"))
+    };
+    ($($arg:tt)*) => {
+        $crate::console::lock(|| {
+            $crate::console::print(format_args!($($arg)*));
+            $crate::console::print(format_args!("\r\n"));
+        })
+    };
+}
@@ -0,0 +1,225 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Crash dump
+//!
+//! A synchronous exception or system error the loader raises itself and a panic could not be recovered from. The
+//! state of the core is saved as crash dump in the retained memory and the device is reset. The dump contains the
+//! registers, the top of the stack and the trace buffer with the latest log messages. On the next start the loader
+//! reports the dump on the console with lines starting with ``CRASH`` and clears it afterwards.
+//!
+//! Nothing is printed while crashing, as the console could be locked or in an inconsistent state. ``x0`` is
+//! overwritten by the exception vector with the exception id, so it is not part of the dump of an exception.
+//!
+
+use crate::crc;
+use crate::guest::ExceptionFrame;
+use crate::log::{self, TRACE_SIZE};
+use crate::pm;
+use core::fmt::{self, Write};
+use core::mem::{size_of, MaybeUninit};
+use core::panic::PanicInfo;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+use ruspiro_cache as cache;
+
+/// The magic value identifying a valid crash dump, "RPCD"
+const MAGIC: u32 = 0x4443_5052;
+/// The cause of a crash dump from a panic, other causes are the id of the exception taken
+const CAUSE_PANIC: u64 = 0;
+/// The number of bytes saved from the top of the stack
+const STACK_SIZE: usize = 256;
+/// The maximum length of the panic message saved
+const MESSAGE_SIZE: usize = 160;
+
+#[repr(C)]
+struct CrashDump {
+    magic: u32,
+    message_len: u32,
+    cause: u64,
+    esr: u64,
+    spsr: u64,
+    far: u64,
+    elr: u64,
+    sp: u64,
+    x: [u64; 31],
+    stack_len: u64,
+    trace_len: u64,
+    message: [u8; MESSAGE_SIZE],
+    stack: [u8; STACK_SIZE],
+    trace: [u8; TRACE_SIZE],
+    /// CRC-32 of all the fields before
+    crc: u32,
+}
+
+impl CrashDump {
+    const fn empty() -> Self {
+        CrashDump {
+            magic: MAGIC,
+            message_len: 0,
+            cause: CAUSE_PANIC,
+            esr: 0,
+            spsr: 0,
+            far: 0,
+            elr: 0,
+            sp: 0,
+            x: [0; 31],
+            stack_len: 0,
+            trace_len: 0,
+            message: [0; MESSAGE_SIZE],
+            stack: [0; STACK_SIZE],
+            trace: [0; TRACE_SIZE],
+            crc: 0,
+        }
+    }
+
+    fn check_value(&self) -> u32 {
+        // the crc is the last field, the check covers all the fields before
+        let len = &self.crc as *const u32 as usize - self as *const _ as usize;
+        let data = unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, len) };
+        crc::crc32(data)
+    }
+}
+
+/// The message of a panic, longer messages are truncated
+impl Write for CrashDump {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = self.message_len as usize;
+        let count = core::cmp::min(s.len(), MESSAGE_SIZE - len);
+        self.message[len..len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.message_len += count as u32;
+        Ok(())
+    }
+}
+
+#[link_section = ".retained"]
+static mut CRASH_DUMP: MaybeUninit<CrashDump> = MaybeUninit::uninit();
+/// Set once crashing, a crash while saving the dump resets the device right away
+static CRASHING: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    /// linker symbols marking the memory used by the stacks
+    static __stack_end__: u8;
+    static __stack_top__: u8;
+}
+
+/// Handler for the exceptions of the loader it could not recover from, called from the exception trampoline
+#[no_mangle]
+extern "C" fn __loader_crash_handler(
+    kind: u64,
+    esr: u64,
+    spsr: u64,
+    far: u64,
+    elr: u64,
+    frame: &ExceptionFrame,
+) -> ! {
+    if !CRASHING.swap(true, Ordering::Relaxed) {
+        let mut dump = CrashDump::empty();
+        dump.cause = kind;
+        dump.esr = esr;
+        dump.spsr = spsr;
+        dump.far = far;
+        dump.elr = elr;
+        dump.x = frame.x;
+        // the stack pointer before the trampoline has saved the registers
+        dump.sp = frame as *const _ as u64 + size_of::<ExceptionFrame>() as u64;
+        save_stack(&mut dump);
+        save(dump);
+    }
+    pm::reset()
+}
+
+/// Save the panic as crash dump and reset the device
+#[cfg(not(feature = "no_panic"))]
+pub fn panic(info: &PanicInfo) -> ! {
+    if !CRASHING.swap(true, Ordering::Relaxed) {
+        let mut dump = CrashDump::empty();
+        let _ = write!(dump, "{}", info);
+        let sp: u64;
+        unsafe { llvm_asm!("mov $0, sp" : "=r"(sp) ::: "volatile") };
+        dump.sp = sp;
+        save_stack(&mut dump);
+        save(dump);
+    }
+    pm::reset()
+}
+
+/// Copy the top of the stack, only if the stack pointer is within the stacks of the loader, as the crash might be
+/// caused by a broken stack pointer
+fn save_stack(dump: &mut CrashDump) {
+    let (start, end) = unsafe {
+        (
+            &__stack_end__ as *const u8 as u64,
+            &__stack_top__ as *const u8 as u64,
+        )
+    };
+    if dump.sp < start || dump.sp >= end {
+        return;
+    }
+    let len = core::cmp::min(end - dump.sp, STACK_SIZE as u64);
+    for (idx, byte) in dump.stack.iter_mut().take(len as usize).enumerate() {
+        *byte = unsafe { read_volatile((dump.sp as *const u8).add(idx)) };
+    }
+    dump.stack_len = len;
+}
+
+/// Add the trace buffer and write the dump to the retained memory, as the caches do not survive the reset
+fn save(mut dump: CrashDump) {
+    dump.trace_len = log::copy_trace(&mut dump.trace) as u64;
+    dump.crc = dump.check_value();
+    unsafe { write_volatile(CRASH_DUMP.as_mut_ptr(), dump) };
+    cache::cleaninvalidate();
+}
+
+/// Report the crash dump left by the previous run of the loader on the console and clear it. Nothing is reported
+/// if there is no valid dump.
+pub fn report() {
+    let dump = unsafe { &*CRASH_DUMP.as_ptr() };
+    if dump.magic != MAGIC || dump.crc != dump.check_value() {
+        return;
+    }
+    if dump.cause == CAUSE_PANIC {
+        let len = core::cmp::min(dump.message_len as usize, MESSAGE_SIZE);
+        println!(
+            "CRASH {}",
+            core::str::from_utf8(&dump.message[..len]).unwrap_or("panic")
+        );
+    } else {
+        println!(
+            "CRASH exception {:#x} at {:#x}, ESR {:#x}, FAR {:#x}, SPSR {:#x}",
+            dump.cause, dump.elr, dump.esr, dump.far, dump.spsr
+        );
+        for (row, regs) in dump.x.chunks(4).enumerate() {
+            print!("CRASH");
+            for (idx, reg) in regs.iter().enumerate() {
+                print!(" x{:<2} {:#018x}", row * 4 + idx, reg);
+            }
+            println!();
+        }
+    }
+    println!("CRASH stack at {:#x}", dump.sp);
+    let stack_len = core::cmp::min(dump.stack_len as usize, STACK_SIZE);
+    for (row, bytes) in dump.stack[..stack_len].chunks(16).enumerate() {
+        print!("CRASH {:#x}:", dump.sp + row as u64 * 16);
+        for byte in bytes {
+            print!(" {:02x}", byte);
+        }
+        println!();
+    }
+    println!("CRASH trace of the latest log messages");
+    let trace_len = core::cmp::min(dump.trace_len as usize, TRACE_SIZE);
+    for line in dump.trace[..trace_len].split(|&byte| byte == b'\n') {
+        if let Ok(line) = core::str::from_utf8(line) {
+            if !line.is_empty() {
+                println!("CRASH | {}", line);
+            }
+        }
+    }
+
+    unsafe { write_volatile(CRASH_DUMP.as_mut_ptr() as *mut u32, 0) };
+    cache::cleaninvalidate();
+}
@@ -0,0 +1,35 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Checksums
+//!
+//! The CRC-32 (IEEE 802.3) used to verify data kept in memory or received from the host. It is calculated bitwise
+//! to not spend memory on a lookup table.
+//!
+
+const CRC32_POLYNOMIAL: u32 = 0xEDB8_8320;
+
+/// Calculate the CRC-32 of the given data
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Continue the CRC-32 calculation of previous data with the given data
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ CRC32_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
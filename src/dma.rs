@@ -0,0 +1,193 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # DMA controller
+//!
+//! Minimal driver for the channels of the DMA controller. A channel runs a chain of control blocks, each moving a
+//! number of bytes from its source to its destination, optionally paced by the data requests of a peripheral. The
+//! controller reads the control blocks and accesses the memory through the bus, so all addresses it is given are bus
+//! addresses and the memory it reads or writes need to bypass the caches of the ARM.
+//!
+//! The firmware uses some of the channels itself, the loader only takes the channels the firmware leaves to the ARM.
+//!
+
+use crate::board::{self, DMA_BASE, PERIPHERAL_BASE, PERIPHERAL_BUS_BASE, VC_BUS_ALIAS};
+use crate::mailbox;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// The channels the firmware leaves to the ARM usually, taken if the firmware could not be asked
+const DEFAULT_CHANNELS: u32 = 0x7F35;
+/// The channels located at [DMA_BASE], channel 15 is located elsewhere and never used
+const CHANNELS: u32 = 15;
+const CHANNEL_SIZE: u64 = 0x100;
+const DMA_ENABLE: *mut u32 = board::register(DMA_BASE, 0xFF0);
+
+/// the registers of a channel
+const CS: u64 = 0x00;
+const CONBLK_AD: u64 = 0x04;
+const DEST_AD: u64 = 0x10;
+const DEBUG: u64 = 0x20;
+
+const CS_ACTIVE: u32 = 1 << 0;
+const CS_ERROR: u32 = 1 << 8;
+/// The priority of the AXI transactions of the channel and the priority while the peripheral panics
+const CS_PRIORITY: u32 = 8 << 16 | 15 << 20;
+const CS_WAIT_FOR_OUTSTANDING_WRITES: u32 = 1 << 28;
+const CS_RESET: u32 = 1 << 31;
+/// The read last not set, FIFO and read errors in the debug register, cleared by writing them
+const DEBUG_ERRORS: u32 = 0b111;
+
+/// The flags of the transfer information of a control block
+pub const TI_WAIT_RESP: u32 = 1 << 3;
+pub const TI_DEST_INC: u32 = 1 << 4;
+pub const TI_SRC_DREQ: u32 = 1 << 10;
+
+/// The data request of the PL011 receiving data
+pub const DREQ_UART0_RX: u32 = 14;
+
+/// The flags of the transfer information selecting the peripheral whose data requests pace the transfer
+pub const fn ti_permap(dreq: u32) -> u32 {
+    dreq << 16
+}
+
+/// The channels taken so far
+static TAKEN: AtomicU32 = AtomicU32::new(0);
+
+/// Reasons why the DMA controller could not be used
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DmaError {
+    /// All channels left to the ARM are taken
+    NoChannel,
+}
+
+/// A transfer run by a channel. The channel continues with the next control block once the transfer has been
+/// completed.
+#[repr(C, align(32))]
+#[derive(Debug, Clone, Copy)]
+pub struct ControlBlock {
+    pub transfer_info: u32,
+    /// The bus address the data is read from
+    pub source: u32,
+    /// The bus address the data is written to
+    pub destination: u32,
+    /// The number of bytes to transfer, at most 65535 for the lite channels 7 to 14
+    pub length: u32,
+    pub stride: u32,
+    /// The bus address of the next control block, 0 to stop once this one has been completed
+    pub next: u32,
+    reserved: [u32; 2],
+}
+
+impl ControlBlock {
+    pub const EMPTY: ControlBlock = ControlBlock::new(0, 0, 0, 0);
+
+    /// A transfer of ``length`` bytes the channel stops after
+    pub const fn new(transfer_info: u32, source: u32, destination: u32, length: u32) -> Self {
+        ControlBlock {
+            transfer_info,
+            source,
+            destination,
+            length,
+            stride: 0,
+            next: 0,
+            reserved: [0; 2],
+        }
+    }
+}
+
+/// A DMA channel taken by the loader
+#[derive(Debug)]
+pub struct Channel {
+    index: u32,
+}
+
+impl Channel {
+    /// Take the first free channel the firmware leaves to the ARM and reset it
+    pub fn take() -> Result<Channel, DmaError> {
+        let available = mailbox::dma_channels().unwrap_or(DEFAULT_CHANNELS) & ((1 << CHANNELS) - 1);
+        loop {
+            let taken = TAKEN.load(Ordering::Acquire);
+            let free = available & !taken;
+            if free == 0 {
+                return Err(DmaError::NoChannel);
+            }
+            let index = free.trailing_zeros();
+            if TAKEN
+                .compare_exchange(
+                    taken,
+                    taken | 1 << index,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+            {
+                let channel = Channel { index };
+                unsafe { write_volatile(DMA_ENABLE, read_volatile(DMA_ENABLE) | 1 << index) };
+                channel.reset();
+                return Ok(channel);
+            }
+        }
+    }
+
+    /// Run the chain of control blocks starting with the given one
+    ///
+    /// # Safety
+    /// The control blocks and the memory they transfer to need to stay valid as long as the channel runs.
+    pub unsafe fn start(&self, block: *const ControlBlock) {
+        // the control blocks need to be written before the channel reads them
+        llvm_asm!("dsb sy" :::: "volatile");
+        write_volatile(self.register(DEBUG), DEBUG_ERRORS);
+        write_volatile(self.register(CONBLK_AD), bus_address(block as u64));
+        write_volatile(
+            self.register(CS),
+            CS_WAIT_FOR_OUTSTANDING_WRITES | CS_PRIORITY | CS_ACTIVE,
+        );
+    }
+
+    /// Abort the transfer and reset the channel
+    pub fn reset(&self) {
+        unsafe { write_volatile(self.register(CS), CS_RESET) };
+    }
+
+    /// The bus address the channel writes the next data to
+    pub fn destination(&self) -> u32 {
+        unsafe { read_volatile(self.register(DEST_AD)) }
+    }
+
+    /// The errors the channel has run into as given by its debug register, ``None`` if there are none
+    pub fn error(&self) -> Option<u32> {
+        unsafe {
+            if read_volatile(self.register(CS)) & CS_ERROR == 0 {
+                None
+            } else {
+                Some(read_volatile(self.register(DEBUG)) & DEBUG_ERRORS)
+            }
+        }
+    }
+
+    fn register(&self, offset: u64) -> *mut u32 {
+        board::register(DMA_BASE + self.index as u64 * CHANNEL_SIZE, offset)
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.reset();
+        TAKEN.fetch_and(!(1 << self.index), Ordering::AcqRel);
+    }
+}
+
+/// The bus address of the memory at the physical address, bypassing the L2 cache of the VideoCore
+pub fn bus_address(address: u64) -> u32 {
+    address as u32 | VC_BUS_ALIAS
+}
+
+/// The bus address of the peripheral register
+pub fn peripheral_bus_address(register: *mut u32) -> u32 {
+    (register as u64 - PERIPHERAL_BASE) as u32 | PERIPHERAL_BUS_BASE
+}
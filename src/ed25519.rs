@@ -0,0 +1,349 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # ed25519 signatures
+//!
+//! Verification of ed25519 signatures as specified in RFC 8032, following the compact implementation of TweetNaCl.
+//! A field element of GF(2^255 - 19) is kept in 16 limbs of 16 bits, a point of the curve in extended coordinates
+//! (X, Y, Z, T). Verifying a signature only involves public data, so the code is not hardened against timing side
+//! channels.
+//!
+//! Signatures with an ``S`` not reduced modulo the group order are refused, so a signature could not be altered
+//! into another valid one.
+//!
+
+use crate::sha2::Sha512;
+
+/// The size of a public key
+pub const PUBLIC_KEY_SIZE: usize = 32;
+/// The size of a signature
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// An element of the field GF(2^255 - 19)
+type Field = [i64; 16];
+/// A point of the curve in extended coordinates
+type Point = [Field; 4];
+
+const ZERO: Field = [0; 16];
+const ONE: Field = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+/// The curve constant d
+#[rustfmt::skip]
+const D: Field = [
+    0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070,
+    0xe898, 0x7779, 0x4079, 0x8cc7, 0xfe73, 0x2b6f, 0x6cee, 0x5203,
+];
+/// 2 * d
+#[rustfmt::skip]
+const D2: Field = [
+    0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0,
+    0xd130, 0xeef3, 0x80f2, 0x198e, 0xfce7, 0x56df, 0xd9dc, 0x2406,
+];
+/// The x coordinate of the base point
+#[rustfmt::skip]
+const X: Field = [
+    0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c,
+    0xdc5c, 0xfdd6, 0xe231, 0xc0a4, 0x53fe, 0xcd6e, 0x36d3, 0x2169,
+];
+/// The y coordinate of the base point
+#[rustfmt::skip]
+const Y: Field = [
+    0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+    0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+];
+/// A square root of -1
+#[rustfmt::skip]
+const SQRT_M1: Field = [
+    0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43,
+    0xd7a7, 0x3dfb, 0x0099, 0x2b4d, 0xdf0b, 0x4fc1, 0x2480, 0x2b83,
+];
+/// The order of the base point, little endian
+#[rustfmt::skip]
+const L: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+];
+
+/// Verify the ed25519 signature of the message made with the secret key of the public key
+pub fn verify(
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+    message: &[u8],
+    signature: &[u8; SIGNATURE_SIZE],
+) -> bool {
+    let mut q = match unpack_negated(public_key) {
+        Some(q) => q,
+        None => return false,
+    };
+    let (r, s) = signature.split_at(32);
+    if !is_reduced(s) {
+        return false;
+    }
+    let mut hash = Sha512::new();
+    hash.update(r);
+    hash.update(public_key);
+    hash.update(message);
+    let h = reduce(&hash.finish());
+
+    // R == [S]B - [h]A
+    let mut p = scalar_mult(&mut q, &h);
+    add(&mut p, &scalar_base(s));
+    pack(&p) == r
+}
+
+/// Check whether the scalar is below the group order
+fn is_reduced(s: &[u8]) -> bool {
+    for i in (0..32).rev() {
+        if s[i] as i64 != L[i] {
+            return (s[i] as i64) < L[i];
+        }
+    }
+    false
+}
+
+fn carry(o: &mut Field) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+        o[i] -= c << 16;
+    }
+}
+
+/// Swap ``p`` and ``q`` if ``b`` is 1
+fn select(p: &mut Field, q: &mut Field, b: i64) {
+    let c = !(b - 1);
+    for i in 0..16 {
+        let t = c & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+/// The element fully reduced modulo 2^255 - 19, little endian
+fn pack_field(n: &Field) -> [u8; 32] {
+    let mut t = *n;
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+    for _ in 0..2 {
+        let mut m = ZERO;
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - (m[i - 1] >> 16 & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - (m[14] >> 16 & 1);
+        let b = m[15] >> 16 & 1;
+        m[14] &= 0xffff;
+        select(&mut t, &mut m, 1 - b);
+    }
+    let mut o = [0; 32];
+    for i in 0..16 {
+        o[2 * i] = t[i] as u8;
+        o[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+    o
+}
+
+fn unpack_field(n: &[u8]) -> Field {
+    let mut o = ZERO;
+    for i in 0..16 {
+        o[i] = n[2 * i] as i64 + ((n[2 * i + 1] as i64) << 8);
+    }
+    o[15] &= 0x7fff;
+    o
+}
+
+fn field_eq(a: &Field, b: &Field) -> bool {
+    pack_field(a) == pack_field(b)
+}
+
+fn parity(a: &Field) -> u8 {
+    pack_field(a)[0] & 1
+}
+
+fn sum(a: &Field, b: &Field) -> Field {
+    let mut o = ZERO;
+    for i in 0..16 {
+        o[i] = a[i] + b[i];
+    }
+    o
+}
+
+fn diff(a: &Field, b: &Field) -> Field {
+    let mut o = ZERO;
+    for i in 0..16 {
+        o[i] = a[i] - b[i];
+    }
+    o
+}
+
+fn mul(a: &Field, b: &Field) -> Field {
+    let mut t = [0_i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+    let mut o = ZERO;
+    o.copy_from_slice(&t[..16]);
+    carry(&mut o);
+    carry(&mut o);
+    o
+}
+
+fn square(a: &Field) -> Field {
+    mul(a, a)
+}
+
+/// a^(2^252 - 3), used for the square root while decompressing a point
+fn pow2523(a: &Field) -> Field {
+    let mut c = *a;
+    for i in (0..251).rev() {
+        c = square(&c);
+        if i != 1 {
+            c = mul(&c, a);
+        }
+    }
+    c
+}
+
+/// a^-1 as a^(p - 2)
+fn invert(a: &Field) -> Field {
+    let mut c = *a;
+    for i in (0..254).rev() {
+        c = square(&c);
+        if i != 2 && i != 4 {
+            c = mul(&c, a);
+        }
+    }
+    c
+}
+
+/// p = p + q
+fn add(p: &mut Point, q: &Point) {
+    let a = mul(&diff(&p[1], &p[0]), &diff(&q[1], &q[0]));
+    let b = mul(&sum(&p[0], &p[1]), &sum(&q[0], &q[1]));
+    let c = mul(&mul(&p[3], &q[3]), &D2);
+    let d = mul(&p[2], &q[2]);
+    let d = sum(&d, &d);
+    let e = diff(&b, &a);
+    let f = diff(&d, &c);
+    let g = sum(&d, &c);
+    let h = sum(&b, &a);
+    p[0] = mul(&e, &f);
+    p[1] = mul(&h, &g);
+    p[2] = mul(&g, &f);
+    p[3] = mul(&e, &h);
+}
+
+fn swap(p: &mut Point, q: &mut Point, b: i64) {
+    for i in 0..4 {
+        select(&mut p[i], &mut q[i], b);
+    }
+}
+
+/// The compressed encoding of the point, y with the parity of x in the top bit
+fn pack(p: &Point) -> [u8; 32] {
+    let zi = invert(&p[2]);
+    let tx = mul(&p[0], &zi);
+    let ty = mul(&p[1], &zi);
+    let mut r = pack_field(&ty);
+    r[31] ^= parity(&tx) << 7;
+    r
+}
+
+/// [s]q with the little endian scalar s, q is changed on the way
+fn scalar_mult(q: &mut Point, s: &[u8]) -> Point {
+    let mut p = [ZERO, ONE, ONE, ZERO];
+    for i in (0..256).rev() {
+        let b = (s[i / 8] >> (i & 7) & 1) as i64;
+        swap(&mut p, q, b);
+        add(q, &p);
+        let copy = p;
+        add(&mut p, &copy);
+        swap(&mut p, q, b);
+    }
+    p
+}
+
+/// [s]B with the base point B
+fn scalar_base(s: &[u8]) -> Point {
+    let mut q = [X, Y, ONE, mul(&X, &Y)];
+    scalar_mult(&mut q, s)
+}
+
+/// Decompress the point and negate it, ``None`` if the encoding is not a point of the curve
+fn unpack_negated(p: &[u8; 32]) -> Option<Point> {
+    let z = ONE;
+    let y = unpack_field(p);
+    let num = square(&y);
+    let den = mul(&num, &D);
+    let num = diff(&num, &z);
+    let den = sum(&z, &den);
+
+    let den2 = square(&den);
+    let den4 = square(&den2);
+    let den6 = mul(&den4, &den2);
+    let mut t = mul(&mul(&den6, &num), &den);
+    t = pow2523(&t);
+    t = mul(&mul(&mul(&t, &num), &den), &den);
+    let mut x = mul(&t, &den);
+
+    if !field_eq(&mul(&square(&x), &den), &num) {
+        x = mul(&x, &SQRT_M1);
+    }
+    if !field_eq(&mul(&square(&x), &den), &num) {
+        return None;
+    }
+    if parity(&x) == p[31] >> 7 {
+        x = diff(&ZERO, &x);
+    }
+    let t = mul(&x, &y);
+    Some([x, y, z, t])
+}
+
+/// Reduce the 64 byte little endian number modulo the group order
+fn reduce(hash: &[u8; 64]) -> [u8; 32] {
+    let mut x = [0_i64; 64];
+    for i in 0..64 {
+        x[i] = hash[i] as i64;
+    }
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        let mut j = i - 32;
+        while j < i - 12 {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+            j += 1;
+        }
+        x[j] += carry;
+        x[i] = 0;
+    }
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+    let mut r = [0; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        r[i] = x[i] as u8;
+    }
+    r
+}
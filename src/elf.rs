@@ -0,0 +1,173 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # ELF kernels
+//!
+//! aarch64 kernels could be sent as ELF64 executable, like the artifact of ``cargo build``, instead of a raw binary
+//! extracted with ``objcopy``. The ``PT_LOAD`` segments of the program header table are copied to their physical
+//! addresses, the memory of a segment beyond its data in the file, like ``.bss``, is zeroed and the kernel is
+//! entered at ``e_entry``. The sections are not looked at.
+//!
+//! The kernel is validated like a raw kernel placed from its lowest to its highest segment address. The ELF file is
+//! kept as received, so a kernel saved for a roll back or written to a kernel slot is loaded the same way again.
+//!
+
+use crate::image::ImageError;
+
+/// The magic value of ELF files
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_AARCH64: u16 = 183;
+const PT_LOAD: u32 = 1;
+const ELF64_HEADER_SIZE: usize = 64;
+const ELF64_PHDR_SIZE: usize = 56;
+
+/// The memory an ELF kernel is loaded to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ElfLayout {
+    /// The lowest physical address of the segments
+    pub start: u64,
+    /// The end of the segment with the highest physical address
+    pub end: u64,
+    /// The address the kernel is entered at
+    pub entry: u64,
+}
+
+/// A segment to be loaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Segment {
+    /// The physical address of the segment
+    address: u64,
+    /// The offset of the data of the segment within the file
+    offset: usize,
+    /// The size of the data in the file
+    file_size: usize,
+    /// The size of the segment in memory, the part beyond the data is zeroed
+    memory_size: u64,
+}
+
+/// Check whether the binary is an ELF file of any kind
+pub fn is_elf(binary: &[u8]) -> bool {
+    binary.len() >= ELF_MAGIC.len() && &binary[..4] == ELF_MAGIC
+}
+
+/// Validate the ELF file as aarch64 executable and provide the memory it is loaded to. All segments need to lie
+/// within the file and the entry address within a segment.
+pub fn parse(binary: &[u8]) -> Result<ElfLayout, ImageError> {
+    if !is_elf(binary) || binary.len() < ELF64_HEADER_SIZE {
+        return Err(ImageError::BadElf);
+    }
+    if binary[4] != ELFCLASS64 || le16(binary, 18) != EM_AARCH64 {
+        return Err(ImageError::WrongMachine);
+    }
+    if binary[5] != ELFDATA2LSB {
+        return Err(ImageError::BigEndian);
+    }
+    if le16(binary, 16) != ET_EXEC {
+        return Err(ImageError::BadElf);
+    }
+    let entry = le64(binary, 24);
+    let mut layout: Option<ElfLayout> = None;
+    let mut entry_found = false;
+    for segment in segments(binary)? {
+        let end = segment.address + segment.memory_size;
+        entry_found |= entry >= segment.address && entry < end;
+        layout = Some(match layout {
+            Some(layout) => ElfLayout {
+                start: layout.start.min(segment.address),
+                end: layout.end.max(end),
+                entry,
+            },
+            None => ElfLayout {
+                start: segment.address,
+                end,
+                entry,
+            },
+        });
+    }
+    match layout {
+        Some(layout) if entry_found => Ok(layout),
+        _ => Err(ImageError::BadElf),
+    }
+}
+
+/// The non-empty ``PT_LOAD`` segments of the ELF file, each validated to lie within the file and the address space.
+/// The file need to be at least as large as the ELF header.
+fn segments(binary: &[u8]) -> Result<impl Iterator<Item = Segment> + '_, ImageError> {
+    let table = le64(binary, 32) as usize;
+    let entry_size = le16(binary, 54) as usize;
+    let count = le16(binary, 56) as usize;
+    let table_end = count
+        .checked_mul(entry_size)
+        .and_then(|size| size.checked_add(table))
+        .ok_or(ImageError::BadElf)?;
+    if entry_size < ELF64_PHDR_SIZE || table_end > binary.len() {
+        return Err(ImageError::BadElf);
+    }
+    let headers = binary[table..table_end].chunks(entry_size);
+    let file_fits = |offset: u64, size: u64| {
+        offset
+            .checked_add(size)
+            .map_or(false, |end| end <= binary.len() as u64)
+    };
+    let valid = headers
+        .clone()
+        .filter(|header| le32(header, 0) == PT_LOAD)
+        .all(|header| {
+            let (offset, address) = (le64(header, 8), le64(header, 24));
+            let (file_size, memory_size) = (le64(header, 32), le64(header, 40));
+            file_fits(offset, file_size)
+                && file_size <= memory_size
+                && address.checked_add(memory_size).is_some()
+        });
+    if !valid {
+        return Err(ImageError::BadElf);
+    }
+    Ok(headers
+        .filter(|header| le32(header, 0) == PT_LOAD && le64(header, 40) != 0)
+        .map(|header| Segment {
+            address: le64(header, 24),
+            offset: le64(header, 8) as usize,
+            file_size: le64(header, 32) as usize,
+            memory_size: le64(header, 40),
+        }))
+}
+
+/// Copy the segments of the validated ELF file to their addresses and zero the rest of each segment
+///
+/// # Safety
+/// The memory of the segments must not be used by anything else, see [parse] for the memory covered.
+pub unsafe fn load(binary: &[u8]) -> Result<(), ImageError> {
+    for segment in segments(binary)? {
+        let target = segment.address as *mut u8;
+        core::ptr::copy_nonoverlapping(
+            binary[segment.offset..].as_ptr(),
+            target,
+            segment.file_size,
+        );
+        core::ptr::write_bytes(
+            target.add(segment.file_size),
+            0,
+            (segment.memory_size - segment.file_size as u64) as usize,
+        );
+    }
+    Ok(())
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    data[offset] as u16 | (data[offset + 1] as u16) << 8
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    le16(data, offset) as u32 | (le16(data, offset + 2) as u32) << 16
+}
+
+fn le64(data: &[u8], offset: usize) -> u64 {
+    le32(data, offset) as u64 | (le32(data, offset + 4) as u64) << 32
+}
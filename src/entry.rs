@@ -13,14 +13,62 @@
 //! allow branching into rust code line
 //!
 
+#[macro_use]
+mod console;
+#[macro_use]
+mod assert;
+#[macro_use]
+mod log;
+mod block;
+mod board;
+mod bootargs;
+mod buildinfo;
+mod cache;
+mod command;
+mod crash;
+mod crc;
+#[cfg(feature = "uart_dma")]
+mod dma;
+#[cfg(feature = "signed_kernels")]
+mod ed25519;
+mod elf;
+mod exfat;
+mod fat;
+mod fdt;
+mod guest;
+mod gzip;
+mod hyp;
+mod image;
+mod integrity;
+pub mod layout;
+mod led;
 mod loader;
+mod mailbox;
 pub mod mmu;
+mod model;
+mod monitor;
 mod panic;
+mod partition;
+#[cfg(feature = "second_uart")]
+mod pl011;
+mod pm;
+mod pmu;
+mod retained;
+mod sched;
+mod sd;
+mod services;
+mod sha2;
+mod slot;
+mod smp;
+mod storage;
 mod stubs;
+mod supply;
+mod time;
+mod verify;
 
 use ruspiro_interrupt::IRQ_MANAGER;
-use ruspiro_timer as timer;
 use ruspiro_uart::Uart1;
+use time::Duration;
 
 /// Entry point that is called by the bootstrapping code.
 ///
@@ -34,11 +82,12 @@ pub fn __rust_entry(core: u32) -> ! {
     // very first thing is to setup the MMU which allows us to
     // use atomic operations in the upcomming initialization
     mmu::initialize_mmu(core);
+    time::calibrate();
 
     // once MMU is setup we would like to let the outside world know that we are booting
     // so we initialze the uart1 interface with default settings and print some message
     let mut uart = Uart1::new();
-    let _ = uart.initialize(250_000_000, 115_200);
+    let _ = uart.initialize(board::CORE_CLOCK_RATE, 115_200);
     uart.send_string("\r\n########## RusPiRo ---------- Bootloader v1.0 ---------- ##########\r\n");
 
     // now initialize the interrupt manager
@@ -47,7 +96,7 @@ pub fn __rust_entry(core: u32) -> ! {
     // spend some time doing nothing as the followup entry point may want to re-initialize the
     // uart and this would interfere the current data transfer of the welcome string that might
     // not yet be finished...(from the device point of view)
-    timer::sleep(200_000);
+    time::sleep(Duration::from_millis(200));
     drop(uart); // release uart recources before calling the boot loader
 
     // now start the bootloader code
@@ -0,0 +1,351 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # exFAT file system
+//!
+//! Read-only access to exFAT formatted SD cards, as cards larger than 32GB are shipped with exFAT. The files are
+//! looked up case insensitive like on [crate::fat] and the directory entries are returned as the same
+//! [DirEntry]. Only 512 byte sectors are supported, the up-case table of the volume is not used, so names only
+//! match case insensitive within ASCII.
+//!
+//! Files and directories could be stored in contiguous clusters without a chain in the allocation table, e.g. a
+//! kernel copied to a freshly formatted card. Such entries are read without touching the allocation table.
+//!
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use crate::fat::{le16, le32, le64, DirEntry, FatError, BOOT_SIGNATURE};
+use alloc::{string::String, vec, vec::Vec};
+
+/// The file system name in the boot sector
+const FILE_SYSTEM_NAME: &[u8; 8] = b"EXFAT   ";
+/// The sectors need to match the blocks of 512 bytes
+const SECTOR_SHIFT: u8 = 9;
+/// Clusters are at most 32MB
+const MAX_CLUSTER_SHIFT: u8 = 16;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xC0;
+const ENTRY_NAME: u8 = 0xC1;
+/// Secondary entries in use belong to the entry set of the preceding file entry
+const SECONDARY_IN_USE: u8 = 0xC0;
+/// A file entry set contains the stream extension and at least one name entry, at most 17 name entries
+const MIN_SECONDARY: usize = 2;
+const MAX_SECONDARY: usize = 18;
+/// The number of characters of the name stored in each name entry
+const NAME_CHARS: usize = 15;
+
+const ATTR_DIRECTORY: u16 = 0x10;
+/// The clusters of the stream follow each other, there is no chain in the allocation table
+const FLAG_NO_FAT_CHAIN: u8 = 0x02;
+
+/// The cluster number marking the end of the cluster chain
+const END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+
+/// A mounted exFAT file system
+pub struct ExFat<D: BlockDevice> {
+    device: D,
+    /// The first block of the file allocation table
+    fat_start: u64,
+    /// The first block of the cluster heap, where cluster 2 starts
+    heap_start: u64,
+    blocks_per_cluster: u32,
+    /// The number of clusters in the cluster heap
+    clusters: u32,
+    root_cluster: u32,
+    /// The last block of the file allocation table read
+    fat_cache: Option<(u64, [u8; BLOCK_SIZE])>,
+}
+
+impl<D: BlockDevice> ExFat<D> {
+    /// Mount the exFAT file system starting at the given block of the device
+    pub fn mount(mut device: D, start: u64) -> Result<Self, FatError<D::Error>> {
+        let mut block = [0; BLOCK_SIZE];
+        device
+            .read_blocks(start, &mut block)
+            .map_err(FatError::Device)?;
+        if !is_boot_sector(&block) {
+            return Err(FatError::NoFileSystem);
+        }
+
+        let fat_offset = le32(&block[80..]) as u64;
+        let heap_offset = le32(&block[88..]) as u64;
+        let clusters = le32(&block[92..]);
+        let root_cluster = le32(&block[96..]);
+        let fs = ExFat {
+            device,
+            fat_start: start + fat_offset,
+            heap_start: start + heap_offset,
+            blocks_per_cluster: 1 << block[109],
+            clusters,
+            root_cluster,
+            fat_cache: None,
+        };
+        fs.check_cluster(root_cluster)?;
+        Ok(fs)
+    }
+
+    /// The size of a cluster in bytes
+    pub fn cluster_size(&self) -> usize {
+        self.blocks_per_cluster as usize * BLOCK_SIZE
+    }
+
+    /// Find the file or directory with the given path
+    pub fn find(&mut self, path: &str) -> Result<DirEntry, FatError<D::Error>> {
+        let mut components = path.split('/').filter(|name| !name.is_empty()).peekable();
+        let mut entries = self.read_dir(self.root_cluster, false, None)?;
+        while let Some(name) = components.next() {
+            let entry = entries
+                .into_iter()
+                .find(|entry| entry.name.eq_ignore_ascii_case(name))
+                .ok_or(FatError::NotFound)?;
+            if components.peek().is_none() {
+                return Ok(entry);
+            }
+            if !entry.is_dir {
+                return Err(FatError::NotADirectory);
+            }
+            entries = self.read_dir(entry.cluster, entry.contiguous, Some(entry.size))?;
+        }
+        Err(FatError::NotFound)
+    }
+
+    /// Read the entries of the directory with the given path, the root directory for an empty path or ``/``. The
+    /// allocation bitmap, the up-case table and the volume label are skipped.
+    pub fn list(&mut self, path: &str) -> Result<Vec<DirEntry>, FatError<D::Error>> {
+        if path.split('/').all(|name| name.is_empty()) {
+            return self.read_dir(self.root_cluster, false, None);
+        }
+        let entry = self.find(path)?;
+        if !entry.is_dir {
+            return Err(FatError::NotADirectory);
+        }
+        self.read_dir(entry.cluster, entry.contiguous, Some(entry.size))
+    }
+
+    /// Read the whole file, files larger than ``max_size`` are refused
+    pub fn read_file(
+        &mut self,
+        entry: &DirEntry,
+        max_size: usize,
+    ) -> Result<Vec<u8>, FatError<D::Error>> {
+        if entry.is_dir {
+            return Err(FatError::NotAFile);
+        }
+        if entry.size > max_size as u64 {
+            return Err(FatError::TooLarge(entry.size as usize));
+        }
+        let size = entry.size as usize;
+        let cluster_size = self.cluster_size();
+        let mut data = vec![0; (size + cluster_size - 1) / cluster_size * cluster_size];
+        let mut cluster = entry.cluster;
+        for chunk in data.chunks_mut(cluster_size) {
+            self.read_cluster(cluster, chunk)?;
+            // the chain may end with the last cluster
+            cluster = self.next_cluster(cluster, entry.contiguous)?.unwrap_or(0);
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+
+    /// Read the entries of the directory starting at the given cluster. The size of the root directory is not
+    /// known, it ends with its cluster chain.
+    fn read_dir(
+        &mut self,
+        cluster: u32,
+        contiguous: bool,
+        size: Option<u64>,
+    ) -> Result<Vec<DirEntry>, FatError<D::Error>> {
+        let cluster_size = self.cluster_size() as u64;
+        let count = match size {
+            Some(size) => (size + cluster_size - 1) / cluster_size,
+            None => self.clusters as u64,
+        };
+        let mut entries = Vec::new();
+        let mut data = vec![0; self.cluster_size()];
+        let mut set = EntrySet::new();
+        let mut cluster = cluster;
+        for _ in 0..count {
+            self.read_cluster(cluster, &mut data)?;
+            for raw in data.chunks(ENTRY_SIZE) {
+                match raw[0] {
+                    ENTRY_END => return Ok(entries),
+                    ENTRY_FILE => set.start(raw),
+                    kind if kind & SECONDARY_IN_USE == SECONDARY_IN_USE => set.add(raw),
+                    // deleted entries and the other primary entries end the entry set
+                    _ => set.clear(),
+                }
+                if let Some(entry) = set.take() {
+                    entries.push(entry);
+                }
+            }
+            cluster = match self.next_cluster(cluster, contiguous)? {
+                Some(next) => next,
+                None => return Ok(entries),
+            };
+        }
+        match size {
+            Some(_) => Ok(entries),
+            None => Err(FatError::BadCluster),
+        }
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), FatError<D::Error>> {
+        if cluster < 2 || cluster - 2 >= self.clusters {
+            return Err(FatError::BadCluster);
+        }
+        Ok(())
+    }
+
+    fn read_cluster(&mut self, cluster: u32, buffer: &mut [u8]) -> Result<(), FatError<D::Error>> {
+        self.check_cluster(cluster)?;
+        let block = self.heap_start + (cluster - 2) as u64 * self.blocks_per_cluster as u64;
+        self.device
+            .read_blocks(block, buffer)
+            .map_err(FatError::Device)
+    }
+
+    /// The cluster following the given one, ``None`` at the end of the chain. Contiguous clusters are not checked
+    /// against the end of the cluster heap, this is done when reading them.
+    fn next_cluster(
+        &mut self,
+        cluster: u32,
+        contiguous: bool,
+    ) -> Result<Option<u32>, FatError<D::Error>> {
+        if contiguous {
+            return Ok(cluster.checked_add(1));
+        }
+        let offset = cluster as u64 * 4;
+        let block = self.fat_start + offset / BLOCK_SIZE as u64;
+        let cached = self.fat_cache.as_ref().map(|(cached, _)| *cached);
+        if cached != Some(block) {
+            let mut data = [0; BLOCK_SIZE];
+            self.device
+                .read_blocks(block, &mut data)
+                .map_err(FatError::Device)?;
+            self.fat_cache = Some((block, data));
+        }
+        let data = match &self.fat_cache {
+            Some((_, data)) => data,
+            None => return Err(FatError::BadCluster),
+        };
+        let idx = (offset % BLOCK_SIZE as u64) as usize;
+        let next = le32(&data[idx..]);
+        if next == END_OF_CHAIN {
+            return Ok(None);
+        }
+        self.check_cluster(next)?;
+        Ok(Some(next))
+    }
+}
+
+/// The entries of a file entry set collected until its secondary entries are complete. A set may span clusters.
+struct EntrySet {
+    raw: Vec<u8>,
+    /// The number of secondary entries still missing, ``None`` if there is no set in progress
+    missing: Option<usize>,
+}
+
+impl EntrySet {
+    fn new() -> Self {
+        EntrySet {
+            raw: Vec::new(),
+            missing: None,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.missing = None;
+    }
+
+    fn start(&mut self, raw: &[u8]) {
+        let secondary = raw[1] as usize;
+        self.clear();
+        if (MIN_SECONDARY..=MAX_SECONDARY).contains(&secondary) {
+            self.raw.clear();
+            self.raw.extend_from_slice(raw);
+            self.missing = Some(secondary);
+        }
+    }
+
+    fn add(&mut self, raw: &[u8]) {
+        match self.missing {
+            Some(missing) if missing > 0 => {
+                self.raw.extend_from_slice(raw);
+                self.missing = Some(missing - 1);
+            }
+            _ => self.clear(),
+        }
+    }
+
+    /// Take the entry once the set is complete, sets with a wrong checksum or without a valid name are dropped
+    fn take(&mut self) -> Option<DirEntry> {
+        if self.missing != Some(0) {
+            return None;
+        }
+        self.clear();
+        let checksum = self
+            .raw
+            .iter()
+            .enumerate()
+            // the checksum itself is not part of the checksum
+            .filter(|&(idx, _)| idx != 2 && idx != 3)
+            .fold(0u16, |sum, (_, &c)| {
+                sum.rotate_right(1).wrapping_add(c as u16)
+            });
+        if checksum != le16(&self.raw[2..]) {
+            return None;
+        }
+        let (file, secondary) = self.raw.split_at(ENTRY_SIZE);
+        let (stream, names) = secondary.split_at(ENTRY_SIZE);
+        if stream[0] != ENTRY_STREAM {
+            return None;
+        }
+        let name_len = stream[3] as usize;
+        let mut chars = Vec::with_capacity(name_len);
+        for raw in names
+            .chunks(ENTRY_SIZE)
+            .take_while(|raw| raw[0] == ENTRY_NAME)
+        {
+            chars.extend(raw[2..2 + 2 * NAME_CHARS].chunks(2).map(le16));
+        }
+        if name_len == 0 || chars.len() < name_len {
+            return None;
+        }
+        let name = core::char::decode_utf16(chars[..name_len].iter().copied())
+            .map(|c| c.ok())
+            .collect::<Option<String>>()?;
+        // the timestamps combine the date and time like on FAT
+        let modified = le32(&file[12..]);
+        Some(DirEntry {
+            name,
+            is_dir: le16(&file[4..]) & ATTR_DIRECTORY != 0,
+            size: le64(&stream[24..]),
+            cluster: le32(&stream[20..]),
+            contiguous: stream[1] & FLAG_NO_FAT_CHAIN != 0,
+            date: (modified >> 16) as u16,
+            time: modified as u16,
+        })
+    }
+}
+
+/// Check whether the block is the boot sector of an exFAT file system with 512 byte sectors
+pub fn is_boot_sector(block: &[u8; BLOCK_SIZE]) -> bool {
+    let fat_offset = le32(&block[80..]);
+    let heap_offset = le32(&block[88..]);
+    le16(&block[510..]) == BOOT_SIGNATURE
+        && &block[3..11] == FILE_SYSTEM_NAME
+        // the range of the BIOS parameter block of FAT is zero to keep FAT drivers off the volume
+        && block[11..64].iter().all(|&byte| byte == 0)
+        && block[108] == SECTOR_SHIFT
+        && block[109] <= MAX_CLUSTER_SHIFT
+        && (block[110] == 1 || block[110] == 2)
+        && fat_offset >= 24
+        && heap_offset > fat_offset
+        && le32(&block[92..]) != 0
+}
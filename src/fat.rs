@@ -0,0 +1,390 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # FAT32 file system
+//!
+//! Read-only access to the FAT32 boot partition of the SD card the firmware loads the loader from. Files are looked
+//! up with their long name or their 8.3 name, both case insensitive, and paths could contain directories separated
+//! with ``/``. The entries of a directory are described with [DirEntry], which is shared with [crate::exfat].
+//!
+//! The file system is only built upon [BlockDevice], so it does not depend on the SD card driver. All structures
+//! read from the device are checked before they are used, a corrupted file system results in an error.
+//!
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use alloc::{string::String, vec, vec::Vec};
+
+pub const BOOT_SIGNATURE: u16 = 0xAA55;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ENTRY_SIZE: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+/// The last entry of a long name is stored first and marked with this flag in its sequence number
+const LONG_NAME_LAST: u8 = 0x40;
+/// The number of characters of a long name stored in each entry
+const LONG_NAME_CHARS: usize = 13;
+const MAX_LONG_NAME: usize = 255;
+/// The 8.3 name and extension are shown in lower case if these flags are set
+const NAME_LOWER_CASE: u8 = 0x08;
+const EXT_LOWER_CASE: u8 = 0x10;
+
+/// Cluster numbers from this value on mark the end of the cluster chain
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+const CLUSTER_MASK: u32 = 0x0FFF_FFFF;
+
+/// Errors accessing a FAT32 or exFAT file system
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FatError<E> {
+    /// Reading the device failed
+    Device(E),
+    /// There is no supported file system on the device
+    NoFileSystem,
+    /// The file or directory does not exist
+    NotFound,
+    /// A directory has been given where a file was expected
+    NotAFile,
+    /// A file has been given where a directory was expected
+    NotADirectory,
+    /// The file is larger than the maximum size accepted, the size of the file is given
+    TooLarge(usize),
+    /// A cluster chain refers to a cluster outside of the file system or does not end
+    BadCluster,
+}
+
+/// An entry of a directory
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    /// The long name of the entry or its 8.3 name if there is no long name
+    pub name: String,
+    /// The entry is a directory
+    pub is_dir: bool,
+    /// The size of the file in bytes
+    pub size: u64,
+    /// The first cluster of the file or directory
+    pub cluster: u32,
+    /// The clusters follow each other without a chain in the allocation table, this is only used by exFAT
+    pub contiguous: bool,
+    /// The date of the last modification, bits 15-9 are the years since 1980, bits 8-5 the month and bits 4-0 the day
+    pub date: u16,
+    /// The time of the last modification, bits 15-11 are the hours, bits 10-5 the minutes and bits 4-0 two seconds
+    pub time: u16,
+}
+
+/// A mounted FAT32 file system
+pub struct FileSystem<D: BlockDevice> {
+    device: D,
+    /// The first block of the file allocation table
+    fat_start: u64,
+    /// The first block of the data region, where cluster 2 starts
+    data_start: u64,
+    blocks_per_cluster: u32,
+    /// The number of clusters in the data region
+    clusters: u32,
+    root_cluster: u32,
+    /// The last block of the file allocation table read
+    fat_cache: Option<(u64, [u8; BLOCK_SIZE])>,
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /// Mount the FAT32 file system starting at the given block of the device
+    pub fn mount(mut device: D, start: u64) -> Result<Self, FatError<D::Error>> {
+        let mut block = [0; BLOCK_SIZE];
+        device
+            .read_blocks(start, &mut block)
+            .map_err(FatError::Device)?;
+        if !is_boot_sector(&block) {
+            return Err(FatError::NoFileSystem);
+        }
+
+        let blocks_per_cluster = block[13] as u32;
+        let reserved = le16(&block[14..]) as u64;
+        let fats = block[16] as u64;
+        let total = match le16(&block[19..]) {
+            0 => le32(&block[32..]) as u64,
+            total => total as u64,
+        };
+        let fat_size = le32(&block[36..]) as u64;
+        let data_start = reserved + fats * fat_size;
+        if total <= data_start {
+            return Err(FatError::NoFileSystem);
+        }
+        let clusters = ((total - data_start) / blocks_per_cluster as u64) as u32;
+        let root_cluster = le32(&block[44..]);
+
+        let fs = FileSystem {
+            device,
+            fat_start: start + reserved,
+            data_start: start + data_start,
+            blocks_per_cluster,
+            clusters,
+            root_cluster,
+            fat_cache: None,
+        };
+        fs.check_cluster(root_cluster)?;
+        Ok(fs)
+    }
+
+    /// The size of a cluster in bytes
+    pub fn cluster_size(&self) -> usize {
+        self.blocks_per_cluster as usize * BLOCK_SIZE
+    }
+
+    /// Find the file or directory with the given path
+    pub fn find(&mut self, path: &str) -> Result<DirEntry, FatError<D::Error>> {
+        let mut components = path.split('/').filter(|name| !name.is_empty()).peekable();
+        let mut cluster = self.root_cluster;
+        while let Some(name) = components.next() {
+            let entry = self
+                .read_dir(cluster)?
+                .into_iter()
+                .find(|entry| entry.name.eq_ignore_ascii_case(name))
+                .ok_or(FatError::NotFound)?;
+            if components.peek().is_none() {
+                return Ok(entry);
+            }
+            if !entry.is_dir {
+                return Err(FatError::NotADirectory);
+            }
+            cluster = entry.cluster;
+        }
+        Err(FatError::NotFound)
+    }
+
+    /// Read the entries of the directory with the given path, the root directory for an empty path or ``/``
+    pub fn list(&mut self, path: &str) -> Result<Vec<DirEntry>, FatError<D::Error>> {
+        if path.split('/').all(|name| name.is_empty()) {
+            return self.read_dir(self.root_cluster);
+        }
+        let entry = self.find(path)?;
+        if !entry.is_dir {
+            return Err(FatError::NotADirectory);
+        }
+        self.read_dir(entry.cluster)
+    }
+
+    /// Read the entries of the directory starting at the given cluster. The volume label and the ``.`` and ``..``
+    /// entries are skipped.
+    pub fn read_dir(&mut self, cluster: u32) -> Result<Vec<DirEntry>, FatError<D::Error>> {
+        let mut entries = Vec::new();
+        let mut data = vec![0; self.cluster_size()];
+        let mut long_name = LongName::new();
+        let mut cluster = cluster;
+        for _ in 0..self.clusters {
+            self.read_cluster(cluster, &mut data)?;
+            for raw in data.chunks(ENTRY_SIZE) {
+                match raw[0] {
+                    ENTRY_END => return Ok(entries),
+                    ENTRY_DELETED => long_name.clear(),
+                    _ if raw[11] & ATTR_LONG_NAME == ATTR_LONG_NAME => long_name.add(raw),
+                    _ if raw[11] & ATTR_VOLUME_ID != 0 || raw[0] == b'.' => long_name.clear(),
+                    _ => {
+                        let name = long_name
+                            .take(short_name_checksum(&raw[0..11]))
+                            .unwrap_or_else(|| short_name(raw));
+                        entries.push(DirEntry {
+                            name,
+                            is_dir: raw[11] & ATTR_DIRECTORY != 0,
+                            size: le32(&raw[28..]) as u64,
+                            cluster: (le16(&raw[20..]) as u32) << 16 | le16(&raw[26..]) as u32,
+                            contiguous: false,
+                            date: le16(&raw[24..]),
+                            time: le16(&raw[22..]),
+                        });
+                    }
+                }
+            }
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None => return Ok(entries),
+            };
+        }
+        Err(FatError::BadCluster)
+    }
+
+    /// Read the whole file, files larger than ``max_size`` are refused
+    pub fn read_file(
+        &mut self,
+        entry: &DirEntry,
+        max_size: usize,
+    ) -> Result<Vec<u8>, FatError<D::Error>> {
+        if entry.is_dir {
+            return Err(FatError::NotAFile);
+        }
+        if entry.size > max_size as u64 {
+            return Err(FatError::TooLarge(entry.size as usize));
+        }
+        let size = entry.size as usize;
+        let cluster_size = self.cluster_size();
+        let mut data = vec![0; (size + cluster_size - 1) / cluster_size * cluster_size];
+        let mut cluster = entry.cluster;
+        for chunk in data.chunks_mut(cluster_size) {
+            self.read_cluster(cluster, chunk)?;
+            // the chain may end with the last cluster
+            cluster = self.next_cluster(cluster)?.unwrap_or(0);
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), FatError<D::Error>> {
+        if cluster < 2 || cluster - 2 >= self.clusters {
+            return Err(FatError::BadCluster);
+        }
+        Ok(())
+    }
+
+    fn read_cluster(&mut self, cluster: u32, buffer: &mut [u8]) -> Result<(), FatError<D::Error>> {
+        self.check_cluster(cluster)?;
+        let block = self.data_start + (cluster - 2) as u64 * self.blocks_per_cluster as u64;
+        self.device
+            .read_blocks(block, buffer)
+            .map_err(FatError::Device)
+    }
+
+    /// The cluster following the given one in its chain, ``None`` at the end of the chain
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, FatError<D::Error>> {
+        let offset = cluster as u64 * 4;
+        let block = self.fat_start + offset / BLOCK_SIZE as u64;
+        let cached = self.fat_cache.as_ref().map(|(cached, _)| *cached);
+        if cached != Some(block) {
+            let mut data = [0; BLOCK_SIZE];
+            self.device
+                .read_blocks(block, &mut data)
+                .map_err(FatError::Device)?;
+            self.fat_cache = Some((block, data));
+        }
+        let data = match &self.fat_cache {
+            Some((_, data)) => data,
+            None => return Err(FatError::BadCluster),
+        };
+        let idx = (offset % BLOCK_SIZE as u64) as usize;
+        let next = le32(&data[idx..]) & CLUSTER_MASK;
+        if next >= END_OF_CHAIN {
+            return Ok(None);
+        }
+        self.check_cluster(next)?;
+        Ok(Some(next))
+    }
+}
+
+/// The long name collected from the entries preceding the 8.3 entry
+struct LongName {
+    chars: [u16; MAX_LONG_NAME + 1],
+    len: usize,
+    checksum: Option<u8>,
+}
+
+impl LongName {
+    fn new() -> Self {
+        LongName {
+            chars: [0; MAX_LONG_NAME + 1],
+            len: 0,
+            checksum: None,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.checksum = None;
+    }
+
+    fn add(&mut self, raw: &[u8]) {
+        let sequence = (raw[0] & !LONG_NAME_LAST) as usize;
+        if sequence == 0 || sequence * LONG_NAME_CHARS > self.chars.len() {
+            self.clear();
+            return;
+        }
+        let start = (sequence - 1) * LONG_NAME_CHARS;
+        if raw[0] & LONG_NAME_LAST != 0 {
+            self.len = start + LONG_NAME_CHARS;
+            self.checksum = Some(raw[13]);
+        } else if self.checksum != Some(raw[13]) {
+            self.clear();
+            return;
+        }
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (idx, offset) in offsets.enumerate() {
+            self.chars[start + idx] = le16(&raw[offset..]);
+        }
+    }
+
+    /// Take the long name if it belongs to the 8.3 entry with the given checksum
+    fn take(&mut self, checksum: u8) -> Option<String> {
+        if self.checksum.take() != Some(checksum) {
+            return None;
+        }
+        let len = self.chars[..self.len]
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.len);
+        core::char::decode_utf16(self.chars[..len].iter().copied())
+            .map(|c| c.ok())
+            .collect()
+    }
+}
+
+/// The checksum of the 8.3 name stored in the long name entries
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// The 8.3 name of the entry as ``NAME.EXT``
+fn short_name(raw: &[u8]) -> String {
+    let mut name = String::new();
+    let convert = |c: u8, lower: bool| {
+        let c = if c == 0x05 { ENTRY_DELETED } else { c };
+        let c = if lower { c.to_ascii_lowercase() } else { c };
+        // characters beyond ASCII depend on the code page, they are not translated
+        if c.is_ascii() {
+            c as char
+        } else {
+            '_'
+        }
+    };
+    for &c in raw[0..8].iter().take_while(|&&c| c != b' ') {
+        name.push(convert(c, raw[12] & NAME_LOWER_CASE != 0));
+    }
+    if raw[8] != b' ' {
+        name.push('.');
+        for &c in raw[8..11].iter().take_while(|&&c| c != b' ') {
+            name.push(convert(c, raw[12] & EXT_LOWER_CASE != 0));
+        }
+    }
+    name
+}
+
+/// Check whether the block is the boot sector of a FAT32 file system with 512 bytes per sector
+pub fn is_boot_sector(block: &[u8; BLOCK_SIZE]) -> bool {
+    let blocks_per_cluster = block[13];
+    le16(&block[510..]) == BOOT_SIGNATURE
+        && le16(&block[11..]) as usize == BLOCK_SIZE
+        && blocks_per_cluster.is_power_of_two()
+        && le16(&block[14..]) != 0
+        && block[16] != 0
+        // FAT32 has no fixed root directory and the FAT size only in the extended boot record
+        && le16(&block[17..]) == 0
+        && le16(&block[22..]) == 0
+        && le32(&block[36..]) != 0
+}
+
+pub fn le16(data: &[u8]) -> u16 {
+    data[0] as u16 | (data[1] as u16) << 8
+}
+
+pub fn le32(data: &[u8]) -> u32 {
+    le16(data) as u32 | (le16(&data[2..]) as u32) << 16
+}
+
+pub fn le64(data: &[u8]) -> u64 {
+    le32(data) as u64 | (le32(&data[4..]) as u64) << 32
+}
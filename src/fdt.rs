@@ -0,0 +1,352 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Flattened device tree
+//!
+//! Minimal support for flattened device tree blobs (DTB) as they are passed from the firmware to the kernel. A
+//! device tree can be validated and the properties of the `/chosen` node the kernel reads its command line and
+//! initial ramdisk location from can be patched. Memory the kernel must leave alone could be added to the memory
+//! reservation block with the same patch.
+//!
+
+use alloc::vec::Vec;
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Size of the device tree header (version 17)
+const HEADER_SIZE: usize = 40;
+/// The device trees provided with the firmware are usually about 30kB. Anything beyond this limit is treated as
+/// not being a valid device tree
+const MAX_SIZE: usize = 0x10_0000;
+
+/// Reasons why a device tree is not accepted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FdtError {
+    /// The blob does not start with the device tree magic
+    BadMagic,
+    /// The total size given in the header does not fit the available data
+    BadSize,
+    /// The device tree version is not supported
+    BadVersion,
+    /// The blocks referred to in the header are not within the blob
+    BadLayout,
+    /// The structure block contains unexpected tokens
+    BadStructure,
+}
+
+/// The patches that could be applied to the `/chosen` node of a device tree
+#[derive(Debug, Default)]
+pub struct ChosenPatch<'a> {
+    /// The kernel command line stored in the `bootargs` property
+    pub bootargs: Option<&'a str>,
+    /// Start and end address of the initial ramdisk stored in the `linux,initrd-start` and `linux,initrd-end`
+    /// properties
+    pub initrd: Option<(u64, u64)>,
+    /// Start address and size of memory added to the memory reservation block, it is not added twice
+    pub reserve: Option<(u64, u64)>,
+}
+
+impl ChosenPatch<'_> {
+    /// Check whether there is actually anything to patch
+    pub fn is_empty(&self) -> bool {
+        self.bootargs.is_none() && self.initrd.is_none() && self.reserve.is_none()
+    }
+}
+
+/// A validated flattened device tree
+pub struct Fdt<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Validate the device tree stored in the given slice. The slice may be larger than the device tree.
+    pub fn from_slice(data: &'a [u8]) -> Result<Self, FdtError> {
+        if data.len() < HEADER_SIZE || be32(data, 0)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = be32(data, 4)? as usize;
+        if total_size < HEADER_SIZE || total_size > data.len() {
+            return Err(FdtError::BadSize);
+        }
+        // we understand version 16 and 17 but accept any newer one that is backwards compatible
+        if be32(data, 20)? < 16 || be32(data, 24)? > 17 {
+            return Err(FdtError::BadVersion);
+        }
+        let fdt = Fdt {
+            data: &data[..total_size],
+        };
+        let (struct_offset, struct_size) = (fdt.header(8)?, fdt.header(36)?);
+        let (strings_offset, strings_size) = (fdt.header(12)?, fdt.header(32)?);
+        let rsvmap_offset = fdt.header(16)?;
+        if struct_offset % 4 != 0
+            || rsvmap_offset % 8 != 0
+            || !fdt.contains(struct_offset, struct_size)
+            || !fdt.contains(strings_offset, strings_size)
+            || !fdt.contains(rsvmap_offset, 16)
+        {
+            return Err(FdtError::BadLayout);
+        }
+
+        Ok(fdt)
+    }
+
+    /// Validate the device tree stored at the given memory address
+    ///
+    /// # Safety
+    /// The address need to point to readable memory of at least the size of the device tree header.
+    pub unsafe fn from_address(addr: u64) -> Result<Self, FdtError> {
+        if addr == 0 || addr % 8 != 0 {
+            return Err(FdtError::BadMagic);
+        }
+        let header = core::slice::from_raw_parts(addr as *const u8, HEADER_SIZE);
+        if be32(header, 0)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = be32(header, 4)? as usize;
+        if total_size > MAX_SIZE {
+            return Err(FdtError::BadSize);
+        }
+        Self::from_slice(core::slice::from_raw_parts(addr as *const u8, total_size))
+    }
+
+    /// The size of the device tree in bytes
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The raw data of the device tree
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Create a copy of the device tree with the patches applied to the `/chosen` node and the memory reservation
+    /// block. Properties that are about to be patched are replaced, all others are kept. The `/chosen` node is
+    /// created if it does not exist.
+    pub fn patch_chosen(&self, patch: &ChosenPatch) -> Result<Vec<u8>, FdtError> {
+        let mut rsvmap = Vec::from(self.memory_reservations()?);
+        if let Some((address, size)) = patch.reserve {
+            let mut entry = Vec::from(&address.to_be_bytes()[..]);
+            entry.extend_from_slice(&size.to_be_bytes());
+            // the new entry is placed in front of the terminating entry
+            let entries = rsvmap.len() - 16;
+            if !rsvmap[..entries]
+                .chunks(16)
+                .any(|existing| existing == &entry[..])
+            {
+                rsvmap.splice(entries..entries, entry);
+            }
+        }
+        let strings_offset = self.header(12)?;
+        let mut strings = Vec::from(&self.data[strings_offset..strings_offset + self.header(32)?]);
+        let mut dt_struct = Vec::new();
+
+        // only the properties given by the patch are replaced
+        let mut replaced: Vec<&[u8]> = Vec::new();
+        let mut props: Vec<(u32, Vec<u8>)> = Vec::new();
+        if let Some(bootargs) = patch.bootargs {
+            let mut value = Vec::from(bootargs.as_bytes());
+            value.push(0);
+            props.push((string_offset(&mut strings, "bootargs"), value));
+            replaced.push(b"bootargs");
+        }
+        if let Some((start, end)) = patch.initrd {
+            let start_name = string_offset(&mut strings, "linux,initrd-start");
+            let end_name = string_offset(&mut strings, "linux,initrd-end");
+            props.push((start_name, Vec::from(&start.to_be_bytes()[..])));
+            props.push((end_name, Vec::from(&end.to_be_bytes()[..])));
+            replaced.push(b"linux,initrd-start");
+            replaced.push(b"linux,initrd-end");
+        }
+
+        let write_props = |dt_struct: &mut Vec<u8>| {
+            for (name, value) in props.iter() {
+                put_prop(dt_struct, *name, value);
+            }
+        };
+
+        let mut offset = self.header(8)?;
+        let struct_end = offset + self.header(36)?;
+        let mut depth = 0;
+        let mut in_chosen = false;
+        let mut chosen_found = false;
+        let mut props_written = false;
+        while offset < struct_end {
+            let token = be32(self.data, offset)?;
+            let start = offset;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = self.c_str(offset)?;
+                    offset = align4(offset + name.len() + 1);
+                    if depth == 2 && in_chosen && !props_written {
+                        // the properties of a node precede its child nodes
+                        write_props(&mut dt_struct);
+                        props_written = true;
+                    }
+                    depth += 1;
+                    if depth == 2 && name == b"chosen" {
+                        in_chosen = true;
+                        chosen_found = true;
+                    }
+                }
+                FDT_END_NODE => {
+                    if depth == 2 && in_chosen {
+                        if !props_written {
+                            write_props(&mut dt_struct);
+                            props_written = true;
+                        }
+                        in_chosen = false;
+                    } else if depth == 1 && !chosen_found {
+                        // the root node ends without a chosen node, so add one
+                        put_u32(&mut dt_struct, FDT_BEGIN_NODE);
+                        dt_struct.extend_from_slice(b"chosen\0\0");
+                        write_props(&mut dt_struct);
+                        put_u32(&mut dt_struct, FDT_END_NODE);
+                    }
+                    if depth == 0 {
+                        return Err(FdtError::BadStructure);
+                    }
+                    depth -= 1;
+                }
+                FDT_PROP => {
+                    let len = be32(self.data, offset)? as usize;
+                    let name = be32(self.data, offset + 4)?;
+                    offset = align4(offset + 8 + len);
+                    if offset > struct_end {
+                        return Err(FdtError::BadStructure);
+                    }
+                    if in_chosen && depth == 2 {
+                        let name = self.string_at(name)?;
+                        if replaced.contains(&name) {
+                            // skip the original property, it will be written with the patched value
+                            continue;
+                        }
+                    }
+                }
+                FDT_NOP => (),
+                FDT_END => {
+                    dt_struct.extend_from_slice(&self.data[start..offset]);
+                    break;
+                }
+                _ => return Err(FdtError::BadStructure),
+            }
+            dt_struct.extend_from_slice(&self.data[start..offset]);
+        }
+
+        // build the new device tree from the header, the memory reservation block, the structure block and the
+        // strings block
+        let struct_offset = HEADER_SIZE + rsvmap.len();
+        let strings_offset = struct_offset + dt_struct.len();
+        let total_size = strings_offset + strings.len();
+        let mut blob = Vec::with_capacity(total_size);
+        put_u32(&mut blob, FDT_MAGIC);
+        put_u32(&mut blob, total_size as u32);
+        put_u32(&mut blob, struct_offset as u32);
+        put_u32(&mut blob, strings_offset as u32);
+        put_u32(&mut blob, HEADER_SIZE as u32);
+        put_u32(&mut blob, 17);
+        put_u32(&mut blob, 16);
+        put_u32(&mut blob, self.header(28)? as u32);
+        put_u32(&mut blob, strings.len() as u32);
+        put_u32(&mut blob, dt_struct.len() as u32);
+        blob.extend_from_slice(&rsvmap);
+        blob.extend_from_slice(&dt_struct);
+        blob.extend_from_slice(&strings);
+
+        Ok(blob)
+    }
+
+    /// The memory reservation block including its terminating entry
+    fn memory_reservations(&self) -> Result<&'a [u8], FdtError> {
+        let start = self.header(16)?;
+        let mut offset = start;
+        loop {
+            let addr = be64(self.data, offset)?;
+            let size = be64(self.data, offset + 8)?;
+            offset += 16;
+            if addr == 0 && size == 0 {
+                return Ok(&self.data[start..offset]);
+            }
+        }
+    }
+
+    /// Read a header field as offset or size value
+    fn header(&self, offset: usize) -> Result<usize, FdtError> {
+        be32(self.data, offset).map(|value| value as usize)
+    }
+
+    /// Check whether the block given by offset and size is within the device tree
+    fn contains(&self, offset: usize, size: usize) -> bool {
+        offset
+            .checked_add(size)
+            .map_or(false, |end| offset >= HEADER_SIZE && end <= self.data.len())
+    }
+
+    /// Get the name stored at the given offset of the strings block
+    fn string_at(&self, offset: u32) -> Result<&'a [u8], FdtError> {
+        let strings_offset = self.header(12)?;
+        let strings = &self.data[strings_offset..strings_offset + self.header(32)?];
+        c_str(strings, offset as usize).ok_or(FdtError::BadStructure)
+    }
+
+    /// Get the zero terminated string at the given offset without the terminating zero
+    fn c_str(&self, offset: usize) -> Result<&'a [u8], FdtError> {
+        c_str(self.data, offset).ok_or(FdtError::BadStructure)
+    }
+}
+
+/// Get the zero terminated string at the given offset without the terminating zero
+fn c_str(data: &[u8], offset: usize) -> Option<&[u8]> {
+    let tail = data.get(offset..)?;
+    tail.iter().position(|&b| b == 0).map(|len| &tail[..len])
+}
+
+/// Get the offset of the name in the strings block, the name is appended if it is not yet contained
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> u32 {
+    let mut offset = 0;
+    while let Some(existing) = c_str(strings, offset) {
+        if existing == name.as_bytes() {
+            return offset as u32;
+        }
+        offset += existing.len() + 1;
+    }
+    let offset = strings.len();
+    strings.extend_from_slice(name.as_bytes());
+    strings.push(0);
+    offset as u32
+}
+
+fn put_prop(dt_struct: &mut Vec<u8>, name: u32, value: &[u8]) {
+    put_u32(dt_struct, FDT_PROP);
+    put_u32(dt_struct, value.len() as u32);
+    put_u32(dt_struct, name);
+    dt_struct.extend_from_slice(value);
+    dt_struct.resize(align4(dt_struct.len()), 0);
+}
+
+fn put_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_be_bytes());
+}
+
+fn be32(data: &[u8], offset: usize) -> Result<u32, FdtError> {
+    let mut value = [0; 4];
+    value.copy_from_slice(data.get(offset..offset + 4).ok_or(FdtError::BadLayout)?);
+    Ok(u32::from_be_bytes(value))
+}
+
+fn be64(data: &[u8], offset: usize) -> Result<u64, FdtError> {
+    Ok((be32(data, offset)? as u64) << 32 | be32(data, offset + 4)? as u64)
+}
+
+const fn align4(value: usize) -> usize {
+    (value + 3) & !3
+}
@@ -0,0 +1,148 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Guest exceptions
+//!
+//! Decoding of the exceptions the guest takes to EL2 in the resident EL2 mode, see [crate::hyp]. The exception
+//! vector saves the registers of the guest to the stack before it loads x0 with the id of the exception, so the saved
+//! frame holds the registers as the guest has left them, e.g. the function id of a hypervisor call in x0. The
+//! decoding does not access the hardware and is tested on the host.
+//!
+
+/// The register state of the guest saved by the exception vector, x0-x30 followed by SPSR_EL2 and ELR_EL2
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub x: [u64; 31],
+    _reserved: u64,
+    pub spsr: u64,
+    pub elr: u64,
+}
+
+/// Exception classes of the ESR_EL2
+pub const EC_WFX: u64 = 0x01;
+pub const EC_HVC64: u64 = 0x16;
+pub const EC_SMC64: u64 = 0x17;
+pub const EC_SYSREG: u64 = 0x18;
+pub const EC_IABT_LOWER: u64 = 0x20;
+pub const EC_DABT_LOWER: u64 = 0x24;
+
+/// The hypervisor call function ids passed in x0
+pub const HVC_VERSION: u64 = 0;
+pub const HVC_RELOAD: u64 = 1;
+pub const HVC_RESET: u64 = 2;
+pub const HVC_BOOT_SUCCEEDED: u64 = 3;
+
+/// The result of a secure monitor call that is not supported
+pub const SMC_NOT_SUPPORTED: u64 = 0xFFFF_FFFF;
+
+/// Encoding of a system register as (op0, op1, CRn, CRm, op2) from the ISS of a trapped ``msr``/``mrs``
+pub type SysregId = (u64, u64, u64, u64, u64);
+
+/// The synchronous exceptions of the guest the loader handles, decoded from the ESR_EL2
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GuestException {
+    HypervisorCall,
+    Wfi,
+    Wfe,
+    Smc,
+    /// ``msr`` writing the system register from the register with the given number, 31 is xzr
+    SysregWrite(SysregId, usize),
+    /// ``mrs`` reading the system register into the register with the given number, 31 is xzr
+    SysregRead(SysregId, usize),
+    Abort,
+    Other(u64),
+}
+
+/// The hypervisor calls of the guest, decoded from x0 of the saved frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HypervisorCall {
+    Version,
+    Reload,
+    Reset,
+    BootSucceeded,
+    Unknown(u64),
+}
+
+impl ExceptionFrame {
+    /// The value of the register with the given number, 31 is xzr
+    pub fn register(&self, rt: usize) -> u64 {
+        if rt == 31 {
+            0
+        } else {
+            self.x[rt]
+        }
+    }
+
+    /// Set the register with the given number to the value the guest continues with, writes to xzr are dropped
+    pub fn set_register(&mut self, rt: usize, value: u64) {
+        if rt != 31 {
+            self.x[rt] = value;
+        }
+    }
+
+    /// Complete the emulated operation in the frame the guest resumes with: ``smc`` returns ``NOT_SUPPORTED`` in x0,
+    /// ``mrs`` the value read in its register and the trapped instruction is stepped over. All other registers are
+    /// resumed as the guest has left them.
+    pub fn complete(&mut self, exception: GuestException, value: u64) {
+        match exception {
+            GuestException::Smc => self.x[0] = SMC_NOT_SUPPORTED,
+            GuestException::SysregRead(_, rt) => self.set_register(rt, value),
+            // wfi and wfe are treated as nop, msr has been performed on behalf of the guest
+            _ => (),
+        }
+        // all of the trapped instructions are re-executed when returning, so skip them
+        self.elr += 4;
+    }
+
+    /// The hypervisor call requested with the function id in x0
+    pub fn hypervisor_call(&self) -> HypervisorCall {
+        match self.x[0] {
+            HVC_VERSION => HypervisorCall::Version,
+            HVC_RELOAD => HypervisorCall::Reload,
+            HVC_RESET => HypervisorCall::Reset,
+            HVC_BOOT_SUCCEEDED => HypervisorCall::BootSucceeded,
+            function => HypervisorCall::Unknown(function),
+        }
+    }
+}
+
+impl GuestException {
+    /// Decode the synchronous exception from its syndrome
+    pub fn decode(esr: u64) -> Self {
+        match exception_class(esr) {
+            EC_HVC64 => GuestException::HypervisorCall,
+            EC_WFX if esr & 1 == 0 => GuestException::Wfi,
+            EC_WFX => GuestException::Wfe,
+            EC_SMC64 => GuestException::Smc,
+            EC_SYSREG => {
+                let rt = (esr >> 5 & 0x1F) as usize;
+                if esr & 1 == 0 {
+                    GuestException::SysregWrite(sysreg_id(esr), rt)
+                } else {
+                    GuestException::SysregRead(sysreg_id(esr), rt)
+                }
+            }
+            EC_IABT_LOWER | EC_DABT_LOWER => GuestException::Abort,
+            ec => GuestException::Other(ec),
+        }
+    }
+}
+
+/// The exception class of the syndrome
+pub fn exception_class(esr: u64) -> u64 {
+    esr >> 26 & 0x3F
+}
+
+fn sysreg_id(esr: u64) -> SysregId {
+    (
+        esr >> 20 & 0x3,
+        esr >> 14 & 0x7,
+        esr >> 10 & 0xF,
+        esr >> 1 & 0xF,
+        esr >> 17 & 0x7,
+    )
+}
@@ -0,0 +1,380 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Compressed kernels
+//!
+//! Kernels could be sent gzip compressed, like the ``Image.gz`` of Linux, to cut the time the transfer over the
+//! serial line takes. A binary starting with the gzip magic is inflated right after it has been received and then
+//! handled like a kernel sent uncompressed. The binary need to be a single gzip member ending with its trailer: the
+//! memory for the inflated kernel is allocated upfront with the size given in the trailer and the inflated data need
+//! to match this size and the CRC-32 of the trailer.
+//!
+//! The Huffman codes are decoded bit by bit like ``puff`` of zlib does, which is slower than table driven decoding
+//! but does not need any memory beyond the code lengths.
+//!
+
+use crate::crc;
+use alloc::vec::Vec;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const CM_DEFLATE: u8 = 8;
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+const HEADER_SIZE: usize = 10;
+const TRAILER_SIZE: usize = 8;
+
+/// The longest Huffman code of deflate
+const MAX_BITS: usize = 15;
+/// The number of literal/length and distance codes
+const MAX_LITERALS: usize = 288;
+const MAX_DISTANCES: usize = 30;
+const END_OF_BLOCK: u16 = 256;
+
+/// Base lengths and extra bits of the length codes 257..285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base distances and extra bits of the distance codes 0..29
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order the lengths of the code length codes are stored in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Reasons why a compressed kernel could not be inflated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GzipError {
+    /// The gzip header is truncated or uses another compression method than deflate
+    BadHeader,
+    /// The deflate stream is corrupted or truncated
+    BadData,
+    /// The inflated data does not match the size or the CRC-32 given in the trailer
+    BadChecksum,
+    /// The inflated kernel of the size given would be larger than accepted
+    TooLarge(usize),
+    /// The memory for the inflated kernel of the size given could not be allocated
+    OutOfMemory(usize),
+}
+
+/// Check whether the binary is gzip compressed
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.len() >= GZIP_MAGIC.len() && data[..2] == GZIP_MAGIC
+}
+
+/// Inflate the gzip compressed binary, which may not inflate to more than ``max_size`` bytes
+pub fn decompress(data: &[u8], max_size: usize) -> Result<Vec<u8>, GzipError> {
+    let start = header_size(data)?;
+    if data.len() < start + TRAILER_SIZE {
+        return Err(GzipError::BadHeader);
+    }
+    let trailer = &data[data.len() - TRAILER_SIZE..];
+    let crc = le32(trailer, 0);
+    let size = le32(trailer, 4) as usize;
+    if size > max_size {
+        return Err(GzipError::TooLarge(size));
+    }
+    let mut output = Vec::new();
+    if output.try_reserve_exact(size).is_err() {
+        return Err(GzipError::OutOfMemory(size));
+    }
+
+    let mut inflater = Inflater {
+        input: &data[start..data.len() - TRAILER_SIZE],
+        position: 0,
+        buffer: 0,
+        count: 0,
+        output,
+        limit: size,
+    };
+    inflater.inflate()?;
+    if inflater.output.len() != size || crc::crc32(&inflater.output) != crc {
+        return Err(GzipError::BadChecksum);
+    }
+    Ok(inflater.output)
+}
+
+/// The size of the gzip header including the optional fields
+fn header_size(data: &[u8]) -> Result<usize, GzipError> {
+    if !is_gzip(data) || data.len() < HEADER_SIZE || data[2] != CM_DEFLATE {
+        return Err(GzipError::BadHeader);
+    }
+    let flags = data[3];
+    let mut size = HEADER_SIZE;
+    if flags & FEXTRA != 0 {
+        let extra = data.get(size..size + 2).ok_or(GzipError::BadHeader)?;
+        size += 2 + (extra[0] as usize | (extra[1] as usize) << 8);
+    }
+    // the file name and the comment are zero terminated
+    for &field in [FNAME, FCOMMENT].iter() {
+        if flags & field != 0 {
+            let rest = data.get(size..).ok_or(GzipError::BadHeader)?;
+            size += rest
+                .iter()
+                .position(|&byte| byte == 0)
+                .ok_or(GzipError::BadHeader)?
+                + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        size += 2;
+    }
+    if size > data.len() {
+        return Err(GzipError::BadHeader);
+    }
+    Ok(size)
+}
+
+/// A canonical Huffman code given by the number of codes of each length and the symbols ordered by their codes
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: [u16; MAX_LITERALS],
+}
+
+impl Huffman {
+    /// Build the code from the code lengths of the symbols. Incomplete codes are accepted, a code that is not
+    /// assigned is refused once it is decoded.
+    fn new(lengths: &[u8]) -> Result<Self, GzipError> {
+        let mut code = Huffman {
+            counts: [0; MAX_BITS + 1],
+            symbols: [0; MAX_LITERALS],
+        };
+        for &length in lengths {
+            code.counts[length as usize] += 1;
+        }
+        // more codes of a length than there are left would make the code ambiguous
+        let mut left: i32 = 1;
+        for &count in code.counts[1..].iter() {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(GzipError::BadData);
+            }
+        }
+        let mut offsets = [0_u16; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + code.counts[length];
+        }
+        for (symbol, &length) in lengths
+            .iter()
+            .enumerate()
+            .filter(|(_, &length)| length != 0)
+        {
+            code.symbols[offsets[length as usize] as usize] = symbol as u16;
+            offsets[length as usize] += 1;
+        }
+        Ok(code)
+    }
+}
+
+/// The state of the inflation of a deflate stream
+struct Inflater<'a> {
+    input: &'a [u8],
+    position: usize,
+    /// The bits taken from the input that are not consumed yet and their number
+    buffer: u32,
+    count: u32,
+    output: Vec<u8>,
+    /// The size the output could grow to
+    limit: usize,
+}
+
+impl Inflater<'_> {
+    /// Inflate the blocks of the stream up to the last one
+    fn inflate(&mut self) -> Result<(), GzipError> {
+        loop {
+            let last = self.bits(1)? == 1;
+            match self.bits(2)? {
+                0 => self.stored()?,
+                1 => {
+                    let (literals, distances) = fixed_codes()?;
+                    self.codes(&literals, &distances)?;
+                }
+                2 => {
+                    let (literals, distances) = self.dynamic_codes()?;
+                    self.codes(&literals, &distances)?;
+                }
+                _ => return Err(GzipError::BadData),
+            }
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Take the next ``count`` bits of the input, at most 16
+    fn bits(&mut self, count: u32) -> Result<u32, GzipError> {
+        while self.count < count {
+            let byte = *self.input.get(self.position).ok_or(GzipError::BadData)?;
+            self.position += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << count) - 1);
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Copy a stored block, which starts at the next byte boundary
+    fn stored(&mut self) -> Result<(), GzipError> {
+        self.buffer = 0;
+        self.count = 0;
+        let header = self
+            .input
+            .get(self.position..self.position + 4)
+            .ok_or(GzipError::BadData)?;
+        let length = header[0] as usize | (header[1] as usize) << 8;
+        let complement = header[2] as usize | (header[3] as usize) << 8;
+        if length != !complement & 0xFFFF {
+            return Err(GzipError::BadData);
+        }
+        self.position += 4;
+        let data = self
+            .input
+            .get(self.position..self.position + length)
+            .ok_or(GzipError::BadData)?;
+        if self.output.len() + length > self.limit {
+            return Err(GzipError::BadChecksum);
+        }
+        self.output.extend_from_slice(data);
+        self.position += length;
+        Ok(())
+    }
+
+    /// Read the code lengths of a block with dynamic Huffman codes and build its codes
+    fn dynamic_codes(&mut self) -> Result<(Huffman, Huffman), GzipError> {
+        let literals = self.bits(5)? as usize + 257;
+        let distances = self.bits(5)? as usize + 1;
+        let code_lengths = self.bits(4)? as usize + 4;
+        if literals > 286 || distances > MAX_DISTANCES {
+            return Err(GzipError::BadData);
+        }
+        let mut lengths = [0_u8; MAX_LITERALS + MAX_DISTANCES];
+        for &symbol in CODE_LENGTH_ORDER.iter().take(code_lengths) {
+            lengths[symbol] = self.bits(3)? as u8;
+        }
+        let length_code = Huffman::new(&lengths[..CODE_LENGTH_ORDER.len()])?;
+
+        // the lengths of both codes are given as one sequence, a repetition may cross from one to the other
+        let mut idx = 0;
+        while idx < literals + distances {
+            let (length, repeat) = match self.decode(&length_code)? {
+                symbol @ 0..=15 => (symbol as u8, 1),
+                16 if idx > 0 => (lengths[idx - 1], 3 + self.bits(2)?),
+                17 => (0, 3 + self.bits(3)?),
+                18 => (0, 11 + self.bits(7)?),
+                _ => return Err(GzipError::BadData),
+            };
+            let end = idx + repeat as usize;
+            if end > literals + distances {
+                return Err(GzipError::BadData);
+            }
+            for entry in lengths[idx..end].iter_mut() {
+                *entry = length;
+            }
+            idx = end;
+        }
+        // a block without end of block code could not be terminated
+        if lengths[END_OF_BLOCK as usize] == 0 {
+            return Err(GzipError::BadData);
+        }
+        Ok((
+            Huffman::new(&lengths[..literals])?,
+            Huffman::new(&lengths[literals..literals + distances])?,
+        ))
+    }
+
+    /// Decode the next symbol with the given code
+    fn decode(&mut self, huffman: &Huffman) -> Result<u16, GzipError> {
+        let (mut code, mut first, mut index) = (0_u32, 0_u32, 0_u32);
+        for &count in huffman.counts[1..].iter() {
+            code |= self.bits(1)?;
+            let count = count as u32;
+            if code < first + count {
+                return Ok(huffman.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(GzipError::BadData)
+    }
+
+    /// Inflate the literals and matches of a block up to its end of block code
+    fn codes(&mut self, literals: &Huffman, distances: &Huffman) -> Result<(), GzipError> {
+        loop {
+            let symbol = self.decode(literals)?;
+            if symbol == END_OF_BLOCK {
+                return Ok(());
+            }
+            if symbol < END_OF_BLOCK {
+                if self.output.len() == self.limit {
+                    return Err(GzipError::BadChecksum);
+                }
+                self.output.push(symbol as u8);
+                continue;
+            }
+            let symbol = (symbol - 257) as usize;
+            if symbol >= LENGTH_BASE.len() {
+                return Err(GzipError::BadData);
+            }
+            let length =
+                LENGTH_BASE[symbol] as usize + self.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+            let symbol = self.decode(distances)? as usize;
+            if symbol >= DISTANCE_BASE.len() {
+                return Err(GzipError::BadData);
+            }
+            let distance =
+                DISTANCE_BASE[symbol] as usize + self.bits(DISTANCE_EXTRA[symbol] as u32)? as usize;
+            if distance > self.output.len() {
+                return Err(GzipError::BadData);
+            }
+            if self.output.len() + length > self.limit {
+                return Err(GzipError::BadChecksum);
+            }
+            // the match may overlap the data it produces, so it is copied byte by byte
+            let from = self.output.len() - distance;
+            for idx in from..from + length {
+                let byte = self.output[idx];
+                self.output.push(byte);
+            }
+        }
+    }
+}
+
+/// The codes of blocks compressed with fixed Huffman codes
+fn fixed_codes() -> Result<(Huffman, Huffman), GzipError> {
+    let mut lengths = [8_u8; MAX_LITERALS];
+    for length in lengths[144..256].iter_mut() {
+        *length = 9;
+    }
+    for length in lengths[256..280].iter_mut() {
+        *length = 7;
+    }
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; MAX_DISTANCES])?))
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
@@ -0,0 +1,523 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Resident EL2 mode
+//!
+//! Instead of leaving the stage with the handover the loader could stay resident in EL2 and run the kernel in EL1
+//! as a guest using a stage 2 identity mapping. The memory of the loader from its code up to the end of its heap,
+//! including its stacks and the state retained across resets, is mapped read-only to the guest, so the kernel cannot
+//! corrupt the loader it returns to. The loader regains control if
+//! - the kernel calls ``hvc`` requesting a reload or reset
+//! - the guest watchdog timer expires
+//! - the kernel raises an exception that could not be handled, e.g. accessing the loader memory
+//!
+//! Once the loader regains control the secondary cores are stopped and the loader starts over waiting for a new
+//! kernel to be received.
+//!
+//! The hypervisor calls use the function id in x0, the result is returned in x0:
+//!
+//! | x0 | Function                                  |
+//! |----|-------------------------------------------|
+//! | 0  | version, returns "RPHV" << 32 \| version  |
+//! | 1  | stop the kernel and return to the loader  |
+//! | 2  | reset the device                          |
+//! | 3  | signal a successful boot of the kernel    |
+//!
+//! For visibility into early misbehavior of the kernel sensitive operations could be trapped to EL2. They are
+//! logged with their ELR/ESR context to the console and emulated afterwards. ``wfi``/``wfe`` are treated as ``nop``,
+//! ``smc`` returns ``NOT_SUPPORTED`` and the trapped system register accesses are performed on behalf of the kernel.
+//!
+
+use crate::board::{self, ARM_LOCAL_BASE, BLOCK_SIZE, PERIPHERAL_BASE};
+use crate::guest::{ExceptionFrame, GuestException, HypervisorCall, SysregId};
+use crate::pm;
+use crate::retained;
+use crate::smp;
+use crate::time::{self, Duration};
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use ruspiro_interrupt::IRQ_MANAGER;
+
+/// The hypervisor call interface version
+const VERSION: u64 = 0x5250_4856 << 32 | 1;
+
+const HCR_VM: u64 = 1 << 0;
+const HCR_SWIO: u64 = 1 << 1;
+const HCR_FMO: u64 = 1 << 3;
+const HCR_IMO: u64 = 1 << 4;
+const HCR_AMO: u64 = 1 << 5;
+const HCR_TWI: u64 = 1 << 13;
+const HCR_TWE: u64 = 1 << 14;
+const HCR_TSC: u64 = 1 << 19;
+const HCR_TVM: u64 = 1 << 26;
+const HCR_TRVM: u64 = 1 << 30;
+const HCR_RW: u64 = 1 << 31;
+/// The HCR_EL2 value the loader itself is running with
+const HCR_LOADER: u64 = HCR_RW | HCR_SWIO | HCR_AMO | HCR_IMO | HCR_FMO;
+
+/// VTCR_EL2: 32Bit IPA space (T0SZ = 32), start at level 1, inner-shareable write-back caching, 4kB granule,
+/// 32Bit PA range
+const VTCR: u64 = 32 | 0b01 << 6 | 0b01 << 8 | 0b01 << 10 | 0b11 << 12 | 1 << 31;
+
+/// stage 2 descriptor attributes
+const S2_VALID_BLOCK: u64 = 0b01;
+const S2_VALID_TABLE: u64 = 0b11;
+const S2_NORMAL: u64 = 0b1111 << 2 | 0b11 << 8;
+const S2_DEVICE: u64 = 0b0001 << 2 | 1 << 54;
+const S2_READ_ONLY: u64 = 0b01 << 6;
+const S2_READ_WRITE: u64 = 0b11 << 6;
+const S2_AF: u64 = 1 << 10;
+
+/// Exception ids as passed from the exception vector
+const EXC_LOWER_SYNC: u64 = 0x21;
+const EXC_LOWER_FIQ: u64 = 0x23;
+
+/// Return values of the exception handler
+const RESUME: u64 = 0;
+const REENTER: u64 = 1;
+
+/// ARM local peripherals used to route the EL2 timer and the core mailbox 3 to FIQ
+const CORE0_TIMER_IRQCNTL: *mut u32 = board::register(ARM_LOCAL_BASE, 0x40);
+const CORE0_MBOX_IRQCNTL: *mut u32 = board::register(ARM_LOCAL_BASE, 0x50);
+const CORE0_FIQ_SOURCE: *mut u32 = board::register(ARM_LOCAL_BASE, 0x70);
+const CORE0_MBOX3_SET: *mut u32 = board::register(ARM_LOCAL_BASE, 0x8C);
+const CORE0_MBOX3_CLEAR: *mut u32 = board::register(ARM_LOCAL_BASE, 0xCC);
+const TIMER_CNTHP_FIQ: u32 = 1 << 6;
+const MBOX3_FIQ: u32 = 1 << 7;
+const FIQ_SOURCE_CNTHP: u32 = 1 << 2;
+const FIQ_SOURCE_MBOX3: u32 = 1 << 7;
+
+/// Trapping ``wfi`` of the guest
+pub const TRAP_WFI: u32 = 1 << 0;
+/// Trapping ``wfe`` of the guest
+pub const TRAP_WFE: u32 = 1 << 1;
+/// Trapping ``smc`` of the guest
+pub const TRAP_SMC: u32 = 1 << 2;
+/// Trapping the access of the guest to the virtual memory control registers like SCTLR_EL1, TTBRx_EL1, TCR_EL1
+/// and MAIR_EL1
+pub const TRAP_SYSREG: u32 = 1 << 3;
+/// Trapping all of the supported operations
+pub const TRAP_ALL: u32 = TRAP_WFI | TRAP_WFE | TRAP_SMC | TRAP_SYSREG;
+
+/// Each kind of trapped operation is logged the first times it occurs, afterwards only every n-th occurrence is
+/// logged to not flood the console, e.g. with an idle loop issuing ``wfi``
+const TRAP_LOG_FIRST: u32 = 16;
+const TRAP_LOG_EVERY: u32 = 1024;
+
+/// The EL2 configuration used by the bootstrap code when switching to the kernel
+#[repr(C)]
+pub struct El2Config {
+    hcr: u64,
+    vtcr: u64,
+    vttbr: u64,
+}
+
+#[no_mangle]
+static mut __boot_el2_config: El2Config = El2Config {
+    hcr: HCR_RW | HCR_SWIO,
+    vtcr: 0,
+    vttbr: 0,
+};
+
+/// Stage 2 translation tables. Level 1 covers 4GB with 1GB entries, the first GB is split into 2MB blocks at
+/// level 2. The loader memory starts and ends at 2MB boundaries, so it is protected with whole blocks.
+#[repr(C, align(4096))]
+struct Stage2Tables {
+    lvl1: [u64; 512],
+    lvl2: [u64; 512],
+}
+
+static mut STAGE2: Stage2Tables = Stage2Tables {
+    lvl1: [0; 512],
+    lvl2: [0; 512],
+};
+
+extern "C" {
+    /// linker symbols marking the memory used by the loader code, data, stacks, retained state and heap
+    static __loader_start: u8;
+    static __heap_end: u8;
+}
+
+static RESIDENT: AtomicBool = AtomicBool::new(cfg!(feature = "resident_el2"));
+/// Timeout of the guest watchdog in microseconds, 0 if not active
+static WATCHDOG: AtomicU64 = AtomicU64::new(0);
+/// The operations trapped to EL2
+static TRAPS: AtomicU32 = AtomicU32::new(if cfg!(feature = "trap_log") {
+    TRAP_ALL
+} else {
+    0
+});
+/// Number of trapped operations for WFI, WFE, SMC and system register access
+static TRAP_COUNT: [AtomicU32; 4] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+/// Check whether the loader stays resident when starting the next kernel
+pub fn is_resident() -> bool {
+    RESIDENT.load(Ordering::Acquire)
+}
+
+/// Choose whether the loader stays resident when starting the next aarch64 kernel
+pub fn set_resident(resident: bool) {
+    RESIDENT.store(resident, Ordering::Release);
+}
+
+/// Set the time after which the loader stops the guest, zero to let the guest run forever
+pub fn set_watchdog(timeout: Duration) {
+    WATCHDOG.store(timeout.as_micros() as u64, Ordering::Release);
+}
+
+/// Choose the operations of the guest that are trapped to EL2 and logged, a combination of the ``TRAP_*`` values
+pub fn set_traps(traps: u32) {
+    TRAPS.store(traps, Ordering::Release);
+}
+
+/// Get the operations of the guest that are trapped to EL2
+pub fn traps() -> u32 {
+    TRAPS.load(Ordering::Acquire)
+}
+
+/// Prepare the EL2 configuration to run the next kernel as a guest. This need to be called before the caches are
+/// cleaned for the handover as the secondary cores read the configuration with their MMU disabled.
+pub fn prepare_guest() {
+    unsafe {
+        setup_stage2_tables();
+        let traps = traps();
+        let trap_bits = [
+            (TRAP_WFI, HCR_TWI),
+            (TRAP_WFE, HCR_TWE),
+            (TRAP_SMC, HCR_TSC),
+            (TRAP_SYSREG, HCR_TVM | HCR_TRVM),
+        ];
+        let hcr_traps = trap_bits
+            .iter()
+            .filter(|(trap, _)| traps & trap != 0)
+            .fold(0, |hcr, (_, bits)| hcr | bits);
+        for count in TRAP_COUNT.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+        __boot_el2_config = El2Config {
+            hcr: HCR_RW | HCR_SWIO | HCR_VM | HCR_FMO | hcr_traps,
+            vtcr: VTCR,
+            vttbr: &STAGE2.lvl1 as *const _ as u64,
+        };
+        // stale stage 1 & 2 translations of lower ELs shall not survive
+        llvm_asm!(
+            "dsb ishst
+             tlbi alle1is
+             dsb ish
+             isb" :::: "volatile"
+        );
+
+        // stopping the secondary cores is requested with core mailbox 3 routed to FIQ
+        for core in 1..4 {
+            let cntl = CORE0_MBOX_IRQCNTL.add(core);
+            write_volatile(cntl, read_volatile(cntl) | MBOX3_FIQ);
+        }
+        let timeout = WATCHDOG.load(Ordering::Acquire);
+        if timeout != 0 {
+            let ticks = time::ticks(Duration::from_micros(timeout));
+            llvm_asm!(
+                "msr cnthp_tval_el2, $0
+                 msr cnthp_ctl_el2, $1" :: "r"(ticks), "r"(1u64) :: "volatile"
+            );
+            write_volatile(
+                CORE0_TIMER_IRQCNTL,
+                read_volatile(CORE0_TIMER_IRQCNTL) | TIMER_CNTHP_FIQ,
+            );
+        }
+    }
+}
+
+/// Identity map the physical memory for the guest with the loader memory up to the end of its heap being read-only.
+/// The heap is included as the loader allocates from it again once it regains control.
+unsafe fn setup_stage2_tables() {
+    let loader_start = &__loader_start as *const u8 as u64;
+    let loader_end = &__heap_end as *const u8 as u64;
+    // a read-only block reaching beyond the loader memory would cover the memory of the kernel as well
+    loader_assert!(
+        loader_start % BLOCK_SIZE == 0 && loader_end % BLOCK_SIZE == 0,
+        "the loader memory {:#x}..{:#x} is not aligned to blocks",
+        loader_start,
+        loader_end
+    );
+
+    for (idx, block) in STAGE2.lvl2.iter_mut().enumerate() {
+        let addr = idx as u64 * BLOCK_SIZE;
+        *block = match addr {
+            _ if addr >= PERIPHERAL_BASE => {
+                addr | S2_READ_WRITE | S2_DEVICE | S2_AF | S2_VALID_BLOCK
+            }
+            _ if addr >= loader_start && addr < loader_end => {
+                addr | S2_READ_ONLY | S2_NORMAL | S2_AF | S2_VALID_BLOCK
+            }
+            _ => addr | S2_READ_WRITE | S2_NORMAL | S2_AF | S2_VALID_BLOCK,
+        };
+    }
+    STAGE2.lvl1[0] = &STAGE2.lvl2 as *const _ as u64 | S2_VALID_TABLE;
+    // the ARM local peripherals are located in the second GB
+    STAGE2.lvl1[1] = ARM_LOCAL_BASE | S2_READ_WRITE | S2_DEVICE | S2_AF | S2_VALID_BLOCK;
+}
+
+/// Handler for all exceptions taken from the guest to EL2, called from the exception trampoline
+#[no_mangle]
+extern "C" fn __hyp_exception_handler(
+    kind: u64,
+    esr: u64,
+    _spsr: u64,
+    far: u64,
+    elr: u64,
+    frame: &mut ExceptionFrame,
+) -> u64 {
+    let core = smp::core_id() as u64;
+    if core != 0 {
+        return secondary_exception(core, kind, esr, frame);
+    }
+
+    match kind {
+        EXC_LOWER_SYNC => match GuestException::decode(esr) {
+            GuestException::HypervisorCall => hypervisor_call(frame),
+            GuestException::Abort => {
+                println!(
+                    "\r\nkernel aborted at {:#x} accessing {:#x}, ESR {:#x}",
+                    elr, far, esr
+                );
+                REENTER
+            }
+            GuestException::Other(ec) => {
+                println!(
+                    "\r\nunhandled kernel exception class {:#x} at {:#x}, ESR {:#x}",
+                    ec, elr, esr
+                );
+                REENTER
+            }
+            exception => trapped_operation(exception, esr, frame),
+        },
+        EXC_LOWER_FIQ => {
+            let source = unsafe { read_volatile(CORE0_FIQ_SOURCE) };
+            if source & FIQ_SOURCE_CNTHP != 0 {
+                unsafe { llvm_asm!("msr cnthp_ctl_el2, xzr" :::: "volatile") };
+                println!("\r\nkernel watchdog expired");
+                REENTER
+            } else {
+                RESUME
+            }
+        }
+        _ => {
+            println!("\r\nunexpected kernel exception {:#x} at {:#x}", kind, elr);
+            REENTER
+        }
+    }
+}
+
+/// Handle the exceptions of the secondary cores. They run without MMU in EL2 and must not use anything relying on
+/// the caches or the atomics, the console is fine as its lock works without the MMU. The core is parked if the
+/// exception is not a hypervisor call or a trapped operation.
+fn secondary_exception(core: u64, kind: u64, esr: u64, frame: &mut ExceptionFrame) -> u64 {
+    if kind == EXC_LOWER_FIQ {
+        let source = unsafe { read_volatile(CORE0_FIQ_SOURCE.add(core as usize)) };
+        if source & FIQ_SOURCE_MBOX3 == 0 {
+            return RESUME;
+        }
+        // the main core requests to stop this core
+        unsafe { write_volatile(CORE0_MBOX3_CLEAR.add(core as usize * 4), 0xFFFF_FFFF) };
+    } else if kind == EXC_LOWER_SYNC {
+        let action = match GuestException::decode(esr) {
+            GuestException::HypervisorCall => hypervisor_call(frame),
+            GuestException::Abort | GuestException::Other(_) => {
+                println!(
+                    "\r\nunhandled kernel exception class {:#x} on core {}, ESR {:#x}",
+                    crate::guest::exception_class(esr),
+                    core,
+                    esr
+                );
+                REENTER
+            }
+            // trapped operations are emulated without logging on the secondary cores
+            exception => emulate(exception, frame),
+        };
+        if action == RESUME {
+            return RESUME;
+        }
+    }
+    park_secondary(core)
+}
+
+/// Log the operation of the guest trapped to EL2 and emulate it
+fn trapped_operation(exception: GuestException, esr: u64, frame: &mut ExceptionFrame) -> u64 {
+    let (kind, name, sysreg) = match exception {
+        GuestException::Wfi => (0, "wfi", None),
+        GuestException::Wfe => (1, "wfe", None),
+        GuestException::Smc => (2, "smc", None),
+        GuestException::SysregWrite(id, _) => (3, "msr", Some(id)),
+        GuestException::SysregRead(id, _) => (3, "mrs", Some(id)),
+        _ => return emulate(exception, frame),
+    };
+    let count = TRAP_COUNT[kind].fetch_add(1, Ordering::Relaxed);
+    if count < TRAP_LOG_FIRST || count % TRAP_LOG_EVERY == 0 {
+        let (elr, count) = (frame.elr, count + 1);
+        match kind {
+            2 => info!(
+                "trap #{} {} at {:#x}, ESR {:#x}, function {:#x}",
+                count, name, elr, esr, frame.x[0]
+            ),
+            3 => info!(
+                "trap #{} {} at {:#x}, ESR {:#x}, register {}",
+                count,
+                name,
+                elr,
+                esr,
+                sysreg.map_or("unknown", sysreg_name)
+            ),
+            _ => info!("trap #{} {} at {:#x}, ESR {:#x}", count, name, elr, esr),
+        }
+    }
+
+    emulate(exception, frame)
+}
+
+/// Emulate the trapped operation and step over the trapped instruction
+fn emulate(exception: GuestException, frame: &mut ExceptionFrame) -> u64 {
+    let value = match exception {
+        GuestException::SysregWrite(id, rt) => {
+            write_sysreg(id, frame.register(rt));
+            0
+        }
+        GuestException::SysregRead(id, _) => read_sysreg(id).unwrap_or(0),
+        _ => 0,
+    };
+    frame.complete(exception, value);
+    RESUME
+}
+
+const SCTLR_EL1: SysregId = (3, 0, 1, 0, 0);
+const TTBR0_EL1: SysregId = (3, 0, 2, 0, 0);
+const TTBR1_EL1: SysregId = (3, 0, 2, 0, 1);
+const TCR_EL1: SysregId = (3, 0, 2, 0, 2);
+const AFSR0_EL1: SysregId = (3, 0, 5, 1, 0);
+const AFSR1_EL1: SysregId = (3, 0, 5, 1, 1);
+const ESR_EL1: SysregId = (3, 0, 5, 2, 0);
+const FAR_EL1: SysregId = (3, 0, 6, 0, 0);
+const MAIR_EL1: SysregId = (3, 0, 10, 2, 0);
+const AMAIR_EL1: SysregId = (3, 0, 10, 3, 0);
+const CONTEXTIDR_EL1: SysregId = (3, 0, 13, 0, 1);
+
+fn sysreg_name(id: SysregId) -> &'static str {
+    match id {
+        SCTLR_EL1 => "SCTLR_EL1",
+        TTBR0_EL1 => "TTBR0_EL1",
+        TTBR1_EL1 => "TTBR1_EL1",
+        TCR_EL1 => "TCR_EL1",
+        AFSR0_EL1 => "AFSR0_EL1",
+        AFSR1_EL1 => "AFSR1_EL1",
+        ESR_EL1 => "ESR_EL1",
+        FAR_EL1 => "FAR_EL1",
+        MAIR_EL1 => "MAIR_EL1",
+        AMAIR_EL1 => "AMAIR_EL1",
+        CONTEXTIDR_EL1 => "CONTEXTIDR_EL1",
+        _ => "unknown",
+    }
+}
+
+/// Write the trapped system register on behalf of the guest
+fn write_sysreg(id: SysregId, value: u64) {
+    unsafe {
+        match id {
+            SCTLR_EL1 => llvm_asm!("msr sctlr_el1, $0" :: "r"(value) :: "volatile"),
+            TTBR0_EL1 => llvm_asm!("msr ttbr0_el1, $0" :: "r"(value) :: "volatile"),
+            TTBR1_EL1 => llvm_asm!("msr ttbr1_el1, $0" :: "r"(value) :: "volatile"),
+            TCR_EL1 => llvm_asm!("msr tcr_el1, $0" :: "r"(value) :: "volatile"),
+            AFSR0_EL1 => llvm_asm!("msr afsr0_el1, $0" :: "r"(value) :: "volatile"),
+            AFSR1_EL1 => llvm_asm!("msr afsr1_el1, $0" :: "r"(value) :: "volatile"),
+            ESR_EL1 => llvm_asm!("msr esr_el1, $0" :: "r"(value) :: "volatile"),
+            FAR_EL1 => llvm_asm!("msr far_el1, $0" :: "r"(value) :: "volatile"),
+            MAIR_EL1 => llvm_asm!("msr mair_el1, $0" :: "r"(value) :: "volatile"),
+            AMAIR_EL1 => llvm_asm!("msr amair_el1, $0" :: "r"(value) :: "volatile"),
+            CONTEXTIDR_EL1 => llvm_asm!("msr contextidr_el1, $0" :: "r"(value) :: "volatile"),
+            _ => (),
+        }
+    }
+}
+
+/// Read the trapped system register on behalf of the guest
+fn read_sysreg(id: SysregId) -> Option<u64> {
+    let value: u64;
+    unsafe {
+        match id {
+            SCTLR_EL1 => llvm_asm!("mrs $0, sctlr_el1" : "=r"(value) ::: "volatile"),
+            TTBR0_EL1 => llvm_asm!("mrs $0, ttbr0_el1" : "=r"(value) ::: "volatile"),
+            TTBR1_EL1 => llvm_asm!("mrs $0, ttbr1_el1" : "=r"(value) ::: "volatile"),
+            TCR_EL1 => llvm_asm!("mrs $0, tcr_el1" : "=r"(value) ::: "volatile"),
+            AFSR0_EL1 => llvm_asm!("mrs $0, afsr0_el1" : "=r"(value) ::: "volatile"),
+            AFSR1_EL1 => llvm_asm!("mrs $0, afsr1_el1" : "=r"(value) ::: "volatile"),
+            ESR_EL1 => llvm_asm!("mrs $0, esr_el1" : "=r"(value) ::: "volatile"),
+            FAR_EL1 => llvm_asm!("mrs $0, far_el1" : "=r"(value) ::: "volatile"),
+            MAIR_EL1 => llvm_asm!("mrs $0, mair_el1" : "=r"(value) ::: "volatile"),
+            AMAIR_EL1 => llvm_asm!("mrs $0, amair_el1" : "=r"(value) ::: "volatile"),
+            CONTEXTIDR_EL1 => llvm_asm!("mrs $0, contextidr_el1" : "=r"(value) ::: "volatile"),
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+/// Dispatch the hypervisor call requested by the guest
+fn hypervisor_call(frame: &mut ExceptionFrame) -> u64 {
+    match frame.hypervisor_call() {
+        HypervisorCall::Version => {
+            frame.x[0] = VERSION;
+            RESUME
+        }
+        HypervisorCall::Reload => REENTER,
+        HypervisorCall::Reset => pm::reset(),
+        HypervisorCall::BootSucceeded => {
+            retained::boot_succeeded();
+            frame.x[0] = 0;
+            RESUME
+        }
+        HypervisorCall::Unknown(_) => {
+            frame.x[0] = u64::MAX;
+            RESUME
+        }
+    }
+}
+
+/// Entry point to the loader once the guest has been stopped, called from the exception trampoline with a fresh
+/// stack
+#[no_mangle]
+extern "C" fn __loader_reenter() -> ! {
+    unsafe {
+        llvm_asm!("msr cnthp_ctl_el2, xzr" :::: "volatile");
+        write_volatile(
+            CORE0_TIMER_IRQCNTL,
+            read_volatile(CORE0_TIMER_IRQCNTL) & !TIMER_CNTHP_FIQ,
+        );
+        // request the secondary cores to stop running the guest
+        for core in 1..4 {
+            write_volatile(CORE0_MBOX3_SET.add(core * 4), 1);
+        }
+        // the loader runs without stage 2 translation and with all exceptions routed to EL2
+        __boot_el2_config.hcr = HCR_RW | HCR_SWIO;
+        llvm_asm!(
+            "msr hcr_el2, $0
+             isb" :: "r"(HCR_LOADER) :: "volatile"
+        );
+    }
+    println!("\r\nkernel stopped, the loader regained control");
+    IRQ_MANAGER.take_for(|irq_mgr| irq_mgr.initialize());
+    crate::loader::run()
+}
+
+/// Park the current secondary core in the same way it is waiting after a fresh start of the device, so it can be
+/// released through the spin table again
+fn park_secondary(core: u64) -> ! {
+    println!("core {} parked", core);
+    smp::park(core as usize)
+}
@@ -0,0 +1,256 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Kernel image formats
+//!
+//! Recognition of the arm64 ``Image`` header. It is used by Linux and is also provided by the FreeBSD and NetBSD
+//! arm64 kernels. A kernel with this header need to be placed ``text_offset`` bytes from a 2MB aligned base address
+//! and expects the memory up to ``image_size`` to be available, where the part beyond the binary need to be zeroed.
+//! The device tree is passed in x0 while x1-x3 need to be 0.
+//!
+//! Kernels built with RusPiRo could provide the optional [KernelHeader] within their first 4kB. The loader
+//! validates it and fills in the addresses of the board info and the boot services before the kernel is started.
+//!
+
+use crate::crc;
+use crate::gzip::GzipError;
+
+/// The magic value of the arm64 Image header, "ARM\x64"
+const ARM64_MAGIC: u32 = 0x644D_5241;
+/// Size of the arm64 Image header
+const ARM64_HEADER_SIZE: usize = 64;
+/// The header flag indicating a big endian kernel
+const ARM64_FLAG_BE: u64 = 1 << 0;
+
+/// Kernels prior to Linux 3.17 do not provide their image size, assume the memory they need does not exceed this
+const ARM64_DEFAULT_IMAGE_SIZE: u64 = 0x100_0000;
+
+/// The magic value of ELF files
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const EM_ARM: u16 = 40;
+const EM_AARCH64: u16 = 183;
+/// The magic value of U-Boot legacy images, stored big endian
+const UIMAGE_MAGIC: u32 = 0x2705_1956;
+
+/// The magic value of the RusPiRo kernel header, "RPKH"
+pub const KERNEL_HEADER_MAGIC: u32 = 0x484B_5052;
+/// The version of the RusPiRo kernel header layout
+pub const KERNEL_HEADER_VERSION: u32 = 1;
+/// The RusPiRo kernel header is placed 8 byte aligned within this range at the start of the kernel
+const KERNEL_HEADER_RANGE: usize = 0x1000;
+/// The kernel header contains the CRC-32 of the binary, calculated with the ``crc`` field being 0
+pub const KERNEL_FLAG_CRC: u32 = 1 << 0;
+
+/// Reasons why a kernel image is not accepted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageError {
+    /// The kernel is built for big endian execution
+    BigEndian,
+    /// The binary is larger than the image size given in the header or the memory the kernel occupies exceeds the
+    /// address space
+    BadSize,
+    /// The RusPiRo kernel header version or size is not supported
+    BadHeader,
+    /// The checksum of the kernel does not match the one given in the RusPiRo kernel header
+    BadChecksum,
+    /// The kernel cannot be entered in the requested exception level
+    UnsupportedEntryLevel,
+    /// The kernel binary is empty
+    Empty,
+    /// Only aarch32 and aarch64 kernels are supported
+    UnsupportedArchitecture,
+    /// The kernel is built for a different architecture or machine than requested
+    WrongMachine,
+    /// The kernel is an aarch32 ELF file, it need to be converted to a raw binary
+    ElfFile,
+    /// The ELF file is no valid executable, e.g. its segments exceed the file or none contains the entry address
+    BadElf,
+    /// The kernel is wrapped into a U-Boot image, the raw binary is required
+    UImage,
+    /// The kernel entry address is not properly aligned
+    Misaligned,
+    /// The kernel does not fit into the memory assigned to the ARM
+    OutOfMemory,
+    /// The kernel would overlap the memory of the loader
+    OverlapsLoader,
+    /// The kernel would overlap the device tree passed to it
+    OverlapsDeviceTree,
+    /// The kernel would overlap the firmware stub and spin table the secondary cores are parked in
+    OverlapsSpinTable,
+    /// The device tree passed to the kernel is not valid
+    BadDeviceTree,
+    /// The code of the loader has changed since it started, e.g. as the received binary has overwritten it
+    LoaderCorrupted,
+    /// The gzip compressed kernel could not be inflated
+    Gzip(GzipError),
+}
+
+/// The architecture a kernel is started in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BootMode {
+    Aarch32,
+    Aarch64,
+}
+
+impl BootMode {
+    /// The mode of the architecture given as 32 or 64 by the host or the stored kernels
+    pub fn new(aarch: u32) -> Result<Self, ImageError> {
+        match aarch {
+            32 => Ok(BootMode::Aarch32),
+            64 => Ok(BootMode::Aarch64),
+            _ => Err(ImageError::UnsupportedArchitecture),
+        }
+    }
+
+    /// The architecture as 32 or 64
+    pub fn bits(self) -> u32 {
+        match self {
+            BootMode::Aarch32 => 32,
+            BootMode::Aarch64 => 64,
+        }
+    }
+}
+
+/// Check whether the binary is a raw kernel of the given architecture that could be started. Files in a format the
+/// loader does not unpack are refused as jumping into them would execute garbage.
+pub fn check_format(binary: &[u8], mode: BootMode) -> Result<(), ImageError> {
+    if binary.is_empty() {
+        return Err(ImageError::Empty);
+    }
+    if binary.len() >= 20 && &binary[..4] == ELF_MAGIC {
+        let machine = binary[18] as u16 | (binary[19] as u16) << 8;
+        return match (mode, machine) {
+            (BootMode::Aarch32, EM_ARM) | (BootMode::Aarch64, EM_AARCH64) => {
+                Err(ImageError::ElfFile)
+            }
+            _ => Err(ImageError::WrongMachine),
+        };
+    }
+    if binary.len() >= 4
+        && u32::from_be_bytes([binary[0], binary[1], binary[2], binary[3]]) == UIMAGE_MAGIC
+    {
+        return Err(ImageError::UImage);
+    }
+    if mode == BootMode::Aarch32
+        && binary.len() >= ARM64_HEADER_SIZE
+        && le32(binary, 56) == ARM64_MAGIC
+    {
+        return Err(ImageError::WrongMachine);
+    }
+    Ok(())
+}
+
+/// The optional header of RusPiRo kernels
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KernelHeader {
+    /// Always [KERNEL_HEADER_MAGIC]
+    pub magic: u32,
+    /// The [KERNEL_HEADER_VERSION] the kernel was built with
+    pub version: u32,
+    /// The size of the header in bytes
+    pub header_size: u32,
+    /// Flags like [KERNEL_FLAG_CRC]
+    pub flags: u32,
+    /// The CRC-32 of the kernel binary if [KERNEL_FLAG_CRC] is set
+    pub crc: u32,
+    pub _reserved: u32,
+    /// The address of the board info, filled in by the loader
+    pub board_info: u64,
+    /// The address of the boot services table, filled in by the loader, 0 for aarch32 kernels
+    pub services: u64,
+}
+
+impl KernelHeader {
+    /// Search the RusPiRo kernel header in the binary and validate it. The offset of the header within the binary
+    /// is returned or ``None`` if there is no such header.
+    pub fn find(binary: &[u8]) -> Option<Result<usize, ImageError>> {
+        let size = core::mem::size_of::<KernelHeader>();
+        if binary.len() < size {
+            return None;
+        }
+        let range = core::cmp::min(binary.len(), KERNEL_HEADER_RANGE);
+        let offset = (0..=range - size)
+            .step_by(8)
+            .find(|&offset| le32(binary, offset) == KERNEL_HEADER_MAGIC)?;
+        if le32(binary, offset + 4) != KERNEL_HEADER_VERSION
+            || (le32(binary, offset + 8) as usize) < size
+        {
+            return Some(Err(ImageError::BadHeader));
+        }
+        if le32(binary, offset + 12) & KERNEL_FLAG_CRC != 0 {
+            let crc_offset = offset + 16;
+            let crc = crc::crc32(&binary[..crc_offset]);
+            let crc = crc::crc32_update(crc, &[0; 4]);
+            let crc = crc::crc32_update(crc, &binary[crc_offset + 4..]);
+            if crc != le32(binary, crc_offset) {
+                return Some(Err(ImageError::BadChecksum));
+            }
+        }
+        Some(Ok(offset))
+    }
+}
+
+/// The information of the arm64 Image header
+#[derive(Debug, Clone, Copy)]
+pub struct Arm64Image {
+    /// Offset of the image from the 2MB aligned base address
+    pub text_offset: u64,
+    /// Memory size required by the image including the memory to be zeroed beyond the binary
+    pub image_size: u64,
+    /// Informative flags like endianess, page size and physical placement
+    pub flags: u64,
+}
+
+impl Arm64Image {
+    /// Check the binary for an arm64 Image header, ``None`` is returned if there is no such header
+    pub fn parse(binary: &[u8]) -> Option<Result<Self, ImageError>> {
+        if binary.len() < ARM64_HEADER_SIZE || le32(binary, 56) != ARM64_MAGIC {
+            return None;
+        }
+        let image = Arm64Image {
+            text_offset: le64(binary, 8),
+            image_size: match le64(binary, 16) {
+                0 => ARM64_DEFAULT_IMAGE_SIZE,
+                size => size,
+            },
+            flags: le64(binary, 24),
+        };
+        if image.flags & ARM64_FLAG_BE != 0 {
+            return Some(Err(ImageError::BigEndian));
+        }
+        // the header is not trusted, the offset and size must leave the end of the image representable
+        if binary.len() as u64 > image.image_size
+            || image.text_offset.checked_add(image.image_size).is_none()
+        {
+            return Some(Err(ImageError::BadSize));
+        }
+        Some(Ok(image))
+    }
+
+    /// The address the kernel need to be placed at when using the given 2MB aligned base address, ``None`` if it
+    /// exceeds the address space
+    pub fn load_address(&self, base: u64) -> Option<u64> {
+        base.checked_add(self.text_offset)
+    }
+
+    /// The end of the memory the kernel occupies when using the given 2MB aligned base address, ``None`` if it
+    /// exceeds the address space
+    pub fn end(&self, base: u64) -> Option<u64> {
+        self.load_address(base)?.checked_add(self.image_size)
+    }
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn le64(data: &[u8], offset: usize) -> u64 {
+    le32(data, offset) as u64 | (le32(data, offset + 4) as u64) << 32
+}
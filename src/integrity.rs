@@ -0,0 +1,63 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Loader integrity
+//!
+//! The code and the read-only data of the loader must not change while it is running. A kernel placed or a binary
+//! received on top of the loader would change them and let the loader crash in unexpected places, at the latest
+//! when it branches into the kernel. The CRC-32 of both sections is taken when the loader starts, before any
+//! request is received, and checked again right before the loader hands over to a kernel. The linked binary is only
+//! known after the build script has run, so the checksum could not be taken at build time.
+//!
+//! The boot code in front of the loader is not covered, it is replaced by the kernels placed at ``0x80000``.
+//!
+
+use crate::crc;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+extern "C" {
+    /// linker symbols marking the code and the read-only data of the loader
+    static __loader_start: u8;
+    static __loader_code_end: u8;
+}
+
+/// The checksum taken when the loader started
+static EXPECTED: AtomicU32 = AtomicU32::new(0);
+static RECORDED: AtomicBool = AtomicBool::new(false);
+
+/// The code and the read-only data of the loader
+fn sections() -> &'static [u8] {
+    unsafe {
+        let start = &__loader_start as *const u8;
+        let end = &__loader_code_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// Take the checksum of the loader to be checked with [verify] later on
+pub fn record() {
+    EXPECTED.store(crc::crc32(sections()), Ordering::Relaxed);
+    RECORDED.store(true, Ordering::Release);
+}
+
+/// Check that the loader has not changed since [record], the checksum found instead is returned otherwise
+pub fn verify() -> Result<(), u32> {
+    if !RECORDED.load(Ordering::Acquire) {
+        return Ok(());
+    }
+    let expected = EXPECTED.load(Ordering::Relaxed);
+    let actual = crc::crc32(sections());
+    if actual != expected {
+        return Err(actual);
+    }
+    Ok(())
+}
+
+/// The checksum taken when the loader started
+pub fn expected() -> u32 {
+    EXPECTED.load(Ordering::Relaxed)
+}
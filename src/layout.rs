@@ -0,0 +1,47 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Loader layout
+//!
+//! The placement of the loader in memory. This module is shared with the build script, which generates the linker
+//! script from these values and refuses to build a loader whose layout overlaps the regions reserved by the board.
+//! So the values the code relies on always match the layout of the binary. This module must therefore not use
+//! anything outside of ``core``.
+//!
+
+/// The address the firmware loads the loader to and starts it at
+pub const LOAD_ADDRESS: u64 = 0x8_0000;
+/// The address the loader is linked for. The boot code copies the rest of the loader from behind itself to this
+/// address, so a kernel placed at its default address could be as large as the memory up to here. It need to be
+/// 2MB aligned as the loader memory is mapped with its own pages. The heap following the loader need to take the
+/// largest kernel transferred below the kernel images placed from [IMAGE_BASE] on.
+#[cfg(not(feature = "ruspiro_zero2w"))]
+pub const LOADER_ADDRESS: u64 = 0x0800_0000;
+#[cfg(feature = "ruspiro_zero2w")]
+pub const LOADER_ADDRESS: u64 = 0x0400_0000;
+/// The stack size of each of the secondary cores
+pub const CORE_STACK_SIZE: u64 = 0x4000;
+/// The stack size of each exception level of the main core
+pub const EL_STACK_SIZE: u64 = 0x1000;
+/// The alignment of the heap start, the size of a page
+pub const HEAP_ALIGN: u64 = 0x1000;
+/// The end of the heap. The memory above is left to the kernel images, so the heap is never overwritten by a kernel
+/// placed there.
+#[cfg(not(feature = "ruspiro_zero2w"))]
+pub const HEAP_END: u64 = 0x2000_0000;
+#[cfg(feature = "ruspiro_zero2w")]
+pub const HEAP_END: u64 = 0x1000_0000;
+/// Kernels with an arm64 Image header that do not fit below the loader are placed at their text offset from this
+/// 2MB aligned base address. They could use the memory from here up to the end of the ARM memory, which is only
+/// known at runtime as the memory split between the ARM and the VideoCore is configured in the firmware.
+pub const IMAGE_BASE: u64 = HEAP_END;
+/// The largest kernel accepted. The memory for the kernel is allocated before it is received and a compressed
+/// kernel is inflated into a buffer of up to this size as well, so the heap need to take both at once.
+#[cfg(not(feature = "ruspiro_zero2w"))]
+pub const MAX_TRANSFER_SIZE: u64 = 0x0800_0000;
+#[cfg(feature = "ruspiro_zero2w")]
+pub const MAX_TRANSFER_SIZE: u64 = 0x0400_0000;
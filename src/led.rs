@@ -0,0 +1,82 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Activity LED
+//!
+//! The green activity LED of the Raspberry Pi 3 is not connected to a GPIO of the ARM but to the GPIO expander
+//! managed by the firmware, so it is switched with a mailbox call. On the Zero 2 W the LED is connected to GPIO 29
+//! of the ARM and lit with the pin driven low. The LED is chosen from the model detected at startup, see
+//! [crate::model].
+//!
+//! The Compute Modules 3 and 3+ have no activity LED, it is left to the carrier board which might not have one. On
+//! a compute module no pin is touched and a loader stopped with an error tells so on the console from time to time
+//! instead of blinking.
+//!
+
+use crate::board::{self, GPIO_BASE};
+use crate::console;
+use crate::mailbox::{self, MailboxError};
+use crate::model::{self, MODEL_ZERO_2W};
+use crate::time::{self, Duration};
+use core::ptr::{read_volatile, write_volatile};
+
+/// The GPIO expander pin the activity LED is connected to
+const ACT_LED_GPIO: u32 = 130;
+/// The GPIO the activity LED of the Zero 2 W is connected to
+const ACT_LED_PIN: u32 = 29;
+const GPIO_GPFSEL0: *mut u32 = board::register(GPIO_BASE, 0x00);
+const GPIO_GPSET0: *mut u32 = board::register(GPIO_BASE, 0x1C);
+const GPIO_GPCLR0: *mut u32 = board::register(GPIO_BASE, 0x28);
+/// The period the activity LED blinks with once the loader has stopped with an error
+const ERROR_BLINK_PERIOD: Duration = Duration::from_millis(100);
+/// The period a loader stopped with an error tells so on the console if there is no activity LED
+const ERROR_REPORT_PERIOD: Duration = Duration::from_secs(10);
+
+/// Switch the activity LED on or off, nothing is switched on a compute module
+pub fn set_activity(on: bool) -> Result<(), MailboxError> {
+    if model::is_compute_module() {
+        return Ok(());
+    }
+    if model::is(MODEL_ZERO_2W) {
+        set_pin(ACT_LED_PIN, !on);
+        return Ok(());
+    }
+    mailbox::set_gpio_state(ACT_LED_GPIO, on)
+}
+
+/// Drive the GPIO of the ARM high or low
+fn set_pin(pin: u32, high: bool) {
+    let shift = (pin % 10) * 3;
+    unsafe {
+        let select = GPIO_GPFSEL0.add((pin / 10) as usize);
+        // the pin is switched to an output each time, as the kernel might have changed its function
+        write_volatile(
+            select,
+            read_volatile(select) & !(0b111 << shift) | 0b001 << shift,
+        );
+        let register = if high { GPIO_GPSET0 } else { GPIO_GPCLR0 };
+        write_volatile(register.add((pin / 32) as usize), 1 << (pin % 32));
+    }
+}
+
+/// Blink the activity LED rapidly forever, this signals the loader has stopped with an error. On a compute module
+/// the error is repeated on the console instead.
+pub fn error_blink() -> ! {
+    if model::is_compute_module() {
+        loop {
+            time::sleep(ERROR_REPORT_PERIOD);
+            console::set_buffered(false);
+            println!("\r\nloader stopped with an error, reset the board");
+        }
+    }
+    let mut on = false;
+    loop {
+        on = !on;
+        let _ = set_activity(on);
+        time::sleep(ERROR_BLINK_PERIOD);
+    }
+}
@@ -10,56 +10,228 @@
 
 extern crate alloc;
 extern crate ruspiro_allocator;
-use alloc::vec::Vec;
+use alloc::{boxed::Box, vec, vec::Vec};
 
-use crate::mmu;
-use ruspiro_cache as cache;
+use crate::board::{self, KERNEL_ADDRESS_32, KERNEL_ADDRESS_64};
+use crate::bootargs::{self, BootArgs};
+use crate::buildinfo::BuildInfo;
+use crate::cache;
+use crate::command;
+use crate::console::{self, UART};
+use crate::crash;
+use crate::crc;
+use crate::elf::{self, ElfLayout};
+use crate::fdt::{ChosenPatch, Fdt};
+use crate::gzip;
+use crate::hyp;
+use crate::image::{self, Arm64Image, BootMode, ImageError, KernelHeader};
+use crate::integrity;
+use crate::layout::{self, IMAGE_BASE};
+use crate::led;
+use crate::log;
+use crate::mailbox;
+use crate::mmu::{self, MemoryAttributes};
+use crate::model;
+use crate::monitor;
+#[cfg(feature = "second_uart")]
+use crate::pl011;
+use crate::pm;
+use crate::retained;
+use crate::sched::Scheduler;
+use crate::services;
+use crate::slot;
+use crate::smp;
+use crate::storage;
+use crate::supply;
+use crate::time::{self, Deadline, Duration, Instant};
+use crate::verify::{self, VerifyError};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
 use ruspiro_interrupt::*;
-use ruspiro_lock::*;
+use ruspiro_loader_protocol::{xmodem, Arbiter, KernelTransfer, Received, ACK};
 use ruspiro_register::system::*;
 use ruspiro_singleton::Singleton;
-use ruspiro_timer as timer;
 use ruspiro_uart::{InterruptType, Uart1};
 
-/// Define singleton Uart1 accessor to ensure safe access from main processing as well as
-/// from interrupt handler
-static UART: Singleton<Uart1> = Singleton::new(Uart1::new());
-/// Semaphore that indicates whether the kernel has been loaded inside the
-/// receive interrupt handler
-static KERNEL_LOADED: Semaphore = Semaphore::new(0);
-static mut KERNEL: Option<Kernel> = None;
+/// The kernel command line patched into the device tree passed to the kernel. It could be given at build time
+/// with the environment variable ``RUSPIRO_LOADER_BOOTARGS``
+const BOOTARGS: Option<&str> = option_env!("RUSPIRO_LOADER_BOOTARGS");
+
+/// Flag of the extended header requesting a dry run of the transfer
+const FLAG_DRY_RUN: u16 = 1 << 0;
+/// Flag of the extended header requesting to write the kernel to a kernel slot of the SD card before starting it
+const FLAG_SAVE_SLOT: u16 = 1 << 1;
+/// Flag of the extended header sending a device tree for the following kernels instead of a kernel
+const FLAG_DEVICE_TREE: u16 = 1 << 2;
+/// Flag of the extended header telling the binary starts with a verification header, see [verify]
+const FLAG_VERIFY: u16 = 1 << 3;
+/// A transfer is dropped if the host has not sent any data for this time in the middle of it
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+/// The largest kernel accepted, see [layout::MAX_TRANSFER_SIZE]
+pub const MAX_TRANSFER_SIZE: usize = layout::MAX_TRANSFER_SIZE as usize;
+/// The period of the heartbeat LED toggling
+const HEARTBEAT_PERIOD: Duration = Duration::from_millis(500);
+/// The period of the beacon announcing the loader to the host
+const BEACON_PERIOD: Duration = Duration::from_secs(5);
+/// The period the watchdog is petted with, this need to be shorter than the shortest timeout
+const WATCHDOG_PERIOD: Duration = Duration::from_millis(500);
+/// The period the supply voltage and the throttling of the cores is checked with
+const SUPPLY_PERIOD: Duration = Duration::from_secs(1);
+/// The longest the core sleeps while waiting for requests, even if no task is due earlier
+const MAX_IDLE: Duration = Duration::from_secs(1);
+/// The period the UART is fed with buffered output while the core is idle, the 8 byte FIFO of the miniUART takes
+/// about 0.7ms to be sent at 115200 baud
+const TRANSMIT_POLL_PERIOD: Duration = Duration::from_micros(500);
+/// The period the PL011 is polled while the host might talk to it, the 16 byte FIFO is full after about 1.4ms at
+/// 115200 baud. Higher baud rates need the ``uart_dma`` feature, the receive buffers hold the bytes far longer.
+const SECOND_UART_POLL_PERIOD: Duration = Duration::from_millis(1);
+/// The status line showing the progress of a transfer is redrawn at most this often
+const STATUS_PERIOD: Duration = Duration::from_millis(250);
+/// The width of the progress bar in the status line
+const PROGRESS_BAR_WIDTH: usize = 30;
+/// The time the secondary cores released by the loader have to park again before the kernel is started
+const PARK_TIMEOUT: Duration = Duration::from_secs(1);
+/// The XMODEM sender is requested to start or to repeat a block if it has not sent anything for this time
+const XMODEM_TIMEOUT: Duration = Duration::from_secs(3);
+/// The kernel on the boot partition of the SD card booted if no host talks to the loader, see [fallback_timeout]
+const FALLBACK_KERNEL: &str = env!("LOADER_FALLBACK_KERNEL");
+/// The line sent as beacon
+const BEACON: &str = "RUSPIRO-LOADER READY";
+/// The number of transports the loader listens on for requests
+const TRANSPORTS: usize = if cfg!(feature = "second_uart") { 2 } else { 1 };
+/// Telemetry record of a kernel about to be started
+const TELEMETRY_BOOT: u8 = 1;
+/// Telemetry record of a kernel verified in a dry run
+const TELEMETRY_DRY_RUN: u8 = 2;
+
+/// Kernels are received and verified but not started
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+/// Periodically announce the loader to the host while waiting for a request
+static BEACON_ENABLED: AtomicBool = AtomicBool::new(false);
+/// The receiver is waiting for a new request, the beacon is only sent in between requests
+static RECEIVER_IDLE: AtomicBool = AtomicBool::new(true);
+/// The timeout of the watchdog guarding the loader in milliseconds, 0 if the watchdog is not running
+static WATCHDOG_TIMEOUT: AtomicU32 = AtomicU32::new(0);
+/// A kernel a command has requested to boot once the command has been completed
+static PENDING_KERNEL: Singleton<Option<KernelTransfer>> = Singleton::new(None);
+/// The architecture of the kernel a command has requested to receive with XMODEM, 0 if none is requested
+static XMODEM_REQUEST: AtomicU8 = AtomicU8::new(0);
+/// The device tree sent by the host, passed to the kernels instead of the device tree of the firmware
+static DEVICE_TREE: Singleton<Option<Vec<u8>>> = Singleton::new(None);
+
+/// A kernel received with XMODEM or YMODEM on the transport the command requesting it has been received on
+struct XmodemSession {
+    receiver: xmodem::Receiver,
+    transport: usize,
+    aarch: u8,
+}
 
 /// Storing kernel metadata
 #[derive(Debug)]
 struct Kernel {
     pub boot_address: u64,
-    pub boot_mode: u32,
+    pub boot_mode: BootMode,
     pub binary: Vec<u8>,
+    /// The arm64 Image header of the kernel if it has one
+    pub image: Option<Arm64Image>,
+    /// The offset of the RusPiRo kernel header within the binary if it has one
+    pub header: Option<usize>,
+    /// The memory an aarch64 ELF kernel is loaded to, the binary is the ELF file then
+    pub elf: Option<ElfLayout>,
+    /// The exception level the kernel is entered in
+    pub entry_el: u8,
+    /// The flags given with the extended header
+    pub flags: u16,
+    /// The host has requested the address the kernel is placed at
+    pub fixed_address: bool,
+    /// The end of the memory the kernel occupies once it is placed, set when the kernel is validated. It is mapped
+    /// and flushed from the caches before the kernel is started.
+    pub end: u64,
 }
 
 impl Kernel {
-    pub const fn new(addr: u64, mode: u32, data: Vec<u8>) -> Self {
+    pub const fn new(addr: u64, mode: BootMode, data: Vec<u8>) -> Self {
         Kernel {
             boot_address: addr,
             boot_mode: mode,
             binary: data,
+            image: None,
+            header: None,
+            elf: None,
+            entry_el: 1,
+            flags: 0,
+            fixed_address: false,
+            end: addr,
+        }
+    }
+
+    /// The address the kernel is entered at
+    pub fn entry(&self) -> u64 {
+        self.elf.map_or(self.boot_address, |elf| elf.entry)
+    }
+
+    /// The end of the memory the kernel occupies once it is placed, refused if it exceeds the address space
+    pub fn checked_end(&self) -> Result<u64, ImageError> {
+        match (self.elf, self.image) {
+            (Some(elf), _) => Some(elf.end),
+            (None, Some(image)) => self.boot_address.checked_add(image.image_size),
+            // without a binary to copy the code has already been placed up to the end given
+            (None, None) => self
+                .boot_address
+                .checked_add(self.binary.len() as u64)
+                .map(|end| end.max(self.end)),
         }
+        .ok_or(ImageError::BadSize)
+    }
+
+    /// Check whether the memory the kernel occupies once it is placed overlaps the range from ``start`` to ``end``,
+    /// a kernel exceeding the address space overlaps everything
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.checked_end()
+            .map_or(true, |limit| self.boot_address < end && start < limit)
+    }
+}
+
+impl Kernel {
+    /// The kernel of the transfer, refused if its architecture is not supported
+    fn from_transfer(transfer: KernelTransfer) -> Result<Self, ImageError> {
+        let mode = BootMode::new(transfer.aarch.into())?;
+        let address = match mode {
+            BootMode::Aarch32 => KERNEL_ADDRESS_32,
+            BootMode::Aarch64 => KERNEL_ADDRESS_64,
+        };
+        let mut kernel = Kernel::new(address, mode, transfer.binary);
+        if transfer.entry_el != 0 {
+            kernel.entry_el = transfer.entry_el;
+        }
+        kernel.flags = transfer.flags;
+        if transfer.address != 0 {
+            kernel.boot_address = transfer.address;
+            kernel.fixed_address = true;
+        }
+        Ok(kernel)
     }
 }
 
 /// the external functions called for the "re-boot" in either aarch32 or aarch64 mode
-/// depending on the kernel received
+/// depending on the kernel received. The arguments x0-x3 are passed to the kernel entry point as
+/// well as the address of the boot services table in x4, which is 0 for aarch32 kernels
 extern "C" {
-    fn __boot_64(addr: u64) -> !;
-    fn __boot_32(addr: u64) -> !;
+    /// linker symbols marking the memory of the loader, kernels need to fit below or must not overlap
+    static __loader_start: u8;
+    static __heap_end: u8;
+    fn __boot_64(addr: u64, x0: u64, x1: u64, x2: u64, x3: u64, services: u64) -> !;
+    fn __boot_32(addr: u64, x0: u64, x1: u64, x2: u64, x3: u64, services: u64) -> !;
+    fn __boot_64_el2(addr: u64, x0: u64, x1: u64, x2: u64, x3: u64, services: u64) -> !;
 }
 
 /// Run the loader until a new kernel binary has been received and
-/// begin executing the new kernel
+/// begin executing the new kernel. Commands received in the meantime are executed right away
 pub fn run() -> ! {
+    // nothing could have overwritten the loader yet
+    integrity::record();
     // Initialize the Uart1
     UART.take_for(|uart| {
-        let _ = uart.initialize(250_000_000, 115_200);
+        let _ = uart.initialize(board::CORE_CLOCK_RATE, 115_200);
         uart.send_string("prepare boot loader\r\n");
         uart.enable_interrupts(InterruptType::Receive);
     });
@@ -67,78 +239,952 @@ pub fn run() -> ! {
     // enable the interrupt for the Uart1
     IRQ_MANAGER.take_for(|irq_mgr| irq_mgr.activate(Interrupt::Aux));
     enable_interrupts();
+    // the pins of the second UART and the activity LED depend on the board
+    let revision = model::detect();
+    #[cfg(feature = "second_uart")]
+    {
+        pl011::initialize(pl011::BAUD_RATE);
+        console::add_output(console::SECOND_UART);
+    }
 
-    UART.use_for(|uart| {
-        uart.send_string("waiting for a new kernel...\r\n");
-    });
+    println!("{}", BuildInfo);
+    match revision {
+        Some(revision) => {
+            info!("running on {}", revision);
+            if revision.memory_size() < board::MEMORY_SIZE {
+                error!(
+                    "the loader is built for {}MB but the board only has {}MB, build it for this board",
+                    board::MEMORY_SIZE >> 20,
+                    revision.memory_size() >> 20
+                );
+            }
+        }
+        None => warn!("unknown board revision, assuming the board the loader is built for"),
+    }
+    crash::report();
+    let stay_in_loader = retained::take_stay_in_loader();
+    if stay_in_loader {
+        info!("staying in the loader as requested before the reset");
+    }
+    if retained::boot_loop_detected() {
+        warn!(
+            "boot loop detected, {} boot attempts without success signal",
+            retained::boot_count()
+        );
+        if let Some(saved) = retained::rollback() {
+            println!(
+                "ROLLBACK to the last known good kernel, {} bytes",
+                saved.binary.len()
+            );
+            disable_interrupts();
+            let kernel = BootMode::new(saved.mode).map(|mode| {
+                let mut kernel = Kernel::new(saved.address, mode, Vec::from(saved.binary));
+                kernel.entry_el = saved.entry_el;
+                kernel.fixed_address = true;
+                kernel
+            });
+            match kernel.and_then(|mut kernel| {
+                let args = prepare_kernel(&mut kernel)?;
+                unsafe { place_kernel(&kernel) }?;
+                Ok((kernel, args))
+            }) {
+                Ok((kernel, args)) => boot(kernel, args),
+                Err(err) => error!("kernel not accepted: {:?}", err),
+            }
+            enable_interrupts();
+        }
+    }
+    info!("waiting for a new kernel...");
+    monitor::open_window();
+    // the first byte received from a host cancels the fallback to the kernel of the SD card
+    let mut fallback = match fallback_timeout() {
+        Some(timeout) if !stay_in_loader => {
+            info!(
+                "booting {} from the SD card if no host talks within {}s",
+                FALLBACK_KERNEL,
+                timeout.as_secs()
+            );
+            Some(Deadline::after(timeout))
+        }
+        _ => None,
+    };
+
+    // from here on the background tasks run alongside receiving the requests
+    let mut scheduler = background_tasks();
+    let mut arbiter = Arbiter::new(TRANSPORTS, time::ticks(TRANSFER_TIMEOUT), MAX_TRANSFER_SIZE);
+    console::set_buffered(true);
+    let mut redraw_status = Deadline::after(Duration::from_secs(0));
+    let mut xmodem = None;
 
     loop {
-        // to safe power sleep the core until an event eg. interrupt arrises
-        wfe();
-        // wait until the interrupt has signaled that the data has arrived
-        KERNEL_LOADED.down();
-        disable_interrupts();
-
-        UART.use_for(|uart| {
-            uart.send_string("new kernel received, preparing re-boot...\r\n");
-        });
-        // when getting here the kernel binary has been fully received and the data prepared
-        // in KERNEL. It's safe to access this here as the interrupt will no longer concurrently
-        // access the same
-        let kernel = unsafe { KERNEL.take().unwrap() };
-        // copy the retrieved binary to the address it shall be executed from
-        unsafe {
-            core::ptr::copy_nonoverlapping(
-                kernel.binary.as_ptr(),
-                kernel.boot_address as *mut u8,
-                kernel.binary.len(),
+        scheduler.run_due();
+        while let Some(byte) = console::read_byte() {
+            fallback = None;
+            receive(&mut arbiter, &mut xmodem, console::MINI_UART, byte);
+        }
+        #[cfg(feature = "second_uart")]
+        {
+            while let Some(byte) = pl011::read_byte() {
+                fallback = None;
+                receive(&mut arbiter, &mut xmodem, console::SECOND_UART, byte);
+            }
+        }
+        if xmodem.is_none() {
+            let aarch = XMODEM_REQUEST.swap(0, Ordering::AcqRel);
+            if aarch != 0 {
+                xmodem = Some(XmodemSession {
+                    receiver: xmodem::Receiver::new(time::ticks(XMODEM_TIMEOUT), MAX_TRANSFER_SIZE),
+                    transport: arbiter.active().unwrap_or(console::MINI_UART),
+                    aarch,
+                });
+            }
+        }
+        run_xmodem(&mut xmodem, None);
+        if fallback.map_or(false, |deadline| deadline.expired()) {
+            fallback = None;
+            boot_fallback();
+        }
+        if arbiter.poll(Instant::now().ticks()) {
+            warn!(
+                "transfer stalled, {} bytes lost so far, waiting for a new request",
+                console::overruns()
             );
+            report_supply();
+        }
+        RECEIVER_IDLE.store(
+            arbiter.is_idle() && xmodem.is_none() && !monitor::is_active(),
+            Ordering::Release,
+        );
+        // the sender of an XMODEM transfer would take the status line for its replies
+        if xmodem.is_none() {
+            update_status(&arbiter, &mut redraw_status);
         }
-        // after we copied the new kernel to the right memory address clean and invalidate the
-        // caches to ensure the core sees the latest version of memory and instructions
-        cache::cleaninvalidate();
+        // the acknowledges should reach the host without waiting for the next run of the scheduler
+        console::transmit();
+        idle(&scheduler, &arbiter, fallback);
+    }
+}
 
-        UART.use_for(|uart| {
-            uart.send_string("re-boot in progress ...\r\n");
-        });
+/// Redraw the status line with the progress of the kernel binary being received, at most every [STATUS_PERIOD].
+/// The status line is erased once the binary has been received or the transfer has been dropped.
+fn update_status(arbiter: &Arbiter, redraw: &mut Deadline) {
+    match arbiter.progress() {
+        Some((received, size)) if redraw.expired() => {
+            // the binary is only received if it has at least one byte
+            let filled = received * PROGRESS_BAR_WIDTH / size;
+            console::show_status(format_args!(
+                "receiving kernel [{:=<filled$}{:empty$}] {:3}% {}/{} bytes",
+                "",
+                "",
+                received * 100 / size,
+                received,
+                size,
+                filled = filled,
+                empty = PROGRESS_BAR_WIDTH - filled
+            ));
+            *redraw = Deadline::after(STATUS_PERIOD);
+        }
+        Some(_) => (),
+        None => console::clear_status(),
+    }
+}
+
+/// Sleep the core to save power until an interrupt, e.g. of the UART receiving data, arrives or the next background
+/// task is due or the fallback to the kernel of the SD card. While buffered output is waiting or the PL011 needs to be
+/// polled the core wakes up earlier.
+fn idle(scheduler: &Scheduler, arbiter: &Arbiter, fallback: Option<Deadline>) {
+    let now = Instant::now();
+    let mut deadline = now + MAX_IDLE;
+    if let Some(due) = scheduler.next_due() {
+        deadline = deadline.min(due);
+    }
+    if let Some(fallback) = fallback {
+        deadline = deadline.min(now + fallback.remaining());
+    }
+    if console::has_pending_output() {
+        deadline = deadline.min(now + TRANSMIT_POLL_PERIOD);
+    }
+    if cfg!(feature = "second_uart") && arbiter.active() != Some(console::MINI_UART) {
+        deadline = deadline.min(now + SECOND_UART_POLL_PERIOD);
+    }
+    // with the interrupts masked a byte received after the check still wakes the core, it is queued by the interrupt
+    // handler once the interrupts are unmasked again
+    disable_interrupts();
+    if !console::has_received() {
+        time::wait_for_interrupt(deadline);
+    }
+    enable_interrupts();
+}
 
-        // do some arbitrary sleeping before the real re-boot...
-        // and print some "progressing points" to enable the host machine to
-        // start a terminal program and connect via uart after the data has been transmitted
-        for _ in 0..100 {
-            UART.use_for(|uart| uart.send_string("."));
-            timer::sleep(15_000);
-        }
-
-        // restore as many stuff into the boot reset state as possible
-        // as this deactivates MMU no atomic operations from here
-        clean_up_for_reboot(kernel.boot_mode);
-
-        // based on the kernel mode we could either "re-boot" immidiately or
-        // we need to switch to aarch32 mode
-        match kernel.boot_mode {
-            64 => unsafe { __boot_64(kernel.boot_address) },
-            32 => unsafe { __boot_32(kernel.boot_address) },
-            _ => {
-                // well, whatever is requested we cannot handle this here...
-                unimplemented!();
+/// The activities running in the background while the loader waits for requests
+fn background_tasks() -> Scheduler {
+    let mut scheduler = Scheduler::new();
+    scheduler.add(Duration::from_secs(0), Box::new(console::transmit));
+    let mut led_on = false;
+    scheduler.add(
+        HEARTBEAT_PERIOD,
+        Box::new(move || {
+            // the heartbeat stops with the LED switched on as long as the supply voltage is too low
+            led_on = !led_on || supply::is_under_voltage();
+            let _ = led::set_activity(led_on);
+        }),
+    );
+    scheduler.add(
+        SUPPLY_PERIOD,
+        Box::new(|| {
+            supply::check();
+            // an under-voltage is only reported with a request if it happened while receiving it
+            if RECEIVER_IDLE.load(Ordering::Acquire) {
+                supply::take_under_voltage();
+            }
+        }),
+    );
+    scheduler.add(
+        WATCHDOG_PERIOD,
+        Box::new(|| {
+            if let Some(timeout) = watchdog() {
+                pm::watchdog_set(timeout);
+            }
+        }),
+    );
+    scheduler.add(
+        BEACON_PERIOD,
+        Box::new(|| {
+            if is_beacon() && RECEIVER_IDLE.load(Ordering::Acquire) {
+                println!("{}", BEACON);
+            }
+        }),
+    );
+    scheduler
+}
+
+/// Pass a byte received on the transport to the arbiter, or to the XMODEM receiver while a kernel is received with
+/// XMODEM, or to the monitor while it is running. The console output follows the transport the host talks to as soon
+/// as it is known, so the acknowledges and the output of commands only reach this transport.
+fn receive(arbiter: &mut Arbiter, xmodem: &mut Option<XmodemSession>, transport: usize, byte: u8) {
+    match xmodem.as_ref().map(|session| session.transport) {
+        Some(xmodem_transport) if xmodem_transport == transport => {
+            return run_xmodem(xmodem, Some(byte))
+        }
+        Some(_) => return,
+        None => (),
+    }
+    if monitor::receive(transport, byte, arbiter.is_idle()) {
+        return;
+    }
+    let locked = arbiter.active();
+    let output = arbiter.receive(transport, byte, Instant::now().ticks());
+    if locked.is_none() {
+        if let Some(active) = arbiter.active() {
+            console::select_output(active);
+            debug!("host talks on transport {}", active);
+        }
+    }
+    for _ in 0..output.acks {
+        console::send(ACK);
+    }
+    if let Some(err) = output.error {
+        error!("transfer refused: {:?}", err);
+        report_supply();
+    }
+    if let Some(request) = output.request {
+        report_supply();
+        handle_request(request);
+        monitor::take_request(transport);
+    }
+}
+
+/// Pass the received byte, or only the current time if no byte is given, to the XMODEM receiver and send its reply.
+/// A kernel received completely is handled like one transferred by the host tool, the session ends either way.
+fn run_xmodem(xmodem: &mut Option<XmodemSession>, byte: Option<u8>) {
+    let session = match xmodem {
+        Some(session) => session,
+        None => return,
+    };
+    let now = Instant::now().ticks();
+    let output = match byte {
+        Some(byte) => session.receiver.receive(byte, now),
+        None => session.receiver.poll(now),
+    };
+    console::send(output.reply);
+    let result = match output.done {
+        Some(result) => result,
+        None => return,
+    };
+    let aarch = session.aarch;
+    *xmodem = None;
+    report_supply();
+    match result {
+        Ok(file) => {
+            info!(
+                "received {} bytes with {}{}, CRC-32 {:#010x}",
+                file.data.len(),
+                if file.name.is_empty() {
+                    "XMODEM"
+                } else {
+                    "YMODEM "
+                },
+                file.name,
+                crc::crc32(&file.data)
+            );
+            // there are no flags with XMODEM, a verification header is told by its magic
+            let flags = if verify::has_header(&file.data) {
+                FLAG_VERIFY
+            } else {
+                0
+            };
+            handle_request(Received::Kernel(KernelTransfer {
+                aarch,
+                entry_el: 0,
+                flags,
+                address: 0,
+                binary: file.data,
+            }));
+        }
+        Err(err) => error!("XMODEM transfer failed: {:?}", err),
+    }
+}
+
+/// Warn the host if the supply voltage has been too low while the request was received, as this frequently
+/// corrupts the received data
+fn report_supply() {
+    supply::check();
+    if supply::take_under_voltage() {
+        warn!("under-voltage while receiving the request, the received data might be corrupted");
+    }
+}
+
+/// Process a request completely received from the host
+pub fn handle_request(request: Received) {
+    match request {
+        Received::Kernel(mut transfer) => {
+            trace!(
+                "aarch{} kernel of {} bytes received, entry EL{}, flags {:#x}, address {:#x}",
+                transfer.aarch,
+                transfer.binary.len(),
+                transfer.entry_el,
+                transfer.flags,
+                transfer.address
+            );
+            accept_kernel(transfer);
+        }
+        Received::Command(line) => {
+            trace!("command received: {}", line);
+            match command::execute(&line) {
+                Ok(_) => println!("OK"),
+                Err(err) => println!("ERR {:?}", err),
+            }
+            if let Some(transfer) = PENDING_KERNEL.take_for(|pending| pending.take()) {
+                accept_stored_kernel(transfer);
             }
         }
     }
 }
 
+/// Verify the kernel and boot it, a kernel that is not accepted is reported and the loader waits for the next one
+fn accept_kernel(mut transfer: KernelTransfer) {
+    match verify_transfer(&mut transfer) {
+        Ok(_) => handle_kernel(transfer),
+        Err(err) if is_dry_run() || transfer.flags & FLAG_DRY_RUN != 0 => {
+            println!("DRYRUN FAILED {:?}", err)
+        }
+        Err(err) => error!("kernel not accepted: {:?}", err),
+    }
+}
+
+/// Verify and boot a kernel read from the SD card. Anyone with access to the board could change the card, so the
+/// kernel needs a valid verification header as well while the loader requires signed kernels. Like with XMODEM the
+/// header is told by its magic.
+fn accept_stored_kernel(mut transfer: KernelTransfer) {
+    if verify::has_header(&transfer.binary) {
+        transfer.flags |= FLAG_VERIFY;
+    }
+    accept_kernel(transfer);
+}
+
+/// Verify the binary received from the host against its verification header and strip the header. Without the
+/// header the binary is only accepted as long as the loader does not require signed kernels.
+fn verify_transfer(transfer: &mut KernelTransfer) -> Result<(), VerifyError> {
+    if transfer.flags & FLAG_VERIFY == 0 {
+        return if verify::SIGNATURE_REQUIRED {
+            Err(VerifyError::Unsigned)
+        } else {
+            Ok(())
+        };
+    }
+    let payload = verify::verify(&transfer.binary)?;
+    debug!("payload of {} bytes verified", payload.len());
+    transfer.binary.truncate(payload.end);
+    transfer.binary.drain(..payload.start);
+    transfer.flags &= !FLAG_VERIFY;
+    Ok(())
+}
+
+/// Boot the received kernel, or keep the device tree sent instead of a kernel
+fn handle_kernel(transfer: KernelTransfer) {
+    if transfer.flags & FLAG_DEVICE_TREE != 0 {
+        receive_device_tree(transfer.binary);
+        return;
+    }
+    let dry_run = is_dry_run() || transfer.flags & FLAG_DRY_RUN != 0;
+    let kernel = Kernel::from_transfer(transfer);
+    match kernel.and_then(|mut kernel| prepare_kernel(&mut kernel).map(|args| (kernel, args))) {
+        Ok((kernel, _)) if dry_run => dry_run_report(&kernel),
+        Ok((kernel, args)) => {
+            if kernel.flags & FLAG_SAVE_SLOT != 0 {
+                save_to_slot(&kernel);
+            }
+            // keep the kernel to be able to roll back to it once it has booted successfully
+            retained::save_kernel(
+                kernel.boot_address,
+                kernel.boot_mode.bits(),
+                kernel.entry_el,
+                &kernel.binary,
+            );
+            disable_interrupts();
+            match unsafe { place_kernel(&kernel) } {
+                Ok(_) => boot(kernel, args),
+                Err(err) => {
+                    enable_interrupts();
+                    error!("kernel not accepted: {:?}", err);
+                }
+            }
+        }
+        Err(err) if dry_run => println!("DRYRUN FAILED {:?}", err),
+        Err(err) => error!("kernel not accepted: {:?}", err),
+    }
+}
+
+/// Keep the device tree sent by the host for the following kernels. An invalid device tree is refused and the
+/// device tree passed so far is kept.
+fn receive_device_tree(blob: Vec<u8>) {
+    match Fdt::from_slice(&blob).map(|fdt| fdt.total_size()) {
+        Ok(size) => {
+            info!(
+                "device tree of {} bytes received, passed to the following kernels",
+                size
+            );
+            DEVICE_TREE.take_for(|tree| *tree = Some(blob));
+        }
+        Err(err) => error!("device tree not accepted: {:?}", err),
+    }
+}
+
+/// Write the verified kernel to the kernel slot with the older kernel. A failure is logged and the kernel is started
+/// anyway.
+fn save_to_slot(kernel: &Kernel) {
+    let address = if kernel.fixed_address {
+        kernel.boot_address
+    } else {
+        0
+    };
+    match slot::store(
+        kernel.boot_mode.bits() as u8,
+        kernel.entry_el,
+        kernel.flags & !FLAG_SAVE_SLOT,
+        address,
+        &kernel.binary,
+    ) {
+        Ok((idx, version)) => info!(
+            "kernel saved as version {} in slot {}",
+            version,
+            slot::SLOT_NAMES[idx]
+        ),
+        Err(err) => error!("saving the kernel to a slot failed: {:?}", err),
+    }
+}
+
+/// The time without a byte from a host after which [FALLBACK_KERNEL] is booted, given in seconds with
+/// ``RUSPIRO_LOADER_FALLBACK_TIMEOUT`` at build time. ``None`` if the kernel of the SD card is never booted.
+fn fallback_timeout() -> Option<Duration> {
+    match env!("LOADER_FALLBACK_TIMEOUT").parse::<u64>() {
+        Ok(0) | Err(_) => None,
+        Ok(secs) => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// Boot [FALLBACK_KERNEL] from the SD card like the ``bootfile`` command does, no host has talked to the loader
+/// within [fallback_timeout]. If the kernel could not be read or is not accepted the loader keeps waiting for a host.
+fn boot_fallback() {
+    info!(
+        "no host has talked to the loader, booting {} from the SD card",
+        FALLBACK_KERNEL
+    );
+    match storage::read_file(FALLBACK_KERNEL, MAX_TRANSFER_SIZE) {
+        Ok(binary) => {
+            info!("{} bytes read from {}", binary.len(), FALLBACK_KERNEL);
+            accept_stored_kernel(KernelTransfer {
+                aarch: 64,
+                entry_el: 0,
+                flags: 0,
+                address: 0,
+                binary,
+            });
+        }
+        Err(err) => error!("reading {} failed: {:?}", FALLBACK_KERNEL, err),
+    }
+    info!("waiting for a new kernel...");
+}
+
+/// Request to boot the given kernel read from the SD card once the command currently executed has been completed.
+/// The kernel is verified and handled like one transferred by the host.
+pub fn request_boot(transfer: KernelTransfer) {
+    PENDING_KERNEL.take_for(|pending| *pending = Some(transfer));
+}
+
+/// Request to receive a kernel for the architecture, 32 or 64, with XMODEM or YMODEM once the command currently
+/// executed has been completed. Until the transfer has ended, successfully or not, all bytes received on the
+/// transport of the command are passed to the XMODEM receiver.
+pub fn request_xmodem(aarch: u8) {
+    XMODEM_REQUEST.store(aarch, Ordering::Release);
+}
+
+/// Enable or disable the dry run mode. In this mode kernels are received and verified as usual but instead of
+/// starting them the results are reported and the loader waits for the next request.
+pub fn set_dry_run(enable: bool) {
+    DRY_RUN.store(enable, Ordering::Release);
+}
+
+/// Check whether the dry run mode is active
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Acquire)
+}
+
+/// Enable or disable the beacon. While enabled the loader announces itself periodically with a
+/// ``RUSPIRO-LOADER READY`` line, so the host could detect it is waiting for requests.
+pub fn set_beacon(enable: bool) {
+    BEACON_ENABLED.store(enable, Ordering::Release);
+}
+
+/// Check whether the beacon is enabled
+pub fn is_beacon() -> bool {
+    BEACON_ENABLED.load(Ordering::Acquire)
+}
+
+/// Start the watchdog guarding the loader with the given timeout or stop it with ``None``. If the loader does not
+/// pet the watchdog within the timeout the device is reset. The watchdog is stopped before a kernel is started.
+pub fn set_watchdog(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => {
+            WATCHDOG_TIMEOUT.store(timeout.as_millis() as u32, Ordering::Release);
+            pm::watchdog_start(timeout);
+        }
+        None => {
+            WATCHDOG_TIMEOUT.store(0, Ordering::Release);
+            pm::watchdog_stop();
+        }
+    }
+}
+
+/// The timeout of the watchdog guarding the loader if it is running
+pub fn watchdog() -> Option<Duration> {
+    match WATCHDOG_TIMEOUT.load(Ordering::Acquire) {
+        0 => None,
+        millis => Some(Duration::from_millis(millis as u64)),
+    }
+}
+
+/// Send the telemetry record of the given kind describing the kernel. The payload contains the load address (u64),
+/// the binary size (u32), its CRC-32 (u32), the architecture (u8), the entry exception level (u8), the flags of
+/// the extended header (u16) and the boot attempts without success signal (u32), all little endian.
+fn kernel_telemetry(kind: u8, kernel: &Kernel) {
+    if !log::is_binary() {
+        return;
+    }
+    let mut payload = [0; 24];
+    payload[0..8].copy_from_slice(&kernel.boot_address.to_le_bytes());
+    payload[8..12].copy_from_slice(&(kernel.binary.len() as u32).to_le_bytes());
+    payload[12..16].copy_from_slice(&crc::crc32(&kernel.binary).to_le_bytes());
+    payload[16] = kernel.boot_mode.bits() as u8;
+    payload[17] = kernel.entry_el;
+    payload[18..20].copy_from_slice(&kernel.flags.to_le_bytes());
+    payload[20..24].copy_from_slice(&retained::boot_count().to_le_bytes());
+    log::telemetry(module_path!(), kind, &payload);
+}
+
+/// Report the results of receiving and verifying the kernel without starting it
+fn dry_run_report(kernel: &Kernel) {
+    kernel_telemetry(TELEMETRY_DRY_RUN, kernel);
+    println!(
+        "aarch{} kernel, {} bytes, CRC-32 {:#010x}",
+        kernel.boot_mode.bits(),
+        kernel.binary.len(),
+        crc::crc32(&kernel.binary)
+    );
+    println!(
+        "placed at {:#x}..{:#x}, entered in EL{}",
+        kernel.boot_address, kernel.end, kernel.entry_el
+    );
+    if let Some(elf) = kernel.elf {
+        println!("ELF kernel entered at {:#x}", elf.entry);
+    }
+    match kernel.header {
+        Some(offset) => println!("RusPiRo kernel header at offset {:#x}", offset),
+        None => println!("no RusPiRo kernel header"),
+    }
+    println!("DRYRUN OK");
+}
+
+/// Inspect and validate the kernel and provide the arguments passed to it
+fn prepare_kernel(kernel: &mut Kernel) -> Result<BootArgs, ImageError> {
+    if gzip::is_gzip(&kernel.binary) {
+        let inflated =
+            gzip::decompress(&kernel.binary, MAX_TRANSFER_SIZE).map_err(ImageError::Gzip)?;
+        info!(
+            "gzip compressed kernel of {} bytes inflated to {} bytes",
+            kernel.binary.len(),
+            inflated.len()
+        );
+        kernel.binary = inflated;
+    }
+    if kernel.boot_mode == BootMode::Aarch64 && elf::is_elf(&kernel.binary) {
+        let layout = elf::parse(&kernel.binary)?;
+        debug!(
+            "ELF kernel loaded to {:#x}..{:#x}, entry {:#x}",
+            layout.start, layout.end, layout.entry
+        );
+        if kernel.fixed_address && kernel.boot_address != layout.start {
+            warn!(
+                "the ELF kernel is loaded to the addresses of its segments instead of {:#x}",
+                kernel.boot_address
+            );
+        }
+        kernel.boot_address = layout.start;
+        kernel.fixed_address = true;
+        kernel.elf = Some(layout);
+    } else {
+        image::check_format(&kernel.binary, kernel.boot_mode)?;
+    }
+    inspect_kernel(kernel)?;
+    // the kernel receives the same arguments the firmware has passed to the loader, so
+    // especially the device tree is forwarded. If required the device tree gets patched
+    let fw_args = bootargs::firmware();
+    let args = match kernel.image {
+        // the boot protocol of kernel images requires x1-x3 to be 0
+        Some(_) => BootArgs {
+            x0: device_tree(&fw_args, kernel),
+            x1: 0,
+            x2: 0,
+            x3: 0,
+        },
+        None => BootArgs {
+            x0: device_tree(&fw_args, kernel),
+            ..fw_args
+        },
+    };
+    kernel.end = validate_kernel(kernel, &args)?;
+
+    Ok(args)
+}
+
+/// Validate what could be validated before branching into the kernel, the entry address and the memory the kernel
+/// is placed in as well as the device tree passed to it. The checked end of the memory the kernel occupies is
+/// returned.
+fn validate_kernel(kernel: &Kernel, args: &BootArgs) -> Result<u64, ImageError> {
+    if kernel.entry() % 4 != 0 {
+        return Err(ImageError::Misaligned);
+    }
+    let start = kernel.boot_address;
+    let end = kernel.checked_end()?;
+    let overlaps = |base: u64, limit: u64| start < limit && base < end;
+
+    let (arm_base, arm_size) = mailbox::arm_memory().map_err(|_| ImageError::OutOfMemory)?;
+    if start < arm_base as u64 || end > arm_base as u64 + arm_size as u64 {
+        return Err(ImageError::OutOfMemory);
+    }
+    // the firmware stub with the spin table of the secondary cores is located in the first page
+    if overlaps(0, board::SPIN_TABLE_END) {
+        return Err(ImageError::OverlapsSpinTable);
+    }
+    // the loader code, its stacks and the retained memory as well as the heap holding the received binary need to
+    // be kept
+    let (loader_start, heap_end) = unsafe {
+        (
+            &__loader_start as *const u8 as u64,
+            &__heap_end as *const u8 as u64,
+        )
+    };
+    if overlaps(loader_start, heap_end) {
+        return Err(ImageError::OverlapsLoader);
+    }
+    if let Err(actual) = integrity::verify() {
+        error!(
+            "the loader has been overwritten, checksum {:#010x} instead of {:#010x}",
+            actual,
+            integrity::expected()
+        );
+        return Err(ImageError::LoaderCorrupted);
+    }
+    if args.x0 != 0 {
+        let fdt = unsafe { Fdt::from_address(args.x0) }.map_err(|_| ImageError::BadDeviceTree)?;
+        if overlaps(args.x0, args.x0 + fdt.total_size() as u64) {
+            return Err(ImageError::OverlapsDeviceTree);
+        }
+    }
+
+    Ok(end)
+}
+
+/// Copy the received binary, or the segments of an ELF kernel, to the address it shall be executed from and zero the
+/// memory beyond the binary the kernel image expects to be present, up to the end [validate_kernel] has checked.
+/// Nothing has been written if this fails.
+///
+/// # Safety
+/// The kernel need to be validated as this overwrites the memory the kernel is placed at.
+unsafe fn place_kernel(kernel: &Kernel) -> Result<(), ImageError> {
+    if kernel.elf.is_some() {
+        elf::load(&kernel.binary)?;
+    } else {
+        core::ptr::copy_nonoverlapping(
+            kernel.binary.as_ptr(),
+            kernel.boot_address as *mut u8,
+            kernel.binary.len(),
+        );
+    }
+    if kernel.image.is_some() {
+        // the image size is at least the size of the binary, so the memory to zero lies within the checked range
+        let start = kernel.boot_address + kernel.binary.len() as u64;
+        core::ptr::write_bytes(
+            start as *mut u8,
+            0,
+            kernel.end.saturating_sub(start) as usize,
+        );
+    }
+    Ok(())
+}
+
+/// Boot the kernel that has been received and placed with [place_kernel] passing the given arguments
+fn boot(kernel: Kernel, args: BootArgs) -> ! {
+    // the main loop is not running any more, so any output need to be sent right away
+    console::set_buffered(false);
+    info!("new kernel received, preparing re-boot...");
+    kernel_telemetry(TELEMETRY_BOOT, &kernel);
+    // an aarch32 kernel or a guest of the resident loader starts with the MMU of the loader active, so the kernel
+    // memory need to be mapped cacheable and executable
+    let kernel_start = kernel.boot_address & !0xFFF;
+    if let Err(err) = mmu::map_region(
+        kernel_start,
+        kernel.end - kernel_start,
+        MemoryAttributes::Normal,
+    ) {
+        warn!("kernel memory not mapped: {:?}", err);
+    }
+    // bare-metal kernels may use the boot services of the loader. The table holds aarch64 function pointers an
+    // aarch32 kernel cannot call, so those get 0 instead
+    let services = match kernel.boot_mode {
+        BootMode::Aarch64 => services::prepare(),
+        BootMode::Aarch32 => 0,
+    };
+    // kernels with the RusPiRo kernel header get the addresses of the board info and the services in their header
+    if let Some(offset) = kernel.header {
+        let header = (kernel.boot_address + offset as u64) as *mut KernelHeader;
+        unsafe {
+            (*header).board_info = services::board_info();
+            (*header).services = services;
+        }
+    }
+    // the loader may stay resident in EL2 running the kernel as guest
+    let resident =
+        kernel.boot_mode == BootMode::Aarch64 && kernel.entry_el == 1 && hyp::is_resident();
+    if resident {
+        hyp::prepare_guest();
+    }
+
+    // the kernel releases the secondary cores through the spin table, so they need to wait there
+    if !smp::wait_parked(PARK_TIMEOUT) {
+        warn!("secondary cores still running, the kernel could not release them");
+    }
+    // the DMA channel must not write to the memory of the loader while the kernel runs
+    #[cfg(feature = "uart_dma")]
+    pl011::stop_receive_dma();
+
+    // after we copied the new kernel to the right memory address clean and invalidate the
+    // caches to ensure the core sees the latest version of memory and instructions. The kernel memory is flushed by
+    // its addresses as well, the set and way operations do not reach the lines the cluster holds
+    cache::flush_for_execution(kernel_start, kernel.end - kernel_start);
+    cache::cleaninvalidate();
+    cache::invalidate_icache_all();
+
+    // the watchdog of the loader must not reset the device while the kernel is running
+    if watchdog().is_some() {
+        set_watchdog(None);
+    }
+    info!("re-boot in progress ...");
+
+    // do some arbitrary sleeping before the real re-boot...
+    // and print some "progressing points" to enable the host machine to
+    // start a terminal program and connect via uart after the data has been transmitted
+    for _ in 0..100 {
+        print!(".");
+        time::sleep(Duration::from_millis(15));
+    }
+
+    // the boot attempt is counted until the kernel signals a successful boot
+    retained::count_boot_attempt();
+
+    // restore as many stuff into the boot reset state as possible
+    // as this deactivates MMU no atomic operations from here
+    clean_up_for_reboot(kernel.boot_mode, resident);
+
+    // based on the kernel mode we could either "re-boot" immidiately or
+    // we need to switch to aarch32 mode
+    match kernel.boot_mode {
+        BootMode::Aarch64 if kernel.entry_el == 2 => boot_64_el2(kernel.entry(), &args, services),
+        BootMode::Aarch64 => boot_64(kernel.entry(), &args, services),
+        BootMode::Aarch32 => boot_32(kernel.entry(), &args.for_aarch32()),
+    }
+}
+
+/// Boot the code already placed at the address in the exception level, like a kernel written to the memory with the
+/// monitor. Nothing is copied, the ``size`` bytes from the address are flushed from the caches like the memory of a
+/// received kernel. The kernel gets the device tree and the arguments the firmware has passed to the loader.
+pub fn boot_in_place(address: u64, size: u64, aarch: BootMode, entry_el: u8) -> ! {
+    let mut kernel = Kernel::new(address, aarch, Vec::new());
+    kernel.entry_el = entry_el;
+    kernel.fixed_address = true;
+    // the monitor has checked the code to be placed below the peripherals
+    kernel.end = address + size;
+    let fw_args = bootargs::firmware();
+    let args = BootArgs {
+        x0: device_tree(&fw_args, &kernel),
+        ..fw_args
+    };
+    disable_interrupts();
+    boot(kernel, args)
+}
+
+/// Check the headers of the kernel image. The RusPiRo kernel header is validated if there is one. Based on the
+/// header of the kernel image the address the kernel is placed at is chosen. An aarch64 kernel with an arm64 Image
+/// header, like Linux, FreeBSD or NetBSD kernels, is placed at its text offset from a 2MB aligned base address.
+/// Kernels without such header keep their default address. The address requested by the host is always kept.
+fn inspect_kernel(kernel: &mut Kernel) -> Result<(), ImageError> {
+    // the loader runs in EL2, so EL3 firmware like TF-A BL31 could not be entered
+    match (kernel.boot_mode, kernel.entry_el) {
+        (_, 1) | (BootMode::Aarch64, 2) => (),
+        _ => return Err(ImageError::UnsupportedEntryLevel),
+    }
+    // the headers are only looked for in raw binaries, an ELF kernel is placed by its segments
+    if kernel.elf.is_some() {
+        return Ok(());
+    }
+    if let Some(header) = KernelHeader::find(&kernel.binary) {
+        let offset = header?;
+        debug!("RusPiRo kernel header found at offset {:#x}", offset);
+        kernel.header = Some(offset);
+    }
+    if kernel.boot_mode != BootMode::Aarch64 {
+        return Ok(());
+    }
+    let image = match Arm64Image::parse(&kernel.binary) {
+        Some(image) => image?,
+        None => return Ok(()),
+    };
+    kernel.image = Some(image);
+    if kernel.fixed_address {
+        return Ok(());
+    }
+    let loader_start = unsafe { &__loader_start as *const u8 as u64 };
+    // kernels like older Linux versions with a text offset of 0x80000 fit below the loader like any other kernel
+    kernel.boot_address = match (image.load_address(0), image.end(0)) {
+        (Some(low_address), Some(end))
+            if low_address >= KERNEL_ADDRESS_64 && end <= loader_start =>
+        {
+            low_address
+        }
+        _ => image.load_address(IMAGE_BASE).ok_or(ImageError::BadSize)?,
+    };
+    debug!(
+        "arm64 kernel image, text offset {:#x}, image size {:#x}, placed at {:#x}",
+        image.text_offset, image.image_size, kernel.boot_address
+    );
+
+    Ok(())
+}
+
 /// Do some clean up to reset as many as known used registers to their reset values which will make
 /// the re-boot from the bootloader compared to a usual cold boot on the device more predictable
-fn clean_up_for_reboot(boot_mode: u32) {
+fn clean_up_for_reboot(boot_mode: BootMode, resident: bool) {
     // typically the Pi boots with MMU disabled, so disabled it here before re-booting
     // however, disabling MMU in EL2 when switching to aarch32 has shown that the re-boot
     // process will hang for an unknown reason, so keep it active in aarch32 target boot as this
     // seem to work as expected...
-    if boot_mode != 32 {
+    // Staying resident in EL2 requires the MMU to be kept active as well
+    if boot_mode != BootMode::Aarch32 && !resident {
         mmu::disable_mmu();
     }
 }
 
+/// Provide the address of the device tree passed to the kernel. This is the device tree sent by the host or the
+/// one the firmware has loaded, patched with the loader settings if there are any. A device tree the kernel would
+/// be placed over is copied out of its way.
+fn device_tree(fw_args: &BootArgs, kernel: &Kernel) -> u64 {
+    let (address, origin) = DEVICE_TREE
+        .use_for(|tree| tree.as_ref().map(|blob| blob.as_ptr() as u64))
+        .map_or((fw_args.x0, "provided by the firmware"), |address| {
+            (address, "sent by the host")
+        });
+    let fdt = match unsafe { Fdt::from_address(address) } {
+        Ok(fdt) => fdt,
+        Err(err) => {
+            warn!("no valid device tree {}: {:?}", origin, err);
+            return 0;
+        }
+    };
+    debug!(
+        "device tree {} at {:#x}, size {} bytes",
+        origin,
+        address,
+        fdt.total_size()
+    );
+
+    // the boot counter and the kernel slots need to survive the kernel until the next reset
+    let patch = ChosenPatch {
+        bootargs: BOOTARGS,
+        initrd: None,
+        reserve: Some(retained::region()),
+    };
+    if !patch.is_empty() {
+        match fdt.patch_chosen(&patch) {
+            Ok(blob) => return keep_device_tree(&blob),
+            Err(err) => warn!("patching the device tree failed: {:?}", err),
+        }
+    }
+    // the boot protocol requires the device tree to be 8 byte aligned
+    if address % 8 != 0 || kernel.overlaps(address, address + fdt.total_size() as u64) {
+        let relocated = keep_device_tree(fdt.as_bytes());
+        debug!("device tree relocated to {:#x}", relocated);
+        return relocated;
+    }
+    address
+}
+
+/// Copy the device tree to 8 byte aligned memory that stays allocated until the kernel has taken over
+fn keep_device_tree(blob: &[u8]) -> u64 {
+    let mut copy = vec![0_u64; (blob.len() + 7) / 8];
+    let address = copy.as_mut_ptr() as u64;
+    unsafe { core::ptr::copy_nonoverlapping(blob.as_ptr(), address as *mut u8, blob.len()) };
+    Box::leak(copy.into_boxed_slice());
+    address
+}
+
+/// Branch into the aarch64 kernel at the given address passing the boot arguments and the boot
+/// services
+fn boot_64(addr: u64, args: &BootArgs, services: u64) -> ! {
+    unsafe { __boot_64(addr, args.x0, args.x1, args.x2, args.x3, services) }
+}
+
+/// Branch into the aarch64 payload at the given address staying in EL2. The secondary cores are kept in the
+/// spin table of the firmware, so the payload finds the same state as if it has been started by the firmware
+fn boot_64_el2(addr: u64, args: &BootArgs, services: u64) -> ! {
+    unsafe { __boot_64_el2(addr, args.x0, args.x1, args.x2, args.x3, services) }
+}
+
+/// Branch into the aarch32 kernel at the given address passing the boot arguments. The boot services are aarch64
+/// code only, so r4 is 0
+fn boot_32(addr: u64, args: &BootArgs) -> ! {
+    unsafe { __boot_32(addr, args.x0, args.x1, args.x2, args.x3, 0) }
+}
+
 /// Interrupt handler for the UART1 being triggered once new data was received
 ///
 /// # Safety
@@ -148,57 +1194,15 @@ fn clean_up_for_reboot(boot_mode: u32) {
 #[IrqHandler(Aux, Uart1)]
 fn uart_handler() {
     UART.use_for(|uart| {
-        // when the interrupt is triggered we clearly have data received
-        // check if this is the token the host need to send to initiate the transfer
-        // but do not block in case there is to less data received
-        let mut token: [u8; 8] = [0; 8];
-        if let Ok(size) = uart.try_receive_data(&mut token) {
-            if size == 8 && &token == b"DEADBEEF" {
-                // we got the token, so let the host know that we are ready
-                uart.send_string("ACK");
-                // as the transfer has been started we can now wait for the next
-                // data package containing the size of the kernel to be expected
-                // as well as the kernel architecture (aarch32/64). This could be
-                // a blocking receive call as this is the only thing to expect next
-                let mut metadata: [u8; 5] = [0; 5];
-                if uart.receive_data(&mut metadata).is_ok() {
-                    // extract the kernel size from the buffer
-                    //let tmp: [u8;4] = metadata[0..4];
-                    //let size = u32::from_be_bytes(metadata[0..4].try_into().unwrap()) as usize;
-                    let size = metadata[0] as usize
-                        | (metadata[1] as usize) << 8
-                        | (metadata[2] as usize) << 16
-                        | (metadata[3] as usize) << 24;
-                    // extract the kernel architecture type from the metadata buffer
-                    let aarch = metadata[4];
-                    // before receiving the binary create the buffer big enough to store the data
-                    let mut binary_vec = Vec::<u8>::with_capacity(size);
-                    // as the vector creation does not actually allocate memory call resize which
-                    // esnures the memory is allocated
-                    binary_vec.resize(size, 0);
-
-                    // now inform the host that we are ready to receive the data as the heavylifting
-                    // preparation is done
-                    uart.send_string("ACK");
-                    if uart.receive_data(&mut binary_vec).is_ok() {
-                        // let the host know that we have received the whole kernel
-                        uart.send_string("ACK");
-                        // the whole binary is loaded now so we could leave the interrupt handler
-                        // and let the main processing now that it can continue with preparing the
-                        // execution of this kernel
-                        let kernel = Kernel::new(
-                            match aarch {
-                                32 => 0x8000,
-                                64 => 0x80000,
-                                _ => 0x0,
-                            },
-                            aarch.into(),
-                            binary_vec,
-                        );
-                        KERNEL.replace(kernel);
-                        KERNEL_LOADED.up();
-                    }
-                }
+        // when the interrupt is triggered we clearly have data received. Move everything the UART holds into
+        // the receive queue, the main processing takes it from there
+        let mut data: [u8; 8] = [0; 8];
+        while let Ok(size) = uart.try_receive_data(&mut data) {
+            for &byte in data.iter().take(size) {
+                console::push_received(byte);
+            }
+            if size < data.len() {
+                break;
             }
         }
     });
@@ -0,0 +1,379 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Logging
+//!
+//! Diagnostic messages of the loader with a verbosity that could be changed at runtime with the ``log`` command.
+//! Messages with a level above the current one are dropped without being formatted. The output the host relies on,
+//! like acknowledges and command results, is not subject to the log level and always printed with [println!].
+//!
+//! The messages are printed as text lines by default, prefixed with the time since power on in seconds with
+//! microsecond resolution or the time passed since the previous message, as chosen with [set_timestamp], and the
+//! number of the core writing the message, like ``C0``. On terminals supporting ANSI escape sequences the lines are
+//! colored by their level, see [crate::console::set_ansi]. For machine readable boot records the binary format frames
+//! each message together with its timestamp, level and module. The loader additionally sends telemetry records in
+//! this format, like the parameters of the kernel it is about to start. A frame starts with [FRAME_START], a byte
+//! that never occurs in text, so the host could pick the frames from the console output. The frame layout is:
+//!
+//! | offset | size | content                                                 |
+//! |--------|------|---------------------------------------------------------|
+//! | 0      | 1    | [FRAME_START]                                           |
+//! | 1      | 1    | record type, [RECORD_LOG] or [RECORD_TELEMETRY]         |
+//! | 2      | 1    | the [Level] of log records, the kind of telemetry ones  |
+//! | 3      | 1    | the module id, see [MODULES]                            |
+//! | 4      | 8    | time since power on in µs, little endian                |
+//! | 12     | 2    | payload size, little endian                             |
+//! | 14     | n    | payload, the text of log records                        |
+//! | 14 + n | 4    | CRC-32 of the bytes from offset 1 up to the payload end |
+//!
+//! Regardless of the format the latest messages are kept as text in a small trace buffer, that becomes part of the
+//! crash dump if the loader crashes.
+//!
+
+use crate::console;
+use crate::crc;
+use crate::smp;
+use crate::time;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+/// The first byte of each binary frame. It is not valid in UTF-8 and thus never part of the text output.
+pub const FRAME_START: u8 = 0xF5;
+/// Record type of log messages
+pub const RECORD_LOG: u8 = 1;
+/// Record type of telemetry data
+pub const RECORD_TELEMETRY: u8 = 2;
+/// The modules with their ids being the position in this list starting at 1, 0 is used for any other module
+pub const MODULES: &[&str] = &["loader", "hyp", "command", "protocol", "services"];
+/// Log messages longer than this are truncated in the binary format
+const MAX_PAYLOAD: usize = 160;
+const FRAME_HEADER_SIZE: usize = 14;
+/// The size of the trace buffer keeping the latest log messages
+pub const TRACE_SIZE: usize = 512;
+
+/// The levels of the log messages in decreasing severity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+/// All levels in decreasing severity
+pub const LEVELS: [Level; 5] = [
+    Level::Error,
+    Level::Warn,
+    Level::Info,
+    Level::Debug,
+    Level::Trace,
+];
+
+impl Level {
+    /// The name of the level as used with the ``log`` command
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    /// The level with the given name
+    pub fn from_name(name: &str) -> Option<Level> {
+        LEVELS.iter().copied().find(|level| level.name() == name)
+    }
+
+    /// The SGR parameters of the ANSI escape sequence coloring the lines of the level, ``None`` for the default
+    /// color
+    pub fn color(self) -> Option<&'static str> {
+        match self {
+            Level::Error => Some("1;31"),
+            Level::Warn => Some("33"),
+            Level::Info => None,
+            Level::Debug => Some("36"),
+            Level::Trace => Some("2"),
+        }
+    }
+}
+
+/// The timestamp text lines are prefixed with
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Timestamp {
+    /// No timestamp
+    Off = 0,
+    /// The time since power on
+    Absolute = 1,
+    /// The time passed since the previous message
+    Delta = 2,
+}
+
+/// All timestamp modes
+pub const TIMESTAMPS: [Timestamp; 3] = [Timestamp::Off, Timestamp::Absolute, Timestamp::Delta];
+
+impl Timestamp {
+    /// The name of the timestamp mode as used with the ``logtime`` command
+    pub fn name(self) -> &'static str {
+        match self {
+            Timestamp::Off => "off",
+            Timestamp::Absolute => "absolute",
+            Timestamp::Delta => "delta",
+        }
+    }
+
+    /// The timestamp mode with the given name
+    pub fn from_name(name: &str) -> Option<Timestamp> {
+        TIMESTAMPS.iter().copied().find(|mode| mode.name() == name)
+    }
+}
+
+/// The latest log messages in a ring buffer
+struct Trace {
+    data: [u8; TRACE_SIZE],
+    /// The number of bytes written so far, the oldest byte is at this position once the buffer has wrapped around
+    written: usize,
+}
+
+impl Write for Trace {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.data[self.written % TRACE_SIZE] = byte;
+            self.written = self.written.wrapping_add(1);
+        }
+        Ok(())
+    }
+}
+
+/// Only the main core writes the trace buffer, so it does not need a lock
+static mut TRACE: Trace = Trace {
+    data: [0; TRACE_SIZE],
+    written: 0,
+};
+
+/// Copy the latest log messages, the oldest first, and return the number of bytes copied
+pub fn copy_trace(target: &mut [u8; TRACE_SIZE]) -> usize {
+    let trace = unsafe { &TRACE };
+    let len = core::cmp::min(trace.written, TRACE_SIZE);
+    let start = trace.written.wrapping_sub(len);
+    for (idx, byte) in target.iter_mut().take(len).enumerate() {
+        *byte = trace.data[start.wrapping_add(idx) % TRACE_SIZE];
+    }
+    len
+}
+
+/// The most verbose level currently printed
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+/// Log messages are sent in binary frames instead of text lines
+static BINARY: AtomicBool = AtomicBool::new(false);
+/// The timestamp text lines are prefixed with
+static TIMESTAMP: AtomicU8 = AtomicU8::new(Timestamp::Absolute as u8);
+/// The time of the previous message in µs since power on
+static LAST_MESSAGE: AtomicU64 = AtomicU64::new(0);
+
+/// Set the most verbose level to be printed
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// The most verbose level currently printed
+pub fn level() -> Level {
+    let level = LEVEL.load(Ordering::Relaxed);
+    LEVELS
+        .iter()
+        .copied()
+        .find(|&l| l as u8 == level)
+        .unwrap_or(Level::Info)
+}
+
+/// Check whether messages of the given level are printed
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Switch between the text and the binary format
+pub fn set_binary(binary: bool) {
+    BINARY.store(binary, Ordering::Relaxed);
+}
+
+/// Check whether the binary format is active
+pub fn is_binary() -> bool {
+    BINARY.load(Ordering::Relaxed)
+}
+
+/// Set the timestamp text lines are prefixed with
+pub fn set_timestamp(mode: Timestamp) {
+    TIMESTAMP.store(mode as u8, Ordering::Relaxed);
+}
+
+/// The timestamp text lines are prefixed with
+pub fn timestamp() -> Timestamp {
+    let mode = TIMESTAMP.load(Ordering::Relaxed);
+    TIMESTAMPS
+        .iter()
+        .copied()
+        .find(|&m| m as u8 == mode)
+        .unwrap_or(Timestamp::Absolute)
+}
+
+/// The id of the module with the given path
+pub fn module_id(path: &str) -> u8 {
+    let name = path.rsplit("::").next().unwrap_or(path);
+    MODULES
+        .iter()
+        .position(|&module| module == name)
+        .map_or(0, |idx| idx as u8 + 1)
+}
+
+/// Write the log message in the active format. This is used by the [log!] macro.
+pub fn write(level: Level, module: &str, args: fmt::Arguments) {
+    console::lock(|| write_locked(level, module, args))
+}
+
+fn write_locked(level: Level, module: &str, args: fmt::Arguments) {
+    let now = time::system_micros();
+    // the secondary cores run without the MMU and could neither share the trace buffer through the caches nor use
+    // the exclusive access the atomic swap relies on
+    let core = smp::core_id();
+    let main_core = core == 0;
+    if main_core {
+        let trace = unsafe { &mut TRACE };
+        let _ = write!(
+            trace,
+            "[{}.{:06}] {}\n",
+            now / 1_000_000,
+            now % 1_000_000,
+            args
+        );
+    }
+    if is_binary() {
+        let mut payload = Payload {
+            data: [0; MAX_PAYLOAD],
+            len: 0,
+        };
+        let _ = payload.write_fmt(args);
+        send_frame(
+            RECORD_LOG,
+            level as u8,
+            module_id(module),
+            &payload.data[..payload.len],
+        );
+    } else {
+        let last = if main_core {
+            // the status line is only drawn by the main core
+            console::clear_status();
+            LAST_MESSAGE.swap(now, Ordering::Relaxed)
+        } else {
+            LAST_MESSAGE.load(Ordering::Relaxed)
+        };
+        let color = level.color().filter(|_| console::is_ansi());
+        if let Some(color) = color {
+            console::print(format_args!("\x1b[{}m", color));
+        }
+        match timestamp() {
+            Timestamp::Off => (),
+            Timestamp::Absolute => console::print(format_args!(
+                "[{:5}.{:06}] ",
+                now / 1_000_000,
+                now % 1_000_000
+            )),
+            Timestamp::Delta => {
+                let delta = now.saturating_sub(last);
+                console::print(format_args!(
+                    "[+{:4}.{:06}] ",
+                    delta / 1_000_000,
+                    delta % 1_000_000
+                ))
+            }
+        }
+        console::print(format_args!("C{} ", core));
+        console::print(args);
+        if color.is_some() {
+            console::print(format_args!("\x1b[0m"));
+        }
+        console::print(format_args!("\r\n"));
+    }
+}
+
+/// Send a telemetry record of the given kind. Telemetry is only sent with the binary format as there is no text
+/// representation of the records.
+pub fn telemetry(module: &str, kind: u8, payload: &[u8]) {
+    if is_binary() {
+        send_frame(RECORD_TELEMETRY, kind, module_id(module), payload);
+    }
+}
+
+fn send_frame(record: u8, level: u8, module: u8, payload: &[u8]) {
+    let payload = &payload[..core::cmp::min(payload.len(), u16::MAX as usize)];
+    let timestamp = time::system_micros();
+    let mut header = [0; FRAME_HEADER_SIZE];
+    header[0] = FRAME_START;
+    header[1] = record;
+    header[2] = level;
+    header[3] = module;
+    header[4..12].copy_from_slice(&timestamp.to_le_bytes());
+    header[12..14].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+    let crc = crc::crc32_update(crc::crc32(&header[1..]), payload);
+    console::lock(|| {
+        console::send(&header);
+        console::send(payload);
+        console::send(&crc.to_le_bytes());
+    });
+}
+
+/// The payload of a binary log record, longer messages are truncated
+struct Payload {
+    data: [u8; MAX_PAYLOAD],
+    len: usize,
+}
+
+impl Write for Payload {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = core::cmp::min(s.len(), MAX_PAYLOAD - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Print a log message of the given level in the active format
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, module_path!(), format_args!($($arg)*));
+        }
+    };
+}
+
+/// Log an error the loader could not recover from
+macro_rules! error {
+    ($($arg:tt)*) => { log!($crate::log::Level::Error, $($arg)*) };
+}
+
+/// Log a problem the loader could work around
+macro_rules! warn {
+    ($($arg:tt)*) => { log!($crate::log::Level::Warn, $($arg)*) };
+}
+
+/// Log the regular progress of the loader
+macro_rules! info {
+    ($($arg:tt)*) => { log!($crate::log::Level::Info, $($arg)*) };
+}
+
+/// Log details that help to analyse a problem
+macro_rules! debug {
+    ($($arg:tt)*) => { log!($crate::log::Level::Debug, $($arg)*) };
+}
+
+/// Log every single step, this could slow down the loader considerably
+macro_rules! trace {
+    ($($arg:tt)*) => { log!($crate::log::Level::Trace, $($arg)*) };
+}
@@ -0,0 +1,215 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Mailbox
+//!
+//! Minimal access to the mailbox used to communicate with the VideoCore firmware. The property interface
+//! (channel 8) allows to query information like the memory split between the ARM and the VideoCore.
+//!
+
+use crate::board::{self, MAILBOX_BASE, VC_BUS_ALIAS, VC_BUS_LIMIT};
+use crate::time::{self, Duration};
+use core::ptr::{read_volatile, write_volatile};
+use ruspiro_cache as cache;
+
+const MAILBOX_READ: *mut u32 = board::register(MAILBOX_BASE, 0x00);
+const MAILBOX_STATUS: *mut u32 = board::register(MAILBOX_BASE, 0x18);
+const MAILBOX_WRITE: *mut u32 = board::register(MAILBOX_BASE, 0x20);
+const MAILBOX_FULL: u32 = 1 << 31;
+const MAILBOX_EMPTY: u32 = 1 << 30;
+/// The firmware usually answers within a few milliseconds, a request not answered within this time has failed
+const MAILBOX_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The mailbox channel of the property interface
+pub const CHANNEL_PROPERTY: u8 = 8;
+
+/// Property tag to query the board revision
+pub const TAG_BOARD_REVISION: u32 = 0x0001_0002;
+/// Property tag to query the board serial number
+pub const TAG_BOARD_SERIAL: u32 = 0x0001_0004;
+/// Property tag to query the memory split assigned to the ARM
+pub const TAG_ARM_MEMORY: u32 = 0x0001_0005;
+/// Property tag to query the memory split assigned to the VideoCore
+pub const TAG_VC_MEMORY: u32 = 0x0001_0006;
+/// Property tag to query the rate of a clock
+pub const TAG_GET_CLOCK_RATE: u32 = 0x0003_0002;
+/// Property tag to query the under-voltage and throttling state of the board
+pub const TAG_GET_THROTTLED: u32 = 0x0003_0046;
+/// Property tag to switch the power of a device on or off
+pub const TAG_SET_POWER_STATE: u32 = 0x0002_8001;
+/// Property tag to set the state of a pin of the GPIO expander managed by the firmware
+pub const TAG_SET_GPIO_STATE: u32 = 0x0003_8041;
+/// Property tag to query the DMA channels the firmware leaves to the ARM
+#[cfg(feature = "uart_dma")]
+pub const TAG_GET_DMA_CHANNELS: u32 = 0x0006_0001;
+
+/// The device ids used with the power state property
+pub const POWER_SD_CARD: u32 = 0;
+pub const POWER_USB_HCD: u32 = 3;
+
+/// The clock ids used with the clock rate property
+pub const CLOCK_EMMC: u32 = 1;
+pub const CLOCK_UART: u32 = 2;
+
+const POWER_ON: u32 = 1 << 0;
+const POWER_WAIT: u32 = 1 << 1;
+
+const REQUEST: u32 = 0x0;
+const RESPONSE_SUCCESS: u32 = 0x8000_0000;
+/// The number of value words a property buffer can hold
+const MAX_VALUES: usize = 32;
+
+/// Errors when calling the mailbox
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MailboxError {
+    /// The buffer address does not fit the requirements of the mailbox
+    BadBuffer,
+    /// The firmware has not processed the request successfully
+    Failed,
+    /// The firmware has not answered in time
+    Timeout,
+}
+
+/// Memory buffer passed to the firmware for property calls. The mailbox requires a 16 byte aligned address
+#[repr(C, align(16))]
+struct PropertyBuffer {
+    data: [u32; MAX_VALUES + 6],
+}
+
+/// Send the buffer to the given mailbox channel and wait for the firmware to respond. This is doing the plain
+/// mailbox communication without any cache maintenance and is usable with and without MMU being active.
+///
+/// # Safety
+/// The buffer need to point to 16 byte aligned memory containing a valid request for the given channel.
+pub unsafe fn call_raw(channel: u8, buffer: *mut u32) -> Result<(), MailboxError> {
+    let addr = buffer as usize;
+    if addr & 0xF != 0 || addr as u64 >= VC_BUS_LIMIT {
+        return Err(MailboxError::BadBuffer);
+    }
+    // the VideoCore expects the buffer address in its own bus address space using the uncached alias
+    let message = (addr as u32 | VC_BUS_ALIAS) | channel as u32;
+    if !time::wait_for(MAILBOX_TIMEOUT, || {
+        read_volatile(MAILBOX_STATUS) & MAILBOX_FULL == 0
+    }) {
+        return Err(MailboxError::Timeout);
+    }
+    write_volatile(MAILBOX_WRITE, message);
+    // responses to other requests are skipped until the response to this one arrives
+    let answered = time::wait_for(MAILBOX_TIMEOUT, || {
+        read_volatile(MAILBOX_STATUS) & MAILBOX_EMPTY == 0 && read_volatile(MAILBOX_READ) == message
+    });
+    if answered {
+        Ok(())
+    } else {
+        Err(MailboxError::Timeout)
+    }
+}
+
+/// Query a single property from the firmware. The request values are passed to the firmware and the response
+/// values are written to the given response slice. The number of response bytes the firmware reported is returned.
+pub fn property(tag: u32, request: &[u32], response: &mut [u32]) -> Result<usize, MailboxError> {
+    let value_words = core::cmp::max(request.len(), response.len());
+    if value_words > MAX_VALUES {
+        return Err(MailboxError::BadBuffer);
+    }
+    let mut buffer = PropertyBuffer {
+        data: [0; MAX_VALUES + 6],
+    };
+    buffer.data[0] = ((value_words + 6) * 4) as u32;
+    buffer.data[1] = REQUEST;
+    buffer.data[2] = tag;
+    buffer.data[3] = (value_words * 4) as u32;
+    buffer.data[4] = REQUEST;
+    buffer.data[5..5 + request.len()].copy_from_slice(request);
+    // the end tag is already in place as the buffer is zero initialized
+
+    // the firmware reads and writes the buffer bypassing the caches of the ARM core, so ensure the request
+    // is in memory and the response is read from memory
+    cache::cleaninvalidate();
+    unsafe { call_raw(CHANNEL_PROPERTY, buffer.data.as_mut_ptr())? };
+    cache::cleaninvalidate();
+
+    let result = unsafe { read_volatile(&buffer.data) };
+    if result[1] != RESPONSE_SUCCESS || result[4] & RESPONSE_SUCCESS == 0 {
+        return Err(MailboxError::Failed);
+    }
+    let size = (result[4] & !RESPONSE_SUCCESS) as usize;
+    let words = core::cmp::min(response.len(), (size + 3) / 4);
+    response[..words].copy_from_slice(&result[5..5 + words]);
+
+    Ok(size)
+}
+
+/// Get the revision code of the board
+pub fn board_revision() -> Result<u32, MailboxError> {
+    let mut response = [0; 1];
+    property(TAG_BOARD_REVISION, &[], &mut response)?;
+    Ok(response[0])
+}
+
+/// Get the serial number of the board
+pub fn board_serial() -> Result<u64, MailboxError> {
+    let mut response = [0; 2];
+    property(TAG_BOARD_SERIAL, &[], &mut response)?;
+    Ok(response[0] as u64 | (response[1] as u64) << 32)
+}
+
+/// Get base address and size of the memory assigned to the ARM
+pub fn arm_memory() -> Result<(u32, u32), MailboxError> {
+    let mut response = [0; 2];
+    property(TAG_ARM_MEMORY, &[], &mut response)?;
+    Ok((response[0], response[1]))
+}
+
+/// Get base address and size of the memory assigned to the VideoCore
+pub fn vc_memory() -> Result<(u32, u32), MailboxError> {
+    let mut response = [0; 2];
+    property(TAG_VC_MEMORY, &[], &mut response)?;
+    Ok((response[0], response[1]))
+}
+
+/// Get the rate of the given clock in Hz
+pub fn clock_rate(clock: u32) -> Result<u32, MailboxError> {
+    let mut response = [0; 2];
+    property(TAG_GET_CLOCK_RATE, &[clock], &mut response)?;
+    Ok(response[1])
+}
+
+/// Get the under-voltage and throttling state, the lower bits hold the current state and the bits from 16 on the
+/// conditions that occurred since power on
+pub fn throttled() -> Result<u32, MailboxError> {
+    let mut response = [0; 1];
+    property(TAG_GET_THROTTLED, &[0], &mut response)?;
+    Ok(response[0])
+}
+
+/// Switch the power of the given device on or off and wait until the new state is reached
+pub fn set_power_state(device: u32, on: bool) -> Result<(), MailboxError> {
+    let state = if on {
+        POWER_ON | POWER_WAIT
+    } else {
+        POWER_WAIT
+    };
+    let mut response = [0; 2];
+    property(TAG_SET_POWER_STATE, &[device, state], &mut response)?;
+    Ok(())
+}
+
+/// Get the mask of the DMA channels the firmware does not use itself and leaves to the ARM
+#[cfg(feature = "uart_dma")]
+pub fn dma_channels() -> Result<u32, MailboxError> {
+    let mut response = [0; 1];
+    property(TAG_GET_DMA_CHANNELS, &[], &mut response)?;
+    Ok(response[0])
+}
+
+/// Set the output state of a pin of the GPIO expander managed by the firmware
+pub fn set_gpio_state(gpio: u32, on: bool) -> Result<(), MailboxError> {
+    let mut response = [0; 2];
+    property(TAG_SET_GPIO_STATE, &[gpio, on as u32], &mut response)?;
+    Ok(())
+}
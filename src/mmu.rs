@@ -7,19 +7,94 @@
 
 //! # MMU maintenance
 //!
+//! The loader runs with an identity map of the physical memory. The translation tables are taken from a small pool
+//! of statically allocated tables as regions are mapped with [map_region]: the level 1 table covers 1GB with each
+//! entry, level 2 tables cover 2MB blocks and level 3 tables cover 4kB pages. A region is mapped with blocks where
+//! its start and size allow, the blocks at its borders are split into pages.
+//!
+//! The memory of the board and its peripherals are mapped from the board constants at startup. Regions could be
+//! mapped again with other attributes later, as long as the loader does not run from or use the memory whose
+//! mapping changes.
+//!
+use crate::{board, time};
 use ruspiro_register::system::*;
 
+/// The number of entries of a translation table
+const ENTRIES: usize = 512;
+/// The number of translation tables, enough for the memory map of the boards and a few regions mapped with pages
+const TABLE_COUNT: usize = 16;
+/// The memory covered by an entry of the level 1 table
+const LEVEL1_SIZE: u64 = 0x4000_0000;
+const PAGE_SIZE: u64 = 0x1000;
+/// The physical address space configured in TCR_EL2
+const ADDRESS_LIMIT: u64 = 0x1_0000_0000;
+
+/// descriptor types and attributes
+const VALID_BLOCK: u64 = 0b01;
+const VALID_TABLE: u64 = 0b11;
+const VALID_PAGE: u64 = 0b11;
+const TABLE_NS: u64 = 1 << 63;
+const ADDRESS_MASK: u64 = 0x0000_FFFF_FFFF_F000;
+const AF: u64 = 1 << 10;
+const XN: u64 = 1 << 54;
+const INNER_SHAREABLE: u64 = 0b11 << 8;
+const OUTER_SHAREABLE: u64 = 0b10 << 8;
+/// the MAIR indices, see [initialize_mmu]
+const MAIR_NGNRNE: u64 = 0 << 2;
+const MAIR_NC: u64 = 3 << 2;
+const MAIR_NORM: u64 = 4 << 2;
+
+/// The attributes a memory region is mapped with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryAttributes {
+    /// Cacheable memory code could be executed from
+    Normal,
+    /// Cacheable memory that is never executed
+    NormalNoExecute,
+    /// Memory bypassing the caches, e.g. for buffers shared with the VideoCore
+    NonCacheable,
+    /// Peripheral registers
+    Device,
+}
+
+impl MemoryAttributes {
+    /// The attribute bits of a block or page descriptor
+    fn descriptor(self) -> u64 {
+        match self {
+            MemoryAttributes::Normal => AF | INNER_SHAREABLE | MAIR_NORM,
+            MemoryAttributes::NormalNoExecute => XN | AF | INNER_SHAREABLE | MAIR_NORM,
+            MemoryAttributes::NonCacheable => XN | AF | OUTER_SHAREABLE | MAIR_NC,
+            MemoryAttributes::Device => XN | AF | MAIR_NGNRNE,
+        }
+    }
+}
+
+/// Reasons why a region could not be mapped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MmuError {
+    /// The region does not start at a page boundary
+    Misaligned,
+    /// The region reaches beyond the physical address space
+    OutOfRange,
+    /// There are no translation tables left to map the region with pages
+    OutOfTables,
+}
+
 #[repr(align(4096))]
-struct MmuConfig {
-    ttlb_lvl0: [u64; 512],
-    ttlb_lvl1: [u64; 513],
+struct Table([u64; ENTRIES]);
+
+/// The pool of translation tables, the first one is the level 1 table the table walk starts with
+struct Tables {
+    tables: [Table; TABLE_COUNT],
+    used: usize,
 }
 
-/// level 0 translation table, each entry covering 1GB of memory
-/// level 1 translation table, each entry covering 2MB of memory
-static mut MMU_CFG: MmuConfig = MmuConfig {
-    ttlb_lvl0: [0; 512],
-    ttlb_lvl1: [0; 513],
+static mut TABLES: Tables = {
+    const EMPTY: Table = Table([0; ENTRIES]);
+    Tables {
+        tables: [EMPTY; TABLE_COUNT],
+        used: 1,
+    }
 };
 
 pub fn initialize_mmu(core: u32) {
@@ -45,7 +120,7 @@ pub fn initialize_mmu(core: u32) {
 
     // set the ttlb base address, this is where the memory address translation
     // table walk starts
-    let ttlb_base = unsafe { (&MMU_CFG.ttlb_lvl0[0] as *const u64) as u64 };
+    let ttlb_base = unsafe { TABLES.tables[0].0.as_ptr() as u64 };
     ttbr0_el2::write(ttbr0_el2::baddr::with_value(ttlb_base));
 
     // configure the TTLB attributes
@@ -70,24 +145,35 @@ pub fn initialize_mmu(core: u32) {
             | sctlr_el2::I::DISABLE,
     );
 
-    // let 2 cycles pass with a nop to settle the MMU
-    nop();
-    nop();
+    // the instructions following need to be fetched with the MMU enabled
+    time::barrier();
 }
 
 pub fn disable_mmu() {
     // disabling the MMU will also disable data and instruction cache
     sctlr_el2::write(sctlr_el2::M::DISABLE | sctlr_el2::C::DISABLE | sctlr_el2::I::DISABLE);
-    // let 2 cycles pass with a nop to settle the MMU
-    nop();
-    nop();
-    // as we have switched of the MMU we might also invalidate all TTLB entries
-    unsafe {
-        llvm_asm!(
-            "dsb sy                   
-             isb"
-        )
-    };
+    // the instructions following need to be fetched with the MMU disabled
+    time::barrier();
+}
+
+/// Identity map the memory from ``start`` with the given attributes. The size is rounded up to whole pages. Memory
+/// already mapped with the same attributes is left as it is, so mapping a region within a block does not split
+/// the block unless its attributes change.
+///
+/// The tables are only changed by the main core, the secondary cores run without the MMU.
+pub fn map_region(start: u64, size: u64, attributes: MemoryAttributes) -> Result<(), MmuError> {
+    if start % PAGE_SIZE != 0 {
+        return Err(MmuError::Misaligned);
+    }
+    let end = start
+        .checked_add(size)
+        .filter(|&end| end <= ADDRESS_LIMIT)
+        .ok_or(MmuError::OutOfRange)?;
+    let end = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let result = unsafe { map(start, end, attributes.descriptor()) };
+    // the entries written so far are in effect even if the region could not be mapped entirely
+    flush_tlb();
+    result
 }
 
 /// # Safety
@@ -95,37 +181,103 @@ pub fn disable_mmu() {
 /// the main core booting up first only. As long as the MMU is not up and running there is no way
 /// to secure access with atmic operations as they require the MMU to not hang the core
 fn setup_page_tables() {
-    // initial MMU page table setup
-    // this first attempt provides very huge configuration blocks, meaning we
-    // setup the smallest unit to cover 2Mb blocks of memory sharing the same memory attributes
-    unsafe {
-        let level1_addr_1 = &MMU_CFG.ttlb_lvl1[0] as *const u64;
-        let level1_addr_2 = &MMU_CFG.ttlb_lvl1[512] as *const u64;
-
-        // the entries in level 0 (covering 1GB each) need to point to the next level table
-        // that contains more granular config
-        MMU_CFG.ttlb_lvl0[0] = 0x1 << 63 | (level1_addr_1 as u64) | 0b11;
-        MMU_CFG.ttlb_lvl0[1] = 0x1 << 63 | (level1_addr_2 as u64) | 0b11;
-
-        // the entries in level 1 (covering 2MB each) contain the specific memory attributes for
-        // this memory area
-        // first entries up to 0x3F00_0000 are "normal" memory
-        for i in 0..504 {
-            // 1:1 memory mapping with it's attributes
-            // AF = 1 << 10, SH = 3 << 8, MAIR index = 4 << 2
-            MMU_CFG.ttlb_lvl1[i] = (i as u64 * 0x20_0000) | 0x710 | 0b01;
+    // the memory up to the end of the memory or the peripherals is "normal" memory, the blocks between a smaller
+    // memory and the peripherals stay unmapped. The peripherals and the ARM local peripherals are "device" memory
+    let regions = [
+        (
+            0,
+            board::MEMORY_SIZE.min(board::PERIPHERAL_BASE),
+            MemoryAttributes::Normal,
+        ),
+        (
+            board::PERIPHERAL_BASE,
+            board::PERIPHERAL_SIZE,
+            MemoryAttributes::Device,
+        ),
+        (
+            board::ARM_LOCAL_BASE,
+            board::ARM_LOCAL_SIZE,
+            MemoryAttributes::Device,
+        ),
+    ];
+    for &(start, size, attributes) in regions.iter() {
+        // the pool is sized for the memory map of the boards, nothing could be reported this early anyway
+        let _ = map_region(start, size, attributes);
+    }
+}
+
+/// Map the pages from ``start`` to ``end`` with the attribute bits, using blocks where possible
+unsafe fn map(start: u64, end: u64, attributes: u64) -> Result<(), MmuError> {
+    let mut address = start;
+    while address < end {
+        let root = TABLES.tables[0].0.as_mut_ptr();
+        let level2 = next_table(root.add((address / LEVEL1_SIZE) as usize), LEVEL1_SIZE)?;
+        let block = level2.add((address % LEVEL1_SIZE / board::BLOCK_SIZE) as usize);
+        let block_start = address / board::BLOCK_SIZE * board::BLOCK_SIZE;
+        let block_end = block_start + board::BLOCK_SIZE;
+        if *block == block_start | attributes | VALID_BLOCK {
+            address = block_end.min(end);
+        } else if address % board::BLOCK_SIZE == 0 && block_end <= end && !is_table(*block) {
+            write_entry(block, address | attributes | VALID_BLOCK);
+            address = block_end;
+        } else {
+            let pages = next_table(block, board::BLOCK_SIZE)?;
+            while address < block_end.min(end) {
+                let page = pages.add((address % board::BLOCK_SIZE / PAGE_SIZE) as usize);
+                write_entry(page, address | attributes | VALID_PAGE);
+                address += PAGE_SIZE;
+            }
         }
+    }
+    Ok(())
+}
 
-        // entries from 0x3F00_0000 to 0x4020_0000 are "device" memory
-        for i in 504..513 {
-            // 1:1 memory mapping with it's attributes
-            // AF = 1 << 10, SH = 0 << 8, MAIR index = 0 << 2
-            MMU_CFG.ttlb_lvl1[i] = (i as u64 * 0x20_0000) | 0x400 | 0b01;
+/// The table the entry points to. An invalid entry gets an empty table of its own, a block is split into a table of
+/// smaller blocks or pages with the same attributes.
+unsafe fn next_table(entry: *mut u64, entry_size: u64) -> Result<*mut u64, MmuError> {
+    if is_table(*entry) {
+        return Ok((*entry & ADDRESS_MASK) as *mut u64);
+    }
+    if TABLES.used == TABLE_COUNT {
+        return Err(MmuError::OutOfTables);
+    }
+    let table = TABLES.tables[TABLES.used].0.as_mut_ptr();
+    TABLES.used += 1;
+    if *entry & VALID_BLOCK != 0 {
+        let size = entry_size / ENTRIES as u64;
+        let kind = if size == PAGE_SIZE {
+            VALID_PAGE
+        } else {
+            VALID_BLOCK
+        };
+        let base = *entry & ADDRESS_MASK;
+        let attributes = *entry & !ADDRESS_MASK & !VALID_TABLE;
+        for idx in 0..ENTRIES {
+            write_entry(table.add(idx), base + idx as u64 * size | attributes | kind);
         }
+    }
+    write_entry(entry, TABLE_NS | table as u64 | VALID_TABLE);
+    Ok(table)
+}
+
+fn is_table(entry: u64) -> bool {
+    entry & VALID_TABLE == VALID_TABLE
+}
+
+/// Write the descriptor and clean it to memory, the table walk may not look it up in the caches
+unsafe fn write_entry(entry: *mut u64, descriptor: u64) {
+    core::ptr::write_volatile(entry, descriptor);
+    llvm_asm!("dc civac, $0" :: "r"(entry) :: "volatile");
+}
 
+/// Wait for the changed descriptors to be written and drop all translations looked up so far
+fn flush_tlb() {
+    unsafe {
         llvm_asm!(
-            "dsb   ishst
-             tlbi  alle2is"
+            "dsb   ish
+             tlbi  alle2is
+             dsb   ish
+             isb" :::: "volatile"
         );
     }
 }
@@ -0,0 +1,145 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Board model
+//!
+//! The model of the board the loader is running on, decoded from the revision code the firmware reports. All
+//! boards with a 64 bit SoC use the new style revision codes:
+//!
+//! | Bits  | Content                                                    |
+//! |-------|------------------------------------------------------------|
+//! | 0-3   | revision of the board                                      |
+//! | 4-11  | model, e.g. 0x0d for the Raspberry Pi 3 Model B+           |
+//! | 12-15 | SoC, 2 for the BCM2837 and 3 for the BCM2711               |
+//! | 16-19 | manufacturer                                               |
+//! | 20-22 | memory size, 256MB shifted left by the value               |
+//! | 23    | set for new style revision codes                           |
+//!
+//! The board is detected once at startup. The parts of the board that could differ between the boards sharing a
+//! build of the loader, like the activity LED and the pins of the second UART, are chosen from the model detected,
+//! while the memory map is fixed when the loader is linked and only checked against the memory of the board.
+//!
+
+use crate::mailbox;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+const NEW_STYLE: u32 = 1 << 23;
+
+/// The model numbers of the boards
+pub const MODEL_3B: u8 = 0x08;
+pub const MODEL_CM3: u8 = 0x0A;
+pub const MODEL_3B_PLUS: u8 = 0x0D;
+pub const MODEL_3A_PLUS: u8 = 0x0E;
+pub const MODEL_CM3_PLUS: u8 = 0x10;
+pub const MODEL_4B: u8 = 0x11;
+pub const MODEL_ZERO_2W: u8 = 0x12;
+pub const MODEL_400: u8 = 0x13;
+pub const MODEL_CM4: u8 = 0x14;
+
+/// The revision code detected at startup, 0 if it is not known
+static REVISION: AtomicU32 = AtomicU32::new(0);
+
+/// The SoC of a board
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Soc {
+    Bcm2835,
+    Bcm2836,
+    Bcm2837,
+    Bcm2711,
+    Unknown(u8),
+}
+
+/// A new style revision code of a board
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Revision(u32);
+
+impl Revision {
+    /// Decode the revision code, ``None`` for old style codes
+    pub fn new(code: u32) -> Option<Self> {
+        Some(Revision(code)).filter(|_| code & NEW_STYLE != 0)
+    }
+
+    pub fn model(&self) -> u8 {
+        (self.0 >> 4) as u8
+    }
+
+    pub fn soc(&self) -> Soc {
+        match (self.0 >> 12 & 0xF) as u8 {
+            0 => Soc::Bcm2835,
+            1 => Soc::Bcm2836,
+            2 => Soc::Bcm2837,
+            3 => Soc::Bcm2711,
+            soc => Soc::Unknown(soc),
+        }
+    }
+
+    /// Compute modules leave the activity LED and the pins brought out to the carrier board
+    pub fn is_compute_module(&self) -> bool {
+        matches!(self.model(), MODEL_CM3 | MODEL_CM3_PLUS | MODEL_CM4)
+    }
+
+    /// The size of the memory in bytes
+    pub fn memory_size(&self) -> u64 {
+        0x1000_0000 << (self.0 >> 20 & 0x7)
+    }
+
+    /// The name of the model, ``None`` if it is not known to the loader
+    pub fn model_name(&self) -> Option<&'static str> {
+        let name = match self.model() {
+            MODEL_3B => "Raspberry Pi 3 Model B",
+            MODEL_CM3 => "Compute Module 3",
+            MODEL_3B_PLUS => "Raspberry Pi 3 Model B+",
+            MODEL_3A_PLUS => "Raspberry Pi 3 Model A+",
+            MODEL_CM3_PLUS => "Compute Module 3+",
+            MODEL_4B => "Raspberry Pi 4 Model B",
+            MODEL_ZERO_2W => "Raspberry Pi Zero 2 W",
+            MODEL_400 => "Raspberry Pi 400",
+            MODEL_CM4 => "Compute Module 4",
+            _ => return None,
+        };
+        Some(name)
+    }
+}
+
+impl fmt::Display for Revision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.model_name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "unknown model {:#04x}", self.model())?,
+        }
+        write!(
+            f,
+            " rev 1.{}, {:?}, {}MB",
+            self.0 & 0xF,
+            self.soc(),
+            self.memory_size() >> 20
+        )
+    }
+}
+
+/// Query the revision code from the firmware and keep it for [current]
+pub fn detect() -> Option<Revision> {
+    let code = mailbox::board_revision().unwrap_or(0);
+    REVISION.store(code, Ordering::Release);
+    Revision::new(code)
+}
+
+/// The revision detected at startup, ``None`` if it is not known
+pub fn current() -> Option<Revision> {
+    Revision::new(REVISION.load(Ordering::Acquire))
+}
+
+/// Check whether the loader runs on the given model
+pub fn is(model: u8) -> bool {
+    current().map_or(false, |revision| revision.model() == model)
+}
+
+/// Check whether the loader runs on a compute module
+pub fn is_compute_module() -> bool {
+    current().map_or(false, |revision| revision.is_compute_module())
+}
@@ -0,0 +1,376 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Monitor
+//!
+//! An interactive monitor for a terminal program connected to one of the UARTs. It is entered by pressing Enter
+//! within [WINDOW] after the loader has started waiting for a kernel, or later on with the ``monitor`` command. The
+//! typed characters are echoed and could be erased with Backspace, Ctrl-C drops the whole line.
+//!
+//! The monitor commands inspect the board and its memory: ``md`` dumps and ``mw`` writes 32 bit words of the memory
+//! or the peripherals, ``info`` shows the board revision, the exception level and the state of the MMU, and
+//! ``boot`` starts code already placed in memory in EL1 or EL2. ``mw`` and ``boot`` would start code that has not
+//! been verified, so they are refused while the loader requires signed kernels. Any other line is executed as loader
+//! command, so e.g. ``xmodem`` receives a kernel from the terminal program. ``load`` leaves the monitor and the loader
+//! waits for the host tool sending a kernel again.
+//!
+//! Only the carriage return a terminal sends for Enter opens the monitor. The host tool terminates its command lines
+//! with a line feed, so it never opens the monitor by accident.
+//!
+
+use crate::board;
+use crate::cache;
+use crate::command::{self, Command, CommandError};
+use crate::image::BootMode;
+use crate::loader;
+use crate::model;
+use crate::time::{self, Duration, Instant};
+use crate::verify;
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use ruspiro_loader_protocol::Received;
+use ruspiro_singleton::Singleton;
+
+/// The time after startup the monitor could be entered by pressing Enter
+const WINDOW: Duration = Duration::from_secs(2);
+/// The longest line the monitor accepts, further characters are dropped
+const MAX_LINE_LEN: usize = 128;
+/// The number of bytes ``md`` dumps if no length is given
+const DEFAULT_DUMP_SIZE: u64 = 0x100;
+/// The most bytes ``md`` dumps at once
+const MAX_DUMP_SIZE: u64 = 0x1_0000;
+/// The 32 bit words dumped per line
+const WORDS_PER_LINE: u64 = 4;
+/// The size of the pages the code started with ``boot`` is flushed from the caches in
+const PAGE_SIZE: u64 = 0x1000;
+const PROMPT: &str = "> ";
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const CTRL_C: u8 = 0x03;
+
+/// The commands only available within the monitor
+static MONITOR_COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "",
+        help: "list the monitor commands followed by the loader commands",
+        run: help,
+    },
+    Command {
+        name: "boot",
+        usage: "<addr> [32|64] [el1|el2]",
+        help: "boot the aarch64 or the given kernel already placed at the address in EL1 or EL2",
+        run: boot,
+    },
+    Command {
+        name: "info",
+        usage: "",
+        help: "show the board revision, the exception level and the state of the MMU",
+        run: info,
+    },
+    Command {
+        name: "load",
+        usage: "",
+        help: "leave the monitor and wait for a kernel sent by the host tool",
+        run: load,
+    },
+    Command {
+        name: "md",
+        usage: "<addr> [<len>]",
+        help: "dump the 32 bit words of memory or peripherals, 256 bytes by default",
+        run: md,
+    },
+    Command {
+        name: "mw",
+        usage: "<addr> <value>",
+        help: "write the 32 bit value to memory or a peripheral register at the address",
+        run: mw,
+    },
+];
+
+/// The monitor session on the transport a terminal is connected to
+struct Session {
+    transport: usize,
+    line: String,
+}
+
+static SESSION: Singleton<Option<Session>> = Singleton::new(None);
+/// The counter ticks the monitor could be entered with Enter until, 0 once the window is closed
+static WINDOW_END: AtomicU64 = AtomicU64::new(0);
+/// The ``monitor`` command has requested to enter the monitor
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+/// The ``load`` command has requested to leave the monitor
+static LEAVE: AtomicBool = AtomicBool::new(false);
+
+/// What a received byte has done to the line being edited
+enum Edit {
+    /// The line is still being edited
+    Pending,
+    /// The line has been completed
+    Line(String),
+}
+
+/// Allow the monitor to be entered with Enter for the next [WINDOW]
+pub fn open_window() {
+    WINDOW_END.store(
+        Instant::now().ticks() + time::ticks(WINDOW),
+        Ordering::Release,
+    );
+    info!(
+        "press Enter within {}s to enter the monitor",
+        WINDOW.as_secs()
+    );
+}
+
+/// Request to enter the monitor on the transport of the command currently executed once it has been completed
+pub fn request() {
+    REQUESTED.store(true, Ordering::Release);
+}
+
+/// Check whether the monitor is running
+pub fn is_active() -> bool {
+    SESSION.use_for(|session| session.is_some())
+}
+
+/// Process a byte received on the transport. ``idle`` tells whether the loader waits for a new request on the
+/// transport, only then the monitor could be entered. Returns ``true`` if the byte has been taken by the monitor.
+/// While the monitor is running it takes the bytes of all transports, those received on other transports than the
+/// one of the terminal are dropped.
+pub fn receive(transport: usize, byte: u8, idle: bool) -> bool {
+    if !is_active() {
+        let in_window = Instant::now().ticks() < WINDOW_END.load(Ordering::Acquire);
+        if !(idle && in_window && byte == b'\r') {
+            return false;
+        }
+        enter(transport);
+        return true;
+    }
+    let edit = SESSION.take_for(|session| match session {
+        Some(session) if session.transport == transport => edit_line(&mut session.line, byte),
+        _ => Edit::Pending,
+    });
+    if let Edit::Line(line) = edit {
+        execute(&line);
+        if LEAVE.swap(false, Ordering::AcqRel) {
+            SESSION.take_for(|session| *session = None);
+            info!("monitor left, waiting for a new kernel...");
+        } else {
+            print!("{}", PROMPT);
+        }
+    }
+    true
+}
+
+/// Enter the monitor on the transport if the ``monitor`` command has requested it
+pub fn take_request(transport: usize) {
+    if REQUESTED.swap(false, Ordering::AcqRel) {
+        enter(transport);
+    }
+}
+
+fn enter(transport: usize) {
+    WINDOW_END.store(0, Ordering::Release);
+    SESSION.take_for(|session| {
+        *session = Some(Session {
+            transport,
+            line: String::new(),
+        })
+    });
+    println!();
+    println!("RusPiRo loader monitor, 'help' lists the commands");
+    print!("{}", PROMPT);
+}
+
+/// Add the byte to the line, echoing it to the terminal
+fn edit_line(line: &mut String, byte: u8) -> Edit {
+    match byte {
+        b'\r' => {
+            println!();
+            return Edit::Line(core::mem::take(line));
+        }
+        BACKSPACE | DELETE => {
+            if line.pop().is_some() {
+                print!("\x08 \x08");
+            }
+        }
+        CTRL_C => {
+            line.clear();
+            println!("^C");
+            print!("{}", PROMPT);
+        }
+        b' '..=b'~' if line.len() < MAX_LINE_LEN => {
+            line.push(byte as char);
+            print!("{}", byte as char);
+        }
+        _ => (),
+    }
+    Edit::Pending
+}
+
+/// Execute a monitor command or pass the line to the loader commands
+fn execute(line: &str) {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return,
+    };
+    match MONITOR_COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => {
+            let args: Vec<&str> = words.collect();
+            if let Err(err) = (command.run)(&args) {
+                println!("ERR {:?}", err);
+            }
+        }
+        None => {
+            loader::handle_request(Received::Command(String::from(line)));
+            // the monitor is already running
+            REQUESTED.store(false, Ordering::Release);
+        }
+    }
+}
+
+fn help(_args: &[&str]) -> Result<(), CommandError> {
+    // the loader commands start with their own help
+    let loader_commands = command::COMMANDS.iter().skip(1);
+    for command in MONITOR_COMMANDS.iter().chain(loader_commands) {
+        println!("{:8} {:14} {}", command.name, command.usage, command.help);
+    }
+    Ok(())
+}
+
+fn boot(args: &[&str]) -> Result<(), CommandError> {
+    refuse_if_signed()?;
+    let (address, options) = match args.split_first() {
+        Some((address, options)) => (parse(address)?, options),
+        None => return Err(CommandError::BadArguments),
+    };
+    // aarch32 kernels are always entered in EL1
+    let (aarch, entry_el) = match options {
+        [] | ["64"] | ["el1"] | ["64", "el1"] => (BootMode::Aarch64, 1),
+        ["el2"] | ["64", "el2"] => (BootMode::Aarch64, 2),
+        ["32"] | ["32", "el1"] => (BootMode::Aarch32, 1),
+        _ => return Err(CommandError::BadArguments),
+    };
+    if address % 4 != 0 || !is_accessible(address, 4) || address >= board::PERIPHERAL_BASE {
+        return Err(CommandError::BadArguments);
+    }
+    // the words written with mw are flushed right away, the page of the entry point is flushed for code placed
+    // otherwise
+    let size = PAGE_SIZE - address % PAGE_SIZE;
+    loader::boot_in_place(address, size, aarch, entry_el)
+}
+
+fn info(args: &[&str]) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::BadArguments);
+    }
+    match model::current() {
+        Some(revision) => println!("board {}", revision),
+        None => println!("board unknown"),
+    }
+    let (el, sctlr): (u64, u64);
+    unsafe {
+        llvm_asm!("mrs $0, CurrentEL" : "=r"(el) ::: "volatile");
+        llvm_asm!("mrs $0, sctlr_el2" : "=r"(sctlr) ::: "volatile");
+    }
+    println!("el {}", el >> 2 & 0b11);
+    let state = |bit: u64| if sctlr & 1 << bit != 0 { "on" } else { "off" };
+    println!(
+        "mmu {}, data cache {}, instruction cache {}",
+        state(0),
+        state(2),
+        state(12)
+    );
+    Ok(())
+}
+
+fn load(args: &[&str]) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::BadArguments);
+    }
+    LEAVE.store(true, Ordering::Release);
+    Ok(())
+}
+
+fn md(args: &[&str]) -> Result<(), CommandError> {
+    let (address, size) = match args {
+        [address] => (parse(address)?, DEFAULT_DUMP_SIZE),
+        [address, size] => (parse(address)?, parse(size)?),
+        _ => return Err(CommandError::BadArguments),
+    };
+    // the dump covers whole words
+    let size = size.checked_add(3).ok_or(CommandError::BadArguments)? & !3;
+    if address % 4 != 0 || size == 0 || size > MAX_DUMP_SIZE || !is_accessible(address, size) {
+        return Err(CommandError::BadArguments);
+    }
+    let mut line = address;
+    while line < address + size {
+        print!("{:08x}:", line);
+        let mut word = line;
+        while word < (line + WORDS_PER_LINE * 4).min(address + size) {
+            let value = unsafe { core::ptr::read_volatile(word as *const u32) };
+            print!(" {:08x}", value);
+            word += 4;
+        }
+        println!();
+        line += WORDS_PER_LINE * 4;
+    }
+    Ok(())
+}
+
+fn mw(args: &[&str]) -> Result<(), CommandError> {
+    let (address, value) = match args {
+        [address, value] => (parse(address)?, parse(value)?),
+        _ => return Err(CommandError::BadArguments),
+    };
+    refuse_if_signed()?;
+    if address % 4 != 0 || value > u32::MAX as u64 || !is_accessible(address, 4) {
+        return Err(CommandError::BadArguments);
+    }
+    unsafe { core::ptr::write_volatile(address as *mut u32, value as u32) };
+    // the word might be code started with boot later on
+    if address < board::PERIPHERAL_BASE {
+        cache::flush_for_execution(address, 4);
+    }
+    Ok(())
+}
+
+/// Refuse to place or start code in memory while the loader requires signed kernels, as it could not be verified
+fn refuse_if_signed() -> Result<(), CommandError> {
+    if verify::SIGNATURE_REQUIRED {
+        error!("refused, the loader requires signed kernels");
+        return Err(CommandError::Failed);
+    }
+    Ok(())
+}
+
+/// Parse a hexadecimal number with ``0x`` prefix or a decimal number
+fn parse(number: &str) -> Result<u64, CommandError> {
+    let parsed = match number.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => number.parse::<u64>(),
+    };
+    parsed.map_err(|_| CommandError::BadArguments)
+}
+
+/// Check whether the range lies within the memory or the peripherals mapped by the loader, any other access would
+/// fault
+fn is_accessible(address: u64, size: u64) -> bool {
+    let regions = [
+        (0, board::MEMORY_SIZE.min(board::PERIPHERAL_BASE)),
+        (board::PERIPHERAL_BASE, board::PERIPHERAL_SIZE),
+        (board::ARM_LOCAL_BASE, board::ARM_LOCAL_SIZE),
+    ];
+    let end = match address.checked_add(size) {
+        Some(end) => end,
+        None => return false,
+    };
+    regions
+        .iter()
+        .any(|&(start, len)| address >= start && end <= start + len)
+}
@@ -9,14 +9,33 @@
 //!
 //! This module provides panic handler and personality function for a baremetal kernel that does not provide his own.
 //! It will be compiled into if the feature "with_panic" is active
+//!
+//! With the feature "no_panic" the panic handler refers to a symbol that is not defined anywhere. As the linker
+//! drops every function not referred to, the loader only links if no code path is left that could end in the panic
+//! handler. This proves the loader could not abort unpredictably, every fallible path need to return an error
+//! instead.
+//!
 
 use core::panic::PanicInfo;
 
+#[cfg(feature = "no_panic")]
+extern "C" {
+    /// Not defined on purpose, an undefined reference to it names the function still able to panic
+    fn __loader_panic_is_reachable() -> !;
+}
+
+#[cfg(feature = "no_panic")]
 #[panic_handler]
 fn panic(_: &PanicInfo) -> ! {
+    unsafe { __loader_panic_is_reachable() }
+}
+
+#[cfg(not(feature = "no_panic"))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
     // Panicing is undefined behaviour so we are unable to recover from one into a valid state.
-    // Halt the panicing core and safely do nothing!
-    loop {}
+    // Keep the panic as crash dump to be reported after the reset
+    crate::crash::panic(info)
 }
 
 #[lang = "eh_personality"]
@@ -0,0 +1,236 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Partition tables
+//!
+//! The partitions of a block device described by the master boot record or, if the master boot record only
+//! protects a GUID partition table, by the GPT. The primary GPT is used, its header and entries need to pass their
+//! CRC-32 checks. Logical partitions inside an extended MBR partition are not read.
+//!
+//! The loader looks for two kinds of partitions: the boot partition with the FAT32 or exFAT file system and raw
+//! kernel slots holding a kernel image without any file system. Both are identified by their partition type, so
+//! they could be placed anywhere on the card.
+//!
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use crate::crc;
+use crate::fat::{le16, le32, le64, BOOT_SIGNATURE};
+use alloc::{string::String, vec, vec::Vec};
+use core::fmt;
+
+/// The MBR partition types of a boot partition: FAT32 with CHS and LBA addressing and exFAT, which is shared with
+/// NTFS
+const MBR_BOOT: [u8; 3] = [0x0B, 0x0C, 0x07];
+/// The MBR partition type of raw kernel slots, "non file system data"
+const MBR_KERNEL_SLOT: u8 = 0xDA;
+/// The MBR partition type protecting a GPT
+const MBR_PROTECTIVE: u8 = 0xEE;
+
+/// The GPT partition types of a boot partition: EFI system partition and basic data partition
+const GPT_BOOT: [Guid; 2] = [
+    Guid::new(0xC12A_7328, 0xF81F, 0x11D2, 0xBA4B, 0x00A0_C93E_C93B),
+    Guid::new(0xEBD0_A0A2, 0xB9E5, 0x4433, 0x87C0, 0x68B6_B7C7_EAC7),
+];
+/// The GPT partition type of raw kernel slots
+const GPT_KERNEL_SLOT: Guid = Guid::new(0x5275_5069, 0x526F, 0x4B53, 0x8C6F, 0x7265_6C53_6C6F);
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_ENTRY_SIZE: usize = 128;
+/// The entries of the GPT read at most, which is the size reserved by the specification
+const GPT_MAX_ENTRIES_SIZE: usize = 128 * GPT_ENTRY_SIZE;
+/// The number of UTF-16 characters of a GPT partition name
+const GPT_NAME_CHARS: usize = 36;
+
+/// Errors reading the partition table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartitionError<E> {
+    /// Reading the device failed
+    Device(E),
+    /// There is no master boot record on the device
+    NoPartitionTable,
+    /// The GPT header or its entries do not match their checksum
+    BadChecksum,
+    /// The GPT header is not valid
+    BadTable,
+}
+
+/// A GUID as stored in the GPT, the first three fields are little endian
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    const fn new(a: u32, b: u16, c: u16, d: u16, e: u64) -> Self {
+        let a = a.to_le_bytes();
+        let b = b.to_le_bytes();
+        let c = c.to_le_bytes();
+        let d = d.to_be_bytes();
+        let e = e.to_be_bytes();
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], e[2], e[3], e[4], e[5],
+            e[6], e[7],
+        ])
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            le32(&g[0..]),
+            le16(&g[4..]),
+            le16(&g[6..]),
+            g[8],
+            g[9]
+        )?;
+        g[10..]
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02X}", byte))
+    }
+}
+
+/// The type of a partition
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartitionType {
+    /// The type of a partition in the master boot record
+    Mbr(u8),
+    /// The type of a partition in the GPT
+    Gpt(Guid),
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionType::Mbr(kind) => write!(f, "{:#04x}", kind),
+            PartitionType::Gpt(guid) => write!(f, "{}", guid),
+        }
+    }
+}
+
+/// A partition of the device
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// The number of the partition, starting with 1
+    pub number: usize,
+    pub kind: PartitionType,
+    /// The first block of the partition
+    pub start: u64,
+    /// The number of blocks of the partition
+    pub blocks: u64,
+    /// The name of the partition, only a GPT names partitions
+    pub name: String,
+}
+
+impl Partition {
+    /// Check whether the partition could be the boot partition with a FAT32 or exFAT file system
+    pub fn is_boot(&self) -> bool {
+        match self.kind {
+            PartitionType::Mbr(kind) => MBR_BOOT.contains(&kind),
+            PartitionType::Gpt(guid) => GPT_BOOT.contains(&guid),
+        }
+    }
+
+    /// Check whether the partition is a raw kernel slot
+    pub fn is_kernel_slot(&self) -> bool {
+        match self.kind {
+            PartitionType::Mbr(kind) => kind == MBR_KERNEL_SLOT,
+            PartitionType::Gpt(guid) => guid == GPT_KERNEL_SLOT,
+        }
+    }
+}
+
+/// Read the partitions of the device in the order of the partition table, empty entries are skipped
+pub fn read<D: BlockDevice>(device: &mut D) -> Result<Vec<Partition>, PartitionError<D::Error>> {
+    let mut block = [0; BLOCK_SIZE];
+    device
+        .read_blocks(0, &mut block)
+        .map_err(PartitionError::Device)?;
+    if le16(&block[510..]) != BOOT_SIGNATURE {
+        return Err(PartitionError::NoPartitionTable);
+    }
+    let entries = block[446..510].chunks(16);
+    if entries.clone().any(|entry| entry[4] == MBR_PROTECTIVE) {
+        return read_gpt(device);
+    }
+    let partitions = entries
+        .enumerate()
+        .filter(|(_, entry)| entry[4] != 0 && le32(&entry[8..]) != 0 && le32(&entry[12..]) != 0)
+        .map(|(idx, entry)| Partition {
+            number: idx + 1,
+            kind: PartitionType::Mbr(entry[4]),
+            start: le32(&entry[8..]) as u64,
+            blocks: le32(&entry[12..]) as u64,
+            name: String::new(),
+        })
+        .collect();
+    Ok(partitions)
+}
+
+/// Read the partitions of the primary GPT following the protective master boot record
+fn read_gpt<D: BlockDevice>(device: &mut D) -> Result<Vec<Partition>, PartitionError<D::Error>> {
+    let mut header = [0; BLOCK_SIZE];
+    device
+        .read_blocks(1, &mut header)
+        .map_err(PartitionError::Device)?;
+    let header_size = le32(&header[12..]) as usize;
+    if &header[0..8] != GPT_SIGNATURE || header_size < GPT_HEADER_SIZE || header_size > BLOCK_SIZE {
+        return Err(PartitionError::BadTable);
+    }
+    let header_crc = le32(&header[16..]);
+    // the checksum of the header is calculated with the checksum field set to zero
+    header[16..20].copy_from_slice(&[0; 4]);
+    if crc::crc32(&header[..header_size]) != header_crc {
+        return Err(PartitionError::BadChecksum);
+    }
+
+    let entries_start = le64(&header[72..]);
+    let count = le32(&header[80..]) as usize;
+    let entry_size = le32(&header[84..]) as usize;
+    if entry_size < GPT_ENTRY_SIZE
+        || !entry_size.is_power_of_two()
+        || count == 0
+        || count > GPT_MAX_ENTRIES_SIZE / entry_size
+    {
+        return Err(PartitionError::BadTable);
+    }
+    let size = count * entry_size;
+    let mut entries = vec![0; (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE];
+    device
+        .read_blocks(entries_start, &mut entries)
+        .map_err(PartitionError::Device)?;
+    if crc::crc32(&entries[..size]) != le32(&header[88..]) {
+        return Err(PartitionError::BadChecksum);
+    }
+
+    let partitions = entries[..size]
+        .chunks(entry_size)
+        .enumerate()
+        .filter(|(_, entry)| entry[0..16].iter().any(|&byte| byte != 0))
+        .filter(|(_, entry)| le64(&entry[40..]) >= le64(&entry[32..]))
+        .map(|(idx, entry)| {
+            let mut guid = [0; 16];
+            guid.copy_from_slice(&entry[0..16]);
+            let chars = entry[56..56 + 2 * GPT_NAME_CHARS]
+                .chunks(2)
+                .map(le16)
+                .take_while(|&c| c != 0);
+            Partition {
+                number: idx + 1,
+                kind: PartitionType::Gpt(Guid(guid)),
+                start: le64(&entry[32..]),
+                // the last block is part of the partition
+                blocks: le64(&entry[40..]) - le64(&entry[32..]) + 1,
+                name: core::char::decode_utf16(chars)
+                    .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                    .collect(),
+            }
+        })
+        .collect();
+    Ok(partitions)
+}
@@ -0,0 +1,259 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Second UART
+//!
+//! Minimal polled driver for the PL011 UART used as second console next to the miniUART. On the Raspberry Pi 3 the
+//! pin header only carries the UART pins GPIO 14 and 15, which are taken by the miniUART, so the PL011 is routed to
+//! GPIO 32 and 33. These pins are connected to the Bluetooth module on the Raspberry Pi 3 but brought out on the
+//! Compute Module 3.
+//!
+//! Carrier boards of the compute modules bring out different pins, so on a compute module detected at startup the
+//! PL011 is routed to the pins given with ``RUSPIRO_LOADER_PL011_PINS`` at build time, GPIO 32 and 33 or GPIO 36
+//! and 37. The other boards always use GPIO 32 and 33, GPIO 14 and 15 stay with the miniUART.
+//!
+//! The UART is polled from the main loop of the loader, the FIFO holds 16 bytes until they are picked up.
+//!
+//! With the feature ``uart_dma`` a DMA channel takes the received bytes from the FIFO instead, so nothing is lost at
+//! high baud rates while the loader is busy for a while. The channel fills two buffers in turn, the ping-pong
+//! buffers, each byte as the 32 bit word read from the data register. The loader reads the words the channel has
+//! written so far, which is told by the destination address of the channel. If the channel has completed both
+//! buffers since the loader read the oldest word, it has lapped the loader and the unread bytes are dropped.
+//!
+
+use crate::board::{self, GPIO_BASE, UART0_BASE};
+#[cfg(feature = "uart_dma")]
+use crate::cache;
+#[cfg(feature = "uart_dma")]
+use crate::dma::{self, Channel, ControlBlock};
+use crate::mailbox;
+#[cfg(feature = "uart_dma")]
+use crate::mmu::{self, MemoryAttributes};
+use crate::model;
+use core::ptr::{read_volatile, write_volatile};
+#[cfg(feature = "uart_dma")]
+use ruspiro_singleton::Singleton;
+
+const UART0_DR: *mut u32 = board::register(UART0_BASE, 0x00);
+const UART0_FR: *mut u32 = board::register(UART0_BASE, 0x18);
+const UART0_IBRD: *mut u32 = board::register(UART0_BASE, 0x24);
+const UART0_FBRD: *mut u32 = board::register(UART0_BASE, 0x28);
+const UART0_LCRH: *mut u32 = board::register(UART0_BASE, 0x2C);
+const UART0_CR: *mut u32 = board::register(UART0_BASE, 0x30);
+const UART0_ICR: *mut u32 = board::register(UART0_BASE, 0x44);
+#[cfg(feature = "uart_dma")]
+const UART0_DMACR: *mut u32 = board::register(UART0_BASE, 0x48);
+
+const GPIO_GPFSEL0: *mut u32 = board::register(GPIO_BASE, 0x00);
+
+const FR_RX_EMPTY: u32 = 1 << 4;
+const FR_TX_FULL: u32 = 1 << 5;
+/// 8 data bits with the FIFOs enabled
+const LCRH_8N1_FIFO: u32 = 0b11 << 5 | 1 << 4;
+const CR_ENABLE: u32 = 1 << 0;
+const CR_TX_ENABLE: u32 = 1 << 8;
+const CR_RX_ENABLE: u32 = 1 << 9;
+#[cfg(feature = "uart_dma")]
+const DMACR_RX_ENABLE: u32 = 1 << 0;
+
+/// Fallback for the clock of the UART if the firmware could not be asked
+const DEFAULT_CLOCK: u32 = 48_000_000;
+/// The baud rate given with ``RUSPIRO_LOADER_PL011_BAUD_RATE`` at build time, 115200 by default
+pub const BAUD_RATE: u32 = include!(concat!(env!("OUT_DIR"), "/pl011_baud_rate.rs"));
+/// The transmit pin of the boards, the receive pin follows it
+const TX_PIN: u32 = 32;
+/// The transmit pin of the carrier board of a compute module given with ``RUSPIRO_LOADER_PL011_PINS`` at build time
+const CARRIER_TX_PIN: u32 = include!(concat!(env!("OUT_DIR"), "/pl011_tx_pin.rs"));
+/// The bytes each of the receive buffers holds, given with ``RUSPIRO_LOADER_DMA_BUFFER_SIZE`` at build time
+#[cfg(feature = "uart_dma")]
+const BUFFER_SIZE: usize = include!(concat!(env!("OUT_DIR"), "/dma_buffer_size.rs"));
+
+/// The receive buffers the DMA channel fills in turn and the control blocks describing them
+#[cfg(feature = "uart_dma")]
+#[repr(C, align(4096))]
+struct ReceiveBuffers {
+    words: [[u32; BUFFER_SIZE]; 2],
+    blocks: [ControlBlock; 2],
+}
+
+#[cfg(feature = "uart_dma")]
+static mut BUFFERS: ReceiveBuffers = ReceiveBuffers {
+    words: [[0; BUFFER_SIZE]; 2],
+    blocks: [ControlBlock::EMPTY; 2],
+};
+
+/// The DMA channel receiving the bytes and the index of the next word to read from the buffers
+#[cfg(feature = "uart_dma")]
+struct DmaReceiver {
+    channel: Channel,
+    read: usize,
+}
+
+/// The receiver while the DMA channel takes the received bytes, ``None`` while the FIFO is polled
+#[cfg(feature = "uart_dma")]
+static RECEIVER: Singleton<Option<DmaReceiver>> = Singleton::new(None);
+
+/// Route the pins of the board detected to the PL011 and initialize it with the given baud rate
+pub fn initialize(baud_rate: u32) {
+    let clock = mailbox::clock_rate(mailbox::CLOCK_UART).unwrap_or(DEFAULT_CLOCK);
+    // the divider of the baud rate in 1/64th, the UART samples with 16 times the baud rate
+    let divider = (clock as u64 * 4 + baud_rate as u64 / 2) / baud_rate as u64;
+    let tx_pin = if model::is_compute_module() {
+        CARRIER_TX_PIN
+    } else {
+        TX_PIN
+    };
+    // the alternate function routing the pins to the PL011, 3 for GPIO 32/33 and 2 for GPIO 36/37
+    let function = if tx_pin == 32 { 0b111 } else { 0b110 };
+    unsafe {
+        write_volatile(UART0_CR, 0);
+        // both pins are selected by the same register
+        let select = GPIO_GPFSEL0.add((tx_pin / 10) as usize);
+        let shift = (tx_pin % 10) * 3;
+        write_volatile(
+            select,
+            read_volatile(select) & !(0x3F << shift) | (function << 3 | function) << shift,
+        );
+        write_volatile(UART0_ICR, 0x7FF);
+        write_volatile(UART0_IBRD, (divider >> 6) as u32);
+        write_volatile(UART0_FBRD, (divider & 0x3F) as u32);
+        write_volatile(UART0_LCRH, LCRH_8N1_FIFO);
+        write_volatile(UART0_CR, CR_ENABLE | CR_TX_ENABLE | CR_RX_ENABLE);
+    }
+    #[cfg(feature = "uart_dma")]
+    start_receive_dma();
+}
+
+/// Let a DMA channel take the received bytes from the FIFO. The FIFO is polled if there is no channel or the buffers
+/// could not be mapped bypassing the caches.
+#[cfg(feature = "uart_dma")]
+fn start_receive_dma() {
+    let start = unsafe { &BUFFERS as *const ReceiveBuffers as u64 };
+    let size = core::mem::size_of::<ReceiveBuffers>() as u64;
+    // no line of the buffers may be written back over the data of the channel later on
+    cache::clean_invalidate_dcache_range(start, size);
+    if let Err(err) = mmu::map_region(start, size, MemoryAttributes::NonCacheable) {
+        warn!(
+            "PL011 receive buffers not mapped: {:?}, polling the FIFO",
+            err
+        );
+        return;
+    }
+    let channel = match Channel::take() {
+        Ok(channel) => channel,
+        Err(err) => {
+            warn!("no DMA channel for the PL011: {:?}, polling the FIFO", err);
+            return;
+        }
+    };
+    let source = dma::peripheral_bus_address(UART0_DR);
+    unsafe {
+        for idx in 0..2 {
+            let mut block = ControlBlock::new(
+                dma::TI_SRC_DREQ
+                    | dma::ti_permap(dma::DREQ_UART0_RX)
+                    | dma::TI_DEST_INC
+                    | dma::TI_WAIT_RESP,
+                source,
+                dma::bus_address(BUFFERS.words[idx].as_ptr() as u64),
+                (BUFFER_SIZE * 4) as u32,
+            );
+            // the buffers are filled in turn without end
+            block.next = dma::bus_address(&BUFFERS.blocks[1 - idx] as *const ControlBlock as u64);
+            BUFFERS.blocks[idx] = block;
+        }
+        channel.start(&BUFFERS.blocks[0]);
+        write_volatile(UART0_DMACR, DMACR_RX_ENABLE);
+    }
+    debug!(
+        "PL011 receives with DMA into 2 buffers of {} bytes",
+        BUFFER_SIZE
+    );
+    RECEIVER.take_for(|receiver| *receiver = Some(DmaReceiver { channel, read: 0 }));
+}
+
+/// The next byte of the receive buffers, ``None`` if the bytes are not received with DMA. A failed channel is
+/// stopped and the FIFO is polled from then on.
+#[cfg(feature = "uart_dma")]
+fn read_buffers() -> Option<Option<u8>> {
+    RECEIVER.take_for(|receiver| {
+        if let Some(err) = receiver.as_ref()?.channel.error() {
+            error!("DMA of the PL011 failed: {:#x}, polling the FIFO", err);
+            *receiver = None;
+            unsafe { write_volatile(UART0_DMACR, 0) };
+            return None;
+        }
+        receiver.as_mut().map(DmaReceiver::next)
+    })
+}
+
+/// Stop the DMA channel taking the received bytes, the kernel could use the memory of the buffers. The FIFO is
+/// polled again afterwards.
+#[cfg(feature = "uart_dma")]
+pub fn stop_receive_dma() {
+    RECEIVER.take_for(|receiver| {
+        if receiver.take().is_some() {
+            // the channel is reset once it is dropped
+            unsafe { write_volatile(UART0_DMACR, 0) };
+        }
+    });
+}
+
+#[cfg(feature = "uart_dma")]
+impl DmaReceiver {
+    /// The next byte the channel has written to the buffers, which are read as a ring of words
+    fn next(&mut self) -> Option<u8> {
+        let written = self.written();
+        if written == self.read {
+            return None;
+        }
+        // writing behind the next word within the same buffer needs the channel to have lapped the loader
+        if written / BUFFER_SIZE == self.read / BUFFER_SIZE && written < self.read {
+            warn!("PL011 receive buffers overrun, received bytes dropped");
+            self.read = written;
+            return None;
+        }
+        let word = unsafe {
+            read_volatile(&BUFFERS.words[self.read / BUFFER_SIZE][self.read % BUFFER_SIZE])
+        };
+        self.read = (self.read + 1) % (2 * BUFFER_SIZE);
+        // the upper bits of the word carry the error flags of the byte
+        Some(word as u8)
+    }
+
+    /// The index of the word the channel writes next. At the end of a buffer the destination points behind it until
+    /// the channel has loaded the control block of the other buffer.
+    fn written(&self) -> usize {
+        let start = dma::bus_address(unsafe { BUFFERS.words.as_ptr() } as u64);
+        (self.channel.destination().wrapping_sub(start) / 4) as usize % (2 * BUFFER_SIZE)
+    }
+}
+
+/// Take the next received byte from the receive buffers, or from the FIFO if the bytes are not received with DMA
+pub fn read_byte() -> Option<u8> {
+    #[cfg(feature = "uart_dma")]
+    {
+        if let Some(received) = read_buffers() {
+            return received;
+        }
+    }
+    if unsafe { read_volatile(UART0_FR) } & FR_RX_EMPTY != 0 {
+        return None;
+    }
+    // the upper bits of the data register carry the error flags of the byte
+    Some(unsafe { read_volatile(UART0_DR) } as u8)
+}
+
+/// Check whether the UART could take another byte to send
+pub fn is_ready() -> bool {
+    unsafe { read_volatile(UART0_FR) & FR_TX_FULL == 0 }
+}
+
+/// Send the byte, the UART need to be ready
+pub fn write_byte(byte: u8) {
+    unsafe { write_volatile(UART0_DR, byte as u32) };
+}
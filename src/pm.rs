@@ -0,0 +1,99 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Power management
+//!
+//! The power management block contains the watchdog that is used to trigger a reset of the whole SoC. Besides
+//! resetting the SoC on request the watchdog could guard the loader itself, it resets the SoC if it is not petted
+//! within its timeout.
+//!
+
+use crate::board::{self, AUX_BASE, PM_BASE};
+use crate::console;
+use crate::mailbox;
+use crate::smp;
+use crate::time::Duration;
+use core::ptr::{read_volatile, write_volatile};
+use ruspiro_interrupt::{disable_interrupts, Interrupt, IRQ_MANAGER};
+use ruspiro_register::system::wfe;
+
+const PM_RSTC: *mut u32 = board::register(PM_BASE, 0x1C);
+const PM_WDOG: *mut u32 = board::register(PM_BASE, 0x24);
+/// Any write to the power management registers need to contain this password
+const PM_PASSWORD: u32 = 0x5A00_0000;
+const PM_RSTC_WRCFG_CLR: u32 = !0x30;
+const PM_RSTC_WRCFG_FULL_RESET: u32 = 0x20;
+const PM_RSTC_RESET: u32 = 0x102;
+/// The watchdog counts down in ticks of ~16µs, the counter is 20 bits wide
+const PM_WDOG_TICK_NANOS: u128 = 15_259;
+const PM_WDOG_MAX_TICKS: u32 = 0xF_FFFF;
+
+const AUX_MU_IER: *mut u32 = board::register(AUX_BASE, 0x44);
+
+/// The time the secondary cores are given to park in the spin table before halting
+const PARK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Reset the SoC using the watchdog. The firmware will start over as if the device has been powered up, but the
+/// content of the RAM is usually retained.
+pub fn reset() -> ! {
+    unsafe {
+        // let the watchdog expire after a few ticks (~16µs each) and request a full reset once it does
+        write_volatile(PM_WDOG, PM_PASSWORD | 10);
+        let rstc = read_volatile(PM_RSTC) & PM_RSTC_WRCFG_CLR;
+        write_volatile(PM_RSTC, PM_PASSWORD | rstc | PM_RSTC_WRCFG_FULL_RESET);
+    }
+    // nothing to do until the reset kicks in
+    loop {
+        wfe();
+    }
+}
+
+/// Start the watchdog, it resets the SoC unless it is petted within the given timeout. The timeout is limited to
+/// ~16s.
+pub fn watchdog_start(timeout: Duration) {
+    watchdog_set(timeout);
+    unsafe {
+        let rstc = read_volatile(PM_RSTC) & PM_RSTC_WRCFG_CLR;
+        write_volatile(PM_RSTC, PM_PASSWORD | rstc | PM_RSTC_WRCFG_FULL_RESET);
+    }
+}
+
+/// Restart the countdown of the running watchdog with the given timeout
+pub fn watchdog_set(timeout: Duration) {
+    let ticks = core::cmp::min(
+        timeout.as_nanos() / PM_WDOG_TICK_NANOS,
+        PM_WDOG_MAX_TICKS as u128,
+    );
+    unsafe { write_volatile(PM_WDOG, PM_PASSWORD | ticks as u32) };
+}
+
+/// Stop the watchdog
+pub fn watchdog_stop() {
+    unsafe { write_volatile(PM_RSTC, PM_PASSWORD | PM_RSTC_RESET) };
+}
+
+/// Bring the device into a safe state it could be powered off in. Secondary cores released with [smp::release] are
+/// given [PARK_TIMEOUT] to return to the spin table, a core still running its function afterwards cannot be stopped
+/// from the main core and is reported. Then the interrupts are quiesced, the devices not needed any more are switched
+/// off and the main core is parked in ``wfi``. The device only continues after a reset.
+pub fn halt() -> ! {
+    if !smp::wait_parked(PARK_TIMEOUT) {
+        warn!("secondary cores still running, they are not halted");
+        console::flush();
+    }
+    disable_interrupts();
+    IRQ_MANAGER.take_for(|irq_mgr| irq_mgr.deactivate(Interrupt::Aux));
+    unsafe { write_volatile(AUX_MU_IER, 0) };
+    // switching off the USB controller also powers down the USB hub and the LAN chip of the board
+    for &device in [mailbox::POWER_USB_HCD, mailbox::POWER_SD_CARD].iter() {
+        let _ = mailbox::set_power_state(device, false);
+    }
+    // with all interrupts masked and none of them enabled the core stays in the low power state
+    loop {
+        unsafe { llvm_asm!("wfi" :::: "volatile") };
+    }
+}
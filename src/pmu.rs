@@ -0,0 +1,115 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Performance monitors
+//!
+//! The performance monitors of the Cortex-A53 provide a cycle counter and six event counters for the core they are
+//! running on. The loader uses them to measure the cache and TLB behaviour of an operation. The counters are only
+//! enabled while measuring and left as configured by the firmware afterwards. By default events in EL2 are not
+//! counted, so the filter of each counter is set to include them.
+//!
+
+/// An event counted by the performance monitors
+pub struct Event {
+    /// The architectural event number
+    pub number: u64,
+    /// The name the event is reported with
+    pub name: &'static str,
+}
+
+/// The events counted while measuring an operation, one for each event counter of the Cortex-A53
+pub const EVENTS: [Event; 6] = [
+    Event {
+        number: 0x01,
+        name: "L1I refills",
+    },
+    Event {
+        number: 0x03,
+        name: "L1D refills",
+    },
+    Event {
+        number: 0x16,
+        name: "L2D accesses",
+    },
+    Event {
+        number: 0x17,
+        name: "L2D refills",
+    },
+    Event {
+        number: 0x02,
+        name: "L1I TLB refills",
+    },
+    Event {
+        number: 0x05,
+        name: "L1D TLB refills",
+    },
+];
+
+/// The counters of an operation measured
+#[derive(Debug, Default)]
+pub struct Counters {
+    /// The core cycles
+    pub cycles: u64,
+    /// The count of each of the [EVENTS], ``None`` if the core has not enough event counters
+    pub events: [Option<u32>; EVENTS.len()],
+}
+
+/// Enable bit in PMCR_EL0
+const PMCR_E: u64 = 1 << 0;
+/// Reset the event counters with PMCR_EL0
+const PMCR_P: u64 = 1 << 1;
+/// Reset the cycle counter with PMCR_EL0
+const PMCR_C: u64 = 1 << 2;
+/// Cycle counter bit in PMCNTENSET_EL0/PMCNTENCLR_EL0
+const PMCNTEN_C: u64 = 1 << 31;
+/// Count events in EL2 as well in PMEVTYPER<n>_EL0/PMCCFILTR_EL0
+const FILTER_NSH: u64 = 1 << 27;
+
+/// Measure the given operation with the cycle counter and an event counter for each of the [EVENTS]
+pub fn measure<F: FnOnce() -> R, R>(operation: F) -> (R, Counters) {
+    let pmcr: u64;
+    unsafe { llvm_asm!("mrs $0, pmcr_el0" : "=r"(pmcr) ::: "volatile") };
+    let available = core::cmp::min(((pmcr >> 11) & 0x1F) as usize, EVENTS.len());
+    for (counter, event) in EVENTS.iter().take(available).enumerate() {
+        unsafe {
+            llvm_asm!("msr pmselr_el0, $0
+                       isb
+                       msr pmxevtyper_el0, $1"
+                      :: "r"(counter as u64), "r"(event.number | FILTER_NSH) :: "volatile")
+        };
+    }
+    let enable = PMCNTEN_C | ((1 << available) - 1);
+    unsafe {
+        llvm_asm!("msr pmccfiltr_el0, $0
+                   msr pmcntenset_el0, $1
+                   msr pmcr_el0, $2
+                   isb"
+                  :: "r"(FILTER_NSH), "r"(enable), "r"(pmcr | PMCR_E | PMCR_P | PMCR_C) :: "volatile")
+    };
+
+    let result = operation();
+
+    let mut counters = Counters::default();
+    unsafe {
+        llvm_asm!("isb
+                   msr pmcntenclr_el0, $1
+                   mrs $0, pmccntr_el0"
+                  : "=r"(counters.cycles) : "r"(enable) :: "volatile")
+    };
+    for (counter, count) in counters.events.iter_mut().take(available).enumerate() {
+        let value: u64;
+        unsafe {
+            llvm_asm!("msr pmselr_el0, $1
+                       isb
+                       mrs $0, pmxevcntr_el0"
+                      : "=r"(value) : "r"(counter as u64) :: "volatile")
+        };
+        *count = Some(value as u32);
+    }
+    unsafe { llvm_asm!("msr pmcr_el0, $0" :: "r"(pmcr) :: "volatile") };
+    (result, counters)
+}
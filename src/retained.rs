@@ -0,0 +1,260 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Retained state
+//!
+//! A small record the loader keeps across a reset of the SoC. It is placed in a memory section that is neither
+//! part of the binary the firmware loads nor cleared while booting, so its content survives a watchdog reset. As
+//! the memory content is undefined after a power cycle the record is only trusted if its magic and CRC-32 match.
+//! The section is reserved in the device tree passed to the kernels, so a kernel that does not stay in the memory it
+//! is placed in keeps it intact for the loader after the next reset.
+//!
+//! Besides requests for the next boot the record counts the attempts to boot a kernel. The counter is cleared once
+//! the kernel signals a successful boot, so a kernel that keeps resetting the device before doing so is detected
+//! as boot loop.
+//!
+//! Each kernel is saved to one of two slots before it is started. Once it signals a successful boot its slot
+//! becomes the last known good one. If a new kernel ends in a boot loop the loader rolls back to the last known good
+//! kernel. The slots are part of the reserved section as well and hold the SHA-256 of the kernel saved, which is
+//! verified before the loader rolls back to it.
+//!
+//! The crash dump of the loader is kept in the same memory section, see [crate::crash].
+//!
+
+use crate::crc;
+use crate::sha2;
+use core::mem::MaybeUninit;
+use core::ptr::{read_volatile, write_volatile};
+use ruspiro_cache as cache;
+
+/// The magic value identifying a valid record, "RPRT"
+const MAGIC: u32 = 0x5452_5052;
+
+/// Stay in the loader and wait for a kernel after the next reset
+const FLAG_STAY_IN_LOADER: u32 = 1 << 0;
+/// The last boot attempt has been a roll back to the last known good kernel
+const FLAG_ROLLED_BACK: u32 = 1 << 1;
+
+/// The number of kernel slots
+const SLOTS: usize = 2;
+/// Marker for no slot being used
+const NO_SLOT: u32 = u32::MAX;
+/// The maximum size of a kernel that could be saved, larger kernels are not kept to keep the retained memory small
+pub const MAX_KERNEL_SIZE: usize = 0x8_0000;
+
+/// The number of consecutive boot attempts without success signal that is treated as boot loop
+pub const BOOT_LOOP_LIMIT: u32 = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Retained {
+    magic: u32,
+    flags: u32,
+    boot_count: u32,
+    /// The slot of the kernel currently tried to boot
+    trial_slot: u32,
+    /// The slot of the last kernel that has booted successfully
+    good_slot: u32,
+    check: u32,
+}
+
+/// A saved kernel
+#[repr(C)]
+struct KernelSlot {
+    address: u64,
+    mode: u32,
+    entry_el: u32,
+    size: u32,
+    /// The SHA-256 of the saved binary
+    digest: [u8; 32],
+    data: [u8; MAX_KERNEL_SIZE],
+}
+
+extern "C" {
+    /// linker symbols marking the memory kept across a reset
+    static __retained_start: u8;
+    static __retained_end: u8;
+}
+
+/// A kernel saved across the reset
+pub struct SavedKernel {
+    /// The address the kernel need to be executed from
+    pub address: u64,
+    /// The architecture of the kernel, 32 or 64
+    pub mode: u32,
+    /// The exception level the kernel is entered in
+    pub entry_el: u8,
+    /// The kernel binary
+    pub binary: &'static [u8],
+}
+
+impl Retained {
+    fn new() -> Self {
+        let mut retained = Retained {
+            magic: MAGIC,
+            flags: 0,
+            boot_count: 0,
+            trial_slot: NO_SLOT,
+            good_slot: NO_SLOT,
+            check: 0,
+        };
+        retained.check = retained.check_value();
+        retained
+    }
+
+    /// The CRC-32 of all fields but the check value itself
+    fn check_value(&self) -> u32 {
+        let fields = [
+            self.magic,
+            self.flags,
+            self.boot_count,
+            self.trial_slot,
+            self.good_slot,
+        ];
+        let mut data = [0; 20];
+        for (bytes, field) in data.chunks_mut(4).zip(fields.iter()) {
+            bytes.copy_from_slice(&field.to_le_bytes());
+        }
+        crc::crc32(&data)
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC && self.check == self.check_value()
+    }
+}
+
+#[link_section = ".retained"]
+static mut RETAINED: MaybeUninit<Retained> = MaybeUninit::uninit();
+#[link_section = ".retained"]
+static mut KERNEL_SLOTS: MaybeUninit<[KernelSlot; SLOTS]> = MaybeUninit::uninit();
+
+/// Read the record, a fresh one is provided if the memory does not contain a valid record
+fn load() -> Retained {
+    let retained = unsafe { read_volatile(RETAINED.as_ptr()) };
+    if retained.is_valid() {
+        retained
+    } else {
+        Retained::new()
+    }
+}
+
+/// Write the record and ensure it has reached the memory, as the caches do not survive a reset
+fn store(mut retained: Retained) {
+    retained.check = retained.check_value();
+    unsafe { write_volatile(RETAINED.as_mut_ptr(), retained) };
+    cache::cleaninvalidate();
+}
+
+/// The memory kept across a reset as start address and size, the kernels need to leave it alone
+pub fn region() -> (u64, u64) {
+    let (start, end) = unsafe {
+        (
+            &__retained_start as *const u8 as u64,
+            &__retained_end as *const u8 as u64,
+        )
+    };
+    (start, end - start)
+}
+
+/// Request the loader to stay waiting for a kernel after the next reset
+pub fn set_stay_in_loader() {
+    let mut retained = load();
+    retained.flags |= FLAG_STAY_IN_LOADER;
+    store(retained);
+}
+
+/// Check whether the loader has been requested to stay waiting for a kernel. The request is consumed, so it only
+/// applies to a single reset.
+pub fn take_stay_in_loader() -> bool {
+    let mut retained = load();
+    let stay = retained.flags & FLAG_STAY_IN_LOADER != 0;
+    retained.flags &= !FLAG_STAY_IN_LOADER;
+    store(retained);
+    stay
+}
+
+/// Count an attempt to boot a kernel, called right before the handover
+pub fn count_boot_attempt() {
+    let mut retained = load();
+    retained.boot_count = retained.boot_count.saturating_add(1);
+    store(retained);
+}
+
+/// The kernel has booted successfully, so clear the boot attempts. This is called by the kernel through the boot
+/// services or the hypervisor call after the loader has handed over and therefore must not use any atomics.
+pub fn boot_succeeded() {
+    let mut retained = load();
+    retained.boot_count = 0;
+    if retained.trial_slot != NO_SLOT {
+        retained.good_slot = retained.trial_slot;
+    }
+    store(retained);
+}
+
+/// The number of consecutive boot attempts without success signal
+pub fn boot_count() -> u32 {
+    load().boot_count
+}
+
+/// Check whether the previous boot attempts have ended in a boot loop
+pub fn boot_loop_detected() -> bool {
+    boot_count() >= BOOT_LOOP_LIMIT
+}
+
+/// Save the kernel about to be booted, so it could become the last known good kernel. Kernels exceeding
+/// [MAX_KERNEL_SIZE] are not saved.
+pub fn save_kernel(address: u64, mode: u32, entry_el: u8, binary: &[u8]) {
+    let mut retained = load();
+    retained.flags &= !FLAG_ROLLED_BACK;
+    retained.trial_slot = NO_SLOT;
+    if binary.len() <= MAX_KERNEL_SIZE {
+        // never overwrite the last known good kernel
+        let idx = if retained.good_slot == 0 { 1 } else { 0 };
+        let slot = unsafe { &mut (*KERNEL_SLOTS.as_mut_ptr())[idx] };
+        slot.address = address;
+        slot.mode = mode;
+        slot.entry_el = entry_el as u32;
+        slot.size = binary.len() as u32;
+        slot.data[..binary.len()].copy_from_slice(binary);
+        slot.digest = sha2::sha256(binary);
+        retained.trial_slot = idx as u32;
+    }
+    store(retained);
+}
+
+/// Provide the last known good kernel to roll back to after a boot loop. This is only possible once, if the
+/// last known good kernel ends in a boot loop as well, there is nothing left to roll back to.
+pub fn rollback() -> Option<SavedKernel> {
+    let mut retained = load();
+    if retained.good_slot as usize >= SLOTS || retained.good_slot == retained.trial_slot {
+        return None;
+    }
+    let slot = unsafe { &(*KERNEL_SLOTS.as_ptr())[retained.good_slot as usize] };
+    let binary = slot.data.get(..slot.size as usize)?;
+    // the kernel is only booted if it is still the one that has booted successfully
+    if sha2::sha256(binary) != slot.digest {
+        retained.good_slot = NO_SLOT;
+        store(retained);
+        return None;
+    }
+    retained.trial_slot = retained.good_slot;
+    retained.boot_count = 0;
+    retained.flags |= FLAG_ROLLED_BACK;
+    store(retained);
+
+    Some(SavedKernel {
+        address: slot.address,
+        mode: slot.mode,
+        entry_el: slot.entry_el as u8,
+        binary,
+    })
+}
+
+/// Check whether the last boot attempt has been a roll back to the last known good kernel
+pub fn rolled_back() -> bool {
+    load().flags & FLAG_ROLLED_BACK != 0
+}
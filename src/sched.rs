@@ -0,0 +1,72 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Scheduler
+//!
+//! A cooperative scheduler running the background activities of the loader, like the heartbeat LED or sending the
+//! buffered console output, while the main processing is waiting for requests of the host. Each task is run
+//! periodically from the main loop whenever it is due. Tasks are expected to return quickly and must never wait
+//! for anything, as no other task and no received data is processed while a task is running.
+//!
+
+use crate::time::{Duration, Instant};
+use alloc::{boxed::Box, vec::Vec};
+
+/// A task run periodically by the scheduler
+struct Task {
+    period: Duration,
+    next: Instant,
+    run: Box<dyn FnMut()>,
+}
+
+/// The tasks of the loader and when they are due
+pub struct Scheduler {
+    tasks: Vec<Task>,
+}
+
+impl Scheduler {
+    pub const fn new() -> Self {
+        Scheduler { tasks: Vec::new() }
+    }
+
+    /// Add a task that is run every ``period``, a zero period runs it with every call to [Scheduler::run_due]. The
+    /// first run is due right away.
+    pub fn add(&mut self, period: Duration, run: Box<dyn FnMut()>) {
+        self.tasks.push(Task {
+            period,
+            next: Instant::now(),
+            run,
+        });
+    }
+
+    /// The earliest point in time a task with a period is due. Tasks with a zero period are not considered, they run
+    /// whenever something else has woken up the main loop.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.tasks
+            .iter()
+            .filter(|task| task.period > Duration::from_secs(0))
+            .map(|task| task.next)
+            .min()
+    }
+
+    /// Run all tasks that are due. A task that has been held up for more than its period is not run several times
+    /// to catch up, its next run is scheduled one period from now instead.
+    pub fn run_due(&mut self) {
+        for task in self.tasks.iter_mut() {
+            let now = Instant::now();
+            if now < task.next {
+                continue;
+            }
+            (task.run)();
+            task.next = if task.next + task.period > now {
+                task.next + task.period
+            } else {
+                now + task.period
+            };
+        }
+    }
+}
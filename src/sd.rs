@@ -0,0 +1,467 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # SD card
+//!
+//! Minimal driver for the SD card connected to the EMMC controller. The firmware leaves the card attached to its own
+//! SD host controller, so the card pins are routed to the EMMC controller first. The card is initialized once with
+//! the identification sequence of the SD specification and then read and written in blocks of 512 bytes by
+//! polling. The loader only writes to the raw kernel slots, see [crate::slot].
+//!
+//! The Compute Modules 3 and 3+ carry an eMMC device at the pins of the card, only the Lite variants bring these pins
+//! out to an SD card slot on the carrier board. The eMMC does not answer SEND_IF_COND of the SD specification, so it is
+//! identified with SEND_OP_COND of the MMC specification instead and gets its relative address assigned by the
+//! loader. Afterwards it is read and written like a card, so the boot partition and the kernel slots could be on
+//! the eMMC as well. Only the user area of the eMMC is accessed, its boot partitions are left to the firmware.
+//!
+//! The controller checks the CRC of each data block and of the command responses carrying one. Marginal cards and
+//! contacts cause such errors every now and then, so a failed transfer is retried after resetting the command and
+//! data lines. If the retries fail as well, the card is initialized again for a last attempt.
+//!
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use crate::board::{self, EMMC_BASE, GPIO_BASE};
+use crate::mailbox;
+use crate::time::{self, Duration};
+use core::ptr::{read_volatile, write_volatile};
+
+const EMMC_BLKSIZECNT: *mut u32 = board::register(EMMC_BASE, 0x04);
+const EMMC_ARG1: *mut u32 = board::register(EMMC_BASE, 0x08);
+const EMMC_CMDTM: *mut u32 = board::register(EMMC_BASE, 0x0C);
+const EMMC_RESP0: *mut u32 = board::register(EMMC_BASE, 0x10);
+const EMMC_DATA: *mut u32 = board::register(EMMC_BASE, 0x20);
+const EMMC_STATUS: *mut u32 = board::register(EMMC_BASE, 0x24);
+const EMMC_CONTROL0: *mut u32 = board::register(EMMC_BASE, 0x28);
+const EMMC_CONTROL1: *mut u32 = board::register(EMMC_BASE, 0x2C);
+const EMMC_INTERRUPT: *mut u32 = board::register(EMMC_BASE, 0x30);
+const EMMC_INT_MASK: *mut u32 = board::register(EMMC_BASE, 0x34);
+const EMMC_INT_EN: *mut u32 = board::register(EMMC_BASE, 0x38);
+const EMMC_SLOTISR_VER: *mut u32 = board::register(EMMC_BASE, 0xFC);
+
+const GPIO_GPFSEL4: *mut u32 = board::register(GPIO_BASE, 0x10);
+const GPIO_GPFSEL5: *mut u32 = board::register(GPIO_BASE, 0x14);
+const GPIO_GPPUD: *mut u32 = board::register(GPIO_BASE, 0x94);
+const GPIO_GPPUDCLK1: *mut u32 = board::register(GPIO_BASE, 0x9C);
+
+const STATUS_CMD_INHIBIT: u32 = 1 << 0;
+const STATUS_DAT_INHIBIT: u32 = 1 << 1;
+
+const CONTROL1_CLK_INTLEN: u32 = 1 << 0;
+const CONTROL1_CLK_STABLE: u32 = 1 << 1;
+const CONTROL1_CLK_EN: u32 = 1 << 2;
+const CONTROL1_CLK_MASK: u32 = 0xFFE0;
+const CONTROL1_TOUNIT_MAX: u32 = 0xE << 16;
+const CONTROL1_SRST_HC: u32 = 1 << 24;
+const CONTROL1_SRST_CMD: u32 = 1 << 25;
+const CONTROL1_SRST_DATA: u32 = 1 << 26;
+
+const INT_CMD_DONE: u32 = 1 << 0;
+const INT_DATA_DONE: u32 = 1 << 1;
+const INT_WRITE_RDY: u32 = 1 << 4;
+const INT_READ_RDY: u32 = 1 << 5;
+const INT_COMMAND_TIMEOUT: u32 = 1 << 16;
+const INT_COMMAND_CRC: u32 = 1 << 17;
+const INT_DATA_TIMEOUT: u32 = 1 << 20;
+const INT_DATA_CRC: u32 = 1 << 21;
+const INT_ERROR_MASK: u32 = 0xFFFF_8000;
+
+/// Check the CRC of the response
+const CMD_CRC_CHECK: u32 = 1 << 19;
+/// Check that the response carries the index of the command
+const CMD_INDEX_CHECK: u32 = 1 << 20;
+/// The checks of the 48 bit responses R1, R6 and R7
+const CMD_CHECK_R1: u32 = CMD_CRC_CHECK | CMD_INDEX_CHECK;
+
+/// Commands with the response type, data direction and checks as expected by CMDTM. The OCR returned by
+/// SEND_OP_COND has no CRC and the CID no index.
+const CMD_GO_IDLE: u32 = 0x0000_0000;
+const CMD_ALL_SEND_CID: u32 = 0x0201_0000 | CMD_CRC_CHECK;
+const CMD_SEND_REL_ADDR: u32 = 0x0302_0000 | CMD_CHECK_R1;
+const CMD_CARD_SELECT: u32 = 0x0703_0000 | CMD_CHECK_R1;
+const CMD_SEND_IF_COND: u32 = 0x0802_0000 | CMD_CHECK_R1;
+const CMD_STOP_TRANSMISSION: u32 = 0x0C03_0000 | CMD_CHECK_R1;
+const CMD_READ_SINGLE: u32 = 0x1122_0010 | CMD_CHECK_R1;
+/// The controller stops the transmission with an automatic STOP_TRANSMISSION after the last block
+const CMD_READ_MULTI: u32 = 0x1222_0036 | CMD_CHECK_R1;
+const CMD_WRITE_SINGLE: u32 = 0x1822_0000 | CMD_CHECK_R1;
+const CMD_WRITE_MULTI: u32 = 0x1922_0026 | CMD_CHECK_R1;
+const CMD_APP_CMD: u32 = 0x3702_0000 | CMD_CHECK_R1;
+const CMD_SEND_OP_COND: u32 = 0x2902_0000;
+/// SEND_OP_COND of the MMC specification, the response is the OCR like for the SD command
+const CMD_MMC_SEND_OP_COND: u32 = 0x0102_0000;
+
+/// Voltage window and high capacity support requested with SEND_OP_COND
+const OP_COND_ARG: u32 = 0x51FF_8000;
+const OP_COND_READY: u32 = 1 << 31;
+const OP_COND_HIGH_CAPACITY: u32 = 1 << 30;
+/// Check pattern and voltage range sent with SEND_IF_COND
+const IF_COND_ARG: u32 = 0x1AA;
+/// Voltage window and sector addressing requested with SEND_OP_COND of an eMMC
+const MMC_OP_COND_ARG: u32 = 0x40FF_8000;
+/// The relative address the loader assigns to an eMMC, SD cards choose their own
+const MMC_RCA: u32 = 1 << 16;
+
+const CLOCK_IDENTIFICATION: u32 = 400_000;
+const CLOCK_TRANSFER: u32 = 25_000_000;
+/// Fallback for the base clock of the EMMC controller if the firmware could not be asked
+const DEFAULT_BASE_CLOCK: u32 = 41_666_666;
+
+const RESET_TIMEOUT: Duration = Duration::from_millis(100);
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(100);
+const DATA_TIMEOUT: Duration = Duration::from_millis(500);
+/// The card could take up to 1s to finish its power up
+const OP_COND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The number of times a command is sent again if its response is corrupted
+const COMMAND_RETRIES: usize = 3;
+/// The number of times a transfer is retried, the last retry after initializing the card again
+const TRANSFER_RETRIES: usize = 3;
+
+/// Errors accessing the SD card
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SdError {
+    /// The controller has not finished the reset or the clock change in time
+    Controller,
+    /// The card has not answered a command in time, usually there is no card inserted
+    Timeout,
+    /// The response of the card to a command has a wrong CRC
+    CommandCrc,
+    /// The card has not sent the data in time
+    DataTimeout,
+    /// A data block received has a wrong CRC
+    DataCrc,
+    /// The command failed with the given interrupt status
+    Command(u32),
+    /// The card does not support the voltage range or is neither an SD card nor an eMMC
+    Unsupported,
+    /// The buffer or the data is not a multiple of the block size
+    BadBuffer,
+}
+
+/// The initialized SD card
+#[derive(Debug)]
+pub struct SdCard {
+    /// High capacity cards are addressed in blocks, standard capacity cards in bytes
+    high_capacity: bool,
+}
+
+impl SdCard {
+    /// Route the card to the EMMC controller and initialize it, an eMMC is initialized if there is no SD card
+    pub fn initialize() -> Result<Self, SdError> {
+        route_pins();
+        reset_controller()?;
+        let base_clock = mailbox::clock_rate(mailbox::CLOCK_EMMC).unwrap_or(DEFAULT_BASE_CLOCK);
+        set_clock(base_clock, CLOCK_IDENTIFICATION)?;
+
+        command(CMD_GO_IDLE, 0)?;
+        let mmc = match command(CMD_SEND_IF_COND, IF_COND_ARG) {
+            Ok(response) if response & 0xFFF == IF_COND_ARG => false,
+            Ok(_) => return Err(SdError::Unsupported),
+            // an eMMC stays silent, the command line need to be reset after the timeout
+            Err(SdError::Timeout) => {
+                reset_lines(CONTROL1_SRST_CMD)?;
+                true
+            }
+            Err(err) => return Err(err),
+        };
+        let mut ocr = 0;
+        let ready = time::wait_for(OP_COND_TIMEOUT, || {
+            ocr = if mmc {
+                command(CMD_MMC_SEND_OP_COND, MMC_OP_COND_ARG)
+            } else {
+                command(CMD_APP_CMD, 0).and_then(|_| command(CMD_SEND_OP_COND, OP_COND_ARG))
+            }
+            .unwrap_or(0);
+            ocr & OP_COND_READY != 0
+        });
+        if !ready {
+            return Err(if mmc {
+                SdError::Timeout
+            } else {
+                SdError::Unsupported
+            });
+        }
+        command(CMD_ALL_SEND_CID, 0)?;
+        let rca = if mmc {
+            command(CMD_SEND_REL_ADDR, MMC_RCA)?;
+            MMC_RCA
+        } else {
+            command(CMD_SEND_REL_ADDR, 0)? & 0xFFFF_0000
+        };
+        set_clock(base_clock, CLOCK_TRANSFER)?;
+        command(CMD_CARD_SELECT, rca)?;
+        debug!("{} initialized", if mmc { "eMMC" } else { "SD card" });
+
+        Ok(SdCard {
+            // the OCR of an eMMC tells sector addressing with the same bit
+            high_capacity: ocr & OP_COND_HIGH_CAPACITY != 0,
+        })
+    }
+
+    /// Run the transfer of the given number of blocks, it is retried as long as the error is transient
+    fn with_retries<F>(
+        &mut self,
+        kind: &str,
+        block: u64,
+        count: usize,
+        mut transfer: F,
+    ) -> Result<(), SdError>
+    where
+        F: FnMut(&mut Self) -> Result<(), SdError>,
+    {
+        let mut result = transfer(self);
+        for retry in 1..=TRANSFER_RETRIES {
+            match result {
+                Err(err) if err.is_transient() => warn!(
+                    "{} {} blocks at {} failed with {:?}, retry {}",
+                    kind, count, block, err, retry
+                ),
+                _ => return result,
+            }
+            if retry == TRANSFER_RETRIES {
+                *self = SdCard::initialize()?;
+            } else {
+                recover()?;
+            }
+            result = transfer(self);
+        }
+        result
+    }
+
+    /// Start the transfer of the given number of blocks with the single or multiple block command
+    fn start(&self, block: u64, count: usize, single: u32, multiple: u32) -> Result<(), SdError> {
+        let address = if self.high_capacity {
+            block
+        } else {
+            block * BLOCK_SIZE as u64
+        };
+        if !time::wait_for(DATA_TIMEOUT, || unsafe {
+            read_volatile(EMMC_STATUS) & STATUS_DAT_INHIBIT == 0
+        }) {
+            return Err(SdError::DataTimeout);
+        }
+        unsafe { write_volatile(EMMC_BLKSIZECNT, (count as u32) << 16 | BLOCK_SIZE as u32) };
+        let cmd = if count == 1 { single } else { multiple };
+        command(cmd, address as u32).map(|_| ())
+    }
+
+    /// Read the blocks in a single attempt
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), SdError> {
+        let count = buffer.len() / BLOCK_SIZE;
+        self.start(block, count, CMD_READ_SINGLE, CMD_READ_MULTI)?;
+        for block in buffer.chunks_mut(BLOCK_SIZE) {
+            wait_interrupt(INT_READ_RDY, DATA_TIMEOUT)?;
+            for word in block.chunks_mut(4) {
+                let value = unsafe { read_volatile(EMMC_DATA) };
+                word.copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        wait_interrupt(INT_DATA_DONE, DATA_TIMEOUT)
+    }
+
+    /// Write the blocks in a single attempt, the card has finished programming them once the data line is released
+    /// for the next transfer
+    fn write(&mut self, block: u64, data: &[u8]) -> Result<(), SdError> {
+        let count = data.len() / BLOCK_SIZE;
+        self.start(block, count, CMD_WRITE_SINGLE, CMD_WRITE_MULTI)?;
+        for block in data.chunks(BLOCK_SIZE) {
+            wait_interrupt(INT_WRITE_RDY, DATA_TIMEOUT)?;
+            for word in block.chunks(4) {
+                let value = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+                unsafe { write_volatile(EMMC_DATA, value) };
+            }
+        }
+        wait_interrupt(INT_DATA_DONE, DATA_TIMEOUT)
+    }
+}
+
+impl SdError {
+    /// Check whether the error could go away with another attempt
+    fn is_transient(self) -> bool {
+        !matches!(self, SdError::Unsupported | SdError::BadBuffer)
+    }
+}
+
+impl BlockDevice for SdCard {
+    type Error = SdError;
+
+    fn read_blocks(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), SdError> {
+        if buffer.is_empty() || buffer.len() % BLOCK_SIZE != 0 {
+            return Err(SdError::BadBuffer);
+        }
+        let count = buffer.len() / BLOCK_SIZE;
+        self.with_retries("reading", block, count, |card| card.read(block, buffer))
+    }
+
+    fn write_blocks(&mut self, block: u64, data: &[u8]) -> Result<(), SdError> {
+        if data.is_empty() || data.len() % BLOCK_SIZE != 0 {
+            return Err(SdError::BadBuffer);
+        }
+        let count = data.len() / BLOCK_SIZE;
+        self.with_retries("writing", block, count, |card| card.write(block, data))
+    }
+}
+
+/// Route GPIO 48-53 to the EMMC controller (alternate function 3) with pull-ups on the command and data lines
+fn route_pins() {
+    unsafe {
+        // alternate function 3 is 0b111, so the function select bits of the pins are just set
+        write_volatile(GPIO_GPFSEL4, read_volatile(GPIO_GPFSEL4) | 0x3F << 24);
+        write_volatile(GPIO_GPFSEL5, read_volatile(GPIO_GPFSEL5) | 0xFFF);
+        write_volatile(GPIO_GPPUD, 2);
+        time::delay_cycles(150);
+        write_volatile(GPIO_GPPUDCLK1, 0x1F << (49 - 32));
+        time::delay_cycles(150);
+        write_volatile(GPIO_GPPUD, 0);
+        write_volatile(GPIO_GPPUDCLK1, 0);
+    }
+}
+
+/// Reset the command and data lines after a failed transfer and stop the transmission the card might still be in
+fn recover() -> Result<(), SdError> {
+    reset_lines(CONTROL1_SRST_CMD | CONTROL1_SRST_DATA)?;
+    // the card is not transmitting any more if the error has been detected before it started
+    let _ = command(CMD_STOP_TRANSMISSION, 0);
+    Ok(())
+}
+
+/// Reset the given lines of the controller and acknowledge all interrupts
+fn reset_lines(lines: u32) -> Result<(), SdError> {
+    unsafe { write_volatile(EMMC_CONTROL1, read_volatile(EMMC_CONTROL1) | lines) };
+    if !time::wait_for(RESET_TIMEOUT, || unsafe {
+        read_volatile(EMMC_CONTROL1) & lines == 0
+    }) {
+        return Err(SdError::Controller);
+    }
+    unsafe { write_volatile(EMMC_INTERRUPT, 0xFFFF_FFFF) };
+    Ok(())
+}
+
+fn reset_controller() -> Result<(), SdError> {
+    unsafe {
+        write_volatile(EMMC_CONTROL0, 0);
+        write_volatile(
+            EMMC_CONTROL1,
+            read_volatile(EMMC_CONTROL1) | CONTROL1_SRST_HC,
+        );
+    }
+    if !time::wait_for(RESET_TIMEOUT, || unsafe {
+        read_volatile(EMMC_CONTROL1) & CONTROL1_SRST_HC == 0
+    }) {
+        return Err(SdError::Controller);
+    }
+    unsafe {
+        write_volatile(
+            EMMC_CONTROL1,
+            read_volatile(EMMC_CONTROL1) | CONTROL1_CLK_INTLEN | CONTROL1_TOUNIT_MAX,
+        );
+        // all interrupts are signaled in the status register but none is routed to the interrupt controller
+        write_volatile(EMMC_INT_EN, 0);
+        write_volatile(EMMC_INT_MASK, 0xFFFF_FFFF);
+        write_volatile(EMMC_INTERRUPT, 0xFFFF_FFFF);
+    }
+    Ok(())
+}
+
+/// Set the card clock to the highest frequency not above the given one
+fn set_clock(base_clock: u32, frequency: u32) -> Result<(), SdError> {
+    if !time::wait_for(COMMAND_TIMEOUT, || unsafe {
+        read_volatile(EMMC_STATUS) & (STATUS_CMD_INHIBIT | STATUS_DAT_INHIBIT) == 0
+    }) {
+        return Err(SdError::Controller);
+    }
+    // controllers of host specification 3 support a 10 bit divider dividing by twice its value
+    let version = unsafe { read_volatile(EMMC_SLOTISR_VER) } >> 16 & 0xFF;
+    let divider = (base_clock + 2 * frequency - 1) / (2 * frequency);
+    let divider = if version >= 2 {
+        core::cmp::min(divider, 0x3FF)
+    } else {
+        // older controllers only divide by powers of 2
+        core::cmp::min(divider.next_power_of_two(), 0x80)
+    };
+    let bits = (divider & 0xFF) << 8 | (divider >> 8 & 0x3) << 6;
+    unsafe {
+        let control1 = read_volatile(EMMC_CONTROL1) & !CONTROL1_CLK_EN;
+        write_volatile(EMMC_CONTROL1, control1);
+        time::delay_us(10);
+        write_volatile(EMMC_CONTROL1, control1 & !CONTROL1_CLK_MASK | bits);
+        time::delay_us(10);
+        write_volatile(
+            EMMC_CONTROL1,
+            control1 & !CONTROL1_CLK_MASK | bits | CONTROL1_CLK_EN,
+        );
+    }
+    if !time::wait_for(RESET_TIMEOUT, || unsafe {
+        read_volatile(EMMC_CONTROL1) & CONTROL1_CLK_STABLE != 0
+    }) {
+        return Err(SdError::Controller);
+    }
+    Ok(())
+}
+
+/// Send the command and return the first word of the response. The command is sent again if the response is
+/// corrupted.
+fn command(cmd: u32, arg: u32) -> Result<u32, SdError> {
+    let mut result = send_command(cmd, arg);
+    for _ in 1..COMMAND_RETRIES {
+        if result != Err(SdError::CommandCrc) {
+            break;
+        }
+        result = send_command(cmd, arg);
+    }
+    result
+}
+
+fn send_command(cmd: u32, arg: u32) -> Result<u32, SdError> {
+    if !time::wait_for(COMMAND_TIMEOUT, || unsafe {
+        read_volatile(EMMC_STATUS) & STATUS_CMD_INHIBIT == 0
+    }) {
+        return Err(SdError::Timeout);
+    }
+    unsafe {
+        write_volatile(EMMC_INTERRUPT, 0xFFFF_FFFF);
+        write_volatile(EMMC_ARG1, arg);
+        write_volatile(EMMC_CMDTM, cmd);
+    }
+    wait_interrupt(INT_CMD_DONE, COMMAND_TIMEOUT)?;
+    Ok(unsafe { read_volatile(EMMC_RESP0) })
+}
+
+/// Wait for the interrupt status and acknowledge it
+fn wait_interrupt(mask: u32, timeout: Duration) -> Result<(), SdError> {
+    let mut status = 0;
+    if !time::wait_for(timeout, || {
+        status = unsafe { read_volatile(EMMC_INTERRUPT) };
+        status & (mask | INT_ERROR_MASK) != 0
+    }) {
+        return Err(if mask == INT_CMD_DONE {
+            SdError::Timeout
+        } else {
+            SdError::DataTimeout
+        });
+    }
+    unsafe { write_volatile(EMMC_INTERRUPT, status & (mask | INT_ERROR_MASK)) };
+    if status & INT_ERROR_MASK != 0 {
+        return Err(error(status));
+    }
+    Ok(())
+}
+
+/// The error signaled with the interrupt status
+fn error(status: u32) -> SdError {
+    if status & INT_COMMAND_TIMEOUT != 0 {
+        SdError::Timeout
+    } else if status & INT_COMMAND_CRC != 0 {
+        SdError::CommandCrc
+    } else if status & INT_DATA_TIMEOUT != 0 {
+        SdError::DataTimeout
+    } else if status & INT_DATA_CRC != 0 {
+        SdError::DataCrc
+    } else {
+        SdError::Command(status)
+    }
+}
@@ -0,0 +1,244 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Boot services
+//!
+//! A small table of services a bare-metal payload can use instead of bringing its own drivers just to print some
+//! results. The address of the table is passed to aarch64 payloads in x4. The services are aarch64 code and the
+//! table holds aarch64 function pointers, so aarch32 payloads get 0 in r4 and in their kernel header instead.
+//!
+//! The services are called after the loader has handed over to the payload, typically with the MMU switched off.
+//! They therefore access the hardware directly and never use any atomic operation or lock. The payload must not
+//! overwrite the memory of the loader to be able to use them.
+//!
+//! Kernels with the RusPiRo kernel header additionally get the [BoardInfo] describing the board they are running
+//! on. Once such a kernel signals its successful start it is acknowledged on the console with
+//! [BOOT_ACK] for the host to see.
+//!
+
+use crate::board::{
+    self, ARM_LOCAL_BASE, ARM_LOCAL_SIZE, AUX_BASE, PERIPHERAL_BASE, PERIPHERAL_SIZE,
+};
+use crate::mailbox;
+use crate::retained;
+use crate::time;
+use core::ptr::{read_volatile, write_volatile};
+
+/// The magic value at the start of the services table, "RPBS"
+pub const MAGIC: u32 = 0x5342_5052;
+/// The version of the services table layout
+pub const VERSION: u32 = 2;
+/// The magic value at the start of the board info, "RPBI"
+pub const BOARD_INFO_MAGIC: u32 = 0x4942_5052;
+/// The version of the board info layout
+pub const BOARD_INFO_VERSION: u32 = 1;
+/// The line sent to the console once the kernel has signaled its successful start
+pub const BOOT_ACK: &str = "RUSPIRO-BOOT-OK\r\n";
+
+const AUX_MU_IO: *mut u32 = board::register(AUX_BASE, 0x40);
+const AUX_MU_LSR: *mut u32 = board::register(AUX_BASE, 0x54);
+const LSR_DATA_READY: u32 = 1 << 0;
+const LSR_TX_EMPTY: u32 = 1 << 5;
+
+/// The maximum number of entries in the memory map
+const MAX_REGIONS: usize = 8;
+
+/// The services table passed to the payload
+#[repr(C)]
+pub struct BootServices {
+    /// Always [MAGIC]
+    pub magic: u32,
+    /// The [VERSION] of this table
+    pub version: u32,
+    /// Write a single character to the console
+    pub putc: extern "C" fn(c: u8),
+    /// Read a single character from the console, returns -1 if there is none available
+    pub getc: extern "C" fn() -> i32,
+    /// Read the free running system timer in microseconds
+    pub timer_read: extern "C" fn() -> u64,
+    /// Pass the buffer to the given mailbox channel and wait for the response. The buffer need to be 16 byte
+    /// aligned and must not be held in the data cache. Returns 0 on success and -1 on failure
+    pub mailbox_call: extern "C" fn(channel: u32, buffer: *mut u32) -> i32,
+    /// Copy up to ``max`` entries of the memory map to ``regions`` and return the number of entries available
+    pub memory_map: extern "C" fn(regions: *mut MemoryRegion, max: usize) -> usize,
+    /// Signal that the kernel has booted successfully, this clears the boot loop detection of the loader (since
+    /// version 2)
+    pub boot_succeeded: extern "C" fn(),
+}
+
+/// The kind of memory a [MemoryRegion] describes
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryKind {
+    /// RAM usable by the payload
+    Ram = 1,
+    /// RAM used by the loader itself, this need to be kept to be able to use the services
+    Loader = 2,
+    /// RAM assigned to the VideoCore
+    VideoCore = 3,
+    /// Memory mapped peripherals
+    Peripheral = 4,
+}
+
+/// An entry of the memory map
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    pub base: u64,
+    pub size: u64,
+    pub kind: MemoryKind,
+}
+
+/// Information about the board passed to kernels with the RusPiRo kernel header
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BoardInfo {
+    /// Always [BOARD_INFO_MAGIC]
+    pub magic: u32,
+    /// The [BOARD_INFO_VERSION] of this structure
+    pub version: u32,
+    /// The revision code of the board, 0 if unknown
+    pub revision: u32,
+    /// The number of cores
+    pub cores: u32,
+    /// The serial number of the board, 0 if unknown
+    pub serial: u64,
+    /// Base address and size of the memory assigned to the ARM
+    pub arm_memory: (u64, u64),
+    /// Base address and size of the memory assigned to the VideoCore
+    pub vc_memory: (u64, u64),
+    /// The base address of the peripherals
+    pub peripheral_base: u64,
+}
+
+/// The services table handed over to the payload
+pub static BOOT_SERVICES: BootServices = BootServices {
+    magic: MAGIC,
+    version: VERSION,
+    putc,
+    getc,
+    timer_read,
+    mailbox_call,
+    memory_map,
+    boot_succeeded,
+};
+
+/// The memory map is prepared before the handover while the mailbox could still be used with cache maintenance
+static mut MEMORY_MAP: [MemoryRegion; MAX_REGIONS] = [MemoryRegion {
+    base: 0,
+    size: 0,
+    kind: MemoryKind::Ram,
+}; MAX_REGIONS];
+static mut MEMORY_MAP_LEN: usize = 0;
+static mut BOARD_INFO: BoardInfo = BoardInfo {
+    magic: BOARD_INFO_MAGIC,
+    version: BOARD_INFO_VERSION,
+    revision: 0,
+    cores: board::CORES,
+    serial: 0,
+    arm_memory: (0, 0),
+    vc_memory: (0, 0),
+    peripheral_base: board::PERIPHERAL_BASE,
+};
+
+extern "C" {
+    /// linker symbols marking the memory used by the loader
+    static __loader_start: u8;
+    static __heap_end: u8;
+}
+
+/// Prepare the services for the payload and provide the address of the services table to be passed
+pub fn prepare() -> u64 {
+    let loader_start = unsafe { &__loader_start as *const u8 as u64 };
+    let heap_end = unsafe { &__heap_end as *const u8 as u64 };
+    let (arm_base, arm_size) = mailbox::arm_memory().unwrap_or((0, heap_end as u32));
+    let (arm_base, arm_end) = (arm_base as u64, arm_base as u64 + arm_size as u64);
+    // the heap of the loader may be configured to reach into the memory of the VideoCore
+    let loader_end = core::cmp::min(heap_end, arm_end);
+    let mut regions = [
+        region(arm_base, loader_start - arm_base, MemoryKind::Ram),
+        region(loader_start, loader_end - loader_start, MemoryKind::Loader),
+        region(loader_end, arm_end - loader_end, MemoryKind::Ram),
+        region(PERIPHERAL_BASE, PERIPHERAL_SIZE, MemoryKind::Peripheral),
+        region(ARM_LOCAL_BASE, ARM_LOCAL_SIZE, MemoryKind::Peripheral),
+        region(0, 0, MemoryKind::VideoCore),
+    ];
+    if let Ok((vc_base, vc_size)) = mailbox::vc_memory() {
+        regions[5] = region(vc_base as u64, vc_size as u64, MemoryKind::VideoCore);
+    }
+
+    unsafe {
+        MEMORY_MAP_LEN = 0;
+        for entry in regions.iter().filter(|entry| entry.size != 0) {
+            MEMORY_MAP[MEMORY_MAP_LEN] = *entry;
+            MEMORY_MAP_LEN += 1;
+        }
+    }
+
+    &BOOT_SERVICES as *const BootServices as u64
+}
+
+/// Prepare the board info for a kernel with the RusPiRo kernel header and provide its address to be passed
+pub fn board_info() -> u64 {
+    let to_u64 = |(base, size): (u32, u32)| (base as u64, size as u64);
+    unsafe {
+        BOARD_INFO.revision = mailbox::board_revision().unwrap_or(0);
+        BOARD_INFO.serial = mailbox::board_serial().unwrap_or(0);
+        BOARD_INFO.arm_memory = mailbox::arm_memory().map_or((0, 0), to_u64);
+        BOARD_INFO.vc_memory = mailbox::vc_memory().map_or((0, 0), to_u64);
+        &BOARD_INFO as *const BoardInfo as u64
+    }
+}
+
+const fn region(base: u64, size: u64, kind: MemoryKind) -> MemoryRegion {
+    MemoryRegion { base, size, kind }
+}
+
+extern "C" fn putc(c: u8) {
+    unsafe {
+        while read_volatile(AUX_MU_LSR) & LSR_TX_EMPTY == 0 {}
+        write_volatile(AUX_MU_IO, c as u32);
+    }
+}
+
+extern "C" fn getc() -> i32 {
+    unsafe {
+        if read_volatile(AUX_MU_LSR) & LSR_DATA_READY != 0 {
+            (read_volatile(AUX_MU_IO) & 0xFF) as i32
+        } else {
+            -1
+        }
+    }
+}
+
+extern "C" fn timer_read() -> u64 {
+    time::system_micros()
+}
+
+extern "C" fn mailbox_call(channel: u32, buffer: *mut u32) -> i32 {
+    match unsafe { mailbox::call_raw(channel as u8, buffer) } {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+extern "C" fn memory_map(regions: *mut MemoryRegion, max: usize) -> usize {
+    unsafe {
+        let len = MEMORY_MAP_LEN;
+        if !regions.is_null() {
+            for (idx, entry) in MEMORY_MAP[..core::cmp::min(len, max)].iter().enumerate() {
+                write_volatile(regions.add(idx), *entry);
+            }
+        }
+        len
+    }
+}
+
+extern "C" fn boot_succeeded() {
+    retained::boot_succeeded();
+    BOOT_ACK.bytes().for_each(putc);
+}
@@ -0,0 +1,268 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Kernel slots
+//!
+//! Verified kernels could be written to two raw partitions of the SD card, the slots A and B, see
+//! [crate::partition]. Without a file system in between a kernel written there could not be damaged by a host
+//! modifying the boot partition, and a write interrupted by a reset only affects the slot written.
+//!
+//! The first block of a slot holds the metadata of the kernel: its version, the transfer parameters and the hash of
+//! the binary, which follows from the second block on. A new kernel always replaces the older of both kernels, the
+//! newer one is kept until the new kernel has been written completely. The metadata is invalidated before the
+//! binary is written and written last, so a slot either holds a complete kernel or none. The hash is the CRC-32 of
+//! the binary.
+//!
+//! | Offset | Size | Content                                    |
+//! |--------|------|--------------------------------------------|
+//! | 0      | 4    | magic "RPKS"                               |
+//! | 4      | 4    | format of the metadata, 1                  |
+//! | 8      | 4    | version of the kernel, counting up         |
+//! | 12     | 4    | size of the binary in bytes                |
+//! | 16     | 1    | architecture, 32 or 64                     |
+//! | 17     | 1    | exception level to enter, 0 by default     |
+//! | 18     | 2    | flags of the extended header               |
+//! | 20     | 4    | hash of the binary                         |
+//! | 24     | 8    | address to load to, 0 by default           |
+//! | 32     | 4    | CRC-32 of the bytes before                 |
+//!
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use crate::crc;
+use crate::fat::{le16, le32, le64};
+use crate::partition::{self, Partition};
+use crate::sd::{SdCard, SdError};
+use alloc::{vec, vec::Vec};
+use ruspiro_loader_protocol::KernelTransfer;
+
+/// The magic value identifying the metadata of a slot, "RPKS"
+const MAGIC: u32 = 0x534B_5052;
+const FORMAT: u32 = 1;
+const METADATA_SIZE: usize = 36;
+/// The number of slots used, further raw kernel slots are ignored
+pub const SLOTS: usize = 2;
+/// The names of the slots
+pub const SLOT_NAMES: [char; SLOTS] = ['A', 'B'];
+
+/// Errors accessing the kernel slots
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlotError {
+    /// Accessing the SD card failed
+    Device(SdError),
+    /// The card has no valid partition table or less than two raw kernel slots
+    NoSlots,
+    /// The kernel does not fit into the slot, the size of the kernel is given
+    TooLarge(usize),
+    /// None of the slots holds a valid kernel
+    NoKernel,
+    /// The kernel read back after writing it does not match its hash
+    Verify,
+}
+
+/// The metadata of the kernel held by a slot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlotKernel {
+    /// The version of the kernel, each kernel written has a higher version than the kernels already in the slots
+    pub version: u32,
+    /// The size of the binary in bytes
+    pub size: u32,
+    pub aarch: u8,
+    pub entry_el: u8,
+    pub flags: u16,
+    /// The hash of the binary
+    pub hash: u32,
+    pub address: u64,
+}
+
+impl SlotKernel {
+    fn parse(block: &[u8]) -> Option<Self> {
+        if le32(&block[0..]) != MAGIC
+            || le32(&block[4..]) != FORMAT
+            || le32(&block[32..]) != crc::crc32(&block[..METADATA_SIZE - 4])
+        {
+            return None;
+        }
+        Some(SlotKernel {
+            version: le32(&block[8..]),
+            size: le32(&block[12..]),
+            aarch: block[16],
+            entry_el: block[17],
+            flags: le16(&block[18..]),
+            hash: le32(&block[20..]),
+            address: le64(&block[24..]),
+        })
+    }
+
+    fn to_block(&self) -> [u8; BLOCK_SIZE] {
+        let mut block = [0; BLOCK_SIZE];
+        block[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        block[4..8].copy_from_slice(&FORMAT.to_le_bytes());
+        block[8..12].copy_from_slice(&self.version.to_le_bytes());
+        block[12..16].copy_from_slice(&self.size.to_le_bytes());
+        block[16] = self.aarch;
+        block[17] = self.entry_el;
+        block[18..20].copy_from_slice(&self.flags.to_le_bytes());
+        block[20..24].copy_from_slice(&self.hash.to_le_bytes());
+        block[24..32].copy_from_slice(&self.address.to_le_bytes());
+        let check = crc::crc32(&block[..METADATA_SIZE - 4]);
+        block[32..36].copy_from_slice(&check.to_le_bytes());
+        block
+    }
+}
+
+/// The SD card with its kernel slots
+struct Slots {
+    card: SdCard,
+    partitions: [Partition; SLOTS],
+}
+
+impl Slots {
+    /// Initialize the SD card and find the first two raw kernel slots
+    fn open() -> Result<Self, SlotError> {
+        let mut card = SdCard::initialize().map_err(SlotError::Device)?;
+        let mut slots = partition::read(&mut card)
+            .map_err(|_| SlotError::NoSlots)?
+            .into_iter()
+            .filter(|partition| partition.is_kernel_slot());
+        match (slots.next(), slots.next()) {
+            (Some(a), Some(b)) => Ok(Slots {
+                card,
+                partitions: [a, b],
+            }),
+            _ => Err(SlotError::NoSlots),
+        }
+    }
+
+    /// The size of the largest binary the slot could hold, the first block is used by the metadata
+    fn capacity(&self, slot: usize) -> u64 {
+        self.partitions[slot].blocks.saturating_sub(1) * BLOCK_SIZE as u64
+    }
+
+    /// Read the metadata of the slot, ``None`` if it holds no kernel
+    fn metadata(&mut self, slot: usize) -> Result<Option<SlotKernel>, SlotError> {
+        let mut block = [0; BLOCK_SIZE];
+        self.card
+            .read_blocks(self.partitions[slot].start, &mut block)
+            .map_err(SlotError::Device)?;
+        Ok(SlotKernel::parse(&block))
+    }
+
+    /// Read the binary of the kernel in the slot, ``None`` if it does not match its hash
+    fn binary(&mut self, slot: usize, kernel: &SlotKernel) -> Result<Option<Vec<u8>>, SlotError> {
+        let size = kernel.size as usize;
+        if size as u64 > self.capacity(slot) {
+            return Ok(None);
+        }
+        let mut binary = vec![0; (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE];
+        if !binary.is_empty() {
+            self.card
+                .read_blocks(self.partitions[slot].start + 1, &mut binary)
+                .map_err(SlotError::Device)?;
+        }
+        binary.truncate(size);
+        Ok(Some(binary).filter(|binary| crc::crc32(binary) == kernel.hash))
+    }
+}
+
+/// Read the metadata of both slots, ``None`` for a slot holding no kernel. The binaries are not verified.
+pub fn list() -> Result<[Option<SlotKernel>; SLOTS], SlotError> {
+    let mut slots = Slots::open()?;
+    Ok([slots.metadata(0)?, slots.metadata(1)?])
+}
+
+/// Write the kernel to the slot holding the older kernel and return the slot and the version given to the kernel.
+/// The kernel is read back and verified after writing it.
+pub fn store(
+    aarch: u8,
+    entry_el: u8,
+    flags: u16,
+    address: u64,
+    binary: &[u8],
+) -> Result<(usize, u32), SlotError> {
+    let mut slots = Slots::open()?;
+    let kernels = [slots.metadata(0)?, slots.metadata(1)?];
+    let newest = kernels.iter().flatten().map(|kernel| kernel.version).max();
+    // an empty slot or the one with the older kernel is replaced
+    let slot = match (kernels[0], kernels[1]) {
+        (None, _) => 0,
+        (Some(_), None) => 1,
+        (Some(a), Some(b)) if a.version < b.version => 0,
+        _ => 1,
+    };
+    if binary.len() as u64 > slots.capacity(slot) {
+        return Err(SlotError::TooLarge(binary.len()));
+    }
+    let kernel = SlotKernel {
+        version: newest.map_or(1, |version| version.wrapping_add(1)),
+        size: binary.len() as u32,
+        aarch,
+        entry_el,
+        flags,
+        hash: crc::crc32(binary),
+        address,
+    };
+
+    let start = slots.partitions[slot].start;
+    slots
+        .card
+        .write_blocks(start, &[0; BLOCK_SIZE])
+        .map_err(SlotError::Device)?;
+    let full = binary.len() / BLOCK_SIZE * BLOCK_SIZE;
+    if full > 0 {
+        slots
+            .card
+            .write_blocks(start + 1, &binary[..full])
+            .map_err(SlotError::Device)?;
+    }
+    if full < binary.len() {
+        let mut last = [0; BLOCK_SIZE];
+        last[..binary.len() - full].copy_from_slice(&binary[full..]);
+        slots
+            .card
+            .write_blocks(start + 1 + (full / BLOCK_SIZE) as u64, &last)
+            .map_err(SlotError::Device)?;
+    }
+    if slots.binary(slot, &kernel)?.is_none() {
+        return Err(SlotError::Verify);
+    }
+    slots
+        .card
+        .write_blocks(start, &kernel.to_block())
+        .map_err(SlotError::Device)?;
+    Ok((slot, kernel.version))
+}
+
+/// Read the newest kernel matching its hash and return its slot and the kernel as if it had been transferred by the
+/// host. If the newest kernel is damaged, the other one is taken.
+pub fn load() -> Result<(usize, SlotKernel, KernelTransfer), SlotError> {
+    let mut slots = Slots::open()?;
+    let mut kernels = [(0, slots.metadata(0)?), (1, slots.metadata(1)?)];
+    kernels.sort_by_key(|(_, kernel)| core::cmp::Reverse(kernel.map(|kernel| kernel.version)));
+    for (slot, kernel) in kernels.iter() {
+        let kernel = match kernel {
+            Some(kernel) => kernel,
+            None => continue,
+        };
+        match slots.binary(*slot, kernel)? {
+            Some(binary) => {
+                let transfer = KernelTransfer {
+                    aarch: kernel.aarch,
+                    entry_el: kernel.entry_el,
+                    flags: kernel.flags,
+                    address: kernel.address,
+                    binary,
+                };
+                return Ok((*slot, *kernel, transfer));
+            }
+            None => warn!(
+                "kernel version {} in slot {} does not match its hash",
+                kernel.version, SLOT_NAMES[*slot]
+            ),
+        }
+    }
+    Err(SlotError::NoKernel)
+}
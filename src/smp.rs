@@ -0,0 +1,225 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Multiple cores
+//!
+//! Helpers for code running on several cores at the same time. The secondary cores run without the MMU, so their
+//! memory accesses bypass the caches of the main core and the exclusive loads and stores the atomics of ``core``
+//! and the locks of ``ruspiro-lock`` are built on do not work for them.
+//!
+//! The [BakeryLock] is Lamport's bakery algorithm, which only needs plain loads and stores. Each core writes its
+//! ticket to a cache line of its own and the tickets are cleaned to and read from memory bypassing the caches, so
+//! the lock works between cores with and without the MMU.
+//!
+//! The secondary cores wait in the spin table of the firmware stub until their entry address is written to it. The
+//! loader could [release] a core to run a function, e.g. for testing, and the core parks again in the spin table
+//! afterwards the same way the firmware has parked it. So the kernel finds the secondary cores in the state the
+//! arm64 boot protocol defines: interrupts masked, MMU and data cache off, waiting in ``wfe`` for the entry address
+//! and entered with x0-x3 cleared.
+//!
+
+use crate::board;
+use crate::time::{self, Duration};
+use core::cell::UnsafeCell;
+use core::ptr::{read_volatile, write_volatile};
+
+/// The number of cores of the SoC
+pub const CORES: usize = 4;
+
+/// Reasons why a secondary core could not be released
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmpError {
+    /// There is no secondary core with this number
+    NoSuchCore,
+    /// The core is not parked in the spin table
+    Busy,
+}
+
+/// The state of a secondary core shared with the main core, filling a cache line
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct CoreState {
+    /// The function the core has been released to run, 0 while the core is parked
+    entry: u64,
+}
+
+static mut CORE_STATES: [CoreState; CORES] = [CoreState { entry: 0 }; CORES];
+
+extern "C" {
+    /// The entry point of the released cores setting up their stack, see bootstrap.S
+    fn __secondary_entry();
+}
+
+/// The number of the core running this code
+pub fn core_id() -> usize {
+    let mpidr: u64;
+    unsafe { llvm_asm!("mrs $0, mpidr_el1" : "=r"(mpidr) ::: "volatile") };
+    (mpidr & 0x3) as usize
+}
+
+/// The ticket of a core, only written by this core and filling a cache line
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct Ticket {
+    choosing: u32,
+    number: u32,
+    /// How often the core holding the lock has taken it again
+    depth: u32,
+}
+
+/// A spinlock for the cores with and without the MMU. The core holding the lock could take it again, e.g. to write
+/// a log message while printing a line.
+pub struct BakeryLock {
+    tickets: UnsafeCell<[Ticket; CORES]>,
+}
+
+// the tickets are only accessed through the memory bypassing the caches
+unsafe impl Sync for BakeryLock {}
+
+impl BakeryLock {
+    pub const fn new() -> Self {
+        const FREE: Ticket = Ticket {
+            choosing: 0,
+            number: 0,
+            depth: 0,
+        };
+        BakeryLock {
+            tickets: UnsafeCell::new([FREE; CORES]),
+        }
+    }
+
+    /// Run ``f`` with the lock held
+    pub fn with<R>(&self, f: impl FnOnce() -> R) -> R {
+        let core = core_id();
+        let ticket = self.ticket(core);
+        let depth = unsafe { load(&(*ticket).depth) };
+        if depth == 0 {
+            self.acquire(core);
+        }
+        unsafe { store(&mut (*ticket).depth, depth + 1) };
+        let result = f();
+        unsafe { store(&mut (*ticket).depth, depth) };
+        if depth == 0 {
+            unsafe { store(&mut (*ticket).number, 0) };
+        }
+        result
+    }
+
+    fn ticket(&self, core: usize) -> *mut Ticket {
+        unsafe { (self.tickets.get() as *mut Ticket).add(core) }
+    }
+
+    /// Draw a number higher than the numbers of all waiting cores and wait until the cores with lower numbers, or
+    /// the same number and a lower core number, have released the lock
+    fn acquire(&self, core: usize) {
+        let ticket = self.ticket(core);
+        unsafe {
+            store(&mut (*ticket).choosing, 1);
+            let number = (0..CORES)
+                .map(|other| load(&(*self.ticket(other)).number))
+                .max()
+                .unwrap_or(0)
+                + 1;
+            store(&mut (*ticket).number, number);
+            store(&mut (*ticket).choosing, 0);
+            for other in (0..CORES).filter(|&other| other != core) {
+                let waiting = self.ticket(other);
+                while load(&(*waiting).choosing) != 0 {}
+                loop {
+                    let other_number = load(&(*waiting).number);
+                    if other_number == 0 || (other_number, other) > (number, core) {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Release the parked secondary core to run ``entry`` with its number, it parks again once ``entry`` returns
+pub fn release(core: usize, entry: fn(usize)) -> Result<(), SmpError> {
+    if core == 0 || core >= CORES {
+        return Err(SmpError::NoSuchCore);
+    }
+    unsafe {
+        if load(&CORE_STATES[core].entry) != 0 || load(spin_slot(core)) != 0 {
+            return Err(SmpError::Busy);
+        }
+        store(&mut CORE_STATES[core].entry, entry as usize as u64);
+        store(spin_slot(core), __secondary_entry as usize as u64);
+        llvm_asm!("sev" :::: "volatile");
+    }
+    Ok(())
+}
+
+/// Check whether the secondary core is running a function it has been released to
+pub fn is_running(core: usize) -> bool {
+    unsafe { load(&CORE_STATES[core].entry) != 0 }
+}
+
+/// Wait until all secondary cores are parked in the spin table. Returns whether they are.
+pub fn wait_parked(timeout: Duration) -> bool {
+    time::wait_for(timeout, || (1..CORES).all(|core| !is_running(core)))
+}
+
+/// Park the current secondary core in the spin table until an entry address is written to its slot. The core is
+/// entered there with x0-x3 cleared like the firmware stub does.
+pub fn park(core: usize) -> ! {
+    unsafe {
+        llvm_asm!("msr daifset, #0xf" :::: "volatile");
+        // the slot still holds the address the core has been released with
+        store(spin_slot(core), 0);
+        store(&mut CORE_STATES[core].entry, 0);
+        loop {
+            llvm_asm!("wfe" :::: "volatile");
+            let entry = load(spin_slot(core));
+            if entry != 0 {
+                llvm_asm!(
+                    "ic    iallu
+                     dsb   sy
+                     isb
+                     mov   x0, xzr
+                     mov   x1, xzr
+                     mov   x2, xzr
+                     mov   x3, xzr
+                     br    x4" :: "{x4}"(entry) : "x0", "x1", "x2", "x3" : "volatile"
+                );
+            }
+        }
+    }
+}
+
+/// Run the function the secondary core has been released to, called by the bootstrap code with the stack of the
+/// core set up
+#[no_mangle]
+extern "C" fn __secondary_main(core: usize) -> ! {
+    let entry = unsafe { load(&CORE_STATES[core].entry) };
+    if entry != 0 {
+        let entry: fn(usize) = unsafe { core::mem::transmute(entry as usize) };
+        entry(core);
+    }
+    park(core)
+}
+
+/// The slot of the core in the spin table
+fn spin_slot(core: usize) -> *mut u64 {
+    (board::SPIN_TABLE + core as u64 * 8) as *mut u64
+}
+
+/// Read the value from memory, a cached copy is written back and dropped first
+unsafe fn load<T: Copy>(value: *const T) -> T {
+    llvm_asm!("dc civac, $0
+               dsb sy" :: "r"(value) :: "volatile");
+    read_volatile(value)
+}
+
+/// Write the value to memory, a cached copy is written back right away
+unsafe fn store<T: Copy>(value: *mut T, data: T) {
+    write_volatile(value, data);
+    llvm_asm!("dc civac, $0
+               dsb sy" :: "r"(value) :: "volatile");
+}
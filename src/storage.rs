@@ -0,0 +1,117 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Boot storage
+//!
+//! Access to the files on the boot partition of the SD card. The card is initialized and the file system mounted
+//! for each access, as the card might have been replaced while the loader has been waiting.
+//!
+//! The boot partition is the first FAT32 or exFAT partition of the master boot record or the GPT, see
+//! [crate::partition]. Cards formatted without a partition table carry the file system at the very start of the
+//! card.
+//!
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use crate::exfat::{self, ExFat};
+use crate::fat::{self, DirEntry, FatError, FileSystem};
+use crate::partition::{self, Partition, PartitionError};
+use crate::sd::{SdCard, SdError};
+use alloc::vec::Vec;
+
+/// Errors accessing the files of the SD card
+pub type StorageError = FatError<SdError>;
+
+/// The mounted boot partition
+pub enum Volume {
+    Fat(FileSystem<SdCard>),
+    ExFat(ExFat<SdCard>),
+}
+
+impl Volume {
+    /// Find the file or directory with the given path
+    pub fn find(&mut self, path: &str) -> Result<DirEntry, StorageError> {
+        match self {
+            Volume::Fat(fs) => fs.find(path),
+            Volume::ExFat(fs) => fs.find(path),
+        }
+    }
+
+    /// Read the entries of the directory with the given path
+    pub fn list(&mut self, path: &str) -> Result<Vec<DirEntry>, StorageError> {
+        match self {
+            Volume::Fat(fs) => fs.list(path),
+            Volume::ExFat(fs) => fs.list(path),
+        }
+    }
+
+    /// Read the whole file, files larger than ``max_size`` are refused
+    pub fn read_file(
+        &mut self,
+        entry: &DirEntry,
+        max_size: usize,
+    ) -> Result<Vec<u8>, StorageError> {
+        match self {
+            Volume::Fat(fs) => fs.read_file(entry, max_size),
+            Volume::ExFat(fs) => fs.read_file(entry, max_size),
+        }
+    }
+}
+
+/// Initialize the SD card and mount its boot partition
+pub fn mount() -> Result<Volume, StorageError> {
+    let mut card = SdCard::initialize().map_err(FatError::Device)?;
+    let mut block = [0; BLOCK_SIZE];
+    card.read_blocks(0, &mut block).map_err(FatError::Device)?;
+    let start = if fat::is_boot_sector(&block) || exfat::is_boot_sector(&block) {
+        0
+    } else {
+        let start = read_partitions(&mut card)?
+            .iter()
+            .find(|partition| partition.is_boot())
+            .map(|partition| partition.start)
+            .ok_or(FatError::NoFileSystem)?;
+        card.read_blocks(start, &mut block)
+            .map_err(FatError::Device)?;
+        start
+    };
+    if fat::is_boot_sector(&block) {
+        FileSystem::mount(card, start).map(Volume::Fat)
+    } else if exfat::is_boot_sector(&block) {
+        ExFat::mount(card, start).map(Volume::ExFat)
+    } else {
+        Err(FatError::NoFileSystem)
+    }
+}
+
+/// Initialize the SD card and read its partitions
+pub fn partitions() -> Result<Vec<Partition>, StorageError> {
+    let mut card = SdCard::initialize().map_err(FatError::Device)?;
+    read_partitions(&mut card)
+}
+
+/// Read the entries of the directory with the given path on the boot partition
+pub fn list(path: &str) -> Result<Vec<DirEntry>, StorageError> {
+    mount()?.list(path)
+}
+
+/// Read the file with the given path from the boot partition, files larger than ``max_size`` are refused
+pub fn read_file(path: &str, max_size: usize) -> Result<Vec<u8>, StorageError> {
+    let mut volume = mount()?;
+    let entry = volume.find(path)?;
+    volume.read_file(&entry, max_size)
+}
+
+/// Read the partition table of the card, a broken partition table is reported as missing file system
+fn read_partitions(card: &mut SdCard) -> Result<Vec<Partition>, StorageError> {
+    partition::read(card).map_err(|err| match err {
+        PartitionError::Device(err) => FatError::Device(err),
+        err => {
+            warn!("no valid partition table: {:?}", err);
+            FatError::NoFileSystem
+        }
+    })
+}
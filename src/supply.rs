@@ -0,0 +1,62 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Supply monitoring
+//!
+//! The firmware reports an under-voltage of the supply and the throttling of the cores through the mailbox. A
+//! supply that could not keep up with the board frequently corrupts the serial reception at high baud rates, so the
+//! loader polls the state while waiting for requests and warns whenever a condition starts. While the supply
+//! voltage is too low the heartbeat of the activity LED stops with the LED switched on.
+//!
+
+use crate::mailbox;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+const UNDER_VOLTAGE: u32 = 1 << 0;
+const FREQUENCY_CAPPED: u32 = 1 << 1;
+const THROTTLED: u32 = 1 << 2;
+const TEMPERATURE_LIMIT: u32 = 1 << 3;
+/// The bits of the current state, the upper half of the state holds the conditions that occurred since power on
+const CURRENT_STATE: u32 = 0xF;
+
+/// The current state at the last check
+static STATE: AtomicU32 = AtomicU32::new(0);
+/// An under-voltage has been detected since [take_under_voltage] was called the last time
+static UNDER_VOLTAGE_SEEN: AtomicBool = AtomicBool::new(false);
+
+/// Query the state from the firmware and warn about the conditions that have started since the last check
+pub fn check() {
+    let state = match mailbox::throttled() {
+        Ok(state) => state & CURRENT_STATE,
+        Err(_) => return,
+    };
+    let previous = STATE.swap(state, Ordering::AcqRel);
+    let started = state & !previous;
+    if started & UNDER_VOLTAGE != 0 {
+        UNDER_VOLTAGE_SEEN.store(true, Ordering::Release);
+        warn!("under-voltage detected, the serial reception might get corrupted");
+    }
+    if started & (FREQUENCY_CAPPED | THROTTLED) != 0 {
+        warn!("the cores are throttled by the firmware");
+    }
+    if started & TEMPERATURE_LIMIT != 0 {
+        warn!("soft temperature limit reached");
+    }
+    if previous != 0 && state == 0 {
+        info!("supply voltage and temperature back to normal");
+    }
+}
+
+/// Check whether the supply voltage was too low at the last check
+pub fn is_under_voltage() -> bool {
+    STATE.load(Ordering::Acquire) & UNDER_VOLTAGE != 0
+}
+
+/// Check whether the supply voltage has been too low at any check since the last call of this function
+pub fn take_under_voltage() -> bool {
+    UNDER_VOLTAGE_SEEN.swap(false, Ordering::AcqRel) || is_under_voltage()
+}
@@ -0,0 +1,243 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Time
+//!
+//! Points in time and durations based on the ARM generic timer. The physical counter is running with the frequency
+//! the firmware has configured in CNTFRQ_EL0 and is accessible in all exception levels the loader and the boot
+//! services are running in. Reading it neither requires the MMU nor any lock, so the same time base is used for
+//! every timeout, delay and measurement of the loader.
+//!
+//! Delays shorter than the resolution needed for a [Duration] are given in core cycles. The core clock is calibrated
+//! against the counter once at startup, so these delays take the same time whatever core clock the firmware has
+//! configured, and still work under QEMU where the counter is the only reliable clock.
+//!
+
+use crate::board::{self, ARM_LOCAL_BASE, SYSTIMER_BASE};
+use crate::pmu;
+pub use core::time::Duration;
+use core::{
+    ops::{Add, Sub},
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A point in time measured with the physical counter of the generic timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    ticks: u64,
+}
+
+impl Instant {
+    /// The current point in time
+    pub fn now() -> Self {
+        let ticks: u64;
+        unsafe {
+            // the isb ensures the counter is not read ahead of the preceding instructions
+            llvm_asm!("isb
+                       mrs $0, cntpct_el0" : "=r"(ticks) ::: "volatile")
+        };
+        Instant { ticks }
+    }
+
+    /// The time passed since this point in time
+    pub fn elapsed(&self) -> Duration {
+        Instant::now() - *self
+    }
+
+    /// The time passed between the earlier point in time and this one, zero if the earlier one is actually later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        from_ticks(self.ticks.saturating_sub(earlier.ticks))
+    }
+
+    /// The raw counter value of this point in time
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant {
+            ticks: self.ticks.saturating_add(ticks(duration)),
+        }
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// A timeout starting with its creation
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    end: Instant,
+}
+
+impl Deadline {
+    /// Create a deadline that expires after the given duration from now
+    pub fn after(timeout: Duration) -> Self {
+        Deadline {
+            end: Instant::now() + timeout,
+        }
+    }
+
+    /// Check whether the deadline has passed
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.end
+    }
+
+    /// The time left until the deadline passes
+    pub fn remaining(&self) -> Duration {
+        self.end - Instant::now()
+    }
+}
+
+/// The frequency of the physical counter in Hz
+pub fn frequency() -> u64 {
+    let freq: u64;
+    unsafe { llvm_asm!("mrs $0, cntfrq_el0" : "=r"(freq) ::: "volatile") };
+    freq
+}
+
+/// Convert the duration into ticks of the physical counter
+pub fn ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * frequency() as u128 / 1_000_000_000) as u64
+}
+
+/// Convert ticks of the physical counter into a duration
+pub fn from_ticks(ticks: u64) -> Duration {
+    let freq = frequency();
+    let secs = ticks / freq;
+    let nanos = (ticks % freq) * 1_000_000_000 / freq;
+    Duration::new(secs, nanos as u32)
+}
+
+const SYSTIMER_CLO: *mut u32 = board::register(SYSTIMER_BASE, 0x04);
+const SYSTIMER_CHI: *mut u32 = board::register(SYSTIMER_BASE, 0x08);
+
+/// The ARM local interrupt routing of the timers of core 0, the EL2 timer raises an IRQ with bit 2 set
+const CORE0_TIMER_IRQCNTL: *mut u32 = board::register(ARM_LOCAL_BASE, 0x40);
+const TIMER_CNTHP_IRQ: u32 = 1 << 2;
+
+/// Sleep the core in ``wfi`` until an interrupt is pending or the deadline has passed. The EL2 timer raises an
+/// interrupt at the deadline to wake the core and is switched off again before returning, so it never reaches an
+/// interrupt handler. The caller should mask the interrupts, otherwise one arriving right before the ``wfi`` is
+/// handled without waking the core and the core sleeps until the deadline. A pending interrupt is handled once the
+/// caller unmasks the interrupts again.
+pub fn wait_for_interrupt(deadline: Instant) {
+    unsafe {
+        llvm_asm!("msr cnthp_cval_el2, $0
+                   msr cnthp_ctl_el2, $1
+                   isb" :: "r"(deadline.ticks), "r"(1u64) :: "volatile");
+        write_volatile(
+            CORE0_TIMER_IRQCNTL,
+            read_volatile(CORE0_TIMER_IRQCNTL) | TIMER_CNTHP_IRQ,
+        );
+        llvm_asm!("dsb sy
+                   wfi" :::: "volatile");
+        llvm_asm!("msr cnthp_ctl_el2, xzr" :::: "volatile");
+        write_volatile(
+            CORE0_TIMER_IRQCNTL,
+            read_volatile(CORE0_TIMER_IRQCNTL) & !TIMER_CNTHP_IRQ,
+        );
+    }
+}
+
+/// The microseconds passed since power on, read from the free running 1MHz system timer. The firmware starts it
+/// long before the loader, so it shows the whole boot time.
+pub fn system_micros() -> u64 {
+    unsafe {
+        // the two halves of the timer are not read atomically, so re-read if the upper half has changed
+        loop {
+            let hi = read_volatile(SYSTIMER_CHI);
+            let lo = read_volatile(SYSTIMER_CLO);
+            if read_volatile(SYSTIMER_CHI) == hi {
+                return (hi as u64) << 32 | lo as u64;
+            }
+        }
+    }
+}
+
+/// Busy wait for the given duration. All memory accesses and instructions issued before are completed before the
+/// delay starts, so the delay could be used to let a device or system register change settle.
+pub fn sleep(duration: Duration) {
+    barrier();
+    let deadline = Deadline::after(duration);
+    while !deadline.expired() {}
+}
+
+/// Busy wait for the given number of microseconds, see [sleep]
+pub fn delay_us(micros: u64) {
+    sleep(Duration::from_micros(micros));
+}
+
+/// Busy wait for the given number of core cycles, see [sleep]. The cycles are converted into counter ticks with the
+/// calibrated core clock and rounded up, so the delay is never shorter than requested.
+pub fn delay_cycles(cycles: u64) {
+    barrier();
+    let clock = CORE_CLOCK.load(Ordering::Relaxed) as u128;
+    let ticks = (cycles as u128 * frequency() as u128 + clock - 1) / clock;
+    let end = Instant::now().ticks.saturating_add(ticks as u64);
+    while Instant::now().ticks < end {}
+}
+
+/// The core clock in Hz, the default core clock of the Raspberry Pi 3 until calibrated
+static CORE_CLOCK: AtomicU64 = AtomicU64::new(1_200_000_000);
+/// The time the cycle counter is compared to the physical counter while calibrating
+const CALIBRATION_TIME: Duration = Duration::from_millis(1);
+
+/// Calibrate the core clock used by [delay_cycles] against the physical counter. The cycle counter of the
+/// performance monitors is only used while calibrating and left disabled afterwards. If it does not count, as
+/// with some emulators, the default core clock is kept.
+pub fn calibrate() {
+    let (nanos, counters) = pmu::measure(|| {
+        let start = Instant::now();
+        let deadline = start + CALIBRATION_TIME;
+        let mut end = start;
+        while end < deadline {
+            end = Instant::now();
+        }
+        (end - start).as_nanos()
+    });
+    if counters.cycles != 0 && nanos != 0 {
+        let clock = counters.cycles as u128 * 1_000_000_000 / nanos;
+        CORE_CLOCK.store(clock as u64, Ordering::Relaxed);
+    }
+}
+
+/// The core clock in Hz as calibrated
+pub fn core_clock() -> u64 {
+    CORE_CLOCK.load(Ordering::Relaxed)
+}
+
+/// Complete all memory accesses and instructions issued so far
+pub fn barrier() {
+    unsafe {
+        llvm_asm!("dsb sy
+                   isb" :::: "volatile")
+    };
+}
+
+/// Wait until the condition is met or the timeout has passed. Returns whether the condition has been met.
+pub fn wait_for<F: FnMut() -> bool>(timeout: Duration, mut condition: F) -> bool {
+    let deadline = Deadline::after(timeout);
+    loop {
+        if condition() {
+            return true;
+        }
+        if deadline.expired() {
+            return false;
+        }
+    }
+}
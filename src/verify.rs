@@ -0,0 +1,114 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann
+ * License: Apache License 2.0
+ **********************************************************************************************************************/
+
+//! # Kernel verification
+//!
+//! The host could put a verification header in front of the binary it sends, all values little endian:
+//!
+//! | Offset | Size | Content                                                          |
+//! |--------|------|------------------------------------------------------------------|
+//! | 0      | 4    | magic ``"RPVH"``                                                 |
+//! | 4      | 2    | version of the header, 1                                         |
+//! | 6      | 2    | size of the header, 112                                          |
+//! | 8      | 4    | size of the payload following the header                         |
+//! | 12     | 4    | reserved, 0                                                      |
+//! | 16     | 32   | SHA-256 of the payload                                           |
+//! | 48     | 64   | ed25519 signature of the SHA-256, 0 if the payload is not signed |
+//!
+//! The payload need to match its digest. Data beyond the payload, like the padding of an XMODEM transfer, is
+//! dropped. Built with the ``signed_kernels`` feature the loader only accepts payloads signed with the secret key
+//! of the public key given with ``RUSPIRO_LOADER_PUBLIC_KEY`` at build time.
+//!
+
+#[cfg(feature = "signed_kernels")]
+use crate::ed25519;
+use crate::sha2;
+use core::ops::Range;
+
+const MAGIC: &[u8; 4] = b"RPVH";
+const VERSION: u16 = 1;
+/// The size of the verification header
+pub const HEADER_SIZE: usize = 112;
+const DIGEST_OFFSET: usize = 16;
+const SIGNATURE_OFFSET: usize = 48;
+
+/// Kernels need to be signed
+pub const SIGNATURE_REQUIRED: bool = cfg!(feature = "signed_kernels");
+
+/// The public key the signatures are verified with, generated by the build script
+#[cfg(feature = "signed_kernels")]
+const PUBLIC_KEY: [u8; ed25519::PUBLIC_KEY_SIZE] =
+    include!(concat!(env!("OUT_DIR"), "/public_key.rs"));
+
+/// Reasons why a binary is refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerifyError {
+    /// The verification header is missing or malformed
+    BadHeader,
+    /// The payload does not match the digest of the header
+    DigestMismatch,
+    /// The payload is not signed but the loader requires a signature
+    Unsigned,
+    /// The signature does not match the payload or the public key
+    BadSignature,
+}
+
+/// Check whether the binary starts with a verification header
+pub fn has_header(binary: &[u8]) -> bool {
+    binary.len() >= MAGIC.len() && &binary[..4] == MAGIC
+}
+
+/// Verify the payload following the verification header at the start of the binary and provide its range within
+/// the binary
+pub fn verify(binary: &[u8]) -> Result<Range<usize>, VerifyError> {
+    if !has_header(binary)
+        || binary.len() < HEADER_SIZE
+        || le16(binary, 4) != VERSION
+        || le16(binary, 6) as usize != HEADER_SIZE
+    {
+        return Err(VerifyError::BadHeader);
+    }
+    let size = le32(binary, 8) as usize;
+    if size > binary.len() - HEADER_SIZE {
+        return Err(VerifyError::BadHeader);
+    }
+    let payload = HEADER_SIZE..HEADER_SIZE + size;
+    let digest = sha2::sha256(&binary[payload.clone()]);
+    if digest[..] != binary[DIGEST_OFFSET..DIGEST_OFFSET + digest.len()] {
+        return Err(VerifyError::DigestMismatch);
+    }
+    check_signature(&digest, &binary[SIGNATURE_OFFSET..HEADER_SIZE])?;
+    Ok(payload)
+}
+
+#[cfg(feature = "signed_kernels")]
+fn check_signature(digest: &[u8; 32], signature: &[u8]) -> Result<(), VerifyError> {
+    if signature.iter().all(|&byte| byte == 0) {
+        return Err(VerifyError::Unsigned);
+    }
+    let mut bytes = [0; ed25519::SIGNATURE_SIZE];
+    bytes.copy_from_slice(signature);
+    if ed25519::verify(&PUBLIC_KEY, digest, &bytes) {
+        Ok(())
+    } else {
+        Err(VerifyError::BadSignature)
+    }
+}
+
+/// Without the ``signed_kernels`` feature there is no key to check the signature with
+#[cfg(not(feature = "signed_kernels"))]
+fn check_signature(_digest: &[u8; 32], _signature: &[u8]) -> Result<(), VerifyError> {
+    Ok(())
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    data[offset] as u16 | (data[offset + 1] as u16) << 8
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    le16(data, offset) as u32 | (le16(data, offset + 2) as u32) << 16
+}